@@ -0,0 +1,110 @@
+// This benchmark compares the permission evaluation of auth_guard
+// with the previous implementation, that took a write lock on
+// a HashMap for every request, under concurrent load.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use arc_swap::ArcSwap;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use landing_form::{middleware::auth_guard::has_permission, utils::permissions::PermissionTrie};
+
+// The number of permission checks performed by every thread.
+const CHECKS_PER_THREAD: usize = 1_000;
+
+/// This function generates a permission table similar to the one
+/// used by the application.
+fn allowed_roles() -> HashMap<String, HashSet<String>> {
+    let mut allowed_roles: HashMap<String, HashSet<String>> = HashMap::new();
+
+    for route in ["/metrics", "/dispatch_email", "/admin", "/admin/leads"] {
+        allowed_roles.insert(
+            route.to_string(),
+            HashSet::from(["Admin".to_string(), "Manager".to_string()]),
+        );
+    } // end for
+
+    allowed_roles
+} // end fn allowed_roles
+
+/// This is the previous implementation of has_permission.
+/// NOTE: The write lock is intentional, this is what is measured.
+#[allow(clippy::readonly_write_lock)]
+fn has_permission_rwlock(
+    roles: &[String],
+    path: &str,
+    allowed_routes: &RwLock<HashMap<String, HashSet<String>>>,
+) -> bool {
+    let allowed_routes = allowed_routes.write().unwrap();
+    let mut assembled_path = String::new();
+
+    for part in path.split('/') {
+        if !part.is_empty() {
+            assembled_path.push_str(&format!("/{}", part));
+        } // end if
+
+        if let Some(allowed_roles) = allowed_routes.get(&assembled_path) {
+            if !roles.iter().any(|role| allowed_roles.contains(role)) {
+                return false;
+            } // end if
+        } // end if
+    } // end for
+
+    true
+} // end fn has_permission_rwlock
+
+/// This function runs the same number of checks on every thread
+/// and waits for all of them to finish.
+fn run_concurrently<F>(threads: usize, check: F)
+where
+    F: Fn(&[String], &str) -> bool + Sync,
+{
+    let roles = vec!["User".to_string(), "Manager".to_string()];
+    let paths = ["/metrics", "/admin/leads/export", "/insert", "/auth/login"];
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for i in 0..CHECKS_PER_THREAD {
+                    black_box(check(&roles, paths[i % paths.len()]));
+                } // end for
+            });
+        } // end for
+    });
+} // end fn run_concurrently
+
+fn permission_checks(c: &mut Criterion) {
+    let rwlock = Arc::new(RwLock::new(allowed_roles()));
+    let trie = Arc::new(ArcSwap::from_pointee(PermissionTrie::compile(
+        &allowed_roles(),
+    )));
+
+    let mut group = c.benchmark_group("has_permission");
+    for threads in [1, 4, 16] {
+        group.bench_with_input(
+            BenchmarkId::new("rwlock", threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    run_concurrently(threads, |roles, path| {
+                        has_permission_rwlock(roles, path, &rwlock)
+                    })
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("trie", threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    run_concurrently(threads, |roles, path| has_permission(roles, path, &trie))
+                })
+            },
+        );
+    } // end for
+    group.finish();
+} // end fn permission_checks
+
+criterion_group!(benches, permission_checks);
+criterion_main!(benches);
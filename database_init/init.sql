@@ -1,15 +1,66 @@
+-- The trigram indexes keep the search of the leads fast.
+CREATE EXTENSION IF NOT EXISTS "pg_trgm";
+
+-- This table contains the landing forms, every landing page
+-- submits its own form identified by the public slug.
+CREATE TABLE "forms" (
+    "id" SERIAL PRIMARY KEY,
+    "slug" VARCHAR(50) NOT NULL UNIQUE,
+    "title" VARCHAR(100) NOT NULL,
+    -- The address the emails of the form are sent from.
+    "sender" VARCHAR(255) NOT NULL,
+    -- The welcome email can contain {name}, {confirmation_link},
+    -- {waitlist_link} and {consent_link}.
+    "welcome_subject" VARCHAR(255) NOT NULL,
+    "welcome_template" TEXT NOT NULL,
+    -- The pages the client is redirected to.
+    "success_redirect" TEXT DEFAULT NULL,
+    -- NOTE: It is deprecated and ignored, the returning leads get
+    -- the same response as the new ones.
+    "duplicate_redirect" TEXT DEFAULT NULL,
+    "confirmed_redirect" TEXT DEFAULT NULL,
+    -- The optional fields that are required by the form (e.g. email).
+    "required_fields" TEXT[] NOT NULL DEFAULT '{}',
+    -- The addresses that are notified about every new lead.
+    "notification_recipients" TEXT[] NOT NULL DEFAULT '{}',
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- The submissions have to pass a CAPTCHA.
+    "captcha_required" BOOLEAN NOT NULL DEFAULT FALSE
+);
+
 -- This table contains some general information about a user.
 CREATE TABLE "users" (
     "id" SERIAL PRIMARY KEY,
     "name" VARCHAR(50) NOT NULL,
-    "email" VARCHAR(50) DEFAULT NULL,
+    -- The email is stored lowercased with an ASCII domain,
+    -- RFC 5321 allows up to 254 characters.
+    "email" VARCHAR(254) DEFAULT NULL,
+    -- The phone number is stored in E.164 (e.g. "+79991234567"),
+    -- the country calling code is kept separately as well.
     "phone_number_code" INT NOT NULL,
-    "phone_number" VARCHAR(15) NOT NULL,
+    "phone_number" VARCHAR(16) NOT NULL UNIQUE,
     "password" VARCHAR(50) DEFAULT NULL,
     "token" VARCHAR(200) DEFAULT NULL,
-    "verified" BOOLEAN DEFAULT FALSE NOT NULL
+    "verified" BOOLEAN DEFAULT FALSE NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- A subscription stays pending until the email is confirmed.
+    "confirmed_at" TIMESTAMPTZ DEFAULT NULL,
+    -- The values of the custom fields declared in the form definition.
+    "custom_fields" JSONB NOT NULL DEFAULT '{}',
+    -- The form the user has subscribed with.
+    "form_id" INT DEFAULT NULL,
+    FOREIGN KEY (form_id) REFERENCES "forms" (id) ON DELETE SET NULL
 );
 
+-- These indexes serve the search of the leads, i.e. the text in the
+-- names, the emails and the phone numbers, and the pages by time and by name.
+CREATE INDEX "users_name_trgm_idx" ON "users" USING GIN ("name" gin_trgm_ops);
+CREATE INDEX "users_email_trgm_idx" ON "users" USING GIN ("email" gin_trgm_ops);
+CREATE INDEX "users_phone_number_trgm_idx" ON "users" USING GIN ("phone_number" gin_trgm_ops);
+CREATE INDEX "users_created_at_idx" ON "users" ("created_at", "id");
+CREATE INDEX "users_name_idx" ON "users" ("name", "id");
+CREATE INDEX "users_form_id_idx" ON "users" ("form_id");
+
 /* 
     This table contains some roles that could be
     assigned to the users.
@@ -20,13 +71,349 @@ CREATE TABLE "roles" (
     "description" TEXT 
 );
 
--- This table joins "users" table with "roles" table.
+/*
+    This table joins "users" table with "roles" table.
+    A grant with "expires_at" is temporary, it is ignored
+    after that moment and removed by a background job.
+*/
 CREATE TABLE "users_roles" (
     "id" SERIAL PRIMARY KEY,
     "user_id" INT NOT NULL,
     "role_id" INT NOT NULL,
-    FOREIGN KEY (user_id) REFERENCES "users" (id),
-    FOREIGN KEY (role_id) REFERENCES "roles" (id)
+    "granted_by" INT DEFAULT NULL,
+    "expires_at" TIMESTAMPTZ DEFAULT NULL,
+    "reason" TEXT DEFAULT NULL,
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE,
+    FOREIGN KEY (role_id) REFERENCES "roles" (id),
+    FOREIGN KEY (granted_by) REFERENCES "users" (id) ON DELETE SET NULL
+);
+
+CREATE INDEX "users_roles_expires_at_idx" ON "users_roles" ("expires_at")
+    WHERE "expires_at" IS NOT NULL;
+
+-- This table contains an append-only log of important events.
+CREATE TABLE "audit_log" (
+    "id" SERIAL PRIMARY KEY,
+    "user_id" INT DEFAULT NULL,
+    "action" VARCHAR(50) NOT NULL,
+    "details" JSONB NOT NULL DEFAULT '{}',
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- This table contains the marketing attribution of the leads,
+-- i.e. where the client came from when they subscribed.
+CREATE TABLE "lead_attribution" (
+    "id" SERIAL PRIMARY KEY,
+    "user_id" INT NOT NULL,
+    "utm_source" VARCHAR(255) DEFAULT NULL,
+    "utm_medium" VARCHAR(255) DEFAULT NULL,
+    "utm_campaign" VARCHAR(255) DEFAULT NULL,
+    "utm_term" VARCHAR(255) DEFAULT NULL,
+    "utm_content" VARCHAR(255) DEFAULT NULL,
+    "referrer" TEXT DEFAULT NULL,
+    "landing_url" TEXT DEFAULT NULL,
+    "user_agent" TEXT DEFAULT NULL,
+    "ip" VARCHAR(45) DEFAULT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE
+);
+
+CREATE INDEX "lead_attribution_user_id_idx" ON "lead_attribution" ("user_id");
+CREATE INDEX "lead_attribution_campaign_idx"
+    ON "lead_attribution" ("utm_source", "utm_medium", "utm_campaign");
+
+-- This table contains every submission of the landing forms, the lead
+-- is created by the first one and is filled in by the next ones.
+CREATE TABLE "lead_submissions" (
+    "id" SERIAL PRIMARY KEY,
+    "user_id" INT NOT NULL,
+    "form_id" INT DEFAULT NULL,
+    -- The lead has been created by this submission.
+    "created_lead" BOOLEAN NOT NULL,
+    -- "form" or "import", the imported leads get no emails.
+    "source" VARCHAR(16) NOT NULL DEFAULT 'form',
+    -- The submitted data, the email and the phone number are normalized.
+    "name" VARCHAR(50) NOT NULL,
+    "email" VARCHAR(254) DEFAULT NULL,
+    "phone_number" VARCHAR(16) NOT NULL,
+    "custom_fields" JSONB NOT NULL DEFAULT '{}',
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE,
+    FOREIGN KEY (form_id) REFERENCES "forms" (id) ON DELETE SET NULL
+);
+
+CREATE INDEX "lead_submissions_user_id_idx" ON "lead_submissions" ("user_id");
+
+-- This table contains the tags the managers have put on the leads.
+CREATE TABLE "lead_tags" (
+    "user_id" INT NOT NULL,
+    "tag" VARCHAR(64) NOT NULL,
+    PRIMARY KEY (user_id, tag),
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE
+);
+
+CREATE INDEX "lead_tags_tag_idx" ON "lead_tags" ("tag");
+
+-- This table contains the waitlist of the leads. The leads are ordered
+-- by their ticket minus the number of places their referrals have skipped.
+CREATE TABLE "waitlist" (
+    "user_id" INT PRIMARY KEY,
+    -- The order the leads have joined the waitlist in.
+    "ticket" SERIAL NOT NULL,
+    "boost" INT NOT NULL DEFAULT 0,
+    "referral_code" VARCHAR(16) NOT NULL UNIQUE,
+    "referred_by" INT DEFAULT NULL,
+    -- The referrer has been rewarded for this lead.
+    "referral_rewarded" BOOLEAN NOT NULL DEFAULT FALSE,
+    "joined_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE,
+    FOREIGN KEY (referred_by) REFERENCES "users" (id) ON DELETE SET NULL
+);
+
+CREATE INDEX "waitlist_rank_idx" ON "waitlist" (("ticket" - "boost"), "ticket");
+CREATE INDEX "waitlist_referred_by_idx" ON "waitlist" ("referred_by");
+
+-- This table contains the endpoints that are notified about the events,
+-- e.g. "lead.created". The deliveries are signed with the secret.
+CREATE TABLE "webhooks" (
+    "id" SERIAL PRIMARY KEY,
+    "url" TEXT NOT NULL,
+    "secret" VARCHAR(64) NOT NULL,
+    "events" TEXT[] NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- This table contains the deliveries of the events to the webhooks.
+CREATE TABLE "webhook_deliveries" (
+    "id" SERIAL PRIMARY KEY,
+    "webhook_id" INT NOT NULL,
+    "event" VARCHAR(64) NOT NULL,
+    "payload" JSONB NOT NULL,
+    -- "pending", "delivered" or "failed".
+    "status" VARCHAR(16) NOT NULL DEFAULT 'pending',
+    "attempts" INT NOT NULL DEFAULT 0,
+    "next_attempt_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- The result of the last attempt.
+    "response_status" INT DEFAULT NULL,
+    "last_error" TEXT DEFAULT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "delivered_at" TIMESTAMPTZ DEFAULT NULL,
+    FOREIGN KEY (webhook_id) REFERENCES "webhooks" (id) ON DELETE CASCADE
+);
+
+CREATE INDEX "webhook_deliveries_pending_idx"
+    ON "webhook_deliveries" ("next_attempt_at") WHERE "status" = 'pending';
+CREATE INDEX "webhook_deliveries_webhook_id_idx" ON "webhook_deliveries" ("webhook_id", "id");
+
+-- This table contains the queued emails that notify the people
+-- responsible for a form about its submissions, and the other emails
+-- that must not hold up the requests (e.g. the new waitlist places).
+-- The sent emails are removed, like the ones that have run out of attempts.
+CREATE TABLE "form_notifications" (
+    "id" SERIAL PRIMARY KEY,
+    -- The lead the email is about.
+    "user_id" INT NOT NULL,
+    "recipient" VARCHAR(255) NOT NULL,
+    -- The name of the recipient, the address is used without it.
+    "full_name" VARCHAR(255) DEFAULT NULL,
+    -- The emails without a sender are sent from SMTP_SENDER.
+    "sender" VARCHAR(255) DEFAULT NULL,
+    -- The marketing emails are not sent to the leads who refuse them.
+    "transactional" BOOLEAN NOT NULL DEFAULT TRUE,
+    "subject" TEXT NOT NULL,
+    "message" TEXT NOT NULL,
+    "attempts" INT NOT NULL DEFAULT 0,
+    "next_attempt_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "last_error" TEXT DEFAULT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE
+);
+
+CREATE INDEX "form_notifications_next_attempt_at_idx" ON "form_notifications" ("next_attempt_at");
+CREATE INDEX "form_notifications_user_id_idx" ON "form_notifications" ("user_id");
+
+-- This table is the ledger of the consents of the users, e.g. to the
+-- privacy policy and to the marketing emails. The rows are never changed,
+-- a withdrawal is a new row, the latest row of a purpose is in force.
+CREATE TABLE "consents" (
+    "id" SERIAL PRIMARY KEY,
+    "user_id" INT NOT NULL,
+    -- "privacy_policy" or "marketing".
+    "purpose" VARCHAR(32) NOT NULL,
+    "granted" BOOLEAN NOT NULL,
+    -- A grant that follows a withdrawal or a refusal is pending, i.e. not
+    -- in force, until the user confirms it with the confirmation link.
+    "pending" BOOLEAN NOT NULL DEFAULT FALSE,
+    -- The version of the privacy policy the user has seen.
+    "policy_version" VARCHAR(32) NOT NULL,
+    -- "form", "registration", "withdrawal", "unsubscribe", "import"
+    -- or "confirmation".
+    "source" VARCHAR(32) NOT NULL,
+    "ip" VARCHAR(45) DEFAULT NULL,
+    "user_agent" TEXT DEFAULT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE
+);
+
+CREATE INDEX "consents_user_id_idx" ON "consents" ("user_id", "purpose", "id");
+
+-- The consents are the proof of what the users have agreed to,
+-- so they cannot be changed. They are removed together with the user.
+CREATE FUNCTION "consents_append_only"() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'The consents cannot be changed, record a new one instead';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER "consents_append_only" BEFORE UPDATE ON "consents"
+    FOR EACH ROW EXECUTE FUNCTION "consents_append_only"();
+
+-- This table contains the positions of the synchronization with a CRM:
+-- the last lead pushed as a new one and the last lead reconciled.
+CREATE TABLE "crm_sync_cursors" (
+    "connector" VARCHAR(64) PRIMARY KEY,
+    "last_user_id" INT NOT NULL DEFAULT 0,
+    "reconciled_user_id" INT NOT NULL DEFAULT 0,
+    -- The replica that is pushing the leads holds the cursor until then.
+    "leased_until" TIMESTAMPTZ DEFAULT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- This table links the leads to the contacts in a CRM.
+CREATE TABLE "crm_contacts" (
+    "connector" VARCHAR(64) NOT NULL,
+    "user_id" INT NOT NULL,
+    -- The id of the contact in the CRM, it is NULL until it is created.
+    "external_id" VARCHAR(255) DEFAULT NULL,
+    -- "synced", "conflict" or "failed".
+    "status" VARCHAR(16) NOT NULL,
+    -- The conflicting fields or the error of the last attempt.
+    "last_error" TEXT DEFAULT NULL,
+    -- The failed attempts in a row and when the lead is retried.
+    "attempts" INT NOT NULL DEFAULT 0,
+    "next_attempt_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "synced_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (connector, user_id),
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE
+);
+
+CREATE INDEX "crm_contacts_status_idx" ON "crm_contacts" ("status", "next_attempt_at") WHERE "status" <> 'synced';
+
+-- This table contains the rules the leads are scored with. A rule adds
+-- its points (negative ones as well) to every lead it matches.
+CREATE TABLE "scoring_rules" (
+    "id" SERIAL PRIMARY KEY,
+    "name" VARCHAR(100) NOT NULL,
+    -- A field of the lead, of its attribution or engagement,
+    -- or a custom field (e.g. "custom.company").
+    "field" VARCHAR(100) NOT NULL,
+    -- "exists", "equals", "not_equals", "contains", "gte" or "lte".
+    "operator" VARCHAR(16) NOT NULL,
+    "value" JSONB DEFAULT NULL,
+    "points" INT NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- This table contains the current scores of the leads.
+CREATE TABLE "lead_scores" (
+    "user_id" INT PRIMARY KEY,
+    "score" INT NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE
+);
+
+-- This index serves the pages of the leads by score.
+CREATE INDEX "lead_scores_score_idx" ON "lead_scores" ("score" DESC, "user_id" DESC);
+
+-- This table contains every change of the scores of the leads.
+CREATE TABLE "lead_score_history" (
+    "id" SERIAL PRIMARY KEY,
+    "user_id" INT NOT NULL,
+    "score" INT NOT NULL,
+    "previous_score" INT DEFAULT NULL,
+    -- What has caused the change, e.g. "submission" or "rules".
+    "reason" VARCHAR(32) NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE
+);
+
+CREATE INDEX "lead_score_history_user_id_idx" ON "lead_score_history" ("user_id", "id");
+
+-- This table contains the stages of the funnel every user has reached,
+-- once per stage, so that every user is counted in the rollup once.
+CREATE TABLE "funnel_events" (
+    "user_id" INT NOT NULL,
+    -- "subscribed", "confirmed", "registered" or "first_login".
+    "stage" VARCHAR(16) NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY ("user_id", "stage"),
+    FOREIGN KEY (user_id) REFERENCES "users" (id) ON DELETE CASCADE
+);
+
+-- This table contains the number of the users that have reached
+-- a stage of the funnel by day (UTC), form, campaign and country.
+-- It is updated with every event, so the reports never scan the users.
+-- NOTE: The numbers are anonymous, they are kept after the erasure.
+CREATE TABLE "funnel_daily" (
+    "day" DATE NOT NULL,
+    "stage" VARCHAR(16) NOT NULL,
+    -- The values that are not known are empty.
+    "form" VARCHAR(50) NOT NULL DEFAULT '',
+    "campaign" VARCHAR(255) NOT NULL DEFAULT '',
+    -- The country of the phone number, e.g. "RU".
+    "country" VARCHAR(2) NOT NULL DEFAULT '',
+    "count" INT NOT NULL DEFAULT 0,
+    PRIMARY KEY ("day", "stage", "form", "campaign", "country")
+);
+
+-- This table logs the requests of the users to export or erase their data.
+-- NOTE: The ids are not foreign keys, the log is kept after the erasure.
+CREATE TABLE "data_requests" (
+    "id" SERIAL PRIMARY KEY,
+    -- The user the data belongs to.
+    "subject_id" INT NOT NULL,
+    -- "export" or "erasure".
+    "kind" VARCHAR(16) NOT NULL,
+    -- The user themselves or the Admin acting for them.
+    "requested_by" INT NOT NULL,
+    -- "pending", "completed" or "failed".
+    "status" VARCHAR(16) NOT NULL DEFAULT 'pending',
+    "error" TEXT DEFAULT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "completed_at" TIMESTAMPTZ DEFAULT NULL
+);
+
+CREATE INDEX "data_requests_subject_id_idx" ON "data_requests" ("subject_id");
+
+-- This table contains the hashes of the challenges of the forms that
+-- have been used, so that a solved challenge cannot be replayed.
+-- The rows are removed once the challenges have expired.
+CREATE TABLE "spent_form_tokens" (
+    "token_hash" VARCHAR(64) PRIMARY KEY,
+    "expires_at" TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX "spent_form_tokens_expires_at_idx" ON "spent_form_tokens" ("expires_at");
+
+-- This table counts the requests to the public endpoints that could be
+-- abused (e.g. to flood the inbox of a lead) by key in fixed windows.
+-- NOTE: The keys are hashed, so that no address is kept here.
+-- The rows are removed once their windows have ended.
+CREATE TABLE "rate_limits" (
+    "key_hash" VARCHAR(64) PRIMARY KEY,
+    "hits" INT NOT NULL DEFAULT 1,
+    "expires_at" TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX "rate_limits_expires_at_idx" ON "rate_limits" ("expires_at");
+
+-- This table contains the email addresses that have unsubscribed.
+-- NOTE: The addresses are stored in lowercase and are kept
+-- even if the user is removed, so that no email is sent to them again.
+CREATE TABLE "unsubscribes" (
+    "email" VARCHAR(254) PRIMARY KEY,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
 );
 
 
@@ -37,4 +424,16 @@ INSERT INTO "roles" ("title", "description")
 VALUES
     ('User', 'A general application user'),
     ('Admin', 'A user with a rather high access level'),
-    ('Manager', 'A user with super high access level');
\ No newline at end of file
+    ('Manager', 'A user with super high access level');
+
+/*
+    Insert the default form, it is used by the "/insert" endpoint.
+*/
+INSERT INTO "forms" ("slug", "title", "sender", "welcome_subject", "welcome_template")
+VALUES (
+    'default',
+    'Manuspect',
+    'Manuspect <manuspect.prod@gmail.com>',
+    'Please confirm your subscription',
+    E'Welcome to Manuspect!\n\nPlease confirm your subscription by following the link below:\n{confirmation_link}\n\nIf you have not subscribed, just ignore this email.'
+);
\ No newline at end of file
@@ -0,0 +1,257 @@
+// This file contains the subcommands that bootstrap and maintain
+// privileged accounts.
+//
+// NOTE: These subcommands never take a password from the command
+// line arguments, so that it does not end up in the shell history
+// or in the process list of a pod.
+
+use std::env;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use chrono::Utc;
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+
+use crate::{
+    models::{NewRoleGrant, NewUser, User},
+    routes::auth::register::{is_valid_form, is_valid_password},
+    schema::{users, users_roles},
+    utils::{emails, phones, roles, security::hash_password},
+};
+
+use super::{CreateAdminArgs, GrantRoleArgs, ResetPasswordArgs, UserSelector};
+
+/// This function creates a verified user with the Admin role.
+pub async fn create_admin(args: CreateAdminArgs) -> Result<String, String> {
+    let mut user = NewUser {
+        name: args.name,
+        email: Some(args.email),
+        phone_number_code: args.phone_number_code,
+        phone_number: args.phone_number,
+        password: Some(read_password()?),
+    }; // end NewUser
+
+    // The account is checked the same way as in the registration form.
+    let (passed, message) = is_valid_form(&mut user);
+    if !passed {
+        return Err(message);
+    } // end if
+
+    // Hash the password the same way as the registration does.
+    user.password = Some(hash(user.password.take().unwrap_or_default()).await?);
+
+    let mut conn = establish_connection().await?;
+
+    // Make sure that the user does not exist yet.
+    let existing = users::table
+        .filter(
+            users::phone_number
+                .eq(&user.phone_number)
+                .or(users::email.eq(&user.email)),
+        )
+        .count()
+        .get_result::<i64>(&mut conn)
+        .await
+        .map_err(|error| error.to_string())?;
+    if existing > 0 {
+        return Err(
+            "A user with this phone number or email already exists, use grant-role instead"
+                .to_string(),
+        );
+    } // end if
+
+    let admin_role = find_role(&mut conn, "Admin").await?;
+    let user_role = find_role(&mut conn, "User").await?;
+
+    // Insert the user and assign the roles at once, so that
+    // there is no half-created admin if something fails.
+    let user_id = conn
+        .transaction::<i32, diesel::result::Error, _>(|conn| {
+            async move {
+                let user_id = diesel::insert_into(users::table)
+                    .values((&user, users::verified.eq(true)))
+                    .returning(users::id)
+                    .get_result::<i32>(conn)
+                    .await?;
+
+                diesel::insert_into(users_roles::table)
+                    .values(&vec![
+                        (
+                            users_roles::user_id.eq(user_id),
+                            users_roles::role_id.eq(user_role),
+                        ),
+                        (
+                            users_roles::user_id.eq(user_id),
+                            users_roles::role_id.eq(admin_role),
+                        ),
+                    ])
+                    .execute(conn)
+                    .await?;
+
+                Ok(user_id)
+            }
+            .scope_boxed()
+        })
+        .await
+        .map_err(|error| error.to_string())?;
+
+    Ok(format!("The admin has been created with id {}", user_id))
+} // end fn create_admin
+
+/// This function assigns a role to an existing user.
+/// NOTE: Granting a role the user already has for good changes nothing.
+pub async fn grant_role(args: GrantRoleArgs) -> Result<String, String> {
+    let mut conn = establish_connection().await?;
+
+    let user = find_user(&mut conn, &args.user).await?;
+    let role_id = find_role(&mut conn, &args.role).await?;
+
+    // Find out when the grant expires, if it is temporary.
+    let expires_at = roles::expiry(args.days, args.expires_at, Utc::now());
+
+    let granted = roles::grant_role(
+        &mut conn,
+        NewRoleGrant {
+            user_id: user.id,
+            role_id,
+            granted_by: None,
+            expires_at,
+            reason: args.reason,
+        },
+    )
+    .await
+    .map_err(|error| error.to_string())?;
+
+    if !granted {
+        return Ok(format!(
+            "The user {} already has the role {}",
+            user.id, args.role
+        ));
+    } // end if
+
+    Ok(match expires_at {
+        Some(expires_at) => format!(
+            "The role {} has been granted to the user {} until {}",
+            args.role, user.id, expires_at
+        ),
+        None => format!(
+            "The role {} has been granted to the user {}",
+            args.role, user.id
+        ),
+    }) // end Ok
+} // end fn grant_role
+
+/// This function sets a new password for an existing user.
+///
+/// NOTE: The current session of the user is revoked, so they
+/// have to log in with the new password.
+pub async fn reset_password(args: ResetPasswordArgs) -> Result<String, String> {
+    let password = read_password()?;
+
+    // The password follows the same rules as in the registration form.
+    let (passed, message) = is_valid_password(&password);
+    if !passed {
+        return Err(message);
+    } // end if
+
+    let password = hash(password).await?;
+
+    let mut conn = establish_connection().await?;
+    let user = find_user(&mut conn, &args.user).await?;
+
+    diesel::update(users::table)
+        .filter(users::id.eq(user.id))
+        .set((
+            users::password.eq(password),
+            users::token.eq(None::<String>),
+        ))
+        .execute(&mut conn)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    Ok(format!(
+        "The password of the user {} has been reset",
+        user.id
+    ))
+} // end fn reset_password
+
+/// This function opens a connection to the database specified
+/// in the DATABASE_URL environment variable.
+pub(super) async fn establish_connection() -> Result<AsyncPgConnection, String> {
+    let database_url = env::var("DATABASE_URL")
+        .map_err(|_error| "Failed to find the environment variable DATABASE_URL".to_string())?;
+
+    AsyncPgConnection::establish(&database_url)
+        .await
+        .map_err(|error| error.to_string())
+} // end fn establish_connection
+
+/// This function hashes a password with the same function
+/// that is used by the HTTP handlers.
+async fn hash(password: String) -> Result<String, String> {
+    hash_password(password)
+        .await
+        .map_err(|_error| "Failed to hash the password, check ARGON2_SALT".to_string())
+} // end fn hash
+
+/// This function loads the id of a role by its title.
+async fn find_role(conn: &mut AsyncPgConnection, title: &str) -> Result<i32, String> {
+    roles::find_role_id(conn, title)
+        .await
+        .map_err(|error| error.to_string())?
+        .ok_or_else(|| format!("The role {} does not exist", title))
+} // end fn find_role
+
+/// This function loads the user identified by their email
+/// or phone number.
+async fn find_user(conn: &mut AsyncPgConnection, selector: &UserSelector) -> Result<User, String> {
+    let mut query = users::table.into_boxed();
+
+    if let (Some(phone_number_code), Some(phone_number)) =
+        (&selector.phone_number_code, &selector.phone_number)
+    {
+        // The phone numbers are stored in E.164.
+        let phone = phones::parse(*phone_number_code, phone_number)?;
+        query = query.filter(users::phone_number.eq(phone.e164));
+    } else {
+        // The emails are stored lowercased.
+        let email = emails::normalize(selector.email.as_deref().unwrap_or_default())?;
+        query = query.filter(users::email.eq(email));
+    } // end if
+
+    query
+        .first::<User>(conn)
+        .await
+        .optional()
+        .map_err(|error| error.to_string())?
+        .ok_or_else(|| "The user does not exist".to_string())
+} // end fn find_user
+
+/// This function reads a password from the LANDING_FORM_PASSWORD
+/// environment variable or from the standard input.
+fn read_password() -> Result<String, String> {
+    if let Ok(password) = env::var("LANDING_FORM_PASSWORD") {
+        return Ok(password);
+    } // end if
+
+    // Prompt the operator only if they are typing.
+    if io::stdin().is_terminal() {
+        eprint!("Password: ");
+        io::stderr().flush().ok();
+    } // end if
+
+    let mut password = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|error| error.to_string())?;
+
+    // Get rid of the line ending.
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        return Err("The password cannot be absent".to_string());
+    } // end if
+
+    Ok(password)
+} // end fn read_password
@@ -0,0 +1,189 @@
+// This file contains the subcommand that normalizes the contacts
+// stored before the normalization was introduced.
+//
+// NOTE: The lookups (the login, the duplicates of the forms and the
+// other subcommands) compare the normalized values only, so this has
+// to be run once after the upgrade.
+
+use diesel::{ExpressionMethods, NullableExpressionMethods, QueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+
+use crate::{
+    schema::{unsubscribes, users},
+    utils::{
+        emails::{self, StoredEmail},
+        phones::{self, StoredPhone},
+    },
+};
+
+use super::{admin::establish_connection, NormalizeContactsArgs};
+
+/// This function normalizes the stored contacts and reports
+/// the changed rows and the rows that have been left as they are.
+pub async fn normalize_contacts(args: NormalizeContactsArgs) -> Result<String, String> {
+    let mut conn = establish_connection().await?;
+
+    let mut lines = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            async move {
+                let mut lines = normalize_phones(conn, args.dry_run).await?;
+                lines.extend(normalize_emails(conn, args.dry_run).await?);
+                lines.extend(normalize_unsubscribes(conn, args.dry_run).await?);
+                Ok(lines)
+            }
+            .scope_boxed()
+        })
+        .await
+        .map_err(|error| error.to_string())?;
+
+    lines.push(if args.dry_run {
+        "A dry run, nothing has been changed".to_string()
+    } else {
+        "The contacts have been normalized".to_string()
+    });
+
+    Ok(lines.join("\n"))
+} // end fn normalize_contacts
+
+/// This function brings the stored phone numbers to E.164.
+async fn normalize_phones(
+    conn: &mut AsyncPgConnection,
+    dry_run: bool,
+) -> diesel::QueryResult<Vec<String>> {
+    let stored = users::table
+        .select((users::id, users::phone_number_code, users::phone_number))
+        .for_update()
+        .load::<(i32, i32, String)>(conn)
+        .await?
+        .into_iter()
+        .map(|(user_id, phone_number_code, phone_number)| StoredPhone {
+            user_id,
+            phone_number_code,
+            phone_number,
+        })
+        .collect::<Vec<_>>();
+
+    let mut lines = Vec::new();
+    for step in phones::plan_backfill(&stored) {
+        let line = match step {
+            phones::Backfill::Update { user_id, phone } => {
+                if !dry_run {
+                    diesel::update(users::table.find(user_id))
+                        .set((
+                            users::phone_number_code.eq(phone.country_code),
+                            users::phone_number.eq(&phone.e164),
+                        ))
+                        .execute(conn)
+                        .await?;
+                } // end if
+                format!("user {}: the phone number is {}", user_id, phone.e164)
+            } // end Update
+            phones::Backfill::Invalid { user_id, message } => {
+                format!("user {}: {}, it has to be corrected by hand", user_id, message)
+            } // end Invalid
+            phones::Backfill::Taken {
+                user_id,
+                e164,
+                owner_id,
+            } => format!(
+                "user {}: the phone number {} belongs to the user {}, the users have to be merged by hand",
+                user_id, e164, owner_id
+            ),
+        }; // end match
+        lines.push(line);
+    } // end for
+
+    Ok(lines)
+} // end fn normalize_phones
+
+/// This function brings the stored email addresses
+/// of the users to the normalized form.
+async fn normalize_emails(
+    conn: &mut AsyncPgConnection,
+    dry_run: bool,
+) -> diesel::QueryResult<Vec<String>> {
+    let stored = users::table
+        .filter(users::email.is_not_null())
+        .select((users::id, users::email.assume_not_null()))
+        .for_update()
+        .load::<(i32, String)>(conn)
+        .await?
+        .into_iter()
+        .map(|(user_id, email)| StoredEmail { user_id, email })
+        .collect::<Vec<_>>();
+
+    let mut lines = Vec::new();
+    for step in emails::plan_backfill(&stored) {
+        let line = match step {
+            emails::Backfill::Update { user_id, email } => {
+                if !dry_run {
+                    diesel::update(users::table.find(user_id))
+                        .set(users::email.eq(&email))
+                        .execute(conn)
+                        .await?;
+                } // end if
+                format!("user {}: the email is {}", user_id, email)
+            } // end Update
+            emails::Backfill::Invalid { user_id, message } => {
+                format!(
+                    "user {}: {}, it has to be corrected by hand",
+                    user_id, message
+                )
+            } // end Invalid
+            emails::Backfill::Taken {
+                user_id,
+                email,
+                owner_id,
+            } => format!(
+                "user {}: the email {} belongs to the user {}, the users have to be merged by hand",
+                user_id, email, owner_id
+            ),
+        }; // end match
+        lines.push(line);
+    } // end for
+
+    Ok(lines)
+} // end fn normalize_emails
+
+/// This function brings the unsubscribed addresses to the normalized
+/// form, the addresses that turn out to be the same are kept once.
+async fn normalize_unsubscribes(
+    conn: &mut AsyncPgConnection,
+    dry_run: bool,
+) -> diesel::QueryResult<Vec<String>> {
+    let stored = unsubscribes::table
+        .select(unsubscribes::email)
+        .for_update()
+        .load::<String>(conn)
+        .await?;
+
+    let mut lines = Vec::new();
+    for stored in stored {
+        let email = match emails::normalize(&stored) {
+            Ok(email) if email == stored => continue,
+            Ok(email) => email,
+            Err(message) => {
+                lines.push(format!(
+                    "unsubscribed {}: {}, it is left as it is",
+                    stored, message
+                ));
+                continue;
+            } // end Err
+        }; // end match
+
+        if !dry_run {
+            diesel::insert_into(unsubscribes::table)
+                .values(unsubscribes::email.eq(&email))
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .await?;
+            diesel::delete(unsubscribes::table.find(&stored))
+                .execute(conn)
+                .await?;
+        } // end if
+        lines.push(format!("unsubscribed {}: the email is {}", stored, email));
+    } // end for
+
+    Ok(lines)
+} // end fn normalize_unsubscribes
@@ -0,0 +1,20 @@
+// This file contains the subcommand that records the stages of the funnel
+// the users that came before it have reached (see utils/funnel.rs).
+
+use crate::utils::funnel;
+
+use super::admin::establish_connection;
+
+/// This function records the stages of the existing users
+/// and reports the number of the recorded ones.
+pub async fn backfill_funnel() -> Result<String, String> {
+    let mut conn = establish_connection().await?;
+    let recorded = funnel::backfill(&mut conn)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    Ok(format!(
+        "{} stages of the users have been recorded",
+        recorded
+    ))
+} // end fn backfill_funnel
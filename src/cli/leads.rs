@@ -0,0 +1,81 @@
+// This file contains the subcommands that work with the leads.
+
+use std::fs;
+
+use crate::utils::{
+    emails::EmailPolicy,
+    forms::FormDefinitions,
+    imports::{self, ColumnMapping, ImportError, ImportOptions, ImportStatus},
+    lead_forms::find_form,
+};
+
+use super::{admin::establish_connection, ImportLeadsArgs};
+
+/// This function imports the leads from a CSV file and reports
+/// the result of every row.
+pub async fn import_leads(args: ImportLeadsArgs) -> Result<String, String> {
+    let file =
+        fs::read(&args.file).map_err(|error| format!("{}: {}", args.file.display(), error))?;
+    let options = ImportOptions {
+        columns: ColumnMapping::parse(&args.columns)?,
+        phone_number_code: args.phone_number_code,
+        dry_run: args.dry_run,
+        imported_by: None,
+        consent: args.consent.filter(|consent| !consent.trim().is_empty()),
+    }; // end ImportOptions
+
+    // The rows are checked the same way as by the server.
+    let forms = FormDefinitions::load()?;
+    let emails = EmailPolicy::from_env()?;
+
+    let mut conn = establish_connection().await?;
+    let form = find_form(&mut conn, &args.form)
+        .await
+        .map_err(|error| error.to_string())?
+        .ok_or_else(|| format!("The form \"{}\" does not exist", args.form))?;
+
+    let report = imports::import_leads(
+        &mut conn,
+        &file,
+        &form,
+        forms.get(&form.slug),
+        &emails,
+        &options,
+    )
+    .await
+    .map_err(|error| match error {
+        ImportError::InvalidFile(message) => message,
+        ImportError::Database(error) => error.to_string(),
+    })?;
+
+    let mut lines = report
+        .rows
+        .iter()
+        .map(|row| match (row.status, row.user_id, &row.reason) {
+            (ImportStatus::Imported, Some(user_id), _) => {
+                format!("Line {}: imported as the lead {}", row.line, user_id)
+            }
+            (ImportStatus::Merged, Some(user_id), _) => {
+                format!("Line {}: merged into the lead {}", row.line, user_id)
+            }
+            (_, _, reason) => format!(
+                "Line {}: rejected, {}",
+                row.line,
+                reason.as_deref().unwrap_or_default()
+            ),
+        })
+        .collect::<Vec<_>>();
+    lines.push(format!(
+        "Imported: {}, merged: {}, rejected: {}{}",
+        report.imported,
+        report.merged,
+        report.rejected,
+        if report.dry_run {
+            " (a dry run, nothing has been saved)"
+        } else {
+            ""
+        }
+    ));
+
+    Ok(lines.join("\n"))
+} // end fn import_leads
@@ -0,0 +1,330 @@
+// This file contains the command line interface of the application.
+//
+// Running the binary without a subcommand starts the server, the
+// other subcommands are administrative tasks that are performed
+// directly on the database and exit afterwards.
+
+pub mod admin;
+pub mod contacts;
+pub mod funnel;
+pub mod leads;
+pub mod retention;
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+
+/// This struct represents the command line arguments.
+#[derive(Parser, Debug)]
+#[command(name = "landing_form", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+} // end struct Cli
+
+/// This enum contains all the available subcommands.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the server (this is the default).
+    Serve,
+    /// Create a verified user with the Admin role.
+    ///
+    /// The password is read from LANDING_FORM_PASSWORD or, if it is
+    /// not set, from the first line of the standard input.
+    CreateAdmin(CreateAdminArgs),
+    /// Assign a role to an existing user, permanently or for a while.
+    GrantRole(GrantRoleArgs),
+    /// Set a new password for an existing user.
+    ///
+    /// The password is read from LANDING_FORM_PASSWORD or, if it is
+    /// not set, from the first line of the standard input.
+    ResetPassword(ResetPasswordArgs),
+    /// Import the leads from a CSV file, e.g. the list of an event.
+    ///
+    /// Every row is checked like a submission of the form and is merged
+    /// into the lead with the same phone number or email if there is one.
+    /// The result of every row is printed.
+    ImportLeads(ImportLeadsArgs),
+    /// Remove the data that is kept longer than its retention policy
+    /// allows (see RETENTION_<CLASS>_DAYS).
+    ///
+    /// The number of the removed rows of every class is printed.
+    Purge(PurgeArgs),
+    /// Bring the contacts stored before they were normalized to the
+    /// current format, e.g. the phone numbers to E.164.
+    ///
+    /// The changed rows and the rows that have to be corrected
+    /// by hand are printed.
+    NormalizeContacts(NormalizeContactsArgs),
+    /// Record the stages of the funnel the users that came before it
+    /// have reached, without counting them in the reports.
+    ///
+    /// It has to be run once after the upgrade, otherwise the next
+    /// login of every registered user is counted as their first one.
+    BackfillFunnel,
+} // end enum Command
+
+/// This struct contains the arguments of the create-admin subcommand.
+#[derive(Args, Debug)]
+pub struct CreateAdminArgs {
+    #[arg(long)]
+    pub name: String,
+    #[arg(long)]
+    pub email: String,
+    #[arg(long)]
+    pub phone_number_code: i32,
+    #[arg(long)]
+    pub phone_number: String,
+} // end struct CreateAdminArgs
+
+/// This struct contains the arguments of the grant-role subcommand.
+#[derive(Args, Debug)]
+pub struct GrantRoleArgs {
+    #[command(flatten)]
+    pub user: UserSelector,
+    /// The title of the role, e.g. "Admin" or "Manager".
+    #[arg(long)]
+    pub role: String,
+    /// The moment the grant expires at (RFC 3339), e.g. 2024-01-31T18:00:00Z.
+    #[arg(long, conflicts_with = "days")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The number of days the grant is valid for.
+    #[arg(long)]
+    pub days: Option<u32>,
+    /// The reason the role is granted for.
+    #[arg(long)]
+    pub reason: Option<String>,
+} // end struct GrantRoleArgs
+
+/// This struct contains the arguments of the reset-password subcommand.
+#[derive(Args, Debug)]
+pub struct ResetPasswordArgs {
+    #[command(flatten)]
+    pub user: UserSelector,
+} // end struct ResetPasswordArgs
+
+/// This struct contains the arguments of the import-leads subcommand.
+#[derive(Args, Debug)]
+pub struct ImportLeadsArgs {
+    /// The CSV file with a header.
+    pub file: PathBuf,
+    /// The slug of the form the leads are imported into.
+    #[arg(long, default_value = "default")]
+    pub form: String,
+    /// The fields that are in the columns with other names,
+    /// e.g. "name:Full name,email:E-mail".
+    #[arg(long, default_value = "")]
+    pub columns: String,
+    /// The phone number code of the rows without "phone_number_code".
+    #[arg(long)]
+    pub phone_number_code: Option<i32>,
+    /// Check the file and report the results without saving anything.
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Where the consents of the leads have been collected, the new
+    /// leads are confirmed only if it is given.
+    #[arg(long)]
+    pub consent: Option<String>,
+} // end struct ImportLeadsArgs
+
+/// This struct contains the arguments of the purge subcommand.
+#[derive(Args, Debug)]
+pub struct PurgeArgs {
+    /// Report the rows that would be removed without removing anything.
+    #[arg(long)]
+    pub dry_run: bool,
+} // end struct PurgeArgs
+
+/// This struct contains the arguments of the normalize-contacts subcommand.
+#[derive(Args, Debug)]
+pub struct NormalizeContactsArgs {
+    /// Report the changes without saving anything.
+    #[arg(long)]
+    pub dry_run: bool,
+} // end struct NormalizeContactsArgs
+
+/// This struct identifies an existing user either by their email
+/// or by their phone number.
+#[derive(Args, Debug)]
+#[group(required = true, multiple = true)]
+pub struct UserSelector {
+    #[arg(long, conflicts_with_all = ["phone_number_code", "phone_number"])]
+    pub email: Option<String>,
+    #[arg(long, requires = "phone_number")]
+    pub phone_number_code: Option<i32>,
+    #[arg(long, requires = "phone_number_code")]
+    pub phone_number: Option<String>,
+} // end struct UserSelector
+
+/// This function runs an administrative subcommand.
+/// It returns a message that should be displayed to the operator.
+pub async fn run_command(command: Command) -> Result<String, String> {
+    match command {
+        // The server is started by the caller.
+        Command::Serve => Ok(String::new()),
+        Command::CreateAdmin(args) => admin::create_admin(args).await,
+        Command::GrantRole(args) => admin::grant_role(args).await,
+        Command::ResetPassword(args) => admin::reset_password(args).await,
+        Command::ImportLeads(args) => leads::import_leads(args).await,
+        Command::Purge(args) => retention::purge(args).await,
+        Command::NormalizeContacts(args) => contacts::normalize_contacts(args).await,
+        Command::BackfillFunnel => funnel::backfill_funnel().await,
+    } // end match
+} // end fn run_command
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// This is a helper function that parses the arguments
+    /// after the name of the binary.
+    fn parse(args: &[&str]) -> Result<Option<Command>, clap::Error> {
+        Cli::try_parse_from(std::iter::once("landing_form").chain(args.iter().copied()))
+            .map(|cli| cli.command)
+    } // end fn parse
+
+    #[test]
+    fn serve_is_the_default() {
+        assert!(parse(&[]).unwrap().is_none());
+        assert!(matches!(parse(&["serve"]).unwrap(), Some(Command::Serve)));
+    }
+
+    #[test]
+    fn create_admin_arguments() {
+        let command = parse(&[
+            "create-admin",
+            "--name",
+            "Admin",
+            "--email",
+            "admin@example.com",
+            "--phone-number-code",
+            "1",
+            "--phone-number",
+            "2015550100",
+        ])
+        .unwrap();
+        let Some(Command::CreateAdmin(args)) = command else {
+            panic!("expected create-admin, got {:?}", command);
+        };
+        assert_eq!(args.name, "Admin");
+        assert_eq!(args.email, "admin@example.com");
+        assert_eq!(args.phone_number_code, 1);
+        assert_eq!(args.phone_number, "2015550100");
+
+        // The password is never taken from the arguments.
+        assert!(parse(&[
+            "create-admin",
+            "--name",
+            "Admin",
+            "--email",
+            "admin@example.com",
+            "--phone-number-code",
+            "1",
+            "--phone-number",
+            "2015550100",
+            "--password",
+            "secret",
+        ])
+        .is_err());
+        assert!(parse(&["create-admin", "--name", "Admin"]).is_err());
+    }
+
+    #[test]
+    fn grant_role_arguments() {
+        let command = parse(&[
+            "grant-role",
+            "--email",
+            "manager@example.com",
+            "--role",
+            "Manager",
+            "--days",
+            "7",
+            "--reason",
+            "Holidays",
+        ])
+        .unwrap();
+        let Some(Command::GrantRole(args)) = command else {
+            panic!("expected grant-role, got {:?}", command);
+        };
+        assert_eq!(args.user.email.as_deref(), Some("manager@example.com"));
+        assert_eq!(args.role, "Manager");
+        assert_eq!(args.days, Some(7));
+        assert_eq!(args.expires_at, None);
+        assert_eq!(args.reason.as_deref(), Some("Holidays"));
+
+        let command = parse(&[
+            "grant-role",
+            "--email",
+            "manager@example.com",
+            "--role",
+            "Manager",
+            "--expires-at",
+            "2024-01-31T18:00:00Z",
+        ])
+        .unwrap();
+        let Some(Command::GrantRole(args)) = command else {
+            panic!("expected grant-role, got {:?}", command);
+        };
+        assert_eq!(
+            args.expires_at.map(|expires_at| expires_at.to_rfc3339()),
+            Some("2024-01-31T18:00:00+00:00".to_string())
+        );
+
+        // The expiration is given either as a moment or in days.
+        assert!(parse(&[
+            "grant-role",
+            "--email",
+            "manager@example.com",
+            "--role",
+            "Manager",
+            "--days",
+            "7",
+            "--expires-at",
+            "2024-01-31T18:00:00Z",
+        ])
+        .is_err());
+        assert!(parse(&[
+            "grant-role",
+            "--email",
+            "manager@example.com",
+            "--role",
+            "Manager",
+            "--expires-at",
+            "tomorrow",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn user_selector_arguments() {
+        let command = parse(&[
+            "reset-password",
+            "--phone-number-code",
+            "1",
+            "--phone-number",
+            "2015550100",
+        ])
+        .unwrap();
+        let Some(Command::ResetPassword(args)) = command else {
+            panic!("expected reset-password, got {:?}", command);
+        };
+        assert_eq!(args.user.email, None);
+        assert_eq!(args.user.phone_number_code, Some(1));
+        assert_eq!(args.user.phone_number.as_deref(), Some("2015550100"));
+
+        // The user must be selected, by the email or by the whole phone number.
+        assert!(parse(&["reset-password"]).is_err());
+        assert!(parse(&["reset-password", "--phone-number", "2015550100"]).is_err());
+        assert!(parse(&[
+            "reset-password",
+            "--email",
+            "admin@example.com",
+            "--phone-number-code",
+            "1",
+            "--phone-number",
+            "2015550100",
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,37 @@
+// This file contains the subcommand that applies the retention policies.
+
+use crate::utils::retention::Retention;
+
+use super::{admin::establish_connection, PurgeArgs};
+
+/// This function removes the expired data and reports
+/// the number of the removed rows of every class.
+pub async fn purge(args: PurgeArgs) -> Result<String, String> {
+    let mut retention = Retention::from_env()?;
+    retention.dry_run |= args.dry_run;
+
+    let mut conn = establish_connection().await?;
+    let results = retention
+        .purge(&mut conn)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    let mut lines = results
+        .iter()
+        .map(|result| {
+            format!(
+                "{}: {} rows older than {} days",
+                result.policy.class.name(),
+                result.rows,
+                result.policy.days
+            )
+        })
+        .collect::<Vec<_>>();
+    lines.push(if retention.dry_run {
+        "A dry run, nothing has been removed".to_string()
+    } else {
+        "The rows have been removed".to_string()
+    });
+
+    Ok(lines.join("\n"))
+} // end fn purge
@@ -0,0 +1,358 @@
+// This file contains the background jobs of the application.
+//
+// NOTE: Every replica of the application runs its own copy of
+// the jobs, so the jobs must be safe to run concurrently.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{extract::State, Json};
+
+use crate::{
+    routes::{
+        dispatch_email::{dispatch_email, EmailPayload},
+        AppState,
+    },
+    utils::{
+        antispam::forget_expired_tokens,
+        crm::CrmSync,
+        lead_forms::{claim_notification, record_notification_attempt},
+        rate_limits::forget_expired_limits,
+        retention::Retention,
+        roles::expire_role_grants,
+        scoring::{self, rescore_all},
+        webhooks::{self, deliver_due},
+    },
+};
+
+// How often the expired role grants are removed.
+const ROLE_GRANTS_CLEANUP_PERIOD: Duration = Duration::from_secs(60);
+
+// How often the used challenges of the forms that have expired are forgotten.
+const FORM_TOKENS_CLEANUP_PERIOD: Duration = Duration::from_secs(600);
+
+// How often the windows of the rate limits that have ended are forgotten.
+const RATE_LIMITS_CLEANUP_PERIOD: Duration = Duration::from_secs(600);
+
+// How often the data is checked against the retention policies.
+const RETENTION_PURGE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+// How often the due webhook deliveries are checked
+// if no event wakes the job up earlier.
+const WEBHOOKS_DELIVERY_PERIOD: Duration = Duration::from_secs(5);
+
+// How often the due notifications about the submissions
+// are checked if no submission wakes the job up earlier.
+const NOTIFICATIONS_PERIOD: Duration = Duration::from_secs(30);
+
+// How often all the leads are scored again
+// if the scoring rules do not change earlier.
+const LEADS_RESCORE_PERIOD: Duration = Duration::from_secs(6 * 60 * 60);
+
+// How often the new leads are pushed to the CRM.
+const CRM_SYNC_PERIOD: Duration = Duration::from_secs(60);
+
+// How often all the leads are reconciled with the CRM.
+const CRM_RECONCILE_PERIOD: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// This function starts all the background jobs.
+pub fn spawn_jobs(app_state: AppState) {
+    tokio::spawn(clean_up_role_grants(app_state.clone()));
+    tokio::spawn(clean_up_form_tokens(app_state.clone()));
+    tokio::spawn(clean_up_rate_limits(app_state.clone()));
+    let retention = Retention::from_env().expect("Failed to configure the retention policies");
+    tokio::spawn(purge_expired_data(app_state.clone(), retention));
+    if let Some(crm) = app_state.crm.clone() {
+        tokio::spawn(sync_crm(app_state.clone(), crm.clone()));
+        tokio::spawn(reconcile_crm(app_state.clone(), crm));
+    } // end if
+    tokio::spawn(rescore_leads(app_state.clone()));
+    tokio::spawn(send_notifications(app_state.clone()));
+    tokio::spawn(deliver_webhooks(app_state));
+} // end fn spawn_jobs
+
+/// This job periodically removes the expired role grants.
+async fn clean_up_role_grants(app_state: AppState) {
+    let mut interval = tokio::time::interval(ROLE_GRANTS_CLEANUP_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        let mut conn = match app_state.pool.get().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            } // end Err
+        }; // end match
+
+        match expire_role_grants(&mut conn).await {
+            Ok(0) => (),
+            Ok(expired) => println!("{} expired role grants have been removed", expired),
+            Err(error) => eprintln!("{}", error),
+        } // end match
+    } // end loop
+} // end fn clean_up_role_grants
+
+/// This job periodically forgets the used challenges of the forms
+/// that have expired, they cannot be replayed anyway.
+async fn clean_up_form_tokens(app_state: AppState) {
+    let mut interval = tokio::time::interval(FORM_TOKENS_CLEANUP_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        let mut conn = match app_state.pool.get().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            } // end Err
+        }; // end match
+
+        if let Err(error) = forget_expired_tokens(&mut conn).await {
+            eprintln!("{}", error);
+        } // end if
+    } // end loop
+} // end fn clean_up_form_tokens
+
+/// This job periodically forgets the windows of the rate limits
+/// that have ended.
+async fn clean_up_rate_limits(app_state: AppState) {
+    let mut interval = tokio::time::interval(RATE_LIMITS_CLEANUP_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        let mut conn = match app_state.pool.get().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            } // end Err
+        }; // end match
+
+        if let Err(error) = forget_expired_limits(&mut conn).await {
+            eprintln!("{}", error);
+        } // end if
+    } // end loop
+} // end fn clean_up_rate_limits
+
+/// This job periodically removes the data that is kept longer
+/// than its retention policy allows, or reports it in a dry run.
+async fn purge_expired_data(app_state: AppState, retention: Retention) {
+    let mut interval = tokio::time::interval(RETENTION_PURGE_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        let mut conn = match app_state.pool.get().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            } // end Err
+        }; // end match
+
+        match retention.purge(&mut conn).await {
+            Ok(results) => {
+                for result in results.iter().filter(|result| result.rows > 0) {
+                    if retention.dry_run {
+                        println!(
+                            "{} rows of {} have expired (a dry run, nothing has been removed)",
+                            result.rows,
+                            result.policy.class.name()
+                        );
+                    } else {
+                        println!(
+                            "{} rows of {} have been removed",
+                            result.rows,
+                            result.policy.class.name()
+                        );
+                    } // end if
+                } // end for
+            } // end Ok
+            Err(error) => eprintln!("{}", error),
+        } // end match
+    } // end loop
+} // end fn purge_expired_data
+
+/// This job sends the webhook deliveries that are due,
+/// i.e. the new events and the retries.
+async fn deliver_webhooks(app_state: AppState) {
+    let client = webhooks::client().expect("Failed to create a client for the webhooks");
+
+    loop {
+        // The new events wake the job up right away.
+        let _ = tokio::time::timeout(WEBHOOKS_DELIVERY_PERIOD, app_state.webhooks.notified()).await;
+
+        let mut conn = match app_state.pool.get().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            } // end Err
+        }; // end match
+
+        // Keep sending until nothing is due.
+        loop {
+            match deliver_due(&mut conn, &client).await {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    break;
+                } // end Err
+            } // end match
+        } // end loop
+    } // end loop
+} // end fn deliver_webhooks
+
+/// This job sends the queued emails that are due, e.g. the notifications
+/// about the submissions of the forms, i.e. the new ones and the retries.
+async fn send_notifications(app_state: AppState) {
+    loop {
+        // The new submissions wake the job up right away.
+        let _ =
+            tokio::time::timeout(NOTIFICATIONS_PERIOD, app_state.notifications.notified()).await;
+
+        let mut conn = match app_state.pool.get().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            } // end Err
+        }; // end match
+
+        // Keep sending until nothing is due.
+        loop {
+            let notification = match claim_notification(&mut conn).await {
+                Ok(Some(notification)) => notification,
+                Ok(None) => break,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    break;
+                } // end Err
+            }; // end match
+
+            let response = dispatch_email(
+                State(app_state.clone()),
+                Json(EmailPayload {
+                    full_name: notification
+                        .full_name
+                        .clone()
+                        .unwrap_or_else(|| notification.recipient.clone()),
+                    subject: notification.subject.clone(),
+                    email: notification.recipient.clone(),
+                    message: notification.message.clone(),
+                    sender: notification.sender.clone(),
+                    transactional: notification.transactional,
+                }),
+            )
+            .await;
+            let result = if response.status_code.is_success() {
+                Ok(())
+            } else {
+                Err(response.message.unwrap_or_default())
+            }; // end if
+
+            if let Err(error) = record_notification_attempt(&mut conn, &notification, result).await
+            {
+                eprintln!("{}", error);
+                break;
+            } // end if
+        } // end loop
+    } // end loop
+} // end fn send_notifications
+
+/// This job scores all the leads again when the scoring rules
+/// have changed, and periodically, e.g. for the new referrals.
+async fn rescore_leads(app_state: AppState) {
+    loop {
+        // The changed rules wake the job up right away.
+        let reason =
+            match tokio::time::timeout(LEADS_RESCORE_PERIOD, app_state.scoring.notified()).await {
+                Ok(()) => scoring::RULES,
+                Err(_elapsed) => scoring::REFRESH,
+            }; // end match
+
+        let mut conn = match app_state.pool.get().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            } // end Err
+        }; // end match
+
+        match rescore_all(&mut conn, reason).await {
+            Ok(0) => (),
+            Ok(changed) => println!("The scores of {} leads have changed", changed),
+            Err(error) => eprintln!("{}", error),
+        } // end match
+    } // end loop
+} // end fn rescore_leads
+
+/// This job periodically pushes the new leads to the CRM
+/// and retries the leads that have failed.
+async fn sync_crm(app_state: AppState, crm: Arc<CrmSync>) {
+    let mut interval = tokio::time::interval(CRM_SYNC_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        let mut conn = match app_state.pool.get().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            } // end Err
+        }; // end match
+
+        match crm.push_new_leads(&mut conn).await {
+            Ok(report) if report.total() == 0 => (),
+            Ok(report) => println!("The leads have been pushed to the CRM: {:?}", report),
+            Err(error) => eprintln!("{}", error),
+        } // end match
+    } // end loop
+} // end fn sync_crm
+
+/// This job periodically reconciles all the leads with the CRM,
+/// e.g. the leads that have changed or have been confirmed later.
+async fn reconcile_crm(app_state: AppState, crm: Arc<CrmSync>) {
+    // The first reconciliation waits for a period, so that it does not
+    // hold up the new leads after every start.
+    let mut interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + CRM_RECONCILE_PERIOD,
+        CRM_RECONCILE_PERIOD,
+    );
+
+    loop {
+        interval.tick().await;
+
+        let mut conn = match app_state.pool.get().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            } // end Err
+        }; // end match
+
+        // Reconcile the leads batch by batch until all of them are done.
+        loop {
+            match crm.reconcile_batch(&mut conn).await {
+                Ok((report, done)) => {
+                    if report.total() > 0 {
+                        println!("The leads have been reconciled with the CRM: {:?}", report);
+                    } // end if
+                    if done {
+                        break;
+                    } // end if
+                } // end Ok
+                Err(error) => {
+                    eprintln!("{}", error);
+                    break;
+                } // end Err
+            } // end match
+        } // end loop
+    } // end loop
+} // end fn reconcile_crm
@@ -1,5 +1,11 @@
-use routes::create_routes;
+use std::net::SocketAddr;
 
+use jobs::spawn_jobs;
+use routes::{create_app_state, create_router};
+use utils::lead_forms::captcha_forms;
+
+pub mod cli;
+pub mod jobs;
 pub mod middleware;
 pub mod models;
 pub mod routes;
@@ -8,12 +14,33 @@ pub mod utils;
 
 /// This function runs a server on a specified port, using pre-set-up router.
 pub async fn run() {
+    // Create the state shared by the endpoints and the background jobs.
+    let app_state = create_app_state();
+
+    // The forms that require a CAPTCHA cannot be submitted without a verifier.
+    if app_state.captcha.is_none() {
+        match app_state.pool.get().await {
+            Ok(mut conn) => match captcha_forms(&mut conn).await {
+                Ok(forms) if !forms.is_empty() => panic!(
+                    "CAPTCHA_PROVIDER is not set, but these forms require a CAPTCHA: {}",
+                    forms.join(", ")
+                ),
+                Ok(_forms) => (),
+                Err(error) => eprintln!("{}", error),
+            },
+            Err(error) => eprintln!("{}", error),
+        } // end match
+    } // end if
+
+    // Start the background jobs.
+    spawn_jobs(app_state.clone());
+
     // Get a router with all the routes, middleware and so on.
-    let app = create_routes().await;
+    let app = create_router(app_state);
 
     // Run a server based on the router specified above.
     axum::Server::bind(&"0.0.0.0:8181".parse().unwrap())
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
@@ -1,4 +1,6 @@
+use clap::Parser;
 use dotenvy::dotenv;
+use landing_form::cli::{run_command, Cli, Command};
 use landing_form::run;
 
 #[tokio::main]
@@ -6,7 +8,20 @@ async fn main() {
     // Load environmental variables.
     dotenv().ok();
 
-    // Run the main function which makes the server up and
-    // continues until termination.
-    run().await;
+    // Parse the command line arguments.
+    let cli = Cli::parse();
+
+    match cli.command {
+        // Run the main function which makes the server up and
+        // continues until termination.
+        None | Some(Command::Serve) => run().await,
+        // Run an administrative task and exit.
+        Some(command) => match run_command(command).await {
+            Ok(message) => println!("{}", message),
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }, // end Some
+    } // end match
 } // end fn main
@@ -1,8 +1,7 @@
 // This file contains middleware that prevents unauthorized
 // access to some resources.
 
-use std::collections::{HashMap, HashSet};
-
+use arc_swap::ArcSwap;
 use axum::{
     extract::State,
     headers::{authorization::Bearer, Authorization},
@@ -14,15 +13,30 @@ use axum::{
 use diesel::{ExpressionMethods, QueryDsl};
 use diesel_async::RunQueryDsl;
 
-use std::sync::{Arc, RwLock};
-
 use crate::{
     models::User,
     routes::AppState,
     schema::users::dsl,
-    utils::{jwt::is_valid_jwt, responses::DefaultResponse},
+    utils::{jwt::is_valid_jwt, permissions::PermissionTrie, responses::DefaultResponse},
 };
 
+/// This struct represents the client that has passed auth_guard.
+/// It is added to the request extensions, so the handlers behind
+/// the guard can get it with `Extension<AuthenticatedUser>`.
+#[derive(Clone)]
+pub struct AuthenticatedUser {
+    pub user: User,
+    // The titles of all the roles assigned to the user.
+    pub roles: Vec<String>,
+} // end struct AuthenticatedUser
+
+impl AuthenticatedUser {
+    /// This function checks if the user has at least one of the roles.
+    pub fn has_any_role(&self, roles: &[&str]) -> bool {
+        self.roles.iter().any(|role| roles.contains(&role.as_str()))
+    } // end fn has_any_role
+} // end impl AuthenticatedUser
+
 /// This function is middleware that protects some endpoints from unauthorized
 /// access.
 pub async fn auth_guard<B>(
@@ -52,10 +66,11 @@ pub async fn auth_guard<B>(
         } // end Err
     }; // end match// end establish_connection()
 
-    // Try to load the user by their token with their roles.
+    // Try to load the user by their token with their active roles.
     let mut users: Vec<(User, String)> = match dsl::users
         .filter(crate::schema::users::columns::token.eq(&token))
         .inner_join(crate::schema::users_roles::table.inner_join(crate::schema::roles::table))
+        .filter(crate::utils::roles::active())
         .select((
             crate::schema::users::all_columns,
             crate::schema::roles::title,
@@ -106,16 +121,19 @@ pub async fn auth_guard<B>(
 
     // Get a single user with all their roles assigned.
     let user = users.pop().unwrap();
-    let mut user: (User, Vec<String>) = (user.0, vec![user.1]);
+    let mut user = AuthenticatedUser {
+        user: user.0,
+        roles: vec![user.1],
+    }; // end AuthenticatedUser
 
     // Assign all user roles to the client.
     for (_, role) in users.into_iter() {
-        user.1.push(role);
+        user.roles.push(role);
     } // end for
 
     // Check if the user has permission to access
     // the route they want to access.
-    if !has_permission(&user.1, req.uri().path(), app_state.allowed_roles) {
+    if !has_permission(&user.roles, req.uri().path(), &app_state.allowed_roles) {
         // The user is not allowed to access the route.
         return Err(DefaultResponse {
             status_code: StatusCode::UNAUTHORIZED,
@@ -134,60 +152,13 @@ pub async fn auth_guard<B>(
 
 /// This function checks if the user is allowed to access the
 /// route they want to access.
-fn has_permission(
-    roles: &Vec<String>,
+///
+/// NOTE: The permissions are read from the currently published
+/// PermissionTrie without taking any locks.
+pub fn has_permission(
+    roles: &[String],
     path: &str,
-    allowed_routes: Arc<RwLock<HashMap<String, HashSet<String>>>>,
+    allowed_routes: &ArcSwap<PermissionTrie>,
 ) -> bool {
-    // Get the exclusive rights on variable manipulation.
-    let allowed_routes = (*allowed_routes)
-        .write()
-        .expect("An error occurred while unwrapping RwLock for writing");
-
-    // This variable is required to check if the
-    // user has access to all the parent routes of the request.
-    let mut assembled_path = String::new();
-
-    // Assemble the entire path and on each step check
-    // if the user has access to the parent path.
-    for part in path.split("/") {
-        // Add the next part of the path.
-        // If the part is empty, then there is no need to add it
-        // to the path.
-        //
-        // NOTE: This logic assumes that the root path "/" is UNPROTECTED.
-        if !part.is_empty() {
-            assembled_path.push_str(&format!("/{}", part));
-        } // end if
-
-        println!("{}", assembled_path);
-
-        // Check if the route is added to the set of protected routes.
-        if let Some(allowed_roles) = allowed_routes.get(&assembled_path) {
-            // Check if the user can access the route.
-            let mut allowed = false;
-            // Traverse all the roles the client has.
-            for role in roles {
-                // Check if the current role grands the client
-                // permission to access the route.
-                if allowed_roles.contains(role) {
-                    // A user can access the route.
-                    allowed = true;
-                    break;
-                } // end if
-            } // end for
-
-            // Check if the client has at least one
-            // of the required roles assigned.
-            if !allowed {
-                // The user is not allowed to
-                // access the route.
-                return false;
-            } // end if
-        } // end if
-    } // end for
-
-    // All checks have been performed,
-    // a user can access the route.
-    true
+    allowed_routes.load().permits(roles, path)
 } // end fn has_permission
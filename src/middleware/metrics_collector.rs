@@ -31,7 +31,7 @@ pub async fn metrics_collector<B>(req: Request<B>, next: Next<B>) -> Result<Resp
 
             // Start the timer that measures the request time.
             let histogram_timer = lazy_static::HTTP_RESPONSE_TIME_SECONDS
-                .with_label_values(&[&request_method, &request_path])
+                .with_label_values(&[&request_method, request_path])
                 .start_timer();
 
             // Proceed to the requested source.
@@ -1,15 +1,55 @@
+use crate::routes::admin::analytics::__path_funnel_report;
+use crate::routes::admin::attribution::__path_campaign_report;
+use crate::routes::admin::data_requests::__path_list_data_requests;
+use crate::routes::admin::forms::{__path_create_form, __path_list_forms, __path_update_form};
+use crate::routes::admin::leads::{
+    __path_export_leads, __path_import_leads, __path_lead_score, __path_search_leads,
+    __path_set_lead_tags, LeadScore, LeadTagsPayload,
+};
+use crate::routes::admin::roles::{__path_grant_role, RoleGrantPayload};
+use crate::routes::admin::scoring::{
+    __path_create_rule, __path_delete_rule, __path_list_rules, __path_update_rule,
+    ScoringRulePayload,
+};
+use crate::routes::admin::webhooks::{
+    __path_create_webhook, __path_delete_webhook, __path_list_deliveries, __path_list_webhooks,
+    __path_redeliver_delivery, CreatedWebhook, WebhookPayload,
+};
 use crate::routes::auth::login::__path_login;
 use crate::routes::auth::register::__path_register;
-use crate::routes::dispatch_email::{EmailPayload, __path_dispatch_email};
-use crate::routes::insert::__path_insert;
-use crate::schema::users;
-use crate::utils::responses::{DefaultResponseJson, LoginResponseJson};
+use crate::routes::consent::{
+    __path_consent_page, __path_withdraw_consent, WithdrawConsentPayload,
+};
+use crate::routes::data::{
+    __path_data_page, __path_erase_data, __path_export_data, __path_request_data_link,
+    DataLinkPayload,
+};
+use crate::routes::dispatch_email::{__path_dispatch_email, EmailPayload};
+use crate::routes::insert::{__path_form_challenge, __path_insert, __path_insert_into_form};
+use crate::routes::subscription::{__path_confirm_subscription, __path_confirmation_page};
+use crate::routes::unsubscribe::{__path_unsubscribe, __path_unsubscribe_page};
+use crate::routes::users::{__path_erase_user, __path_export_user, __path_get_user};
+use crate::routes::waitlist::__path_waitlist_status;
+use crate::schema::{
+    audit_log, consents, crm_contacts, data_requests, form_notifications, forms, lead_attribution,
+    lead_submissions, scoring_rules, users, users_roles, waitlist, webhook_deliveries, webhooks,
+};
+use crate::utils::antispam::Challenge;
+use crate::utils::attribution::CampaignReportRow;
+use crate::utils::forms::{FieldDefinition, FieldType, FormDefinition};
+use crate::utils::funnel::{FunnelInterval, FunnelReport, FunnelRow, FunnelStages, FunnelTotal};
+use crate::utils::imports::{ImportReport, ImportStatus, ImportedRow};
+use crate::utils::lead_search::{LeadPage, LeadSummary};
+use crate::utils::responses::{DefaultResponseJson, LoginResponseJson, UserResponseJson};
+use crate::utils::scoring::Operator;
+use crate::utils::waitlist::WaitlistStatus;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use utoipa::{OpenApi, ToSchema};
 
 /// This is a struct for retrieving a user from a database.
-#[derive(Queryable)]
+#[derive(Queryable, Clone)]
 pub struct User {
     pub id: i32,
     pub name: String,
@@ -19,6 +59,13 @@ pub struct User {
     pub password: Option<String>,
     pub token: Option<String>,
     pub verified: bool,
+    pub created_at: DateTime<Utc>,
+    // If it is None, then the subscription is pending.
+    pub confirmed_at: Option<DateTime<Utc>>,
+    // The values of the custom fields of the form.
+    pub custom_fields: serde_json::Value,
+    // The form the user has subscribed with.
+    pub form_id: Option<i32>,
 } // end struct User
 
 // This is a struct for inserting a user in a database.
@@ -31,7 +78,9 @@ pub struct NewUser {
     pub email: Option<String>,
     #[schema(example = 1)]
     pub phone_number_code: i32,
-    #[schema(example = "9999999999")]
+    // Any format of the country, e.g. "(201) 555-0123" or "+1 201 555 0123",
+    // it is stored in E.164.
+    #[schema(example = "(201) 555-0123")]
     pub phone_number: String,
     #[schema(example = "qwerty123")]
     pub password: Option<String>,
@@ -45,12 +94,367 @@ pub struct LoginUser {
     pub email: Option<String>,
     #[schema(example = 1)]
     pub phone_number_code: Option<i32>,
-    #[schema(example = "9999999999")]
+    #[schema(example = "(201) 555-0123")]
     pub phone_number: Option<String>,
     #[schema(example = "qwerty123")]
     pub password: String,
 } // end struct LoginUser
 
+/// This is a struct for granting a role to a user.
+/// NOTE: A grant without "expires_at" is permanent.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = users_roles)]
+pub struct NewRoleGrant {
+    pub user_id: i32,
+    pub role_id: i32,
+    pub granted_by: Option<i32>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+} // end struct NewRoleGrant
+
+/// This is a struct for inserting an entry in the audit log.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = audit_log)]
+pub struct NewAuditEntry {
+    // The user the event is about.
+    pub user_id: Option<i32>,
+    pub action: String,
+    pub details: serde_json::Value,
+} // end struct NewAuditEntry
+
+/// This struct represents a landing form with its settings.
+#[derive(Queryable, Serialize, ToSchema, Clone, Debug)]
+pub struct LeadForm {
+    pub id: i32,
+    #[schema(example = "default")]
+    pub slug: String,
+    #[schema(example = "Manuspect")]
+    pub title: String,
+    #[schema(example = "Manuspect <manuspect.prod@gmail.com>")]
+    pub sender: String,
+    #[schema(example = "Please confirm your subscription")]
+    pub welcome_subject: String,
+    #[schema(example = "Hello, {name}! Please confirm your subscription: {confirmation_link}")]
+    pub welcome_template: String,
+    pub success_redirect: Option<String>,
+    // It is kept for the compatibility only, see LeadFormPayload.
+    #[schema(deprecated)]
+    pub duplicate_redirect: Option<String>,
+    pub confirmed_redirect: Option<String>,
+    #[schema(example = json!(["email"]))]
+    pub required_fields: Vec<String>,
+    #[schema(example = json!(["sales@example.com"]))]
+    pub notification_recipients: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    #[schema(example = false)]
+    pub captcha_required: bool,
+} // end struct LeadForm
+
+/// This struct represents a queued email about a submission of a form.
+#[derive(Queryable, Debug)]
+pub struct FormNotification {
+    pub id: i32,
+    pub user_id: i32,
+    pub recipient: String,
+    pub full_name: Option<String>,
+    pub sender: Option<String>,
+    pub transactional: bool,
+    pub subject: String,
+    pub message: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+} // end struct FormNotification
+
+/// This is a struct for queueing an email about a submission of a form
+/// or another email that is sent in the background.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = form_notifications)]
+pub struct NewFormNotification {
+    pub user_id: i32,
+    pub recipient: String,
+    pub full_name: Option<String>,
+    pub sender: Option<String>,
+    pub transactional: bool,
+    pub subject: String,
+    pub message: String,
+} // end struct NewFormNotification
+
+/// This is a struct for creating or changing a landing form.
+/// NOTE: The absent redirects are cleared when a form is changed.
+#[derive(Insertable, AsChangeset, Deserialize, ToSchema, Debug)]
+#[diesel(table_name = forms, treat_none_as_null = true)]
+pub struct LeadFormPayload {
+    #[schema(example = "spring-sale")]
+    pub slug: String,
+    #[schema(example = "Spring sale")]
+    pub title: String,
+    #[schema(example = "Manuspect <manuspect.prod@gmail.com>")]
+    pub sender: String,
+    #[schema(example = "Please confirm your subscription")]
+    pub welcome_subject: String,
+    #[schema(example = "Hello, {name}! Please confirm your subscription: {confirmation_link}")]
+    pub welcome_template: String,
+    #[schema(example = "https://example.com/thanks.html")]
+    pub success_redirect: Option<String>,
+    // NOTE: It is ignored, since the returning leads get the same
+    // response as the new ones, and is going to be removed.
+    #[schema(deprecated, example = "https://example.com/already_subscribed.html")]
+    pub duplicate_redirect: Option<String>,
+    #[schema(example = "https://example.com/subscribed.html")]
+    pub confirmed_redirect: Option<String>,
+    #[serde(default)]
+    #[schema(example = json!(["email"]))]
+    pub required_fields: Vec<String>,
+    #[serde(default)]
+    #[schema(example = json!(["sales@example.com"]))]
+    pub notification_recipients: Vec<String>,
+    // The submissions have to pass a CAPTCHA, see CAPTCHA_PROVIDER.
+    #[serde(default)]
+    #[schema(example = false)]
+    pub captcha_required: bool,
+} // end struct LeadFormPayload
+
+/// This is a struct for recording where a lead came from.
+#[derive(Insertable, Debug, Default)]
+#[diesel(table_name = lead_attribution)]
+pub struct NewLeadAttribution {
+    pub user_id: i32,
+    pub utm_source: Option<String>,
+    pub utm_medium: Option<String>,
+    pub utm_campaign: Option<String>,
+    pub utm_term: Option<String>,
+    pub utm_content: Option<String>,
+    pub referrer: Option<String>,
+    pub landing_url: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+} // end struct NewLeadAttribution
+
+/// This is a struct for recording a submission of a landing form.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = lead_submissions)]
+pub struct NewLeadSubmission<'a> {
+    pub user_id: i32,
+    pub form_id: i32,
+    pub created_lead: bool,
+    // "form" or "import".
+    pub source: &'a str,
+    pub name: &'a str,
+    pub email: Option<&'a str>,
+    pub phone_number: &'a str,
+    pub custom_fields: &'a serde_json::Value,
+} // end struct NewLeadSubmission
+
+/// This struct represents a lead in the waitlist.
+#[derive(Queryable, Debug)]
+pub struct WaitlistEntry {
+    pub user_id: i32,
+    pub ticket: i32,
+    pub boost: i32,
+    pub referral_code: String,
+    pub referred_by: Option<i32>,
+    pub referral_rewarded: bool,
+    pub joined_at: DateTime<Utc>,
+} // end struct WaitlistEntry
+
+/// This is a struct for adding a lead to the waitlist.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = waitlist)]
+pub struct NewWaitlistEntry {
+    pub user_id: i32,
+    pub referral_code: String,
+    pub referred_by: Option<i32>,
+} // end struct NewWaitlistEntry
+
+/// This struct represents an endpoint that is notified about the events.
+#[derive(Queryable, Serialize, ToSchema, Debug)]
+pub struct Webhook {
+    #[schema(example = 1)]
+    pub id: i32,
+    #[schema(example = "https://crm.example.com/hooks/leads")]
+    pub url: String,
+    // The key the deliveries are signed with, it is shown only once.
+    #[serde(skip)]
+    pub secret: String,
+    #[schema(example = json!(["lead.created"]))]
+    pub events: Vec<String>,
+    pub created_at: DateTime<Utc>,
+} // end struct Webhook
+
+/// This is a struct for registering a webhook.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = webhooks)]
+pub struct NewWebhook {
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<String>,
+} // end struct NewWebhook
+
+/// This struct represents a delivery of an event to a webhook.
+#[derive(Queryable, Serialize, ToSchema, Debug)]
+pub struct WebhookDelivery {
+    #[schema(example = 42)]
+    pub id: i32,
+    #[schema(example = 1)]
+    pub webhook_id: i32,
+    #[schema(example = "lead.created")]
+    pub event: String,
+    pub payload: serde_json::Value,
+    // "pending", "delivered" or "failed".
+    #[schema(example = "delivered")]
+    pub status: String,
+    #[schema(example = 1)]
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    // The status code of the last response.
+    #[schema(example = 200)]
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+} // end struct WebhookDelivery
+
+/// This is a struct for queueing a delivery of an event.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct NewWebhookDelivery<'a> {
+    pub webhook_id: i32,
+    pub event: &'a str,
+    pub payload: &'a serde_json::Value,
+} // end struct NewWebhookDelivery
+
+/// This struct represents a record of the consent ledger.
+#[derive(Queryable, Serialize, ToSchema, Debug)]
+pub struct Consent {
+    #[serde(skip)]
+    pub id: i32,
+    #[serde(skip)]
+    pub user_id: i32,
+    // "privacy_policy" or "marketing".
+    #[schema(example = "marketing")]
+    pub purpose: String,
+    pub granted: bool,
+    #[serde(skip)]
+    pub pending: bool,
+    #[schema(example = "2024-05")]
+    pub policy_version: String,
+    // "form", "registration", "withdrawal", "unsubscribe", "import" or "confirmation".
+    #[schema(example = "form")]
+    pub source: String,
+    #[schema(example = "203.0.113.7")]
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+} // end struct Consent
+
+/// This is a struct for appending a record to the consent ledger.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = consents)]
+pub struct NewConsent {
+    pub user_id: i32,
+    pub purpose: String,
+    pub granted: bool,
+    pub pending: bool,
+    pub policy_version: String,
+    pub source: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+} // end struct NewConsent
+
+/// This is a struct for saving the link of a lead to a contact in a CRM.
+#[derive(Insertable, AsChangeset, Debug)]
+#[diesel(table_name = crm_contacts, treat_none_as_null = true)]
+pub struct NewCrmContact<'a> {
+    pub connector: &'a str,
+    pub user_id: i32,
+    pub external_id: Option<String>,
+    pub status: &'a str,
+    pub last_error: Option<String>,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub synced_at: DateTime<Utc>,
+} // end struct NewCrmContact
+
+/// This struct represents a rule the leads are scored with.
+#[derive(Queryable, Serialize, ToSchema, Clone, Debug)]
+pub struct ScoringRule {
+    #[schema(example = 1)]
+    pub id: i32,
+    #[schema(example = "Came from the newsletter")]
+    pub name: String,
+    #[schema(example = "utm_source")]
+    pub field: String,
+    // "exists", "equals", "not_equals", "contains", "gte" or "lte".
+    #[schema(example = "equals")]
+    pub operator: String,
+    #[schema(example = "newsletter")]
+    pub value: Option<serde_json::Value>,
+    #[schema(example = 10)]
+    pub points: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+} // end struct ScoringRule
+
+/// This is a struct for adding or changing a scoring rule.
+#[derive(Insertable, AsChangeset, Debug)]
+#[diesel(table_name = scoring_rules, treat_none_as_null = true)]
+pub struct NewScoringRule<'a> {
+    pub name: &'a str,
+    pub field: &'a str,
+    pub operator: &'a str,
+    pub value: Option<&'a serde_json::Value>,
+    pub points: i32,
+    pub updated_at: DateTime<Utc>,
+} // end struct NewScoringRule
+
+/// This struct represents a change of the score of a lead.
+#[derive(Queryable, Serialize, ToSchema, Debug)]
+pub struct LeadScoreChange {
+    #[serde(skip)]
+    pub id: i32,
+    #[serde(skip)]
+    pub user_id: i32,
+    #[schema(example = 25)]
+    pub score: i32,
+    #[schema(example = 15)]
+    pub previous_score: Option<i32>,
+    // What has caused the change, e.g. "submission" or "rules".
+    #[schema(example = "confirmed")]
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+} // end struct LeadScoreChange
+
+/// This struct represents a request of a user to export or erase their data.
+#[derive(Queryable, Serialize, ToSchema, Debug)]
+pub struct DataRequest {
+    #[schema(example = 1)]
+    pub id: i32,
+    #[schema(example = 42)]
+    pub subject_id: i32,
+    // "export" or "erasure".
+    #[schema(example = "erasure")]
+    pub kind: String,
+    #[schema(example = 42)]
+    pub requested_by: i32,
+    // "pending", "completed" or "failed".
+    #[schema(example = "completed")]
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+} // end struct DataRequest
+
+/// This is a struct for logging a request to export or erase the data.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = data_requests)]
+pub struct NewDataRequest<'a> {
+    pub subject_id: i32,
+    pub kind: &'a str,
+    pub requested_by: i32,
+} // end struct NewDataRequest
+
 // This is a swagger REST API documentation generator.
 #[derive(OpenApi)]
 #[openapi(
@@ -66,7 +470,7 @@ pub struct LoginUser {
         (url = "http://localhost", description = "This is a local server for testing"),
         (url = "http://95.165.88.39", description = "This is a remote server for testing"),
     ),
-    paths(insert, dispatch_email, register, login),
-    components(schemas(NewUser, DefaultResponseJson, EmailPayload, DefaultResponseJson, LoginUser, LoginResponseJson))
+    paths(insert, insert_into_form, form_challenge, dispatch_email, register, login, get_user, export_user, erase_user, grant_role, confirmation_page, confirm_subscription, unsubscribe_page, unsubscribe, consent_page, withdraw_consent, request_data_link, data_page, export_data, erase_data, funnel_report, campaign_report, list_forms, create_form, update_form, search_leads, export_leads, import_leads, set_lead_tags, lead_score, list_rules, create_rule, update_rule, delete_rule, waitlist_status, list_webhooks, create_webhook, delete_webhook, list_deliveries, redeliver_delivery, list_data_requests),
+    components(schemas(NewUser, DefaultResponseJson, EmailPayload, DefaultResponseJson, LoginUser, LoginResponseJson, UserResponseJson, RoleGrantPayload, CampaignReportRow, FormDefinition, FieldDefinition, FieldType, LeadForm, LeadFormPayload, Challenge, WaitlistStatus, LeadTagsPayload, ImportReport, ImportedRow, ImportStatus, Webhook, WebhookPayload, CreatedWebhook, WebhookDelivery, WithdrawConsentPayload, DataLinkPayload, DataRequest, ScoringRule, ScoringRulePayload, Operator, LeadScore, LeadScoreChange, LeadPage, LeadSummary, FunnelReport, FunnelRow, FunnelTotal, FunnelStages, FunnelInterval))
 )] // end openapi
 pub struct ApiDoc;
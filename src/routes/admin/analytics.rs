@@ -0,0 +1,57 @@
+// This file contains the endpoints that report the conversion
+// funnel of the users.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+
+use crate::{
+    routes::AppState,
+    utils::{
+        funnel::{self, FunnelQuery, FunnelReport},
+        responses::DefaultResponse,
+    },
+};
+
+/// Report the conversion funnel.
+///
+/// The users that have subscribed, confirmed the subscription, registered
+/// and logged in for the first time are counted by day or by week, with
+/// the rates between the stages. The report can be broken down and
+/// filtered by form, campaign and country.
+///
+#[utoipa::path(
+    get,
+    tag = "Administration",
+    path = "/admin/analytics/funnel",
+    params(FunnelQuery),
+    responses(
+        (status = StatusCode::OK, description = "The users at every stage by period", body = FunnelReport),
+        (status = StatusCode::BAD_REQUEST, description = "The report ends before it starts", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin or a Manager", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn funnel_report(
+    State(app_state): State<AppState>,
+    Query(query): Query<FunnelQuery>,
+) -> Result<Json<FunnelReport>, DefaultResponse> {
+    let (from, to) = query.period().map_err(|message| DefaultResponse {
+        status_code: StatusCode::BAD_REQUEST,
+        message: Some(message),
+        redirect: None,
+    })?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    funnel::report(&mut conn, &query, from, to)
+        .await
+        .map(Json)
+        .map_err(DefaultResponse::server_error)
+} // end fn funnel_report
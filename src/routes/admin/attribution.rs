@@ -0,0 +1,47 @@
+// This file contains the endpoints that report the marketing
+// attribution of the leads.
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+
+use crate::{
+    routes::AppState,
+    utils::{
+        attribution::{self, CampaignReportQuery, CampaignReportRow},
+        responses::DefaultResponse,
+    },
+};
+
+/// Report the leads per campaign.
+///
+/// The leads are grouped by their UTM source, medium and campaign.
+/// The leads that came without UTM parameters are grouped together.
+///
+#[utoipa::path(
+    get,
+    tag = "Administration",
+    path = "/admin/attribution/campaigns",
+    params(CampaignReportQuery),
+    responses(
+        (status = StatusCode::OK, description = "The leads per campaign", body = [CampaignReportRow]),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin or a Manager", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn campaign_report(
+    State(app_state): State<AppState>,
+    Query(query): Query<CampaignReportQuery>,
+) -> Result<Json<Vec<CampaignReportRow>>, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    attribution::campaign_report(&mut conn, &query)
+        .await
+        .map(Json)
+        .map_err(DefaultResponse::server_error)
+} // end fn campaign_report
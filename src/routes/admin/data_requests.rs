@@ -0,0 +1,71 @@
+// This file contains the endpoint that shows the log
+// of the requests to export or erase the data.
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    models::DataRequest, routes::AppState, schema::data_requests, utils::responses::DefaultResponse,
+};
+
+// The number of the latest requests that are listed.
+const REQUESTS_LIMIT: i64 = 100;
+
+/// This struct represents the filters of the data requests.
+#[derive(Deserialize, IntoParams, Debug)]
+pub struct DataRequestsQuery {
+    // Only the requests about this user.
+    pub subject_id: Option<i32>,
+    // "pending", "completed" or "failed".
+    pub status: Option<String>,
+} // end struct DataRequestsQuery
+
+/// List the requests to export or erase the data.
+///
+/// Every export and erasure is logged with its status ("pending",
+/// "completed" or "failed"). A request stays pending if the server
+/// has stopped while handling it.
+///
+#[utoipa::path(
+    get,
+    tag = "Administration",
+    path = "/admin/data_requests",
+    params(DataRequestsQuery),
+    responses(
+        (status = StatusCode::OK, description = "The latest 100 requests, the newest first", body = [DataRequest]),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn list_data_requests(
+    State(app_state): State<AppState>,
+    Query(query): Query<DataRequestsQuery>,
+) -> Result<Json<Vec<DataRequest>>, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let mut requests = data_requests::table.into_boxed();
+    if let Some(subject_id) = query.subject_id {
+        requests = requests.filter(data_requests::subject_id.eq(subject_id));
+    } // end if
+    if let Some(status) = query.status {
+        requests = requests.filter(data_requests::status.eq(status));
+    } // end if
+
+    requests
+        .order_by(data_requests::id.desc())
+        .limit(REQUESTS_LIMIT)
+        .load::<DataRequest>(&mut conn)
+        .await
+        .map(Json)
+        .map_err(DefaultResponse::server_error)
+} // end fn list_data_requests
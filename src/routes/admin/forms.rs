@@ -0,0 +1,157 @@
+// This file contains the endpoints that manage the landing forms.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use diesel::result::{DatabaseErrorKind, Error};
+use diesel::{ExpressionMethods, OptionalExtension};
+use diesel_async::RunQueryDsl;
+
+use crate::{
+    models::{LeadForm, LeadFormPayload},
+    routes::AppState,
+    schema::forms,
+    utils::{
+        lead_forms::{is_valid_form_payload, list_forms as load_forms},
+        responses::DefaultResponse,
+    },
+};
+
+/// This function converts an error of saving a form to a response.
+fn save_error(error: Error) -> DefaultResponse {
+    match error {
+        // The slug is taken by another form.
+        Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => DefaultResponse {
+            status_code: StatusCode::CONFLICT,
+            message: Some("The form with this slug already exists".to_string()),
+            redirect: None,
+        },
+        error => DefaultResponse::server_error(error),
+    } // end match
+} // end fn save_error
+
+/// This function checks the settings of a form.
+fn validate(app_state: &AppState, payload: &LeadFormPayload) -> Result<(), DefaultResponse> {
+    is_valid_form_payload(payload, app_state.captcha.is_some()).map_err(|message| DefaultResponse {
+        status_code: StatusCode::BAD_REQUEST,
+        message: Some(message),
+        redirect: None,
+    }) // end map_err
+} // end fn validate
+
+/// List the landing forms.
+///
+#[utoipa::path(
+    get,
+    tag = "Administration",
+    path = "/admin/forms",
+    responses(
+        (status = StatusCode::OK, description = "All the landing forms", body = [LeadForm]),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn list_forms(
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<LeadForm>>, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    load_forms(&mut conn)
+        .await
+        .map(Json)
+        .map_err(DefaultResponse::server_error)
+} // end fn list_forms
+
+/// Create a landing form.
+///
+/// The welcome template has to contain "{confirmation_link}",
+/// it can also contain "{name}". The custom fields of the form are
+/// declared in the form definition with the same name as the slug.
+///
+#[utoipa::path(
+    post,
+    tag = "Administration",
+    path = "/admin/forms",
+    request_body(content = LeadFormPayload, description = "The settings of the form", content_type = "application/json"),
+    responses(
+        (status = StatusCode::CREATED, description = "The form is created", body = LeadForm),
+        (status = StatusCode::BAD_REQUEST, description = "The settings are not valid, e.g. a CAPTCHA is required without a provider", body = DefaultResponseJson),
+        (status = StatusCode::CONFLICT, description = "The slug is taken by another form", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn create_form(
+    State(app_state): State<AppState>,
+    Json(payload): Json<LeadFormPayload>,
+) -> Result<(StatusCode, Json<LeadForm>), DefaultResponse> {
+    validate(&app_state, &payload)?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let form = diesel::insert_into(forms::table)
+        .values(&payload)
+        .get_result::<LeadForm>(&mut conn)
+        .await
+        .map_err(save_error)?;
+
+    Ok((StatusCode::CREATED, Json(form)))
+} // end fn create_form
+
+/// Change the settings of a landing form.
+///
+/// NOTE: The slug can be changed as well, the landing page
+/// has to submit the form to the new address then.
+///
+#[utoipa::path(
+    put,
+    tag = "Administration",
+    path = "/admin/forms/{slug}",
+    params(("slug" = String, Path, description = "The current slug of the form", example = "default")),
+    request_body(content = LeadFormPayload, description = "The new settings of the form", content_type = "application/json"),
+    responses(
+        (status = StatusCode::OK, description = "The form is changed", body = LeadForm),
+        (status = StatusCode::BAD_REQUEST, description = "The settings are not valid, e.g. a CAPTCHA is required without a provider", body = DefaultResponseJson),
+        (status = StatusCode::NOT_FOUND, description = "The form does not exist", body = DefaultResponseJson),
+        (status = StatusCode::CONFLICT, description = "The slug is taken by another form", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn update_form(
+    State(app_state): State<AppState>,
+    Path(slug): Path<String>,
+    Json(payload): Json<LeadFormPayload>,
+) -> Result<Json<LeadForm>, DefaultResponse> {
+    validate(&app_state, &payload)?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    diesel::update(forms::table)
+        .filter(forms::slug.eq(&slug))
+        .set(&payload)
+        .get_result::<LeadForm>(&mut conn)
+        .await
+        .optional()
+        .map_err(save_error)?
+        .map(Json)
+        .ok_or_else(|| DefaultResponse {
+            status_code: StatusCode::NOT_FOUND,
+            message: Some("The form does not exist".to_string()),
+            redirect: None,
+        }) // end ok_or_else
+} // end fn update_form
@@ -0,0 +1,520 @@
+// This file contains the endpoints that search, export, import, tag and score the leads.
+
+use std::io;
+
+use axum::{
+    body::{self, Bytes, StreamBody},
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use diesel::{ExpressionMethods, NullableExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    middleware::auth_guard::AuthenticatedUser,
+    models::{LeadScoreChange, NewAuditEntry},
+    routes::AppState,
+    schema::{lead_score_history, lead_scores, lead_tags, users},
+    utils::{
+        audit,
+        exports::{
+            csv_chunk, fetch_leads, header_row, write_xlsx, ExportFormat, LeadExportQuery,
+            TAG_SEPARATOR,
+        },
+        forms::DEFAULT_FORM,
+        imports::{self, ColumnMapping, ImportError, ImportOptions, ImportReport},
+        lead_forms::find_form,
+        lead_search::{find_leads, LeadPage, LeadSearchQuery, INVALID_CURSOR},
+        responses::DefaultResponse,
+        scoring,
+    },
+};
+
+// The maximum length of a tag.
+const MAX_TAG_LENGTH: usize = 64;
+
+// The number of the latest changes of a score that are shown.
+const SCORE_HISTORY_LIMIT: i64 = 100;
+
+/// This struct represents the tags of a lead.
+#[derive(Deserialize, ToSchema)]
+pub struct LeadTagsPayload {
+    #[schema(example = json!(["hot", "enterprise"]))]
+    pub tags: Vec<String>,
+} // end struct LeadTagsPayload
+
+/// This struct represents the score of a lead and how it has changed.
+#[derive(Serialize, ToSchema)]
+pub struct LeadScore {
+    #[schema(example = 42)]
+    pub user_id: i32,
+    // The lead that has not been scored yet has no score.
+    #[schema(example = 25)]
+    pub score: Option<i32>,
+    pub updated_at: Option<DateTime<Utc>>,
+    // The latest changes first.
+    pub history: Vec<LeadScoreChange>,
+} // end struct LeadScore
+
+/// This struct represents the settings of an import.
+#[derive(Deserialize, IntoParams, Debug)]
+pub struct LeadImportQuery {
+    /// The slug of the form the leads are imported into, "default" by default.
+    pub form: Option<String>,
+    /// The fields that are in the columns with other names,
+    /// e.g. "name:Full name,email:E-mail".
+    pub columns: Option<String>,
+    /// The phone number code of the rows without "phone_number_code".
+    pub phone_number_code: Option<i32>,
+    /// Check the file and report the results without saving anything.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Where the consents of the leads have been collected, e.g. "The signup
+    /// sheet of the meetup on 2024-05-01". The new leads are confirmed only
+    /// if it is given, and only the Admins can give it.
+    pub consent: Option<String>,
+} // end struct LeadImportQuery
+
+/// Search the leads.
+///
+/// The text is searched in the names, the emails and the phone numbers,
+/// the leads can be filtered like the exports and by their role, and are
+/// sorted by the time they came, by score or by name. The pages are
+/// followed with the "next_cursor" of the previous page.
+///
+#[utoipa::path(
+    get,
+    tag = "Administration",
+    path = "/admin/leads",
+    params(LeadSearchQuery),
+    responses(
+        (status = StatusCode::OK, description = "A page of the found leads", body = LeadPage),
+        (status = StatusCode::BAD_REQUEST, description = "The cursor is invalid or belongs to another sort", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin or a Manager", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn search_leads(
+    State(app_state): State<AppState>,
+    Query(query): Query<LeadSearchQuery>,
+) -> Result<Json<LeadPage>, DefaultResponse> {
+    // This is a response for the cursors that cannot be used.
+    let invalid_cursor = || DefaultResponse {
+        status_code: StatusCode::BAD_REQUEST,
+        message: Some(INVALID_CURSOR.to_string()),
+        redirect: None,
+    }; // end invalid_cursor
+
+    let cursor = query.cursor().map_err(|_error| invalid_cursor())?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    find_leads(&mut conn, &query, cursor.as_ref())
+        .await
+        .map_err(DefaultResponse::server_error)?
+        .map(Json)
+        .ok_or_else(invalid_cursor)
+} // end fn search_leads
+
+/// Export the leads.
+///
+/// The leads that match the filters are exported together with their
+/// attribution, tags and custom fields, as CSV or XLSX. The file is
+/// streamed while the leads are read, every export is recorded in
+/// the audit log.
+///
+#[utoipa::path(
+    get,
+    tag = "Administration",
+    path = "/admin/leads/export",
+    params(LeadExportQuery),
+    responses(
+        (status = StatusCode::OK, description = "The file with the leads", content_type = "text/csv"),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin or a Manager", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn export_leads(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Query(query): Query<LeadExportQuery>,
+) -> Result<Response, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    // The exports contain personal data, so they are all recorded.
+    audit::record(
+        &mut conn,
+        &[NewAuditEntry {
+            user_id: None,
+            action: "leads.exported".to_string(),
+            details: json!({
+                "exported_by": client.user.id,
+                "filters": query,
+            }),
+        }],
+    )
+    .await
+    .map_err(DefaultResponse::server_error)?;
+
+    // The custom fields of the exported form become the columns.
+    let custom_fields = app_state.forms.field_names(query.form.as_deref());
+    let format = query.format;
+
+    // The leads are read in the background while the file is sent,
+    // the channel makes the reading wait for a slow client.
+    let (batches, received_batches) = mpsc::channel(2);
+    tokio::spawn(async move {
+        if let Err(error) = fetch_leads(&mut conn, &query, &batches).await {
+            eprintln!("{}", error);
+            let _ = batches.send(Err(error)).await;
+        } // end if
+    });
+
+    let (content_type, extension, body) = match format {
+        ExportFormat::Csv => {
+            let header_chunk = csv_chunk(&[header_row(&custom_fields)]);
+            let rows = ReceiverStream::new(received_batches).map(move |batch| match batch {
+                Ok(rows) => csv_chunk(
+                    &rows
+                        .iter()
+                        .map(|row| row.cells(&custom_fields))
+                        .collect::<Vec<_>>(),
+                ),
+                Err(error) => Err(io::Error::other(error.to_string())),
+            });
+
+            (
+                "text/csv; charset=utf-8",
+                "csv",
+                body::boxed(StreamBody::new(
+                    tokio_stream::once(header_chunk).chain(rows),
+                )),
+            )
+        } // end Csv
+        ExportFormat::Xlsx => {
+            let (chunks, received_chunks) = mpsc::channel(4);
+            tokio::task::spawn_blocking(move || {
+                if let Err(error) = write_xlsx(&custom_fields, received_batches, &chunks) {
+                    eprintln!("{}", error);
+                    let _ = chunks.blocking_send(Err(io::Error::other(error.to_string())));
+                } // end if
+            });
+
+            (
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+                "xlsx",
+                body::boxed(StreamBody::new(ReceiverStream::new(received_chunks))),
+            )
+        } // end Xlsx
+    }; // end match
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"leads.{}\"", extension),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+} // end fn export_leads
+
+/// Import the leads from a CSV file.
+///
+/// The file has a header, the columns are imported as the fields with
+/// the same names ("name", "email", "phone_number_code", "phone_number"
+/// and the custom fields of the form) unless "columns" maps them.
+/// Every row is checked like a submission of the form and is merged into
+/// the lead with the same phone number or email if there is one.
+/// The new leads get no emails and stay pending, unless an Admin
+/// confirms them with the provenance of their consents ("consent").
+///
+#[utoipa::path(
+    post,
+    tag = "Administration",
+    path = "/admin/leads/import",
+    params(LeadImportQuery),
+    request_body(content = String, description = "The CSV file with the leads", content_type = "text/csv"),
+    responses(
+        (status = StatusCode::OK, description = "The result of every row", body = ImportReport),
+        (status = StatusCode::BAD_REQUEST, description = "The file or the column mapping is invalid", body = DefaultResponseJson),
+        (status = StatusCode::NOT_FOUND, description = "The form does not exist", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin or a Manager, or confirms the leads without being an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn import_leads(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Query(query): Query<LeadImportQuery>,
+    file: Bytes,
+) -> Result<Json<ImportReport>, DefaultResponse> {
+    // This is a helper function for the responses about invalid requests.
+    fn bad_request(message: String) -> DefaultResponse {
+        DefaultResponse {
+            status_code: StatusCode::BAD_REQUEST,
+            message: Some(message),
+            redirect: None,
+        }
+    } // end fn bad_request
+
+    // Only the Admins can vouch for the consents of the leads.
+    let consent = query
+        .consent
+        .map(|consent| consent.trim().to_string())
+        .filter(|consent| !consent.is_empty());
+    if consent.is_some() && !client.has_any_role(&["Admin"]) {
+        return Err(DefaultResponse {
+            status_code: StatusCode::UNAUTHORIZED,
+            message: Some("Only the Admins can confirm the imported leads".to_string()),
+            redirect: None,
+        }); // end return
+    } // end if
+
+    let options = ImportOptions {
+        columns: ColumnMapping::parse(query.columns.as_deref().unwrap_or_default())
+            .map_err(bad_request)?,
+        phone_number_code: query.phone_number_code,
+        dry_run: query.dry_run,
+        imported_by: Some(client.user.id),
+        consent,
+    }; // end ImportOptions
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let slug = query.form.as_deref().unwrap_or(DEFAULT_FORM);
+    let form = find_form(&mut conn, slug)
+        .await
+        .map_err(DefaultResponse::server_error)?
+        .ok_or_else(|| DefaultResponse {
+            status_code: StatusCode::NOT_FOUND,
+            message: Some("The form does not exist".to_string()),
+            redirect: None,
+        })?;
+
+    let report = imports::import_leads(
+        &mut conn,
+        &file,
+        &form,
+        app_state.forms.get(slug),
+        &app_state.emails,
+        &options,
+    )
+    .await
+    .map_err(|error| match error {
+        ImportError::InvalidFile(message) => bad_request(message),
+        ImportError::Database(error) => DefaultResponse::server_error(error),
+    })?; // end map_err
+
+    // Score the imported leads.
+    // NOTE: The import does not fail if it cannot be done,
+    // the leads are scored again later.
+    if !report.dry_run {
+        let user_ids = report
+            .rows
+            .iter()
+            .filter_map(|row| row.user_id)
+            .collect::<Vec<_>>();
+        if let Err(error) = scoring::rescore(&mut conn, &user_ids, scoring::IMPORT).await {
+            eprintln!("{}", error);
+        } // end if
+    } // end if
+
+    Ok(Json(report))
+} // end fn import_leads
+
+/// Show the score of a lead.
+///
+/// The score is the sum of the points of the scoring rules the lead
+/// matches, the history shows how it has changed and why.
+///
+#[utoipa::path(
+    get,
+    tag = "Administration",
+    path = "/admin/leads/{id}/score",
+    params(("id" = i32, Path, description = "The id of the lead")),
+    responses(
+        (status = StatusCode::OK, description = "The score of the lead and its latest changes", body = LeadScore),
+        (status = StatusCode::NOT_FOUND, description = "The lead does not exist", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin or a Manager", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn lead_score(
+    State(app_state): State<AppState>,
+    Path(user_id): Path<i32>,
+) -> Result<Json<LeadScore>, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let (score, updated_at) = users::table
+        .left_join(lead_scores::table)
+        .filter(users::id.eq(user_id))
+        .select((
+            lead_scores::score.nullable(),
+            lead_scores::updated_at.nullable(),
+        ))
+        .first::<(Option<i32>, Option<DateTime<Utc>>)>(&mut conn)
+        .await
+        .optional()
+        .map_err(DefaultResponse::server_error)?
+        .ok_or_else(|| DefaultResponse {
+            status_code: StatusCode::NOT_FOUND,
+            message: Some("The lead does not exist".to_string()),
+            redirect: None,
+        })?;
+
+    let history = lead_score_history::table
+        .filter(lead_score_history::user_id.eq(user_id))
+        .order_by(lead_score_history::id.desc())
+        .limit(SCORE_HISTORY_LIMIT)
+        .load::<LeadScoreChange>(&mut conn)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    Ok(Json(LeadScore {
+        user_id,
+        score,
+        updated_at,
+        history,
+    }))
+} // end fn lead_score
+
+/// Set the tags of a lead.
+///
+/// The tags replace the tags the lead had, they are stored in lowercase.
+/// The leads can be exported by their tags.
+///
+#[utoipa::path(
+    put,
+    tag = "Administration",
+    path = "/admin/leads/{id}/tags",
+    params(("id" = i32, Path, description = "The id of the lead")),
+    request_body(content = LeadTagsPayload, description = "The new tags of the lead", content_type = "application/json"),
+    responses(
+        (status = StatusCode::OK, description = "The tags have been saved", body = DefaultResponseJson, example = json!("{\"message\": \"The tags have been saved\", \"redirect\": null}")),
+        (status = StatusCode::BAD_REQUEST, description = "A tag is empty, too long or contains a comma", body = DefaultResponseJson),
+        (status = StatusCode::NOT_FOUND, description = "The lead does not exist", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin or a Manager", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn set_lead_tags(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Path(user_id): Path<i32>,
+    Json(payload): Json<LeadTagsPayload>,
+) -> Result<DefaultResponse, DefaultResponse> {
+    let mut tags = payload
+        .tags
+        .iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .collect::<Vec<_>>();
+    tags.sort();
+    tags.dedup();
+
+    if tags.iter().any(|tag| {
+        tag.is_empty() || tag.chars().count() > MAX_TAG_LENGTH || tag.contains(TAG_SEPARATOR)
+    }) {
+        return Err(DefaultResponse {
+            status_code: StatusCode::BAD_REQUEST,
+            message: Some(format!(
+                "The tags must not be empty, longer than {} symbols or contain \"{}\"",
+                MAX_TAG_LENGTH, TAG_SEPARATOR
+            )),
+            redirect: None,
+        });
+    } // end if
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let found = users::table
+        .filter(users::id.eq(user_id))
+        .count()
+        .get_result::<i64>(&mut conn)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+    if found == 0 {
+        return Err(DefaultResponse {
+            status_code: StatusCode::NOT_FOUND,
+            message: Some("The lead does not exist".to_string()),
+            redirect: None,
+        });
+    } // end if
+
+    conn.transaction::<(), diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::delete(lead_tags::table.filter(lead_tags::user_id.eq(user_id)))
+                .execute(conn)
+                .await?;
+            if !tags.is_empty() {
+                diesel::insert_into(lead_tags::table)
+                    .values(
+                        tags.iter()
+                            .map(|tag| (lead_tags::user_id.eq(user_id), lead_tags::tag.eq(tag)))
+                            .collect::<Vec<_>>(),
+                    )
+                    .execute(conn)
+                    .await?;
+            } // end if
+
+            audit::record(
+                conn,
+                &[NewAuditEntry {
+                    user_id: Some(user_id),
+                    action: "lead_tags.updated".to_string(),
+                    details: json!({
+                        "tags": tags,
+                        "updated_by": client.user.id,
+                    }),
+                }],
+            )
+            .await
+        }
+        .scope_boxed()
+    })
+    .await
+    .map_err(DefaultResponse::server_error)?;
+
+    // The tags can change the score of the lead.
+    // NOTE: The tags are saved even if it cannot be done.
+    if let Err(error) = scoring::rescore_lead(&mut conn, user_id, scoring::TAGS).await {
+        eprintln!("{}", error);
+    } // end if
+
+    Ok(DefaultResponse {
+        status_code: StatusCode::OK,
+        message: Some("The tags have been saved".to_string()),
+        redirect: None,
+    })
+} // end fn set_lead_tags
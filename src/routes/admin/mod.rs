@@ -0,0 +1,55 @@
+use axum::{
+    routing::{delete, get, post, put},
+    Router,
+};
+
+pub mod analytics;
+pub mod attribution;
+pub mod data_requests;
+pub mod forms;
+pub mod leads;
+pub mod roles;
+pub mod scoring;
+pub mod webhooks;
+
+use analytics::funnel_report;
+use attribution::campaign_report;
+use data_requests::list_data_requests;
+use forms::{create_form, list_forms, update_form};
+use leads::{export_leads, import_leads, lead_score, search_leads, set_lead_tags};
+use roles::grant_role;
+use scoring::{create_rule, delete_rule, list_rules, update_rule};
+use webhooks::{
+    create_webhook, delete_webhook, list_deliveries, list_webhooks, redeliver_delivery,
+};
+
+use super::AppState;
+
+/// This function returns a router with routes
+/// for administration.
+///
+/// NOTE: These routes must be protected by auth_guard,
+/// the required roles are set in get_default_allowed_roles.
+pub fn get_admin_router() -> Router<AppState> {
+    Router::new()
+        .route("/roles", post(grant_role))
+        .route("/analytics/funnel", get(funnel_report))
+        .route("/attribution/campaigns", get(campaign_report))
+        .route("/forms", get(list_forms).post(create_form))
+        .route("/forms/:slug", put(update_form))
+        .route("/data_requests", get(list_data_requests))
+        .route("/leads", get(search_leads))
+        .route("/leads/export", get(export_leads))
+        .route("/leads/import", post(import_leads))
+        .route("/leads/:id/tags", put(set_lead_tags))
+        .route("/leads/:id/score", get(lead_score))
+        .route("/scoring/rules", get(list_rules).post(create_rule))
+        .route("/scoring/rules/:id", put(update_rule).delete(delete_rule))
+        .route("/webhooks", get(list_webhooks).post(create_webhook))
+        .route("/webhooks/:id", delete(delete_webhook))
+        .route("/webhooks/:id/deliveries", get(list_deliveries))
+        .route(
+            "/webhooks/:id/deliveries/:delivery_id/redeliver",
+            post(redeliver_delivery),
+        )
+} // end fn get_admin_router
@@ -0,0 +1,114 @@
+// This file contains the endpoints that manage the roles of users.
+
+use axum::{extract::State, http::StatusCode, Extension, Json};
+use chrono::{DateTime, Utc};
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::{
+    middleware::auth_guard::AuthenticatedUser,
+    models::NewRoleGrant,
+    routes::AppState,
+    schema::users,
+    utils::{responses::DefaultResponse, roles},
+};
+
+/// This struct represents a request to grant a role to a user.
+#[derive(Deserialize, ToSchema)]
+pub struct RoleGrantPayload {
+    #[schema(example = 2)]
+    pub user_id: i32,
+    #[schema(example = "Manager")]
+    pub role: String,
+    // If it is absent, then the grant is permanent.
+    #[schema(example = "2024-01-31T18:00:00Z")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[schema(example = "On-call duty")]
+    pub reason: Option<String>,
+} // end struct RoleGrantPayload
+
+/// Grant a role to a user.
+///
+/// The grant can be temporary, in this case it is ignored after
+/// "expires_at" and removed by a background job. Every grant is
+/// recorded in the audit log together with the admin who made it.
+///
+#[utoipa::path(
+    post,
+    tag = "Administration",
+    path = "/admin/roles",
+    request_body(content = RoleGrantPayload, description = "The role to grant", content_type = "application/json"),
+    responses(
+        (status = StatusCode::OK, description = "The role has been granted", body = DefaultResponseJson, example = json!("{\"message\": \"The role has been granted\", \"redirect\": null}")),
+        (status = StatusCode::BAD_REQUEST, description = "The user or the role does not exist, or the grant has already expired", body = DefaultResponseJson, example = json!("{\"message\": \"The role does not exist\", \"redirect\": null}")),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn grant_role(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Json(payload): Json<RoleGrantPayload>,
+) -> DefaultResponse {
+    // This is a helper function for the responses about invalid requests.
+    fn bad_request(message: &str) -> DefaultResponse {
+        DefaultResponse {
+            status_code: StatusCode::BAD_REQUEST,
+            message: Some(message.to_string()),
+            redirect: None,
+        }
+    } // end fn bad_request
+
+    // A grant that has already expired would be ignored anyway.
+    if !roles::is_active(payload.expires_at, Utc::now()) {
+        return bad_request("The grant has already expired");
+    } // end if
+
+    let mut conn = match app_state.pool.get().await {
+        Ok(conn) => conn,
+        Err(error) => return DefaultResponse::server_error(error),
+    }; // end match
+
+    // Check that the user exists.
+    match users::table
+        .filter(users::id.eq(payload.user_id))
+        .count()
+        .get_result::<i64>(&mut conn)
+        .await
+    {
+        Ok(0) => return bad_request("The user does not exist"),
+        Ok(_) => (),
+        Err(error) => return DefaultResponse::server_error(error),
+    } // end match
+
+    // Find the role by its title.
+    let role_id = match roles::find_role_id(&mut conn, &payload.role).await {
+        Ok(Some(role_id)) => role_id,
+        Ok(None) => return bad_request("The role does not exist"),
+        Err(error) => return DefaultResponse::server_error(error),
+    }; // end match
+
+    let grant = NewRoleGrant {
+        user_id: payload.user_id,
+        role_id,
+        granted_by: Some(client.user.id),
+        expires_at: payload.expires_at,
+        reason: payload.reason,
+    }; // end NewRoleGrant
+
+    match roles::grant_role(&mut conn, grant).await {
+        Ok(true) => DefaultResponse {
+            status_code: StatusCode::OK,
+            message: Some("The role has been granted".to_string()),
+            redirect: None,
+        },
+        Ok(false) => DefaultResponse {
+            status_code: StatusCode::OK,
+            message: Some("The user already has the role".to_string()),
+            redirect: None,
+        },
+        Err(error) => DefaultResponse::server_error(error),
+    } // end match
+} // end fn grant_role
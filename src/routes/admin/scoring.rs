@@ -0,0 +1,296 @@
+// This file contains the endpoints that manage the rules
+// the leads are scored with.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::Utc;
+use diesel::{OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use utoipa::ToSchema;
+
+use crate::{
+    middleware::auth_guard::AuthenticatedUser,
+    models::{NewAuditEntry, NewScoringRule, ScoringRule},
+    routes::AppState,
+    schema::scoring_rules,
+    utils::{
+        audit,
+        responses::DefaultResponse,
+        scoring::{self, Operator},
+    },
+};
+
+// The maximum length of the name and of the field of a rule.
+const MAX_LENGTH: usize = 100;
+
+/// This struct represents the settings of a scoring rule.
+#[derive(Deserialize, ToSchema)]
+pub struct ScoringRulePayload {
+    #[schema(example = "Came from the newsletter")]
+    pub name: String,
+    #[schema(example = "utm_source")]
+    pub field: String,
+    pub operator: Operator,
+    // The value the field is compared with, "exists" needs none.
+    #[schema(example = "newsletter")]
+    pub value: Option<Value>,
+    // The points are added to the score, or taken if they are negative.
+    #[schema(example = 10)]
+    pub points: i32,
+} // end struct ScoringRulePayload
+
+impl ScoringRulePayload {
+    /// This function checks the settings and returns them as a row.
+    fn to_row(&self) -> Result<NewScoringRule<'_>, DefaultResponse> {
+        let bad_request = |message: String| DefaultResponse {
+            status_code: StatusCode::BAD_REQUEST,
+            message: Some(message),
+            redirect: None,
+        }; // end bad_request
+
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_LENGTH {
+            return Err(bad_request(format!(
+                "The name of the rule must not be empty or longer than {} symbols",
+                MAX_LENGTH
+            )));
+        } // end if
+        if self.field.chars().count() > MAX_LENGTH {
+            return Err(bad_request(format!(
+                "The field must not be longer than {} symbols",
+                MAX_LENGTH
+            )));
+        } // end if
+        scoring::validate(&self.field, self.operator, self.value.as_ref()).map_err(bad_request)?;
+
+        Ok(NewScoringRule {
+            name,
+            field: &self.field,
+            operator: self.operator.as_str(),
+            // The value of "exists" is ignored anyway.
+            value: self
+                .value
+                .as_ref()
+                .filter(|_value| self.operator != Operator::Exists),
+            points: self.points,
+            updated_at: Utc::now(),
+        })
+    } // end fn to_row
+} // end impl ScoringRulePayload
+
+/// This function returns a response about a missing rule.
+fn not_found() -> DefaultResponse {
+    DefaultResponse {
+        status_code: StatusCode::NOT_FOUND,
+        message: Some("The scoring rule does not exist".to_string()),
+        redirect: None,
+    }
+} // end fn not_found
+
+/// List the scoring rules.
+///
+#[utoipa::path(
+    get,
+    tag = "Administration",
+    path = "/admin/scoring/rules",
+    responses(
+        (status = StatusCode::OK, description = "All the scoring rules", body = [ScoringRule]),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn list_rules(
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<ScoringRule>>, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    scoring_rules::table
+        .order_by(scoring_rules::id)
+        .load::<ScoringRule>(&mut conn)
+        .await
+        .map(Json)
+        .map_err(DefaultResponse::server_error)
+} // end fn list_rules
+
+/// Create a scoring rule.
+///
+/// The rule adds its points to the score of every lead whose field
+/// matches it. Only "exists" matches the leads without the field,
+/// e.g. "not_equals" leaves them alone. All the leads are scored again
+/// in the background.
+///
+#[utoipa::path(
+    post,
+    tag = "Administration",
+    path = "/admin/scoring/rules",
+    request_body(content = ScoringRulePayload, description = "The settings of the rule", content_type = "application/json"),
+    responses(
+        (status = StatusCode::CREATED, description = "The rule has been created", body = ScoringRule),
+        (status = StatusCode::BAD_REQUEST, description = "The name, the field or the value is not valid", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn create_rule(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Json(payload): Json<ScoringRulePayload>,
+) -> Result<(StatusCode, Json<ScoringRule>), DefaultResponse> {
+    let row = payload.to_row()?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let rule = diesel::insert_into(scoring_rules::table)
+        .values(&row)
+        .get_result::<ScoringRule>(&mut conn)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    audit::record(
+        &mut conn,
+        &[NewAuditEntry {
+            user_id: None,
+            action: "scoring_rule.created".to_string(),
+            details: json!({
+                "rule": rule,
+                "created_by": client.user.id,
+            }),
+        }],
+    )
+    .await
+    .map_err(DefaultResponse::server_error)?;
+
+    app_state.scoring.notify_one();
+
+    Ok((StatusCode::CREATED, Json(rule)))
+} // end fn create_rule
+
+/// Change a scoring rule.
+///
+/// All the leads are scored again in the background.
+///
+#[utoipa::path(
+    put,
+    tag = "Administration",
+    path = "/admin/scoring/rules/{id}",
+    params(("id" = i32, Path, description = "The id of the rule")),
+    request_body(content = ScoringRulePayload, description = "The new settings of the rule", content_type = "application/json"),
+    responses(
+        (status = StatusCode::OK, description = "The rule has been changed", body = ScoringRule),
+        (status = StatusCode::BAD_REQUEST, description = "The name, the field or the value is not valid", body = DefaultResponseJson),
+        (status = StatusCode::NOT_FOUND, description = "The rule does not exist", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn update_rule(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Path(rule_id): Path<i32>,
+    Json(payload): Json<ScoringRulePayload>,
+) -> Result<Json<ScoringRule>, DefaultResponse> {
+    let row = payload.to_row()?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let rule = diesel::update(scoring_rules::table.find(rule_id))
+        .set(&row)
+        .get_result::<ScoringRule>(&mut conn)
+        .await
+        .optional()
+        .map_err(DefaultResponse::server_error)?
+        .ok_or_else(not_found)?;
+
+    audit::record(
+        &mut conn,
+        &[NewAuditEntry {
+            user_id: None,
+            action: "scoring_rule.updated".to_string(),
+            details: json!({
+                "rule": rule,
+                "updated_by": client.user.id,
+            }),
+        }],
+    )
+    .await
+    .map_err(DefaultResponse::server_error)?;
+
+    app_state.scoring.notify_one();
+
+    Ok(Json(rule))
+} // end fn update_rule
+
+/// Remove a scoring rule.
+///
+/// All the leads are scored again in the background.
+///
+#[utoipa::path(
+    delete,
+    tag = "Administration",
+    path = "/admin/scoring/rules/{id}",
+    params(("id" = i32, Path, description = "The id of the rule")),
+    responses(
+        (status = StatusCode::OK, description = "The rule has been removed", body = DefaultResponseJson, example = json!("{\"message\": \"The scoring rule has been removed\", \"redirect\": null}")),
+        (status = StatusCode::NOT_FOUND, description = "The rule does not exist", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn delete_rule(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Path(rule_id): Path<i32>,
+) -> Result<DefaultResponse, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let deleted = diesel::delete(scoring_rules::table.find(rule_id))
+        .execute(&mut conn)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+    if deleted == 0 {
+        return Err(not_found());
+    } // end if
+
+    audit::record(
+        &mut conn,
+        &[NewAuditEntry {
+            user_id: None,
+            action: "scoring_rule.deleted".to_string(),
+            details: json!({
+                "rule_id": rule_id,
+                "deleted_by": client.user.id,
+            }),
+        }],
+    )
+    .await
+    .map_err(DefaultResponse::server_error)?;
+
+    app_state.scoring.notify_one();
+
+    Ok(DefaultResponse {
+        status_code: StatusCode::OK,
+        message: Some("The scoring rule has been removed".to_string()),
+        redirect: None,
+    })
+} // end fn delete_rule
@@ -0,0 +1,338 @@
+// This file contains the endpoints that manage the webhooks
+// and their deliveries.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{
+    middleware::auth_guard::AuthenticatedUser,
+    models::{NewAuditEntry, NewWebhook, Webhook, WebhookDelivery},
+    routes::AppState,
+    schema::{webhook_deliveries, webhooks},
+    utils::{
+        audit,
+        responses::DefaultResponse,
+        webhooks::{generate_secret, redeliver, EVENTS},
+    },
+};
+
+// The number of the latest deliveries that are listed.
+const DELIVERIES_LIMIT: i64 = 100;
+
+/// This struct represents a request to register a webhook.
+#[derive(Deserialize, ToSchema)]
+pub struct WebhookPayload {
+    #[schema(example = "https://crm.example.com/hooks/leads")]
+    pub url: String,
+    #[schema(example = json!(["lead.created", "user.registered"]))]
+    pub events: Vec<String>,
+} // end struct WebhookPayload
+
+/// This struct represents a registered webhook together with its secret.
+#[derive(Serialize, ToSchema)]
+pub struct CreatedWebhook {
+    #[schema(example = 1)]
+    pub id: i32,
+    #[schema(example = "https://crm.example.com/hooks/leads")]
+    pub url: String,
+    #[schema(example = json!(["lead.created"]))]
+    pub events: Vec<String>,
+    // The key the deliveries are signed with, it is not shown again.
+    #[schema(example = "8f1c0a6d3b9e4f2a7c5d1e0b9a8f7e6d5c4b3a2918f7e6d5c4b3a29180f1e2d3")]
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+} // end struct CreatedWebhook
+
+/// This function returns a response about a missing webhook or delivery.
+fn not_found(message: &str) -> DefaultResponse {
+    DefaultResponse {
+        status_code: StatusCode::NOT_FOUND,
+        message: Some(message.to_string()),
+        redirect: None,
+    }
+} // end fn not_found
+
+/// This function checks the settings of a webhook.
+fn validate(payload: &WebhookPayload) -> Result<(), String> {
+    let url = reqwest::Url::parse(&payload.url)
+        .map_err(|_error| "The URL of the webhook is not valid".to_string())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("The URL of the webhook must be HTTP or HTTPS".to_string());
+    } // end if
+
+    if payload.events.is_empty() {
+        return Err("The webhook must subscribe to at least one event".to_string());
+    } // end if
+    if let Some(event) = payload
+        .events
+        .iter()
+        .find(|event| !EVENTS.contains(&event.as_str()))
+    {
+        return Err(format!(
+            "The event \"{}\" does not exist, the events are {}",
+            event,
+            EVENTS.join(", ")
+        ));
+    } // end if
+
+    Ok(())
+} // end fn validate
+
+/// List the webhooks.
+///
+#[utoipa::path(
+    get,
+    tag = "Administration",
+    path = "/admin/webhooks",
+    responses(
+        (status = StatusCode::OK, description = "All the webhooks", body = [Webhook]),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn list_webhooks(
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<Webhook>>, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    webhooks::table
+        .order_by(webhooks::id)
+        .load::<Webhook>(&mut conn)
+        .await
+        .map(Json)
+        .map_err(DefaultResponse::server_error)
+} // end fn list_webhooks
+
+/// Register a webhook.
+///
+/// The webhook receives the events it subscribes to ("lead.created",
+/// "user.registered" and "user.verified") as signed POST requests.
+/// The secret of the signatures is returned only once.
+///
+#[utoipa::path(
+    post,
+    tag = "Administration",
+    path = "/admin/webhooks",
+    request_body(content = WebhookPayload, description = "The endpoint and its events", content_type = "application/json"),
+    responses(
+        (status = StatusCode::CREATED, description = "The webhook is registered", body = CreatedWebhook),
+        (status = StatusCode::BAD_REQUEST, description = "The URL or an event is not valid", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn create_webhook(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Json(payload): Json<WebhookPayload>,
+) -> Result<(StatusCode, Json<CreatedWebhook>), DefaultResponse> {
+    validate(&payload).map_err(|message| DefaultResponse {
+        status_code: StatusCode::BAD_REQUEST,
+        message: Some(message),
+        redirect: None,
+    })?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let mut events = payload.events;
+    events.sort();
+    events.dedup();
+    let webhook = diesel::insert_into(webhooks::table)
+        .values(NewWebhook {
+            url: payload.url,
+            secret: generate_secret(),
+            events,
+        })
+        .get_result::<Webhook>(&mut conn)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    audit::record(
+        &mut conn,
+        &[NewAuditEntry {
+            user_id: None,
+            action: "webhook.created".to_string(),
+            details: json!({
+                "webhook_id": webhook.id,
+                "url": webhook.url,
+                "events": webhook.events,
+                "created_by": client.user.id,
+            }),
+        }],
+    )
+    .await
+    .map_err(DefaultResponse::server_error)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedWebhook {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook.events,
+            secret: webhook.secret,
+            created_at: webhook.created_at,
+        }),
+    ))
+} // end fn create_webhook
+
+/// Remove a webhook together with its deliveries.
+///
+#[utoipa::path(
+    delete,
+    tag = "Administration",
+    path = "/admin/webhooks/{id}",
+    params(("id" = i32, Path, description = "The id of the webhook")),
+    responses(
+        (status = StatusCode::OK, description = "The webhook has been removed", body = DefaultResponseJson, example = json!("{\"message\": \"The webhook has been removed\", \"redirect\": null}")),
+        (status = StatusCode::NOT_FOUND, description = "The webhook does not exist", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn delete_webhook(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Path(webhook_id): Path<i32>,
+) -> Result<DefaultResponse, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let deleted = diesel::delete(webhooks::table.find(webhook_id))
+        .execute(&mut conn)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+    if deleted == 0 {
+        return Err(not_found("The webhook does not exist"));
+    } // end if
+
+    audit::record(
+        &mut conn,
+        &[NewAuditEntry {
+            user_id: None,
+            action: "webhook.deleted".to_string(),
+            details: json!({
+                "webhook_id": webhook_id,
+                "deleted_by": client.user.id,
+            }),
+        }],
+    )
+    .await
+    .map_err(DefaultResponse::server_error)?;
+
+    Ok(DefaultResponse {
+        status_code: StatusCode::OK,
+        message: Some("The webhook has been removed".to_string()),
+        redirect: None,
+    })
+} // end fn delete_webhook
+
+/// List the latest deliveries of a webhook.
+///
+/// Every delivery shows its status ("pending", "delivered" or "failed"),
+/// the number of attempts and the result of the last one.
+///
+#[utoipa::path(
+    get,
+    tag = "Administration",
+    path = "/admin/webhooks/{id}/deliveries",
+    params(("id" = i32, Path, description = "The id of the webhook")),
+    responses(
+        (status = StatusCode::OK, description = "The latest 100 deliveries, the newest first", body = [WebhookDelivery]),
+        (status = StatusCode::NOT_FOUND, description = "The webhook does not exist", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn list_deliveries(
+    State(app_state): State<AppState>,
+    Path(webhook_id): Path<i32>,
+) -> Result<Json<Vec<WebhookDelivery>>, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let found = webhooks::table
+        .filter(webhooks::id.eq(webhook_id))
+        .count()
+        .get_result::<i64>(&mut conn)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+    if found == 0 {
+        return Err(not_found("The webhook does not exist"));
+    } // end if
+
+    webhook_deliveries::table
+        .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+        .order_by(webhook_deliveries::id.desc())
+        .limit(DELIVERIES_LIMIT)
+        .load::<WebhookDelivery>(&mut conn)
+        .await
+        .map(Json)
+        .map_err(DefaultResponse::server_error)
+} // end fn list_deliveries
+
+/// Send a delivery again.
+///
+/// The delivery is sent right away with the same id and payload,
+/// it gets all the attempts again, e.g. after the endpoint is fixed.
+///
+#[utoipa::path(
+    post,
+    tag = "Administration",
+    path = "/admin/webhooks/{id}/deliveries/{delivery_id}/redeliver",
+    params(
+        ("id" = i32, Path, description = "The id of the webhook"),
+        ("delivery_id" = i32, Path, description = "The id of the delivery"),
+    ),
+    responses(
+        (status = StatusCode::OK, description = "The delivery is queued", body = DefaultResponseJson, example = json!("{\"message\": \"The delivery is queued\", \"redirect\": null}")),
+        (status = StatusCode::NOT_FOUND, description = "The delivery does not exist", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not an Admin", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn redeliver_delivery(
+    State(app_state): State<AppState>,
+    Path((webhook_id, delivery_id)): Path<(i32, i32)>,
+) -> Result<DefaultResponse, DefaultResponse> {
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    if !redeliver(&mut conn, &app_state.webhooks, webhook_id, delivery_id)
+        .await
+        .map_err(DefaultResponse::server_error)?
+    {
+        return Err(not_found("The delivery does not exist"));
+    } // end if
+
+    Ok(DefaultResponse {
+        status_code: StatusCode::OK,
+        message: Some("The delivery is queued".to_string()),
+        redirect: None,
+    })
+} // end fn redeliver_delivery
@@ -1,11 +1,21 @@
-use axum::{extract::State, http::StatusCode, Form};
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+};
 
 use crate::{
     models::{LoginUser, User},
     routes::AppState,
-    utils::{jwt::create_jwt, responses::LoginResponse, security::hash_password},
+    utils::{
+        emails, forms::FormSubmission, funnel, jwt::create_jwt, phones, responses::LoginResponse,
+        security::hash_password,
+    },
 };
 
+use super::check_captcha;
+
 use diesel::{ExpressionMethods, QueryDsl};
 use diesel_async::RunQueryDsl;
 
@@ -30,6 +40,7 @@ use diesel_async::RunQueryDsl;
     request_body(content = LoginUser, description = "A filled out login form", content_type = "application/x-www-form-urlencoded"),
     responses(
         (status = StatusCode::OK, description = "A user has logged in successfully", body = LoginResponseJson, example = json!("{\"message\": \"SUCCESSFULLY AUTHORIZED\", \"token\": \"293u5429*2%23$#@stuff\"}")),
+        (status = StatusCode::BAD_REQUEST, description = "The CAPTCHA has failed, see CAPTCHA_PROVIDER", body = LoginResponseJson),
         (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side (The user is not inserted into the database in this case)", body = DefaultResponseJson, example = json!("{\"message\": \"An error occurred on the server side. Email could not be sent.\", \"redirect\": null}")),
         (status = StatusCode::UNAUTHORIZED, description = "In this case the user has either made a mistake while filling out the form. \
         E.g. they could specify login or password or both in a wrong way")
@@ -37,13 +48,21 @@ use diesel_async::RunQueryDsl;
 )]
 pub async fn login(
     State(app_state): State<AppState>,
-    Form(user): Form<LoginUser>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    FormSubmission { data: user, fields }: FormSubmission<LoginUser>,
 ) -> LoginResponse {
     // This is a default error message from a server in order not to
     // disclose some information that could be used to
     // destroy the work of servers.
     const SERVER_ERROR: &str = "Something went wrong on the server side";
 
+    // Check the CAPTCHA before anything else.
+    let peer = peer.map(|ConnectInfo(peer)| peer);
+    if let Some(response) = check_captcha(&app_state, &headers, peer, &fields).await {
+        return response;
+    } // end if
+
     // User id is required to check if passwords match later in the code.
     let mut user_id: i32 = -1;
     let mut user_password: String = String::new();
@@ -71,14 +90,18 @@ pub async fn login(
     // NOTE: A user is allowed to specify either an email or a phone number.
 
     // If the user specified the phone number.
-    if user.phone_number_code.is_some() && user.phone_number.is_some() {
+    if let (Some(phone_number_code), Some(phone_number)) =
+        (&user.phone_number_code, &user.phone_number)
+    {
+        // The phone numbers are stored in E.164.
+        // NOTE: An invalid phone number matches nobody.
+        let phone_number = phones::parse(*phone_number_code, phone_number)
+            .map(|phone| phone.e164)
+            .unwrap_or_default();
+
         // Check the phone number.
         match crate::schema::users::dsl::users
-            .filter(
-                crate::schema::users::columns::phone_number_code
-                    .eq(&user.phone_number_code.unwrap()),
-            )
-            .filter(crate::schema::users::columns::phone_number.eq(&user.phone_number.unwrap()))
+            .filter(crate::schema::users::columns::phone_number.eq(&phone_number))
             .load::<User>(&mut conn)
             .await
         {
@@ -106,9 +129,17 @@ pub async fn login(
     } else if user.email.is_some() {
         // If the user specified email instead.
 
+        // The emails are stored lowercased.
+        // NOTE: An invalid email matches nobody.
+        let email = user
+            .email
+            .as_deref()
+            .and_then(|email| emails::normalize(email).ok())
+            .unwrap_or_default();
+
         // Check the email.
         match crate::schema::users::dsl::users
-            .filter(crate::schema::users::columns::email.eq(&user.email))
+            .filter(crate::schema::users::columns::email.eq(&email))
             .load::<User>(&mut conn)
             .await
         {
@@ -164,36 +195,42 @@ pub async fn login(
                     }; // end return
                 } // end if
 
+                // Count the first login in the funnel, the later ones are ignored.
+                // NOTE: The login does not fail if it cannot be done.
+                if let Err(error) = funnel::record(&mut conn, user_id, funnel::FIRST_LOGIN).await {
+                    eprintln!("{}", error);
+                } // end if
+
                 // Return the token to the client.
-                return LoginResponse {
+                LoginResponse {
                     status_code: StatusCode::OK,
                     message: "SUCCESSFUL AUTHORIZATION".to_string(),
                     token: Some(token),
-                }; // end return
+                } // end LoginResponse
             } else {
                 // An error occurred while generating JWT.
-                return LoginResponse {
+                LoginResponse {
                     status_code: StatusCode::INTERNAL_SERVER_ERROR,
                     message: SERVER_ERROR.to_string(),
                     token: None,
-                }; // end return
+                } // end LoginResponse
             } // end if
         } else {
             // The password is incorrect, the user is not verified.
             // NOTE: The user could specify the login incorrectly.
             // But for safety reasons the exact reason is not disclosed.
-            return LoginResponse {
+            LoginResponse {
                 status_code: StatusCode::UNAUTHORIZED,
                 message: "The login or password or both are incorrect".to_string(),
                 token: None,
-            }; // end return
+            } // end LoginResponse
         } // end if
     } else {
         // An error occurred while hashing password.
-        return LoginResponse {
+        LoginResponse {
             status_code: StatusCode::INTERNAL_SERVER_ERROR,
             message: SERVER_ERROR.to_string(),
             token: None,
-        }; // end return
+        } // end LoginResponse
     } // end if
 } // fn login
@@ -1,4 +1,10 @@
-use axum::{routing::post, Router};
+use std::net::SocketAddr;
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
 
 pub mod login;
 pub mod register;
@@ -8,6 +14,12 @@ use register::register;
 
 use super::AppState;
 
+use crate::utils::{
+    attribution::client_ip,
+    captcha::{self, CaptchaError},
+    responses::LoginResponse,
+};
+
 /// This function returns a router with routes
 /// for authentication.
 pub fn get_auth_router() -> Router<AppState> {
@@ -15,3 +27,36 @@ pub fn get_auth_router() -> Router<AppState> {
         .route("/register", post(register))
         .route("/login", post(login))
 } // end fn get_auth_routes
+
+/// This function checks the CAPTCHA of an authentication form.
+/// It returns a response for the client if the CAPTCHA is not passed.
+///
+/// NOTE: The CAPTCHA is required if CAPTCHA_PROVIDER is set,
+/// unless CAPTCHA_AUTH_REQUIRED is "false".
+pub(crate) async fn check_captcha(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    fields: &[(String, String)],
+) -> Option<LoginResponse> {
+    let verifier = app_state.captcha.as_deref();
+    let required = verifier.is_some() && captcha::required_on_auth();
+    let remote_ip = client_ip(headers, peer);
+
+    match captcha::check(verifier, required, fields, remote_ip.as_deref()).await {
+        Ok(()) => None,
+        Err(CaptchaError::Failed) => Some(LoginResponse {
+            status_code: StatusCode::BAD_REQUEST,
+            message: CaptchaError::MESSAGE.to_string(),
+            token: None,
+        }), // end Failed
+        Err(CaptchaError::Unavailable(error)) => {
+            eprintln!("{}", error);
+            Some(LoginResponse {
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                message: "Something went wrong on the server side".to_string(),
+                token: None,
+            }) // end Some
+        } // end Unavailable
+    } // end match
+} // end fn check_captcha
@@ -2,10 +2,24 @@ use crate::{
     models::User,
     routes::AppState,
     schema::users::dsl,
-    utils::{jwt::create_jwt, security::hash_password},
+    utils::{
+        consents::{self, ConsentContext},
+        emails,
+        forms::FormSubmission,
+        funnel,
+        jwt::create_jwt,
+        phones, scoring,
+        security::hash_password,
+        webhooks,
+    },
 };
-use axum::Form;
-use axum::{extract::State, http::StatusCode};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+};
+use std::net::SocketAddr;
+
+use super::check_captcha;
 use diesel::{query_dsl::methods::FilterDsl, ExpressionMethods};
 use diesel_async::RunQueryDsl;
 
@@ -41,19 +55,32 @@ use crate::{models::NewUser, utils::responses::LoginResponse};
     request_body(content = NewUser, description = "A filled out registration form", content_type = "application/x-www-form-urlencoded"),
     responses(
         (status = StatusCode::OK, description = "A user was registered successfully", body = LoginResponseJson, example = json!("{\"message\": \"SUCCESSFULLY AUTHORIZED\", \"token\": \"293u5429*2%23$#@jlasdfl\"}")),
+        (status = StatusCode::BAD_REQUEST, description = "The CAPTCHA has failed, see CAPTCHA_PROVIDER, or a consent flag is not valid", body = LoginResponseJson),
         (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side (The user is not inserted into the database in this case)", body = DefaultResponseJson, example = json!("{\"message\": \"An error occurred on the server side. Email could not be sent.\", \"redirect\": null}")),
-        (status = StatusCode::UNAUTHORIZED, description = "In this case the user has whether made a mistake while filling out the form, or they are already registered")
+        (status = StatusCode::UNAUTHORIZED, description = "In this case the user has whether made a mistake while filling out the form, or they are already registered"),
+        (status = StatusCode::CONFLICT, description = "The user with the same phone number has been registered at the same time", body = LoginResponseJson)
     )
 )]
 pub async fn register(
     State(app_state): State<AppState>,
-    Form(mut user): Form<NewUser>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    FormSubmission {
+        data: mut user,
+        fields,
+    }: FormSubmission<NewUser>,
 ) -> LoginResponse {
     // This is a default error message from a server in order not to
     // disclose some information that could be used to
     // destroy the work of servers.
     const SERVER_ERROR: &str = "Something went wrong on the server side";
 
+    // Check the CAPTCHA before anything else.
+    let peer = peer.map(|ConnectInfo(peer)| peer);
+    if let Some(response) = check_captcha(&app_state, &headers, peer, &fields).await {
+        return response;
+    } // end if
+
     // This variable is required to set up
     // some fields in the database that
     // are not expected from the client.
@@ -78,7 +105,7 @@ pub async fn register(
     }; // end match
 
     // Check that the form is filled out in a proper way.
-    let (passed, message) = is_valid_form(&user);
+    let (passed, message) = is_valid_form(&mut user);
 
     // Check if the form passed the verification.
     if !passed {
@@ -86,14 +113,35 @@ pub async fn register(
         // Return an error response.
         return LoginResponse {
             status_code: StatusCode::UNAUTHORIZED,
-            message: message,
+            message,
             token: None,
         };
     }
 
-    // Get rid of unnecessary variables.
-    drop(passed);
-    drop(message);
+    // Check that the email domain can be used.
+    if let Err(message) = app_state
+        .emails
+        .check(user.email.as_deref().unwrap_or_default())
+        .await
+    {
+        return LoginResponse {
+            status_code: StatusCode::UNAUTHORIZED,
+            message,
+            token: None,
+        }; // end return
+    } // end if
+
+    // Read the consents given with the registration.
+    let consent_flags = match consents::parse_flags(&fields) {
+        Ok(flags) => flags,
+        Err(message) => {
+            return LoginResponse {
+                status_code: StatusCode::BAD_REQUEST,
+                message,
+                token: None,
+            }; // end return
+        } // end Err
+    }; // end match
 
     // Hash the user password.
     if let Ok(hashed_password) = hash_password(user.password.unwrap()).await {
@@ -112,7 +160,6 @@ pub async fn register(
 
     // Try to load the users with the provided phone number.
     let mut res: Vec<User> = match dsl::users
-        .filter(crate::schema::users::columns::phone_number_code.eq(&user.phone_number_code))
         .filter(crate::schema::users::columns::phone_number.eq(&user.phone_number))
         .load::<User>(&mut conn)
         .await
@@ -135,7 +182,8 @@ pub async fn register(
     //
     // So, it might be considered to be guaranteed, that
     // there is only a unique user with a unique phone number.
-    if res.len() > 0 {
+    let created = res.is_empty();
+    if !created {
         // A user with the provided phone number was found.
         //
         // Extract this user from the array.
@@ -177,21 +225,32 @@ pub async fn register(
 
         // Insert a user in a database.
         // Save the user id of the current client.
-        user_id = if let Ok(inserted_user) = diesel::insert_into(crate::schema::users::table)
+        user_id = match diesel::insert_into(crate::schema::users::table)
             .values(&user)
             .get_result::<User>(&mut conn)
             .await
         {
             // The user has been inserted successfully.
-            inserted_user.id
-        } else {
+            Ok(inserted_user) => inserted_user.id,
+            // Another registration with the same phone number
+            // has been done in the meantime.
+            Err(error) if phones::is_taken(&error) => {
+                return LoginResponse {
+                    status_code: StatusCode::CONFLICT,
+                    message: "The user has already been registered".to_string(),
+                    token: None,
+                }; // end return
+            } // end Err
             // An error occurred while inserting data to a database.
-            return LoginResponse {
-                status_code: StatusCode::INTERNAL_SERVER_ERROR,
-                message: SERVER_ERROR.to_string(),
-                token: None,
-            }; // end return
-        }; // end if let
+            Err(error) => {
+                eprintln!("{}", error);
+                return LoginResponse {
+                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                    message: SERVER_ERROR.to_string(),
+                    token: None,
+                }; // end return
+            } // end Err
+        }; // end match
     } // end if
 
     // Assign the basic role to the user.
@@ -212,6 +271,26 @@ pub async fn register(
         }; // end return
     } // end if
 
+    // Append the consents to the ledger, the consents granted for
+    // an existing lead are in force only with the confirmation link.
+    if let Err(error) = consents::submit(
+        &mut conn,
+        user_id,
+        &consent_flags,
+        consents::FROM_REGISTRATION,
+        &ConsentContext::collect(&headers, peer),
+        created,
+    )
+    .await
+    {
+        eprintln!("{}", error);
+        return LoginResponse {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            message: SERVER_ERROR.to_string(),
+            token: None,
+        }; // end return
+    } // end if
+
     // The user is registered.
     // Now it is time to generate JWT for the user
     // and send it to them.
@@ -247,89 +326,91 @@ pub async fn register(
         }; // end return
     } // end if
 
+    // Let the webhooks know about the new user.
+    // NOTE: The registration does not fail if it cannot be done.
+    let data = serde_json::json!({
+        "id": user_id,
+        "name": user.name,
+        "email": user.email,
+        "phone_number_code": user.phone_number_code,
+        "phone_number": user.phone_number,
+    });
+    if let Err(error) = webhooks::emit(
+        &mut conn,
+        &app_state.webhooks,
+        webhooks::USER_REGISTERED,
+        &data,
+    )
+    .await
+    {
+        eprintln!("{}", error);
+    } // end if
+
+    // Count the registration in the funnel, and the subscription
+    // of the user who has not been a lead before.
+    // NOTE: The registration does not fail if it cannot be done.
+    for stage in [funnel::SUBSCRIBED, funnel::REGISTERED] {
+        if let Err(error) = funnel::record(&mut conn, user_id, stage).await {
+            eprintln!("{}", error);
+        } // end if
+    } // end for
+
+    // The registered lead is worth more.
+    // NOTE: The registration does not fail if it cannot be done.
+    if let Err(error) = scoring::rescore_lead(&mut conn, user_id, scoring::REGISTERED).await {
+        eprintln!("{}", error);
+    } // end if
+
     // Return JWT with success status.
-    return LoginResponse {
+    LoginResponse {
         status_code: StatusCode::OK,
         message: "SUCCESSFUL AUTHORIZATION".to_string(),
         token: Some(token),
-    }; // end return
+    } // end LoginResponse
 } // fn register
 
 /// This function verifies that a form is filled out decently.
 /// It returns a status (bool), which indicates whether or not
 /// the verification has been passed, and a message that
 /// contains additional information about the result.
-fn is_valid_form(user: &NewUser) -> (bool, String) {
+///
+/// NOTE: The email is lowercased and the phone number
+/// is normalized to E.164.
+pub(crate) fn is_valid_form(user: &mut NewUser) -> (bool, String) {
     // Validate username.
     if user.name.is_empty() {
         return (false, "The \"name\" field cannot be empty".to_string());
     } // end if
 
-    // Validate email.
-    if let Some(email) = user.email.clone() {
-        if !email.contains("@") {
-            // Email has to contain "@" symbol.
-            return (false, "Email has to contain \"@\" symbol".to_string());
-        } // end if
-        if !email.contains(".") {
-            // Email has to contain "." symbol.
-            return (false, "Email has to contain \".\" symbol".to_string());
-        } // end if
+    // Validate email and bring it to the form it is stored in.
+    if let Some(email) = &user.email {
+        match emails::normalize(email) {
+            Ok(email) => user.email = Some(email),
+            Err(message) => return (false, message),
+        } // end match
     } else {
         // Email cannot be empty.
         return (false, "Email cannot be empty".to_string());
     } // end if
 
-    // Validate phone number code.
-    if user.phone_number_code == 0 || user.phone_number_code > 999 {
-        // The phone number code is invalid.
-        return (false, "The phone number code is invalid".to_string());
-    } // end if
-
-    // Validate phone number.
-    if user.phone_number.len() < 4 {
-        // The phone number is too short.
-        return (false, "The phone number is too short".to_string());
-    } else {
-        // Traverse all the symbols in a phone number and make
-        // sure that they are all decimal digits.
-        for symbol in user.phone_number.chars() {
-            // Check if the current symbol is a valid digit.
-            if !symbol.is_digit(10) {
-                // This is not a valid decimal digit.
-                return (false, "The phone number is not valid".to_string());
-            } // end if
-        } // end for
-    } // end if
+    // Parse the phone number and bring it to E.164,
+    // so that the duplicates are found whatever the format.
+    match phones::parse(user.phone_number_code, &user.phone_number) {
+        Ok(phone) => {
+            user.phone_number_code = phone.country_code;
+            user.phone_number = phone.e164;
+        } // end Ok
+        Err(message) => return (false, message),
+    } // end match
 
     // Validate password.
     // NOTE: The password must be required since now.
-    if let Some(password) = user.password.clone() {
+    if let Some(password) = &user.password {
         // Check that the password meets the rules.
-        // NOTE: The password rules are:
-        //  1. Password length min 7, max 30 symbols.
-        //  2. Password must contain ASCII characters only.
-
-        // Check that the password length meets the requirements.
-        if password.len() < 7 || password.len() > 30 {
-            // The password length is out of boundaries.
-            return (
-                false,
-                "The password length must be between 7 and 30 characters inclusive".to_string(),
-            ); // end return
+        let (passed, message) = is_valid_password(password);
+        if !passed {
+            return (false, message);
         } // end if
-
-        // Check that the password contains ASCII characters only.
-        //
-        // Traverse all the password characters and check if there are
-        // any invalid (non-ASCII) characters.
-        for symbol in password.chars() {
-            // Check if the current symbols is a valid ASCII character.
-            if !symbol.is_ascii() {
-                // The current character is out of ASCII range.
-                (false, "The password must contain only a-z, A-Z, 0-9, !$%#> or some other ASCII characters only");
-            } // end if
-        } // end for
     } else {
         // The password cannot be absent.
         return (false, "The password cannot be absent".to_string());
@@ -337,3 +418,40 @@ fn is_valid_form(user: &NewUser) -> (bool, String) {
 
     (true, "".to_string())
 } // end fn is_valid_form
+
+/// This function verifies that a password meets the rules.
+/// It returns a status (bool), which indicates whether or not
+/// the verification has been passed, and a message that
+/// contains additional information about the result.
+///
+/// NOTE: The password rules are:
+///  1. Password length min 7, max 30 symbols.
+///  2. Password must contain ASCII characters only.
+pub(crate) fn is_valid_password(password: &str) -> (bool, String) {
+    // Check that the password length meets the requirements.
+    if password.len() < 7 || password.len() > 30 {
+        // The password length is out of boundaries.
+        return (
+            false,
+            "The password length must be between 7 and 30 characters inclusive".to_string(),
+        ); // end return
+    } // end if
+
+    // Check that the password contains ASCII characters only.
+    //
+    // Traverse all the password characters and check if there are
+    // any invalid (non-ASCII) characters.
+    for symbol in password.chars() {
+        // Check if the current symbols is a valid ASCII character.
+        if !symbol.is_ascii() {
+            // The current character is out of ASCII range.
+            return (
+                false,
+                "The password must contain only a-z, A-Z, 0-9, !$%#> or some other ASCII characters only"
+                    .to_string(),
+            ); // end return
+        } // end if
+    } // end for
+
+    (true, "".to_string())
+} // end fn is_valid_password
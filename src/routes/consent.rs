@@ -0,0 +1,196 @@
+// This file contains the endpoints that show the consents of a user
+// and withdraw them.
+//
+// NOTE: Like the unsubscribe link, the consent link is opened with
+// a GET request that only shows a page, the consents are withdrawn
+// with a POST request from that page.
+
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::Html,
+    Form,
+};
+use diesel::result::{DatabaseErrorKind, Error};
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::utils::{
+    consents::{self, ConsentContext, FROM_WITHDRAWAL, MARKETING, PRIVACY_POLICY, PURPOSES},
+    jwt::decode_link_token,
+    links::CONSENT,
+    responses::DefaultResponse,
+};
+
+use super::{subscription::LinkQuery, AppState};
+
+/// This struct represents a request to withdraw a consent.
+#[derive(Deserialize, ToSchema)]
+pub struct WithdrawConsentPayload {
+    // "privacy_policy" or "marketing".
+    #[schema(example = "marketing")]
+    pub purpose: String,
+} // end struct WithdrawConsentPayload
+
+/// This function checks the token of a consent link.
+/// It returns the id of the user if the token is valid.
+fn consenting_user(token: &str) -> Result<i32, DefaultResponse> {
+    decode_link_token(token, CONSENT)
+        .and_then(|subject| subject.parse::<i32>().ok())
+        .ok_or_else(|| DefaultResponse {
+            status_code: StatusCode::BAD_REQUEST,
+            message: Some("The consent link is invalid or has expired".to_string()),
+            redirect: None,
+        }) // end ok_or_else
+} // end fn consenting_user
+
+/// This function returns the description of a purpose for the page.
+fn describe(purpose: &str) -> &'static str {
+    match purpose {
+        PRIVACY_POLICY => "the processing of your data under the privacy policy",
+        MARKETING => "the marketing emails",
+        _ => "something else",
+    } // end match
+} // end fn describe
+
+/// Show the consents.
+///
+/// This endpoint is opened from the link in the emails. It shows the
+/// consents the user has given and lets them withdraw every consent.
+///
+#[utoipa::path(
+    get,
+    tag = "Subscription",
+    path = "/consent",
+    params(LinkQuery),
+    responses(
+        (status = StatusCode::OK, description = "The page with the consents", content_type = "text/html"),
+        (status = StatusCode::BAD_REQUEST, description = "The link is invalid or has expired", body = DefaultResponseJson, example = json!("{\"message\": \"The consent link is invalid or has expired\", \"redirect\": null}")),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn consent_page(
+    State(app_state): State<AppState>,
+    Query(query): Query<LinkQuery>,
+) -> Result<Html<String>, DefaultResponse> {
+    let user_id = consenting_user(&query.token)?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let in_force = consents::current(&mut conn, user_id)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    // NOTE: The token is safe to put into the page as is,
+    // a valid token consists only of base64url characters and dots.
+    let items = in_force
+        .iter()
+        .filter(|consent| consent.granted)
+        .map(|consent| {
+            format!(
+                "<li>You have agreed to {} on {}.\
+                <form method=\"post\" action=\"consent/withdraw?token={}\">\
+                <input type=\"hidden\" name=\"purpose\" value=\"{}\">\
+                <button type=\"submit\">Withdraw</button>\
+                </form></li>",
+                describe(&consent.purpose),
+                consent.created_at.format("%Y-%m-%d"),
+                query.token,
+                consent.purpose,
+            )
+        })
+        .collect::<String>();
+    let content = if items.is_empty() {
+        "<p>You have not given any consents.</p>".to_string()
+    } else {
+        format!("<ul>{}</ul>", items)
+    }; // end if
+
+    Ok(Html(format!(
+        "<!DOCTYPE html>\
+        <html>\
+        <head><meta charset=\"utf-8\"><title>Your consents</title></head>\
+        <body>{}</body>\
+        </html>",
+        content
+    )))
+} // end fn consent_page
+
+/// Withdraw a consent.
+///
+/// The withdrawal is appended to the consent ledger. No marketing emails
+/// are sent after the consent to marketing is withdrawn, and no emails
+/// at all after the consent to the privacy policy is withdrawn.
+///
+#[utoipa::path(
+    post,
+    tag = "Subscription",
+    path = "/consent/withdraw",
+    params(LinkQuery),
+    request_body(content = WithdrawConsentPayload, description = "The consent to withdraw", content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = StatusCode::OK, description = "The consent has been withdrawn", content_type = "text/html"),
+        (status = StatusCode::BAD_REQUEST, description = "The link or the purpose is invalid, or the link has expired", body = DefaultResponseJson, example = json!("{\"message\": \"The consent link is invalid or has expired\", \"redirect\": null}")),
+        (status = StatusCode::NOT_FOUND, description = "The user does not exist", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn withdraw_consent(
+    State(app_state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<LinkQuery>,
+    Form(payload): Form<WithdrawConsentPayload>,
+) -> Result<Html<&'static str>, DefaultResponse> {
+    let user_id = consenting_user(&query.token)?;
+    let purpose = PURPOSES
+        .into_iter()
+        .find(|purpose| *purpose == payload.purpose)
+        .ok_or_else(|| DefaultResponse {
+            status_code: StatusCode::BAD_REQUEST,
+            message: Some(format!(
+                "The purpose must be one of {}",
+                PURPOSES.join(", ")
+            )),
+            redirect: None,
+        })?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let context = ConsentContext::collect(&headers, peer.map(|ConnectInfo(peer)| peer));
+    consents::record(
+        &mut conn,
+        user_id,
+        &[(purpose, false)],
+        FROM_WITHDRAWAL,
+        &context,
+    )
+    .await
+    .map_err(|error| match error {
+        // The user has been removed in the meantime.
+        Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => DefaultResponse {
+            status_code: StatusCode::NOT_FOUND,
+            message: Some("The user does not exist".to_string()),
+            redirect: None,
+        },
+        error => DefaultResponse::server_error(error),
+    })?;
+
+    Ok(Html(
+        "<!DOCTYPE html>\
+        <html>\
+        <head><meta charset=\"utf-8\"><title>Your consents</title></head>\
+        <body><p>Your consent has been withdrawn.</p></body>\
+        </html>",
+    ))
+} // end fn withdraw_consent
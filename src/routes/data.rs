@@ -0,0 +1,299 @@
+// This file contains the endpoints that let the leads export or erase
+// their data without an account (see utils/data_requests.rs).
+//
+// NOTE: The lead asks for a signed link with the email, the link is sent
+// only to that address. Like the consent link, the link is opened with
+// a GET request that only shows a page, the data is exported or erased
+// with a POST request from that page.
+//
+// The links are rate-limited by address and by IP, so that the endpoint
+// cannot flood the inbox of a lead. They are queued and sent in the
+// background, so that the known and the unknown addresses are answered
+// in the same time.
+
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse},
+    Form, Json,
+};
+use chrono::Duration;
+use diesel::{ExpressionMethods, NullableExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::{
+    models::NewFormNotification,
+    schema::users,
+    utils::{
+        attribution::client_ip,
+        data_requests::{self, Erasure},
+        jwt::decode_link_token,
+        lead_forms::queue_email,
+        links::{data_link, DATA_REQUEST},
+        rate_limits::{self, RateLimit},
+        responses::DefaultResponse,
+    },
+};
+
+use super::{subscription::LinkQuery, AppState};
+
+// The number of the links an address can be sent in an hour.
+const LINKS_PER_ADDRESS: RateLimit = RateLimit {
+    hits: 3,
+    window: Duration::hours(1),
+};
+
+// The number of the links that can be asked for from an IP in an hour.
+const LINKS_PER_IP: RateLimit = RateLimit {
+    hits: 10,
+    window: Duration::hours(1),
+};
+
+/// This struct represents a request for a link to the data.
+#[derive(Deserialize, ToSchema)]
+pub struct DataLinkPayload {
+    #[schema(example = "johnjohnson@gmail.com")]
+    pub email: String,
+} // end struct DataLinkPayload
+
+/// This function checks the token of a data link.
+/// It returns the id of the user if the token is valid.
+fn requesting_user(token: &str) -> Result<i32, DefaultResponse> {
+    decode_link_token(token, DATA_REQUEST)
+        .and_then(|subject| subject.parse::<i32>().ok())
+        .ok_or_else(|| DefaultResponse {
+            status_code: StatusCode::BAD_REQUEST,
+            message: Some("The data link is invalid or has expired".to_string()),
+            redirect: None,
+        }) // end ok_or_else
+} // end fn requesting_user
+
+/// This function returns a response about a user that does not exist.
+fn user_not_found() -> DefaultResponse {
+    DefaultResponse {
+        status_code: StatusCode::NOT_FOUND,
+        message: Some("The user does not exist".to_string()),
+        redirect: None,
+    }
+} // end fn user_not_found
+
+/// Ask for a link to the data.
+///
+/// A link to export or erase the data is emailed to every lead with the
+/// address, it works for a day. The response is the same whether or not
+/// the address is known. An address can be sent 3 links in an hour,
+/// and 10 links can be asked for from an IP.
+///
+#[utoipa::path(
+    post,
+    tag = "Subscription",
+    path = "/data/request",
+    request_body(content = DataLinkPayload, description = "The email of the lead", content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = StatusCode::OK, description = "The link has been sent if the email is known", body = DefaultResponseJson, example = json!("{\"message\": \"If the email is known, a link has been sent to it\", \"redirect\": null}")),
+        (status = StatusCode::TOO_MANY_REQUESTS, description = "Too many links have been asked for the address or from the IP", body = DefaultResponseJson, example = json!("{\"message\": \"Too many links have been asked for, try again later\", \"redirect\": null}")),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn request_data_link(
+    State(app_state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Form(payload): Form<DataLinkPayload>,
+) -> Result<DefaultResponse, DefaultResponse> {
+    let email = payload.email.trim().to_lowercase();
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    // NOTE: The limits are the same for the known and the unknown
+    // addresses, so they do not disclose who is a lead either.
+    let mut allowed = rate_limits::hit(
+        &mut conn,
+        &format!("data_link:email:{}", email),
+        LINKS_PER_ADDRESS,
+    )
+    .await
+    .map_err(DefaultResponse::server_error)?;
+    if let Some(ip) = client_ip(&headers, peer.map(|ConnectInfo(peer)| peer)) {
+        allowed &= rate_limits::hit(&mut conn, &format!("data_link:ip:{}", ip), LINKS_PER_IP)
+            .await
+            .map_err(DefaultResponse::server_error)?;
+    } // end if
+    if !allowed {
+        return Err(DefaultResponse {
+            status_code: StatusCode::TOO_MANY_REQUESTS,
+            message: Some("Too many links have been asked for, try again later".to_string()),
+            redirect: None,
+        });
+    } // end if
+
+    let leads = users::table
+        .filter(users::email.eq(&email))
+        .select((users::id, users::name, users::email.assume_not_null()))
+        .load::<(i32, String, String)>(&mut conn)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    for (user_id, name, email) in leads {
+        let link = data_link(user_id).ok_or_else(|| {
+            DefaultResponse::server_error("Failed to create the link to the data")
+        })?;
+
+        let email = NewFormNotification {
+            user_id,
+            recipient: email,
+            full_name: Some(name),
+            sender: None,
+            transactional: true,
+            subject: "Your data".to_string(),
+            message: format!(
+                "Follow the link to download or erase your data, it works for a day: {}",
+                link
+            ),
+        };
+        queue_email(&mut conn, &app_state.notifications, &email)
+            .await
+            .map_err(DefaultResponse::server_error)?;
+    } // end for
+
+    Ok(DefaultResponse {
+        status_code: StatusCode::OK,
+        message: Some("If the email is known, a link has been sent to it".to_string()),
+        redirect: None,
+    })
+} // end fn request_data_link
+
+/// Show the data page.
+///
+/// This endpoint is opened from the emailed link. It lets the lead
+/// download or erase their data.
+///
+#[utoipa::path(
+    get,
+    tag = "Subscription",
+    path = "/data",
+    params(LinkQuery),
+    responses(
+        (status = StatusCode::OK, description = "The page with the buttons", content_type = "text/html"),
+        (status = StatusCode::BAD_REQUEST, description = "The link is invalid or has expired", body = DefaultResponseJson, example = json!("{\"message\": \"The data link is invalid or has expired\", \"redirect\": null}")),
+    )
+)]
+pub async fn data_page(Query(query): Query<LinkQuery>) -> Result<Html<String>, DefaultResponse> {
+    requesting_user(&query.token)?;
+
+    // NOTE: The token is safe to put into the page as is,
+    // a valid token consists only of base64url characters and dots.
+    Ok(Html(format!(
+        "<!DOCTYPE html>\
+        <html>\
+        <head><meta charset=\"utf-8\"><title>Your data</title></head>\
+        <body>\
+        <form method=\"post\" action=\"data/export?token={0}\">\
+        <button type=\"submit\">Download my data</button>\
+        </form>\
+        <form method=\"post\" action=\"data/erase?token={0}\">\
+        <p>The erasure cannot be undone.</p>\
+        <button type=\"submit\">Erase my data</button>\
+        </form>\
+        </body>\
+        </html>",
+        query.token
+    )))
+} // end fn data_page
+
+/// Export the data with a link.
+///
+/// The archive is the same as the one of "/users/{id}/export".
+/// Every export is logged in the data requests.
+///
+#[utoipa::path(
+    post,
+    tag = "Subscription",
+    path = "/data/export",
+    params(LinkQuery),
+    responses(
+        (status = StatusCode::OK, description = "The JSON archive of the data", content_type = "application/json"),
+        (status = StatusCode::BAD_REQUEST, description = "The link is invalid or has expired", body = DefaultResponseJson),
+        (status = StatusCode::NOT_FOUND, description = "The user does not exist", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn export_data(
+    State(app_state): State<AppState>,
+    Query(query): Query<LinkQuery>,
+) -> Result<impl IntoResponse, DefaultResponse> {
+    let user_id = requesting_user(&query.token)?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let archive = data_requests::handle_export(&mut conn, user_id, user_id)
+        .await
+        .map_err(DefaultResponse::server_error)?
+        .ok_or_else(user_not_found)?;
+
+    // The archive is downloaded as a file by the browsers.
+    let disposition = format!("attachment; filename=\"user-{}.json\"", user_id);
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(archive)))
+} // end fn export_data
+
+/// Erase the data with a link.
+///
+/// The erasure is the same as the one of "/users/{id}".
+/// Every erasure is logged in the data requests.
+///
+#[utoipa::path(
+    post,
+    tag = "Subscription",
+    path = "/data/erase",
+    params(LinkQuery),
+    responses(
+        (status = StatusCode::OK, description = "The data has been erased", content_type = "text/html"),
+        (status = StatusCode::BAD_REQUEST, description = "The link is invalid or has expired", body = DefaultResponseJson),
+        (status = StatusCode::NOT_FOUND, description = "The user does not exist", body = DefaultResponseJson),
+        (status = StatusCode::CONFLICT, description = "The user is an Admin", body = DefaultResponseJson, example = json!("{\"message\": \"The Admin role has to be revoked before the user is erased\", \"redirect\": null}")),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn erase_data(
+    State(app_state): State<AppState>,
+    Query(query): Query<LinkQuery>,
+) -> Result<Html<&'static str>, DefaultResponse> {
+    let user_id = requesting_user(&query.token)?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let erasure = data_requests::handle_erasure(&mut conn, user_id, user_id)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+    match erasure {
+        Erasure::Erased => Ok(Html(
+            "<!DOCTYPE html>\
+            <html>\
+            <head><meta charset=\"utf-8\"><title>Your data</title></head>\
+            <body><p>Your data has been erased.</p></body>\
+            </html>",
+        )),
+        Erasure::NotFound => Err(user_not_found()),
+        Erasure::Admin => Err(DefaultResponse {
+            status_code: StatusCode::CONFLICT,
+            message: Some(data_requests::ADMIN_ERROR.to_string()),
+            redirect: None,
+        }),
+    } // end match
+} // end fn erase_data
@@ -1,7 +1,8 @@
 // This file contains all the functionality for sending
 // emails.
 
-use axum::{http::StatusCode, Json};
+use axum::{extract::State, http::StatusCode, Json};
+use lettre::message::header::{Header, HeaderName, HeaderValue};
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::Message;
 use lettre::SmtpTransport;
@@ -10,7 +11,54 @@ use serde::Deserialize;
 use std::env;
 use utoipa::ToSchema;
 
-use crate::utils::responses::DefaultResponse;
+use diesel_async::AsyncPgConnection;
+
+use crate::utils::{
+    consents::may_send,
+    links::unsubscribe_link,
+    responses::DefaultResponse,
+    subscriptions::{is_confirmed, is_unsubscribed},
+};
+
+use super::AppState;
+
+/// This struct represents the List-Unsubscribe header (RFC 2369).
+#[derive(Clone)]
+struct ListUnsubscribe(String);
+
+impl Header for ListUnsubscribe {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe")
+    } // end fn name
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self(s.trim_matches(['<', '>']).to_string()))
+    } // end fn parse
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), format!("<{}>", self.0))
+    } // end fn display
+} // end impl Header
+
+/// This struct represents the List-Unsubscribe-Post header (RFC 8058),
+/// which tells the mail clients that they can unsubscribe
+/// the recipient with a single POST request.
+#[derive(Clone)]
+struct ListUnsubscribePost;
+
+impl Header for ListUnsubscribePost {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe-Post")
+    } // end fn name
+
+    fn parse(_s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self)
+    } // end fn parse
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), "List-Unsubscribe=One-Click".to_string())
+    } // end fn display
+} // end impl Header
 
 #[derive(Deserialize, ToSchema)]
 pub struct EmailPayload {
@@ -22,8 +70,24 @@ pub struct EmailPayload {
     pub email: String,
     #[schema(example = "This is a message from a server.")]
     pub message: String,
+    // The address the email is sent from, it is set by the forms.
+    // NOTE: It cannot be set by the clients of the endpoint.
+    #[serde(skip)]
+    pub sender: Option<String>,
+    // Whether the email is needed to provide the service (e.g. the
+    // confirmation of a subscription) rather than marketing.
+    // NOTE: The emails requested by the clients are marketing.
+    #[serde(skip)]
+    pub transactional: bool,
 }
 
+/// This function returns the address the emails are sent from by default,
+/// which is set in the SMTP_SENDER environment variable.
+fn default_sender() -> String {
+    env::var("SMTP_SENDER")
+        .unwrap_or_else(|_error| "Manuspect <manuspect.prod@gmail.com>".to_string())
+} // end fn default_sender
+
 /// Send an email to a user.
 ///
 #[utoipa::path(
@@ -36,30 +100,67 @@ pub struct EmailPayload {
         (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side (Email is not sent in this case)", body = DefaultResponseJson, example = json!("{\"message\": \"An error occurred on the server side. Email could not be sent.\", \"redirect\": null}")),
     )
 )]
-pub async fn dispatch_email(Json(payload): Json<EmailPayload>) -> DefaultResponse {
+pub async fn dispatch_email(
+    State(app_state): State<AppState>,
+    Json(payload): Json<EmailPayload>,
+) -> DefaultResponse {
     const SERVER_ERROR: &str = "Something went wrong on the server side";
 
-    // Destructure the HTTP request body.
-    let EmailPayload {
-        full_name,
-        subject,
-        email,
-        message,
-    } = &payload;
+    // Check if the recipient wants to receive the email.
+    let refusal = match app_state.pool.get().await {
+        Ok(mut connection) => refusal(&mut connection, &payload).await,
+        Err(error) => {
+            eprintln!("{}", error);
+            return DefaultResponse {
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                message: Some(SERVER_ERROR.to_string()),
+                redirect: None,
+            };
+        }
+    };
+    match refusal {
+        Ok(None) => (),
+        Ok(Some(reason)) => {
+            return DefaultResponse {
+                status_code: StatusCode::OK,
+                message: Some(format!("{}, the email was not sent", reason)),
+                redirect: None,
+            };
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            return DefaultResponse {
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                message: Some(SERVER_ERROR.to_string()),
+                redirect: None,
+            };
+        }
+    }
 
-    // Construct email config.
-    let from_address = String::from("Manuspect <manuspect.prod@gmail.com>");
-    let to_address = format!("{full_name} <{email}>");
-    let reply_to = String::from("Manuspect <manuspect.prod@gmail.com>");
-    let email_subject = subject;
+    // Every email contains a personal link to unsubscribe.
+    let unsubscribe_link = match unsubscribe_link(&payload.email) {
+        Some(link) => link,
+        None => {
+            eprintln!("Failed to create an unsubscribe link");
+            return DefaultResponse {
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                message: Some(SERVER_ERROR.to_string()),
+                redirect: None,
+            };
+        }
+    };
 
-    let email = Message::builder()
-        .from(from_address.parse().unwrap())
-        .reply_to(reply_to.parse().unwrap())
-        .to(to_address.parse().unwrap())
-        .subject(email_subject)
-        .body(String::from(message))
-        .unwrap();
+    let email = match build_message(&payload, &unsubscribe_link) {
+        Ok(email) => email,
+        Err(error) => {
+            eprintln!("{}", error);
+            return DefaultResponse {
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                message: Some(SERVER_ERROR.to_string()),
+                redirect: None,
+            };
+        }
+    };
 
     let creds = Credentials::new(
         match env::var("SMTP_USERNAME") {
@@ -130,3 +231,88 @@ pub async fn dispatch_email(Json(payload): Json<EmailPayload>) -> DefaultRespons
         redirect: None,
     }
 } // fn dispatch_email
+
+/// This function checks if the email may be sent to the recipient.
+/// It returns the reason it must not be sent, if there is one.
+async fn refusal(
+    conn: &mut AsyncPgConnection,
+    payload: &EmailPayload,
+) -> diesel::QueryResult<Option<&'static str>> {
+    // NOTE: The unsubscribed recipients still get the transactional
+    // emails, otherwise they could never confirm a new subscription.
+    if !payload.transactional && is_unsubscribed(conn, &payload.email).await? {
+        return Ok(Some("The recipient has unsubscribed"));
+    } // end if
+    if !may_send(conn, &payload.email, payload.transactional).await? {
+        return Ok(Some("The recipient has withdrawn the consent"));
+    } // end if
+
+    // The marketing emails are sent only to the subscriptions
+    // that have been confirmed (double opt-in).
+    if !payload.transactional && !is_confirmed(conn, &payload.email).await? {
+        return Ok(Some("The recipient has not confirmed the subscription"));
+    } // end if
+
+    Ok(None)
+} // end fn refusal
+
+/// This function builds an email with the headers that
+/// let the mail clients unsubscribe the recipient in one click.
+fn build_message(
+    payload: &EmailPayload,
+    unsubscribe_link: &str,
+) -> Result<Message, Box<dyn std::error::Error + Send + Sync>> {
+    // Destructure the HTTP request body.
+    let EmailPayload {
+        full_name,
+        subject,
+        email,
+        message,
+        sender,
+        transactional: _,
+    } = payload;
+
+    // Construct email config.
+    let from_address = sender.clone().unwrap_or_else(default_sender);
+    let to_address = format!("{full_name} <{email}>");
+    let reply_to = from_address.clone();
+    let email_subject = subject;
+
+    Message::builder()
+        .from(from_address.parse()?)
+        .reply_to(reply_to.parse()?)
+        .to(to_address.parse()?)
+        .subject(email_subject)
+        .header(ListUnsubscribe(unsubscribe_link.to_string()))
+        .header(ListUnsubscribePost)
+        .body(format!(
+            "{message}\n\n--\nTo stop receiving these emails, follow the link: {unsubscribe_link}"
+        ))
+        .map_err(|error| error.into())
+} // end fn build_message
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_supports_one_click_unsubscribe() {
+        let payload = EmailPayload {
+            full_name: "John Johnson".to_string(),
+            subject: "A great greeting!".to_string(),
+            email: "john@example.com".to_string(),
+            message: "Hello, world!".to_string(),
+            sender: None,
+            transactional: false,
+        };
+        let link = "http://localhost/unsubscribe?token=abc";
+
+        let formatted = build_message(&payload, link).unwrap().formatted();
+        let formatted = String::from_utf8(formatted).unwrap();
+
+        assert!(
+            formatted.contains("List-Unsubscribe: <http://localhost/unsubscribe?token=abc>\r\n")
+        );
+        assert!(formatted.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n"));
+    }
+}
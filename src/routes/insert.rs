@@ -2,18 +2,39 @@
 // NOTE: This endpoint is used to add unverified accounts
 // to the database.
 
-use axum::{extract::State, http::StatusCode, Form};
-use diesel::{ExpressionMethods, QueryDsl};
-use diesel_async::RunQueryDsl;
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
 use serde_json;
 
 use crate::{
-    models::{NewUser, User},
-    utils::responses::DefaultResponse,
+    models::NewUser,
+    utils::{
+        antispam::{self, AntiSpamConfig, Challenge, SpamReason},
+        attribution::{self, AttributionParams},
+        captcha::{self, CaptchaError},
+        consents::{self, ConsentContext},
+        emails,
+        forms::{FormSubmission, DEFAULT_FORM},
+        funnel,
+        lead_forms::{check_required_fields, find_form, queue_notifications, render_welcome},
+        leads::{self, upsert_lead},
+        links::{confirmation_link, consent_link, waitlist_link},
+        phones,
+        responses::DefaultResponse,
+        scoring,
+        waitlist::{self, ReferralRules},
+        webhooks,
+    },
 };
 
 use crate::routes::dispatch_email;
 use crate::routes::dispatch_email::EmailPayload;
+use crate::routes::waitlist::notify_position_change;
 
 use super::AppState;
 
@@ -23,20 +44,158 @@ use super::AppState;
 /// provided. This function creates an unverified
 /// account for the client.
 ///
+/// If the lead with the same phone number or email already exists,
+/// then the missing fields of the lead are filled in instead.
+///
+/// This endpoint submits the "default" form, see "/insert/{form}".
+///
 #[utoipa::path(
     post,
     tag = "AddUser",
     path = "/insert",
+    params(AttributionParams),
     request_body(content = NewUser, description = "Some data about a user", content_type = "application/x-www-form-urlencoded"),
     responses(
         (status = StatusCode::OK, description = "The user is added to the database successfully", body = DefaultResponseJson, example = json!("{\"message\": \"The user is added successfully!\", \"redirect\": null}")),
+        (status = StatusCode::BAD_REQUEST, description = "A required or a custom field is not valid, or the CAPTCHA has failed", body = DefaultResponseJson),
         (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson, example = json!("{\"message\": \"An error occurred on the server side.\", \"redirect\": null}")),
-        (status = StatusCode::UNAUTHORIZED, description = "The user already exists in the database, no need to add them again", body = DefaultResponseJson, example = json!("{\"message\": \"The user has already been added, no need to do that again.\", \"redirect\": \"http://localhost/user_added.html\"}"))
+        (status = StatusCode::UNAUTHORIZED, description = "The name, the email or the phone number is not valid", body = DefaultResponseJson, example = json!("{\"message\": \"The phone number is not valid\", \"redirect\": null}")),
+        (status = StatusCode::CONFLICT, description = "Another submission with the same phone number is being saved", body = DefaultResponseJson)
     )
 )]
 pub async fn insert(
     State(app_state): State<AppState>,
-    Form(user): Form<NewUser>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(params): Query<AttributionParams>,
+    FormSubmission { data: user, fields }: FormSubmission<NewUser>,
+) -> DefaultResponse {
+    insert_lead(
+        app_state,
+        DEFAULT_FORM,
+        peer.map(|ConnectInfo(peer)| peer),
+        headers,
+        params,
+        user,
+        fields,
+    )
+    .await
+} // end fn insert
+
+/// Submit a landing form.
+///
+/// Every landing page has its own form with its own welcome email,
+/// redirects, required fields and notification recipients.
+///
+/// The subscription is pending until the client follows the
+/// confirmation link from the welcome email. The pending
+/// subscriptions that are not confirmed in time are removed.
+///
+/// A returning lead (the same phone number or email) is not created
+/// again, the submission fills in the fields the lead does not have
+/// yet. The response is the same, so that the form does not disclose
+/// who has subscribed.
+///
+/// The form can have custom fields, they are declared in the
+/// form definition with the same name (see CustomFields_{form}).
+///
+/// The landing page should pass its UTM parameters, its URL and
+/// its referrer in the query string, so that it is known which
+/// campaign the client came from.
+///
+#[utoipa::path(
+    post,
+    tag = "AddUser",
+    path = "/insert/{form}",
+    params(
+        ("form" = String, Path, description = "The public slug of the form", example = "default"),
+        AttributionParams,
+    ),
+    request_body(content = NewUser, description = "Some data about a user and the custom fields of the form", content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = StatusCode::OK, description = "The user is added to the database successfully", body = DefaultResponseJson, example = json!("{\"message\": \"The subscription was successful!\", \"redirect\": \"https://example.com/thanks.html\"}")),
+        (status = StatusCode::BAD_REQUEST, description = "A required or a custom field is not valid, or the CAPTCHA has failed", body = DefaultResponseJson),
+        (status = StatusCode::NOT_FOUND, description = "The form does not exist", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The name, the email or the phone number is not valid", body = DefaultResponseJson),
+        (status = StatusCode::CONFLICT, description = "Another submission with the same phone number is being saved", body = DefaultResponseJson)
+    )
+)]
+pub async fn insert_into_form(
+    State(app_state): State<AppState>,
+    Path(form): Path<String>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(params): Query<AttributionParams>,
+    FormSubmission { data: user, fields }: FormSubmission<NewUser>,
+) -> DefaultResponse {
+    insert_lead(
+        app_state,
+        &form,
+        peer.map(|ConnectInfo(peer)| peer),
+        headers,
+        params,
+        user,
+        fields,
+    )
+    .await
+} // end fn insert_into_form
+
+/// Get a challenge for a landing form.
+///
+/// The landing page should request a challenge when the form is
+/// rendered and submit the token in the "form_token" field. If the
+/// difficulty is above 0, then the page also has to find a nonce, so
+/// that SHA-256 of the token followed by the nonce starts with that
+/// many zero bits, and submit it in the "pow_nonce" field.
+///
+/// The form also has to contain an empty hidden "contact_me_by_fax" field.
+///
+#[utoipa::path(
+    get,
+    tag = "AddUser",
+    path = "/insert/{form}/challenge",
+    params(("form" = String, Path, description = "The public slug of the form", example = "default")),
+    responses(
+        (status = StatusCode::OK, description = "The challenge for the form", body = Challenge),
+        (status = StatusCode::NOT_FOUND, description = "The form does not exist", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn form_challenge(
+    State(app_state): State<AppState>,
+    Path(form): Path<String>,
+) -> Result<Json<Challenge>, DefaultResponse> {
+    let mut connection = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    // Make sure that the form exists.
+    let form = find_form(&mut connection, &form)
+        .await
+        .map_err(DefaultResponse::server_error)?
+        .ok_or_else(|| DefaultResponse {
+            status_code: StatusCode::NOT_FOUND,
+            message: Some("The form does not exist".to_string()),
+            redirect: None,
+        })?;
+
+    antispam::issue_challenge(&form.slug, &AntiSpamConfig::from_env())
+        .map(Json)
+        .ok_or_else(|| DefaultResponse::server_error("Failed to issue a challenge"))
+} // end fn form_challenge
+
+/// This function adds a lead submitted with the form to the database.
+async fn insert_lead(
+    app_state: AppState,
+    slug: &str,
+    peer: Option<SocketAddr>,
+    headers: HeaderMap,
+    params: AttributionParams,
+    mut user: NewUser,
+    fields: Vec<(String, String)>,
 ) -> DefaultResponse {
     // This is a default error message from a server in order not to
     // disclose some information that could be used to
@@ -60,8 +219,43 @@ pub async fn insert(
         } // end Err
     }; // end match
 
+    // Find the form the client has submitted.
+    let form = match find_form(&mut connection, slug).await {
+        Ok(Some(form)) => form,
+        Ok(None) => {
+            return DefaultResponse {
+                status_code: StatusCode::NOT_FOUND,
+                message: Some("The form does not exist".to_string()),
+                redirect: None,
+            }; // end return
+        } // end None
+        Err(error) => {
+            eprintln!("{}", error);
+            return DefaultResponse {
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                message: Some(SERVER_ERROR.to_string()),
+                redirect: None,
+            }; // end return
+        } // end Err
+    }; // end match
+
+    // Bots are told that everything is fine, so that
+    // they do not try to get around the protection.
+    let antispam_config = AntiSpamConfig::from_env();
+    let spam_token = match antispam::check_submission(&form.slug, &fields, &antispam_config) {
+        Ok(token) => token,
+        Err(reason) => {
+            antispam::record_rejection(&form.slug, &reason);
+            return DefaultResponse {
+                status_code: StatusCode::OK,
+                message: Some("The subscription was successful!".to_string()),
+                redirect: form.success_redirect,
+            }; // end return
+        } // end Err
+    }; // end match
+
     // Check if the form is filled out properly.
-    let (status, message) = is_valid_form(&user);
+    let (status, message) = is_valid_form(&mut user);
 
     // Deal with all possible result of the form validation.
     if status != StatusCode::OK {
@@ -74,60 +268,128 @@ pub async fn insert(
         }; // end return
     } // end if
 
-    // Check if the user with the same email or phone number
-    // already exists.
+    // Check that the email domain can be used.
+    if let Some(email) = &user.email {
+        if let Err(message) = app_state.emails.check(email).await {
+            return DefaultResponse {
+                status_code: StatusCode::UNAUTHORIZED,
+                message: Some(message),
+                redirect: None,
+            }; // end return
+        } // end if
+    } // end if
+
+    // Check the fields the form requires.
+    if let Err(message) = check_required_fields(&form, &fields) {
+        return DefaultResponse {
+            status_code: StatusCode::BAD_REQUEST,
+            message: Some(message),
+            redirect: None,
+        }; // end return
+    } // end if
 
-    // Check the phone number.
-    match crate::schema::users::dsl::users
-        .filter(crate::schema::users::columns::phone_number_code.eq(&user.phone_number_code))
-        .filter(crate::schema::users::columns::phone_number.eq(&user.phone_number))
-        .load::<User>(&mut connection)
-        .await
+    // Check the custom fields against the definition of the form.
+    let custom_fields = match app_state
+        .forms
+        .get(&form.slug)
+        .map(|definition| definition.validate(&fields))
     {
-        // The data extraction has been successful.
-        Ok(users) => {
-            // Check that the array of users is empty.
-            if !users.is_empty() {
-                // The user already exists,
-                // reject the request.
-                return DefaultResponse {
-                    status_code: StatusCode::UNAUTHORIZED,
-                    message: Some("The user with this phone number already exists".to_string()),
-                    redirect: None,
-                }; // end return
-            } // end if
-        } // end Ok
-        // An error occurred while extracting data from the database.
-        Err(error) => {
+        Some(Ok(values)) => serde_json::Value::Object(values),
+        Some(Err(message)) => {
+            return DefaultResponse {
+                status_code: StatusCode::BAD_REQUEST,
+                message: Some(message),
+                redirect: None,
+            }; // end return
+        } // end Err
+        None => serde_json::json!({}),
+    }; // end match
+
+    // Read the consents given with the submission.
+    let consent_flags = match consents::parse_flags(&fields) {
+        Ok(flags) => flags,
+        Err(message) => {
+            return DefaultResponse {
+                status_code: StatusCode::BAD_REQUEST,
+                message: Some(message),
+                redirect: None,
+            }; // end return
+        } // end Err
+    }; // end match
+
+    // Check the CAPTCHA if the form requires it.
+    let remote_ip = attribution::client_ip(&headers, peer);
+    match captcha::check(
+        app_state.captcha.as_deref(),
+        form.captcha_required,
+        &fields,
+        remote_ip.as_deref(),
+    )
+    .await
+    {
+        Ok(()) => {}
+        Err(CaptchaError::Failed) => {
+            return DefaultResponse {
+                status_code: StatusCode::BAD_REQUEST,
+                message: Some(CaptchaError::MESSAGE.to_string()),
+                redirect: None,
+            }; // end return
+        } // end Failed
+        Err(CaptchaError::Unavailable(error)) => {
             eprintln!("{}", error);
             return DefaultResponse {
                 status_code: StatusCode::INTERNAL_SERVER_ERROR,
                 message: Some(SERVER_ERROR.to_string()),
                 redirect: None,
             }; // end return
-        } // end Err
-    } // end match
+        } // end Unavailable
+    }; // end match
 
-    // Check the email.
-    match crate::schema::users::dsl::users
-        .filter(crate::schema::users::columns::email.eq(&user.email))
-        .load::<User>(&mut connection)
-        .await
-    {
-        // The data extraction has been successful.
-        Ok(users) => {
-            // Check that the array of users is empty.
-            if !users.is_empty() {
-                // The user already exists,
-                // reject the request.
+    // Every challenge can be used once, a replayed one is spam.
+    // NOTE: The challenge is spent only by a valid submission,
+    // so that the client can correct the mistakes and submit again.
+    if let Some(token) = spam_token {
+        match antispam::spend_token(&mut connection, token, &antispam_config).await {
+            Ok(true) => (),
+            Ok(false) => {
+                antispam::record_rejection(&form.slug, &SpamReason::Replayed);
+                return DefaultResponse {
+                    status_code: StatusCode::OK,
+                    message: Some("The subscription was successful!".to_string()),
+                    redirect: form.success_redirect,
+                }; // end return
+            } // end Ok
+            Err(error) => {
+                eprintln!("{}", error);
                 return DefaultResponse {
-                    status_code: StatusCode::UNAUTHORIZED,
-                    message: Some("The user with this email already exists".to_string()),
+                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                    message: Some(SERVER_ERROR.to_string()),
                     redirect: None,
                 }; // end return
-            } // end if
-        } // end Ok
-        // An error occurred while extracting data from the database.
+            } // end Err
+        } // end match
+    } // end if
+
+    // Create the lead or fill in the lead with the same phone number
+    // or email. The client gets the same response either way.
+    let lead = match upsert_lead(
+        &mut connection,
+        &user,
+        &custom_fields,
+        form.id,
+        leads::FROM_FORM,
+    )
+    .await
+    {
+        Ok(lead) => lead,
+        // The submissions with the same phone number keep racing.
+        Err(error) if phones::is_taken(&error) => {
+            return DefaultResponse {
+                status_code: StatusCode::CONFLICT,
+                message: Some("The form is being submitted already, try again".to_string()),
+                redirect: None,
+            }; // end return
+        } // end Err
         Err(error) => {
             eprintln!("{}", error);
             return DefaultResponse {
@@ -136,15 +398,23 @@ pub async fn insert(
                 redirect: None,
             }; // end return
         } // end Err
-    } // end match
+    }; // end match
+    let user_id = lead.user.id;
 
-    // Try to insert a user to the database.
-    match diesel::insert_into(crate::schema::users::table)
-        .values(&user)
-        .get_result::<User>(&mut connection)
-        .await
+    // Append the consents to the ledger, the lead must not be
+    // contacted without them. The consents granted for a returning
+    // lead are in force only with the confirmation link.
+    let regranted = match consents::submit(
+        &mut connection,
+        user_id,
+        &consent_flags,
+        consents::FROM_FORM,
+        &ConsentContext::collect(&headers, peer),
+        lead.created,
+    )
+    .await
     {
-        Ok(_) => (),
+        Ok(regranted) => regranted,
         Err(error) => {
             eprintln!("{}", error);
             return DefaultResponse {
@@ -155,15 +425,153 @@ pub async fn insert(
         } // end Err
     }; // end match
 
-    // Send an email to the new user if the user specified an email.
-    if let Some(user_email) = user.email {
+    // Remember where the new lead came from.
+    // NOTE: The subscription does not fail if it cannot be done,
+    // the lead is more valuable than its attribution.
+    if lead.created {
+        let lead_attribution = attribution::collect(user_id, params, &headers, peer);
+        if let Err(error) = attribution::record(&mut connection, &lead_attribution).await {
+            eprintln!("{}", error);
+        } // end if
+    } // end if
+
+    // Put the new lead at the end of the waitlist, the lead that has
+    // referred them moves up and is told about it.
+    // NOTE: The subscription does not fail if it cannot be done.
+    if lead.created {
+        let referral_code = fields
+            .iter()
+            .find(|(name, _value)| name == waitlist::REFERRAL_FIELD)
+            .map(|(_name, value)| value.as_str());
+        let rules = ReferralRules::from_env();
+        match waitlist::join(&mut connection, user_id, referral_code, &rules).await {
+            Ok(Some(referrer)) => notify_position_change(&app_state, referrer).await,
+            Ok(None) => (),
+            // The lead joins the waitlist when they ask for their place.
+            Err(error) => eprintln!(
+                "Failed to add the lead {} to the waitlist: {}",
+                user_id, error
+            ),
+        } // end match
+    } // end if
+
+    // Count the new lead in the funnel.
+    // NOTE: The subscription does not fail if it cannot be done.
+    if lead.created {
+        if let Err(error) = funnel::record(&mut connection, user_id, funnel::SUBSCRIBED).await {
+            eprintln!("{}", error);
+        } // end if
+    } // end if
+
+    // Score the lead with what it has told about itself.
+    // NOTE: The subscription does not fail if it cannot be done,
+    // the lead is scored again later.
+    if let Err(error) = scoring::rescore_lead(&mut connection, user_id, scoring::SUBMISSION).await {
+        eprintln!("{}", error);
+    } // end if
+
+    // Let the webhooks know about the new lead.
+    // NOTE: The subscription does not fail if it cannot be done.
+    if lead.created {
+        let data = serde_json::json!({
+            "id": user_id,
+            "name": lead.user.name,
+            "email": lead.user.email,
+            "phone_number_code": lead.user.phone_number_code,
+            "phone_number": lead.user.phone_number,
+            "form": form.slug,
+            "custom_fields": lead.user.custom_fields,
+            "created_at": lead.user.created_at,
+        });
+        if let Err(error) = webhooks::emit(
+            &mut connection,
+            &app_state.webhooks,
+            webhooks::LEAD_CREATED,
+            &data,
+        )
+        .await
+        {
+            eprintln!("{}", error);
+        } // end if
+    } // end if
+
+    // Let the people responsible for the form know about the submission.
+    // NOTE: The emails are sent by a background job, the subscription
+    // does not fail if they cannot be queued.
+    let subject = if lead.created {
+        format!("A new lead from \"{}\"", form.title)
+    } else {
+        format!("A returning lead from \"{}\"", form.title)
+    }; // end if
+    let message = format!(
+        "Name: {}\nEmail: {}\nPhone number: +{} {}\nCustom fields: {}",
+        user.name,
+        user.email.as_deref().unwrap_or("-"),
+        user.phone_number_code,
+        user.phone_number,
+        custom_fields,
+    );
+    if let Err(error) = queue_notifications(
+        &mut connection,
+        &app_state.notifications,
+        &form,
+        user_id,
+        &subject,
+        &message,
+    )
+    .await
+    {
+        eprintln!("{}", error);
+    } // end if
+
+    // Ask the lead to confirm the subscription if it is still pending
+    // and the submission has given the lead its email, or to confirm
+    // the consents granted for the returning lead.
+    // NOTE: Otherwise anyone who knows the phone number or the email
+    // of a lead could make the server send the lead emails.
+    let pending_email = match lead.user.confirmed_at {
+        None if lead.email_added => lead.user.email.clone(),
+        _ if regranted => lead.user.email.clone(),
+        _ => None,
+    }; // end match
+    if let Some(user_email) = pending_email {
+        // Generate the signed links that confirm the subscription,
+        // show the place in the waitlist and the consents.
+        let links = (
+            confirmation_link(user_id),
+            waitlist_link(user_id),
+            consent_link(user_id),
+        );
+        let (link, waitlist_link, consent_link) = match links {
+            (Some(link), Some(waitlist_link), Some(consent_link)) => {
+                (link, waitlist_link, consent_link)
+            } // end Some
+            _ => {
+                eprintln!("Failed to create the links of the welcome email");
+                return DefaultResponse {
+                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                    message: Some(SERVER_ERROR.to_string()),
+                    redirect: None,
+                }; // end return
+            } // end _
+        }; // end match
+
         // The email should be valid, send the email to the user.
         dispatch_email(
+            State(app_state.clone()),
             EmailPayload {
-                full_name: user.name,
-                subject: "Subscription is activated".to_string(),
+                message: render_welcome(
+                    &form,
+                    &lead.user.name,
+                    &link,
+                    &waitlist_link,
+                    &consent_link,
+                ),
+                full_name: lead.user.name,
+                subject: form.welcome_subject.clone(),
                 email: user_email,
-                message: "Welcome to Manuspect!".to_string(),
+                sender: Some(form.sender.clone()),
+                transactional: true,
             }
             .into(),
         )
@@ -174,7 +582,7 @@ pub async fn insert(
     DefaultResponse {
         status_code: StatusCode::OK,
         message: Some("The subscription was successful!".to_string()),
-        redirect: None,
+        redirect: form.success_redirect,
     } // end DefaultResponse
 } // fn insert
 
@@ -182,7 +590,10 @@ pub async fn insert(
 /// It returns a status (StatusCode), which indicates whether or not
 /// the verification has been passed, and a message that
 /// contains additional information about the result.
-fn is_valid_form(user: &NewUser) -> (StatusCode, String) {
+///
+/// NOTE: The email is lowercased and the phone number
+/// is normalized to E.164.
+pub(crate) fn is_valid_form(user: &mut NewUser) -> (StatusCode, String) {
     // Validate username.
     if user.name.is_empty() {
         return (
@@ -191,54 +602,24 @@ fn is_valid_form(user: &NewUser) -> (StatusCode, String) {
         ); // end return
     } // end if
 
-    // Validate email (if it is supplied).
-    if let Some(email) = user.email.clone() {
-        if !email.contains("@") {
-            // Email has to contain "@" symbol.
-            return (
-                StatusCode::UNAUTHORIZED,
-                "Email has to contain \"@\" symbol".to_string(),
-            ); // end return
-        } // end if
-        if !email.contains(".") {
-            // Email has to contain "." symbol.
-            return (
-                StatusCode::UNAUTHORIZED,
-                "Email has to contain \".\" symbol".to_string(),
-            ); // end return
-        } // end if
+    // Validate email (if it is supplied) and bring it
+    // to the form it is stored in.
+    if let Some(email) = &user.email {
+        match emails::normalize(email) {
+            Ok(email) => user.email = Some(email),
+            Err(message) => return (StatusCode::UNAUTHORIZED, message),
+        } // end match
     } // end if
 
-    // Validate phone number code.
-    if user.phone_number_code == 0 || user.phone_number_code > 999 {
-        // The phone number code is invalid.
-        return (
-            StatusCode::UNAUTHORIZED,
-            "The phone number code is invalid".to_string(),
-        ); // end return
-    } // end if
-
-    // Validate phone number.
-    if user.phone_number.len() < 4 {
-        // The phone number is too short.
-        return (
-            StatusCode::UNAUTHORIZED,
-            "The phone number is too short".to_string(),
-        ); // end return
-    } else {
-        // Traverse all the symbols in a phone number and make
-        // sure that they are all decimal digits.
-        for symbol in user.phone_number.chars() {
-            // Check if the current symbol is a valid digit.
-            if !symbol.is_digit(10) {
-                // This is not a valid decimal digit.
-                return (
-                    StatusCode::UNAUTHORIZED,
-                    "The phone number is not valid".to_string(),
-                ); // end return
-            } // end if
-        } // end for
-    } // end if
+    // Parse the phone number and bring it to E.164,
+    // so that the duplicates are found whatever the format.
+    match phones::parse(user.phone_number_code, &user.phone_number) {
+        Ok(phone) => {
+            user.phone_number_code = phone.country_code;
+            user.phone_number = phone.e164;
+        } // end Ok
+        Err(message) => return (StatusCode::UNAUTHORIZED, message),
+    } // end match
 
     (StatusCode::OK, "".to_string())
 } // end fn is_valid_form
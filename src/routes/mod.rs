@@ -1,7 +1,14 @@
+pub mod admin;
 pub mod auth;
+pub mod consent;
+pub mod data;
 pub mod dispatch_email;
 mod index;
 pub mod insert;
+pub mod subscription;
+pub mod unsubscribe;
+pub mod users;
+pub mod waitlist;
 
 use axum::{
     middleware,
@@ -13,6 +20,10 @@ use diesel_async::pooled_connection::AsyncDieselConnectionManager;
 use diesel_async::{pooled_connection::deadpool::Pool, AsyncPgConnection};
 
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use tokio::sync::Notify;
 
 use crate::middleware::{
     auth_guard::auth_guard,
@@ -20,16 +31,28 @@ use crate::middleware::{
 };
 
 use crate::models::ApiDoc;
+use crate::utils::{
+    captcha::{self, CaptchaVerifier},
+    crm::{self, CrmSync},
+    emails::EmailPolicy,
+    forms::FormDefinitions,
+    permissions::PermissionTrie,
+};
 
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
-use std::sync::{Arc, RwLock};
-
+use consent::{consent_page, withdraw_consent};
+use data::{data_page, erase_data, export_data, request_data_link};
 use dispatch_email::dispatch_email;
 use index::index;
-use insert::insert;
+use insert::{form_challenge, insert, insert_into_form};
+use subscription::{confirm_subscription, confirmation_page};
+use unsubscribe::{unsubscribe, unsubscribe_page};
+use users::{erase_user, export_user, get_user};
+use waitlist::waitlist_status;
 
+use self::admin::get_admin_router;
 use self::auth::get_auth_router;
 
 /// This struct contains some information that should be
@@ -38,34 +61,58 @@ use self::auth::get_auth_router;
 pub struct AppState {
     // This is a pool of connections to the database.
     pub pool: Pool<AsyncPgConnection>,
-    // This is a mapping of routes to the set of Roles,
+    // This is a compiled mapping of routes to the set of Roles,
     // that can access the route.
-    pub allowed_roles: Arc<RwLock<HashMap<String, HashSet<String>>>>,
+    // NOTE: To change the permissions, compile a new PermissionTrie
+    // and store it here, the requests in flight keep the old one.
+    pub allowed_roles: Arc<ArcSwap<PermissionTrie>>,
+    // These are the definitions of the custom fields of the forms.
+    pub forms: Arc<FormDefinitions>,
+    // This is the verifier of the CAPTCHAs, it is None
+    // if CAPTCHA_PROVIDER is not set.
+    pub captcha: Option<Arc<dyn CaptchaVerifier>>,
+    // These are the checks of the email domains, i.e.
+    // the disposable domains and the MX records.
+    pub emails: Arc<EmailPolicy>,
+    // This wakes up the delivery of the webhooks
+    // when an event has been queued.
+    pub webhooks: Arc<Notify>,
+    // This is the synchronization of the leads with a CRM,
+    // it is None if CRM_PROVIDER is not set.
+    pub crm: Option<Arc<CrmSync>>,
+    // This wakes up the scoring of all the leads
+    // when the scoring rules have changed.
+    pub scoring: Arc<Notify>,
+    // This wakes up the sending of the notifications
+    // about the submissions of the forms.
+    pub notifications: Arc<Notify>,
 } // end struct AppState
 
 /// This function generates a default HashMap with
 /// application routes accessibility.
+///
+/// NOTE: A client has to have access to the route and
+/// to all of its parent routes.
 fn get_default_allowed_roles() -> HashMap<String, HashSet<String>> {
-    // Initialize the HashMap.
-    let mut allowed_roles: HashMap<String, HashSet<String>> = HashMap::new();
+    // This is a helper function that creates a set of roles.
+    fn roles(roles: &[&str]) -> HashSet<String> {
+        roles.iter().map(|role| role.to_string()).collect()
+    } // end fn roles
 
     // Manually add the restrictions on the routes.
-    allowed_roles.insert("/metrics".to_string(), HashSet::new());
-
-    allowed_roles
-        .get_mut("/metrics")
-        .unwrap()
-        .insert("Admin".to_string());
-    allowed_roles
-        .get_mut("/metrics")
-        .unwrap()
-        .insert("Manager".to_string());
-
-    allowed_roles
+    HashMap::from([
+        ("/metrics".to_string(), roles(&["Admin", "Manager"])),
+        ("/admin".to_string(), roles(&["Admin", "Manager"])),
+        ("/admin/roles".to_string(), roles(&["Admin"])),
+        ("/admin/webhooks".to_string(), roles(&["Admin"])),
+        ("/admin/data_requests".to_string(), roles(&["Admin"])),
+        ("/admin/scoring".to_string(), roles(&["Admin"])),
+        ("/admin/forms".to_string(), roles(&["Admin"])),
+    ])
 } // end fn get_default_allowed_roles
 
 /// This function creates an AppState for the Router.
-fn create_app_state() -> AppState {
+pub fn create_app_state() -> AppState {
     // create a new connection pool with the default config
     let config = AsyncDieselConnectionManager::<diesel_async::AsyncPgConnection>::new(
         std::env::var("DATABASE_URL")
@@ -78,12 +125,37 @@ fn create_app_state() -> AppState {
         .expect("Failed to create a pool of connections to a database");
 
     // Get allowed roles for the routes.
-    let allowed_roles = Arc::new(RwLock::new(get_default_allowed_roles()));
+    let allowed_roles = Arc::new(ArcSwap::from_pointee(PermissionTrie::compile(
+        &get_default_allowed_roles(),
+    )));
+
+    // Load the definitions of the forms.
+    let forms = Arc::new(
+        FormDefinitions::load().expect("Failed to load the form definitions from FORM_DEFINITIONS"),
+    );
+
+    // Choose the verifier of the CAPTCHAs.
+    let captcha = captcha::from_env().expect("Failed to configure the CAPTCHA");
+
+    // Load the disposable domains and set up the DNS resolver.
+    let emails = Arc::new(EmailPolicy::from_env().expect("Failed to configure the email checks"));
+
+    // Choose the CRM the leads are pushed to.
+    let crm = crm::from_env()
+        .expect("Failed to configure the CRM synchronization")
+        .map(Arc::new);
 
     // Return the required AppState.
     AppState {
         pool,
         allowed_roles,
+        forms,
+        captcha,
+        emails,
+        webhooks: Arc::new(Notify::new()),
+        crm,
+        scoring: Arc::new(Notify::new()),
+        notifications: Arc::new(Notify::new()),
     }
 } // end fn create_app_state
 
@@ -94,24 +166,48 @@ fn create_app_state() -> AppState {
 /// If a new route is absent there, it is necessary to add it to this
 /// variable.
 pub async fn create_routes() -> Router {
-    // Create app state.
-    let app_state = create_app_state();
+    create_router(create_app_state())
+} // end fn create_routes
+
+/// This function creates a router that shares the AppState
+/// with the rest of the application (e.g. with the background jobs).
+pub fn create_router(app_state: AppState) -> Router {
+    // The custom fields of the forms are documented as well.
+    let mut api_doc = ApiDoc::openapi();
+    app_state.forms.document(&mut api_doc);
 
     // Create and assemble router.
     Router::new()
         .route("/dispatch_email", post(dispatch_email))
         .route("/metrics", get(metrics_display))
+        .route("/users/:id", get(get_user).delete(erase_user))
+        .route("/users/:id/export", get(export_user))
+        .nest("/admin", get_admin_router())
         .layer(middleware::from_fn_with_state(
             app_state.clone(),
             auth_guard,
         ))
         .route("/", get(index))
         .route("/insert", post(insert))
-        .merge(SwaggerUi::new("/swagger-ui").url("/api-doc/openapi.json", ApiDoc::openapi()))
+        .route("/insert/:form", post(insert_into_form))
+        .route("/insert/:form/challenge", get(form_challenge))
+        .route(
+            "/subscription/confirm",
+            get(confirmation_page).post(confirm_subscription),
+        )
+        .route("/consent", get(consent_page))
+        .route("/consent/withdraw", post(withdraw_consent))
+        .route("/data", get(data_page))
+        .route("/data/request", post(request_data_link))
+        .route("/data/export", post(export_data))
+        .route("/data/erase", post(erase_data))
+        .route("/unsubscribe", get(unsubscribe_page).post(unsubscribe))
+        .route("/waitlist/status", get(waitlist_status))
+        .merge(SwaggerUi::new("/swagger-ui").url("/api-doc/openapi.json", api_doc))
         .nest("/auth", get_auth_router().with_state(app_state.clone()))
         .layer(middleware::from_fn(metrics_collector))
         .with_state(app_state)
-} // end fn create_routes
+} // end fn create_router
 
 /// These are endpoint tests
 ///
@@ -219,7 +315,7 @@ mod tests {
             name: "John".to_string(),
             email: Some("john@example.com".to_string()),
             phone_number_code: 1,
-            phone_number: "1111111111".to_string(),
+            phone_number: "2015550123".to_string(),
             password: None,
         }; // end NewUser
 
@@ -246,30 +342,41 @@ mod tests {
         // Set up a client.
         let client = hyper::Client::new();
 
-        // Send a request to the server.
-        // Send a request and get a response from the server.
-        let response = client
-            .request(
-                Request::builder()
-                    .method(hyper::Method::POST)
-                    .header("Content-Type", "application/x-www-form-urlencoded")
-                    .uri(format!("http://{SERVER_ADDR}/insert"))
-                    .body(Body::from(form_data))
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-
-        // Get a server response.
-        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
-        println!("{:?}", body);
+        // The lead submits the form again with another email, it has to
+        // get the same response, so that the form does not disclose
+        // who has subscribed.
+        let mut responses = Vec::new();
+        for form_data in [
+            form_data.clone(),
+            form_data.replace("john%40example.com", "another%40example.com"),
+        ] {
+            // Send a request and get a response from the server.
+            let response = client
+                .request(
+                    Request::builder()
+                        .method(hyper::Method::POST)
+                        .header("Content-Type", "application/x-www-form-urlencoded")
+                        .uri(format!("http://{SERVER_ADDR}/insert"))
+                        .body(Body::from(form_data))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
 
-        // Assemble the response body in a struct.
-        let res: ResponseBody = serde_json::from_slice(&body).unwrap();
+            // Get a server response.
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            println!("{:?}", body);
+            responses.push((status, body));
+        } // end for
 
         // Kill the server.
         server.abort();
 
+        assert_eq!(responses[0], responses[1]);
+
+        // Assemble the response body in a struct.
+        let res: ResponseBody = serde_json::from_slice(&responses[0].1).unwrap();
         assert_eq!(res.message.unwrap(), "The subscription was successful!");
     }
 }
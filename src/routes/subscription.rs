@@ -0,0 +1,207 @@
+// This file contains the endpoints that manage subscriptions
+// created by the "/insert" endpoint.
+//
+// NOTE: Like the unsubscribe link, the confirmation link is opened with
+// a GET request that only shows a page, because links in emails are
+// often opened by mail scanners. The subscription is confirmed with
+// a POST request from that page.
+
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, Redirect},
+};
+use chrono::{DateTime, Utc};
+use diesel::{QueryDsl, QueryResult};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    schema::users,
+    utils::{
+        consents::{self, ConsentContext},
+        funnel,
+        jwt::decode_link_token,
+        lead_forms::confirmed_redirect,
+        links::{frontend_url, CONFIRM_SUBSCRIPTION},
+        responses::DefaultResponse,
+        scoring,
+        subscriptions::confirm_subscription as confirm,
+        waitlist::{reward_referrer, ReferralRules, RewardOn},
+        webhooks,
+    },
+};
+
+use super::{waitlist::notify_position_change, AppState};
+
+/// This struct represents the query of a signed link.
+#[derive(Deserialize, IntoParams)]
+pub struct LinkQuery {
+    /// The signed token from the link.
+    pub token: String,
+} // end struct LinkQuery
+
+/// This function checks the token of a confirmation link.
+/// It returns the id of the user if the token is valid.
+fn confirming_user(token: &str) -> Result<i32, DefaultResponse> {
+    decode_link_token(token, CONFIRM_SUBSCRIPTION)
+        .and_then(|subject| subject.parse::<i32>().ok())
+        .ok_or_else(invalid_link)
+} // end fn confirming_user
+
+/// This function emits the event about a lead who has
+/// verified their email by confirming the subscription.
+async fn emit_verified(
+    conn: &mut AsyncPgConnection,
+    app_state: &AppState,
+    user_id: i32,
+) -> QueryResult<()> {
+    let (name, email, confirmed_at) = users::table
+        .find(user_id)
+        .select((users::name, users::email, users::confirmed_at))
+        .first::<(String, Option<String>, Option<DateTime<Utc>>)>(conn)
+        .await?;
+    let data = serde_json::json!({
+        "id": user_id,
+        "name": name,
+        "email": email,
+        "verified_at": confirmed_at,
+    });
+    webhooks::emit(conn, &app_state.webhooks, webhooks::USER_VERIFIED, &data).await
+} // end fn emit_verified
+
+/// This function returns a response for all the links that cannot be used.
+fn invalid_link() -> DefaultResponse {
+    DefaultResponse {
+        status_code: StatusCode::BAD_REQUEST,
+        message: Some("The confirmation link is invalid or has expired".to_string()),
+        redirect: None,
+    }
+} // end fn invalid_link
+
+/// Show the confirmation page.
+///
+/// This endpoint is opened from the link in the welcome email.
+/// It asks the lead to confirm the subscription.
+///
+#[utoipa::path(
+    get,
+    tag = "Subscription",
+    path = "/subscription/confirm",
+    params(LinkQuery),
+    responses(
+        (status = StatusCode::OK, description = "The page with the confirmation button", content_type = "text/html"),
+        (status = StatusCode::BAD_REQUEST, description = "The link is invalid or has expired", body = DefaultResponseJson, example = json!("{\"message\": \"The confirmation link is invalid or has expired\", \"redirect\": null}")),
+    )
+)]
+pub async fn confirmation_page(
+    Query(query): Query<LinkQuery>,
+) -> Result<Html<String>, DefaultResponse> {
+    confirming_user(&query.token)?;
+
+    // NOTE: The token is safe to put into the page as is,
+    // a valid token consists only of base64url characters and dots.
+    Ok(Html(format!(
+        "<!DOCTYPE html>\
+        <html>\
+        <head><meta charset=\"utf-8\"><title>Confirm the subscription</title></head>\
+        <body>\
+        <p>Do you want to receive emails from Manuspect?</p>\
+        <form method=\"post\" action=\"?token={}\">\
+        <button type=\"submit\">Confirm the subscription</button>\
+        </form>\
+        </body>\
+        </html>",
+        query.token
+    )))
+} // end fn confirmation_page
+
+/// Confirm a subscription.
+///
+/// This endpoint is called by the confirmation page.
+/// It marks the subscription as confirmed (the "user.verified" event
+/// of the webhooks), puts in force the consents
+/// the lead has granted again after withdrawing them, and redirects
+/// the client to the page of their form that congratulates them.
+///
+#[utoipa::path(
+    post,
+    tag = "Subscription",
+    path = "/subscription/confirm",
+    params(LinkQuery),
+    responses(
+        (status = StatusCode::SEE_OTHER, description = "The subscription is confirmed, the client is redirected to subscribed.html"),
+        (status = StatusCode::BAD_REQUEST, description = "The link is invalid or has expired", body = DefaultResponseJson, example = json!("{\"message\": \"The confirmation link is invalid or has expired\", \"redirect\": null}")),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn confirm_subscription(
+    State(app_state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<LinkQuery>,
+) -> Result<Redirect, DefaultResponse> {
+    // Check the signature and find out whose subscription it is.
+    let user_id = confirming_user(&query.token)?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    // The lead could have been removed after the link had expired.
+    let Some(confirmed_now) = confirm(&mut conn, user_id)
+        .await
+        .map_err(DefaultResponse::server_error)?
+    else {
+        return Err(invalid_link());
+    };
+
+    // Let the webhooks know that the lead has verified their email.
+    // NOTE: The confirmation does not fail if it cannot be done.
+    if confirmed_now {
+        if let Err(error) = emit_verified(&mut conn, &app_state, user_id).await {
+            eprintln!("{}", error);
+        } // end if
+    } // end if
+
+    // The lead has proven that the consents they have granted again are theirs.
+    let context = ConsentContext::collect(&headers, peer.map(|ConnectInfo(peer)| peer));
+    consents::confirm_pending(&mut conn, user_id, &context)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    // Count the confirmation in the funnel, the link can be opened again.
+    // NOTE: The confirmation does not fail if it cannot be done.
+    if let Err(error) = funnel::record(&mut conn, user_id, funnel::CONFIRMED).await {
+        eprintln!("{}", error);
+    } // end if
+
+    // The confirmed lead is worth more.
+    // NOTE: The confirmation does not fail if it cannot be done.
+    if let Err(error) = scoring::rescore_lead(&mut conn, user_id, scoring::CONFIRMED).await {
+        eprintln!("{}", error);
+    } // end if
+
+    // The referrer can be rewarded only for the confirmed leads.
+    let rules = ReferralRules::from_env();
+    if rules.reward_on == RewardOn::Confirmation {
+        match reward_referrer(&mut conn, user_id, &rules).await {
+            Ok(Some(referrer)) => notify_position_change(&app_state, referrer).await,
+            Ok(None) => (),
+            Err(error) => eprintln!("{}", error),
+        } // end match
+    } // end if
+
+    // Every form can have its own page for the confirmed subscriptions.
+    let redirect = confirmed_redirect(&mut conn, user_id)
+        .await
+        .map_err(DefaultResponse::server_error)?
+        .unwrap_or_else(|| format!("{}/HTML/subscribed.html", frontend_url()));
+
+    Ok(Redirect::to(&redirect))
+} // end fn confirm_subscription
@@ -0,0 +1,124 @@
+// This file contains the endpoints that unsubscribe the recipients
+// from the emails.
+//
+// NOTE: The link from the List-Unsubscribe header is used in two ways.
+// Mail clients send a POST request to it (RFC 8058), while people
+// open it in a browser with a GET request. A GET request never
+// unsubscribes anybody, because links in emails are often opened by
+// mail scanners, it only shows a page that asks for confirmation.
+
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::Html,
+};
+
+use crate::utils::{
+    consents::{withdraw_marketing, ConsentContext, FROM_UNSUBSCRIBE},
+    jwt::decode_link_token,
+    links::UNSUBSCRIBE,
+    responses::DefaultResponse,
+    subscriptions::unsubscribe as record_unsubscribe,
+};
+
+use super::{subscription::LinkQuery, AppState};
+
+/// This function checks the token of an unsubscribe link.
+/// It returns the email of the recipient if the token is valid.
+fn recipient(token: &str) -> Result<String, DefaultResponse> {
+    decode_link_token(token, UNSUBSCRIBE).ok_or_else(|| DefaultResponse {
+        status_code: StatusCode::BAD_REQUEST,
+        message: Some("The unsubscribe link is invalid".to_string()),
+        redirect: None,
+    }) // end ok_or_else
+} // end fn recipient
+
+/// Show the unsubscribe page.
+///
+/// This endpoint is opened from the link at the bottom of the emails.
+/// It asks the recipient to confirm that they want to unsubscribe.
+///
+#[utoipa::path(
+    get,
+    tag = "Subscription",
+    path = "/unsubscribe",
+    params(LinkQuery),
+    responses(
+        (status = StatusCode::OK, description = "The page with the unsubscribe button", content_type = "text/html"),
+        (status = StatusCode::BAD_REQUEST, description = "The link is invalid", body = DefaultResponseJson, example = json!("{\"message\": \"The unsubscribe link is invalid\", \"redirect\": null}")),
+    )
+)]
+pub async fn unsubscribe_page(
+    Query(query): Query<LinkQuery>,
+) -> Result<Html<String>, DefaultResponse> {
+    recipient(&query.token)?;
+
+    // NOTE: The token is safe to put into the page as is,
+    // a valid token consists only of base64url characters and dots.
+    Ok(Html(format!(
+        "<!DOCTYPE html>\
+        <html>\
+        <head><meta charset=\"utf-8\"><title>Unsubscribe</title></head>\
+        <body>\
+        <p>Do you want to stop receiving emails from Manuspect?</p>\
+        <form method=\"post\" action=\"?token={}\">\
+        <input type=\"hidden\" name=\"List-Unsubscribe\" value=\"One-Click\">\
+        <button type=\"submit\">Unsubscribe</button>\
+        </form>\
+        </body>\
+        </html>",
+        query.token
+    )))
+} // end fn unsubscribe_page
+
+/// Unsubscribe from the emails.
+///
+/// This endpoint is called by mail clients (RFC 8058 one-click
+/// unsubscribe) and by the unsubscribe page. The consent of the
+/// recipient to marketing is withdrawn as well.
+///
+#[utoipa::path(
+    post,
+    tag = "Subscription",
+    path = "/unsubscribe",
+    params(LinkQuery),
+    request_body(content = String, description = "List-Unsubscribe=One-Click", content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = StatusCode::OK, description = "The recipient has been unsubscribed", content_type = "text/html"),
+        (status = StatusCode::BAD_REQUEST, description = "The link is invalid", body = DefaultResponseJson, example = json!("{\"message\": \"The unsubscribe link is invalid\", \"redirect\": null}")),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn unsubscribe(
+    State(app_state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<LinkQuery>,
+) -> Result<Html<&'static str>, DefaultResponse> {
+    let email = recipient(&query.token)?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    record_unsubscribe(&mut conn, &email)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let context = ConsentContext::collect(&headers, peer.map(|ConnectInfo(peer)| peer));
+    withdraw_marketing(&mut conn, &email, FROM_UNSUBSCRIBE, &context)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    Ok(Html(
+        "<!DOCTYPE html>\
+        <html>\
+        <head><meta charset=\"utf-8\"><title>Unsubscribe</title></head>\
+        <body><p>You have been unsubscribed and will not receive marketing emails from Manuspect anymore.</p></body>\
+        </html>",
+    ))
+} // end fn unsubscribe
@@ -0,0 +1,204 @@
+// This file contains the endpoints that serve user records.
+// NOTE: These endpoints are protected by auth_guard, and every
+// record is additionally checked against an ownership policy.
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+
+use crate::{
+    middleware::auth_guard::AuthenticatedUser,
+    models::User,
+    schema::{roles, users, users_roles},
+    utils::{
+        data_requests::{self, Erasure},
+        policies::{authorize, authorize_owner, SELF_OR_ADMIN, SELF_OR_STAFF},
+        responses::{DefaultResponse, UserResponseJson},
+    },
+};
+
+use super::AppState;
+
+/// This function returns a response about a user that does not exist.
+fn user_not_found() -> DefaultResponse {
+    DefaultResponse {
+        status_code: StatusCode::NOT_FOUND,
+        message: Some("The user does not exist".to_string()),
+        redirect: None,
+    }
+} // end fn user_not_found
+
+/// Get a user record.
+///
+/// A user can get their own record only, while Admins and Managers
+/// can get a record of any user.
+///
+#[utoipa::path(
+    get,
+    tag = "Users",
+    path = "/users/{id}",
+    params(("id" = i32, Path, description = "The id of the user")),
+    responses(
+        (status = StatusCode::OK, description = "The user record", body = UserResponseJson),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not logged in or cannot access the record", body = DefaultResponseJson, example = json!("{\"message\": \"You do not have permissions to access this page\", \"redirect\": null}")),
+        (status = StatusCode::NOT_FOUND, description = "The user does not exist", body = DefaultResponseJson, example = json!("{\"message\": \"The user does not exist\", \"redirect\": null}")),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson, example = json!("{\"message\": \"Something went wrong on the server side\", \"redirect\": null}")),
+    )
+)]
+pub async fn get_user(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Path(user_id): Path<i32>,
+) -> Result<Json<UserResponseJson>, DefaultResponse> {
+    // Try to allocate a connection to the database from the pool.
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    // Load the requested user.
+    let user = users::table
+        .filter(users::id.eq(user_id))
+        .first::<User>(&mut conn)
+        .await
+        .optional()
+        .map_err(DefaultResponse::server_error)?;
+
+    let user = match user {
+        // Check that the client can access the record.
+        Some(user) => {
+            authorize(&SELF_OR_STAFF, &client, &user)?;
+            user
+        } // end Some
+        // The clients that could not access the record anyway
+        // are not told that it does not exist.
+        None => {
+            authorize_owner(&SELF_OR_STAFF, &client, user_id)?;
+            return Err(user_not_found());
+        } // end None
+    }; // end match
+
+    // Load all the active roles of the user.
+    let roles = users_roles::table
+        .inner_join(roles::table)
+        .filter(users_roles::user_id.eq(user.id))
+        .filter(crate::utils::roles::active())
+        .select(roles::title)
+        .load::<String>(&mut conn)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    Ok(Json(UserResponseJson {
+        id: user.id,
+        name: user.name,
+        email: user.email,
+        phone_number_code: user.phone_number_code,
+        phone_number: user.phone_number,
+        verified: user.verified,
+        confirmed_at: user.confirmed_at,
+        custom_fields: user.custom_fields,
+        roles,
+    })) // end Ok
+} // end fn get_user
+
+/// Export all the data of a user.
+///
+/// The users can export their own data, Admins can export the data
+/// of any user on their behalf. The archive contains the record of the
+/// user, the roles, the consents, the attribution, the submissions,
+/// the tags, the waitlist place, the links to the CRM and the audit
+/// log. Every export is logged in the data requests.
+///
+#[utoipa::path(
+    get,
+    tag = "Users",
+    path = "/users/{id}/export",
+    params(("id" = i32, Path, description = "The id of the user")),
+    responses(
+        (status = StatusCode::OK, description = "The JSON archive of the data", content_type = "application/json"),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not logged in or cannot export the data", body = DefaultResponseJson),
+        (status = StatusCode::NOT_FOUND, description = "The user does not exist", body = DefaultResponseJson, example = json!("{\"message\": \"The user does not exist\", \"redirect\": null}")),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn export_user(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Path(user_id): Path<i32>,
+) -> Result<impl IntoResponse, DefaultResponse> {
+    authorize_owner(&SELF_OR_ADMIN, &client, user_id)?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let archive = data_requests::handle_export(&mut conn, user_id, client.user.id)
+        .await
+        .map_err(DefaultResponse::server_error)?
+        .ok_or_else(user_not_found)?;
+
+    // The archive is downloaded as a file by the browsers.
+    let disposition = format!("attachment; filename=\"user-{}.json\"", user_id);
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(archive)))
+} // end fn export_user
+
+/// Erase a user.
+///
+/// The users can erase themselves, Admins can erase any user on their
+/// behalf. The user is removed together with the roles, the session,
+/// the consents, the attribution and the rest of their data, while the
+/// audit log and the queued webhook events are anonymized. Admins have
+/// to lose the role before they are erased. Every erasure is logged in
+/// the data requests.
+///
+#[utoipa::path(
+    delete,
+    tag = "Users",
+    path = "/users/{id}",
+    params(("id" = i32, Path, description = "The id of the user")),
+    responses(
+        (status = StatusCode::OK, description = "The user has been erased", body = DefaultResponseJson, example = json!("{\"message\": \"The user has been erased\", \"redirect\": null}")),
+        (status = StatusCode::UNAUTHORIZED, description = "The client is not logged in or cannot erase the user", body = DefaultResponseJson),
+        (status = StatusCode::NOT_FOUND, description = "The user does not exist", body = DefaultResponseJson),
+        (status = StatusCode::CONFLICT, description = "The user is an Admin", body = DefaultResponseJson, example = json!("{\"message\": \"The Admin role has to be revoked before the user is erased\", \"redirect\": null}")),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn erase_user(
+    State(app_state): State<AppState>,
+    Extension(client): Extension<AuthenticatedUser>,
+    Path(user_id): Path<i32>,
+) -> Result<DefaultResponse, DefaultResponse> {
+    authorize_owner(&SELF_OR_ADMIN, &client, user_id)?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    let erasure = data_requests::handle_erasure(&mut conn, user_id, client.user.id)
+        .await
+        .map_err(DefaultResponse::server_error)?;
+    match erasure {
+        Erasure::Erased => Ok(DefaultResponse {
+            status_code: StatusCode::OK,
+            message: Some("The user has been erased".to_string()),
+            redirect: None,
+        }),
+        Erasure::NotFound => Err(user_not_found()),
+        Erasure::Admin => Err(DefaultResponse {
+            status_code: StatusCode::CONFLICT,
+            message: Some(data_requests::ADMIN_ERROR.to_string()),
+            redirect: None,
+        }),
+    } // end match
+} // end fn erase_user
@@ -0,0 +1,143 @@
+// This file contains the endpoint that shows the place of a lead
+// in the waitlist and the emails about the changes of the place.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use std::env;
+
+use crate::{
+    models::NewFormNotification,
+    schema::users,
+    utils::{
+        jwt::decode_link_token,
+        lead_forms::queue_email,
+        links::{waitlist_link, WAITLIST_STATUS},
+        responses::DefaultResponse,
+        waitlist::{place, status, ReferralRules, WaitlistStatus},
+    },
+};
+
+use super::{subscription::LinkQuery, AppState};
+
+/// Show the place in the waitlist.
+///
+/// This endpoint is opened from the link in the emails of the lead.
+/// It returns the place of the lead in the waitlist, their referral
+/// code and the number of leads that have signed up with it.
+///
+#[utoipa::path(
+    get,
+    tag = "Waitlist",
+    path = "/waitlist/status",
+    params(LinkQuery),
+    responses(
+        (status = StatusCode::OK, description = "The place of the lead in the waitlist", body = WaitlistStatus),
+        (status = StatusCode::BAD_REQUEST, description = "The link is invalid", body = DefaultResponseJson, example = json!("{\"message\": \"The waitlist link is invalid\", \"redirect\": null}")),
+        (status = StatusCode::NOT_FOUND, description = "The lead is not in the waitlist", body = DefaultResponseJson),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "There was an internal error on the server side", body = DefaultResponseJson),
+    )
+)]
+pub async fn waitlist_status(
+    State(app_state): State<AppState>,
+    Query(query): Query<LinkQuery>,
+) -> Result<Json<WaitlistStatus>, DefaultResponse> {
+    // Check the signature and find out whose place it is.
+    let user_id = decode_link_token(&query.token, WAITLIST_STATUS)
+        .and_then(|subject| subject.parse::<i32>().ok())
+        .ok_or_else(|| DefaultResponse {
+            status_code: StatusCode::BAD_REQUEST,
+            message: Some("The waitlist link is invalid".to_string()),
+            redirect: None,
+        })?;
+
+    let mut conn = app_state
+        .pool
+        .get()
+        .await
+        .map_err(DefaultResponse::server_error)?;
+
+    // The lead could have been removed, e.g. if they have
+    // not confirmed the subscription in time.
+    place(&mut conn, user_id, &ReferralRules::from_env())
+        .await
+        .map_err(DefaultResponse::server_error)?
+        .map(Json)
+        .ok_or_else(|| DefaultResponse {
+            status_code: StatusCode::NOT_FOUND,
+            message: Some("The lead is not in the waitlist".to_string()),
+            redirect: None,
+        }) // end ok_or_else
+} // end fn waitlist_status
+
+/// This function tells the lead about their new place in the waitlist,
+/// unless WAITLIST_POSITION_EMAILS is "false".
+///
+/// NOTE: The failures are only logged, the place changes anyway.
+pub async fn notify_position_change(app_state: &AppState, user_id: i32) {
+    if env::var("WAITLIST_POSITION_EMAILS").is_ok_and(|value| value == "false") {
+        return;
+    } // end if
+
+    let mut conn = match app_state.pool.get().await {
+        Ok(conn) => conn,
+        Err(error) => {
+            eprintln!("{}", error);
+            return;
+        } // end Err
+    }; // end match
+
+    // The leads without an email cannot be told anything.
+    let recipient = users::table
+        .filter(users::id.eq(user_id))
+        .select((users::name, users::email))
+        .first::<(String, Option<String>)>(&mut conn)
+        .await;
+    let (name, email) = match recipient {
+        Ok((name, Some(email))) => (name, email),
+        Ok((_name, None)) => return,
+        Err(error) => {
+            eprintln!("{}", error);
+            return;
+        } // end Err
+    }; // end match
+
+    let place = match status(&mut conn, user_id).await {
+        Ok(Some(place)) => place,
+        Ok(None) => return,
+        Err(error) => {
+            eprintln!("{}", error);
+            return;
+        } // end Err
+    }; // end match
+    let link = match waitlist_link(user_id) {
+        Some(link) => link,
+        None => {
+            eprintln!("Failed to create a waitlist link");
+            return;
+        } // end None
+    }; // end match
+
+    // The email is sent in the background, so that a slow SMTP server
+    // does not hold up the request that has moved the lead up.
+    let email = NewFormNotification {
+        user_id,
+        recipient: email,
+        full_name: Some(name.clone()),
+        sender: None,
+        transactional: false,
+        subject: "You have moved up the waitlist".to_string(),
+        message: format!(
+            "Hello, {}! Thanks to your referrals you are now number {} of {} \
+            in the waitlist.\n\nInvite more friends with {} or check your place: {}",
+            name, place.position, place.total, place.referral_link, link,
+        ),
+    };
+    if let Err(error) = queue_email(&mut conn, &app_state.notifications, &email).await {
+        eprintln!("{}", error);
+    } // end if
+} // end fn notify_position_change
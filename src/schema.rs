@@ -1,5 +1,186 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_log (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        action -> Varchar,
+        details -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    consents (id) {
+        id -> Int4,
+        user_id -> Int4,
+        purpose -> Varchar,
+        granted -> Bool,
+        pending -> Bool,
+        policy_version -> Varchar,
+        source -> Varchar,
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    crm_contacts (connector, user_id) {
+        connector -> Varchar,
+        user_id -> Int4,
+        external_id -> Nullable<Varchar>,
+        status -> Varchar,
+        last_error -> Nullable<Text>,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        synced_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    crm_sync_cursors (connector) {
+        connector -> Varchar,
+        last_user_id -> Int4,
+        reconciled_user_id -> Int4,
+        leased_until -> Nullable<Timestamptz>,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    data_requests (id) {
+        id -> Int4,
+        subject_id -> Int4,
+        kind -> Varchar,
+        requested_by -> Int4,
+        status -> Varchar,
+        error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        completed_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    form_notifications (id) {
+        id -> Int4,
+        user_id -> Int4,
+        recipient -> Varchar,
+        full_name -> Nullable<Varchar>,
+        sender -> Nullable<Varchar>,
+        transactional -> Bool,
+        subject -> Text,
+        message -> Text,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    forms (id) {
+        id -> Int4,
+        slug -> Varchar,
+        title -> Varchar,
+        sender -> Varchar,
+        welcome_subject -> Varchar,
+        welcome_template -> Text,
+        success_redirect -> Nullable<Text>,
+        duplicate_redirect -> Nullable<Text>,
+        confirmed_redirect -> Nullable<Text>,
+        required_fields -> Array<Text>,
+        notification_recipients -> Array<Text>,
+        created_at -> Timestamptz,
+        captcha_required -> Bool,
+    }
+}
+
+diesel::table! {
+    funnel_daily (day, stage, form, campaign, country) {
+        day -> Date,
+        stage -> Varchar,
+        form -> Varchar,
+        campaign -> Varchar,
+        country -> Varchar,
+        count -> Int4,
+    }
+}
+
+diesel::table! {
+    funnel_events (user_id, stage) {
+        user_id -> Int4,
+        stage -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    lead_attribution (id) {
+        id -> Int4,
+        user_id -> Int4,
+        utm_source -> Nullable<Varchar>,
+        utm_medium -> Nullable<Varchar>,
+        utm_campaign -> Nullable<Varchar>,
+        utm_term -> Nullable<Varchar>,
+        utm_content -> Nullable<Varchar>,
+        referrer -> Nullable<Text>,
+        landing_url -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    lead_score_history (id) {
+        id -> Int4,
+        user_id -> Int4,
+        score -> Int4,
+        previous_score -> Nullable<Int4>,
+        reason -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    lead_scores (user_id) {
+        user_id -> Int4,
+        score -> Int4,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    lead_submissions (id) {
+        id -> Int4,
+        user_id -> Int4,
+        form_id -> Nullable<Int4>,
+        created_lead -> Bool,
+        source -> Varchar,
+        name -> Varchar,
+        email -> Nullable<Varchar>,
+        phone_number -> Varchar,
+        custom_fields -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    lead_tags (user_id, tag) {
+        user_id -> Int4,
+        tag -> Varchar,
+    }
+}
+
+diesel::table! {
+    rate_limits (key_hash) {
+        key_hash -> Varchar,
+        hits -> Int4,
+        expires_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     roles (id) {
         id -> Int4,
@@ -8,6 +189,33 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    scoring_rules (id) {
+        id -> Int4,
+        name -> Varchar,
+        field -> Varchar,
+        operator -> Varchar,
+        value -> Nullable<Jsonb>,
+        points -> Int4,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    spent_form_tokens (token_hash) {
+        token_hash -> Varchar,
+        expires_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    unsubscribes (email) {
+        email -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Int4,
@@ -18,6 +226,10 @@ diesel::table! {
         password -> Nullable<Varchar>,
         token -> Nullable<Varchar>,
         verified -> Bool,
+        created_at -> Timestamptz,
+        confirmed_at -> Nullable<Timestamptz>,
+        custom_fields -> Jsonb,
+        form_id -> Nullable<Int4>,
     }
 }
 
@@ -26,14 +238,88 @@ diesel::table! {
         id -> Int4,
         user_id -> Int4,
         role_id -> Int4,
+        granted_by -> Nullable<Int4>,
+        expires_at -> Nullable<Timestamptz>,
+        reason -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    waitlist (user_id) {
+        user_id -> Int4,
+        ticket -> Int4,
+        boost -> Int4,
+        referral_code -> Varchar,
+        referred_by -> Nullable<Int4>,
+        referral_rewarded -> Bool,
+        joined_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        webhook_id -> Int4,
+        event -> Varchar,
+        payload -> Jsonb,
+        status -> Varchar,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        response_status -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        delivered_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Int4,
+        url -> Text,
+        secret -> Varchar,
+        events -> Array<Text>,
+        created_at -> Timestamptz,
     }
 }
 
+diesel::joinable!(consents -> users (user_id));
+diesel::joinable!(crm_contacts -> users (user_id));
+diesel::joinable!(form_notifications -> users (user_id));
+diesel::joinable!(funnel_events -> users (user_id));
+diesel::joinable!(lead_attribution -> users (user_id));
+diesel::joinable!(lead_score_history -> users (user_id));
+diesel::joinable!(lead_scores -> users (user_id));
+diesel::joinable!(lead_submissions -> forms (form_id));
+diesel::joinable!(lead_submissions -> users (user_id));
+diesel::joinable!(lead_tags -> users (user_id));
+diesel::joinable!(users -> forms (form_id));
 diesel::joinable!(users_roles -> roles (role_id));
 diesel::joinable!(users_roles -> users (user_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    audit_log,
+    consents,
+    crm_contacts,
+    crm_sync_cursors,
+    data_requests,
+    form_notifications,
+    forms,
+    funnel_daily,
+    funnel_events,
+    lead_attribution,
+    lead_score_history,
+    lead_scores,
+    lead_submissions,
+    lead_tags,
+    rate_limits,
     roles,
+    scoring_rules,
+    spent_form_tokens,
+    unsubscribes,
     users,
     users_roles,
+    waitlist,
+    webhook_deliveries,
+    webhooks,
 );
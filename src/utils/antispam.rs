@@ -0,0 +1,331 @@
+// This file contains the spam protection of the public forms.
+//
+// The protection consists of several layers:
+// 1. A honeypot, i.e. a hidden field that people never fill in.
+// 2. A time trap, i.e. a signed token with the moment the form was
+//    rendered, the forms submitted too fast are filled in by bots.
+// 3. An optional proof-of-work, i.e. the client has to find a nonce,
+//    so that SHA-256 of the token and the nonce starts with the
+//    required number of zero bits.
+//
+// Every token can be used once, the hashes of the used tokens are
+// kept in "spent_form_tokens" until the tokens expire.
+//
+// NOTE: The rejected submissions are answered exactly like the
+// accepted ones, so that the bots cannot find out what gave them away.
+// The rejections are counted in SPAM_REJECTIONS_TOTAL.
+
+use std::env;
+
+use chrono::{Duration, Utc};
+use diesel::{ExpressionMethods, QueryResult};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::schema::spent_form_tokens;
+
+use super::jwt::{create_link_token, decode_link_token};
+use super::lazy_static::SPAM_REJECTIONS_TOTAL;
+
+/// This is the name of the hidden field that people never fill in.
+pub const HONEYPOT_FIELD: &str = "contact_me_by_fax";
+
+/// This is the name of the field with the token of the rendered form.
+pub const TOKEN_FIELD: &str = "form_token";
+
+/// This is the name of the field with the nonce of the proof-of-work.
+pub const NONCE_FIELD: &str = "pow_nonce";
+
+// This is the purpose of the tokens of the rendered forms.
+const FORM_RENDER: &str = "form_render";
+
+/// This enum contains the reasons a submission is considered spam.
+#[derive(Debug, PartialEq)]
+pub enum SpamReason {
+    Honeypot,
+    MissingToken,
+    InvalidToken,
+    TooFast,
+    ProofOfWork,
+    Replayed,
+} // end enum SpamReason
+
+impl SpamReason {
+    /// This function returns the label of the reason in the metrics.
+    pub fn label(&self) -> &'static str {
+        match self {
+            SpamReason::Honeypot => "honeypot",
+            SpamReason::MissingToken => "missing_token",
+            SpamReason::InvalidToken => "invalid_token",
+            SpamReason::TooFast => "too_fast",
+            SpamReason::ProofOfWork => "proof_of_work",
+            SpamReason::Replayed => "replayed",
+        } // end match
+    } // end fn label
+} // end impl SpamReason
+
+/// This struct contains the settings of the spam protection.
+#[derive(Debug, Clone)]
+pub struct AntiSpamConfig {
+    // The submissions without a token are rejected.
+    // NOTE: The token is always required with the proof-of-work.
+    pub require_token: bool,
+    // The minimum time it takes a person to fill in a form.
+    pub min_fill_time: Duration,
+    // The time a rendered form can be submitted within.
+    pub max_token_age: Duration,
+    // The number of leading zero bits of the proof-of-work,
+    // 0 disables the proof-of-work.
+    pub pow_difficulty: u32,
+} // end struct AntiSpamConfig
+
+impl AntiSpamConfig {
+    /// This function reads the settings from the environment variables
+    /// ANTISPAM_REQUIRE_TOKEN, ANTISPAM_MIN_FILL_SECONDS,
+    /// ANTISPAM_MAX_TOKEN_AGE_SECONDS and ANTISPAM_POW_DIFFICULTY.
+    pub fn from_env() -> Self {
+        // This is a helper function that reads a number.
+        fn number(name: &str, default: i64) -> i64 {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        } // end fn number
+
+        AntiSpamConfig {
+            require_token: env::var("ANTISPAM_REQUIRE_TOKEN").is_ok_and(|value| value == "true"),
+            min_fill_time: Duration::seconds(number("ANTISPAM_MIN_FILL_SECONDS", 3)),
+            max_token_age: Duration::seconds(number("ANTISPAM_MAX_TOKEN_AGE_SECONDS", 86400)),
+            pow_difficulty: number("ANTISPAM_POW_DIFFICULTY", 0).clamp(0, 32) as u32,
+        } // end AntiSpamConfig
+    } // end fn from_env
+} // end impl AntiSpamConfig
+
+/// This struct represents a challenge that is issued when a form
+/// is rendered and is sent back with the submission.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct Challenge {
+    // The value of the "form_token" field.
+    #[schema(example = "eyJ0eXAiOiJKV1QiLCJhbGciOiJIUzI1NiJ9...")]
+    pub token: String,
+    // The required number of leading zero bits of SHA-256(token + nonce),
+    // the nonce is sent in the "pow_nonce" field. 0 means that
+    // there is no need to send a nonce.
+    #[schema(example = 16)]
+    pub difficulty: u32,
+} // end struct Challenge
+
+/// This function issues a challenge for a form that is being rendered.
+pub fn issue_challenge(form: &str, config: &AntiSpamConfig) -> Option<Challenge> {
+    // The token remembers the form, the moment and the difficulty.
+    let subject = format!(
+        "{}:{}:{}",
+        form,
+        Utc::now().timestamp_millis(),
+        config.pow_difficulty
+    );
+
+    Some(Challenge {
+        token: create_link_token(FORM_RENDER, &subject, Some(config.max_token_age))?,
+        difficulty: config.pow_difficulty,
+    }) // end Some
+} // end fn issue_challenge
+
+/// This function counts the leading zero bits of SHA-256(token + nonce).
+fn leading_zero_bits(token: &str, nonce: &str) -> u32 {
+    let digest = Sha256::new()
+        .chain_update(token.as_bytes())
+        .chain_update(nonce.as_bytes())
+        .finalize();
+
+    let mut bits = 0;
+    for byte in digest {
+        bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        } // end if
+    } // end for
+
+    bits
+} // end fn leading_zero_bits
+
+/// This function checks a submission of the form.
+/// It returns the reason if the submission is spam, otherwise
+/// it returns the token that has to be spent (see spend_token).
+pub fn check_submission<'a>(
+    form: &str,
+    fields: &'a [(String, String)],
+    config: &AntiSpamConfig,
+) -> Result<Option<&'a str>, SpamReason> {
+    // This is a helper function that finds a non-empty field.
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(field, value)| field == name && !value.is_empty())
+            .map(|(_field, value)| value.as_str())
+    };
+
+    // People do not see the honeypot.
+    if field(HONEYPOT_FIELD).is_some() {
+        return Err(SpamReason::Honeypot);
+    } // end if
+
+    // The proof-of-work cannot be skipped by leaving the token out.
+    let token = match field(TOKEN_FIELD) {
+        Some(token) => token,
+        None if config.require_token || config.pow_difficulty > 0 => {
+            return Err(SpamReason::MissingToken)
+        } // end None
+        None => return Ok(None),
+    }; // end match
+
+    // The token has to be issued for this form and must not be expired.
+    let subject = decode_link_token(token, FORM_RENDER).ok_or(SpamReason::InvalidToken)?;
+    let mut parts = subject.rsplitn(3, ':');
+    let difficulty = parts.next().and_then(|part| part.parse::<u32>().ok());
+    let rendered_at = parts.next().and_then(|part| part.parse::<i64>().ok());
+    let (difficulty, rendered_at) = match (difficulty, rendered_at, parts.next()) {
+        (Some(difficulty), Some(rendered_at), Some(token_form)) if token_form == form => {
+            (difficulty, rendered_at)
+        } // end Some
+        _ => return Err(SpamReason::InvalidToken),
+    }; // end match
+
+    // People need some time to fill in a form.
+    if Utc::now().timestamp_millis() - rendered_at < config.min_fill_time.num_milliseconds() {
+        return Err(SpamReason::TooFast);
+    } // end if
+
+    // NOTE: The difficulty in the token is used, so that the forms
+    // rendered before the difficulty has changed keep working.
+    if difficulty > 0 {
+        let nonce = field(NONCE_FIELD).ok_or(SpamReason::ProofOfWork)?;
+        if leading_zero_bits(token, nonce) < difficulty {
+            return Err(SpamReason::ProofOfWork);
+        } // end if
+    } // end if
+
+    Ok(Some(token))
+} // end fn check_submission
+
+/// This function marks a token as used.
+/// It returns false if the token has already been used.
+///
+/// NOTE: The token is remembered for the longest time it can be valid,
+/// the tokens issued with a longer lifetime before it was shortened
+/// are remembered for the new lifetime only.
+pub async fn spend_token(
+    conn: &mut AsyncPgConnection,
+    token: &str,
+    config: &AntiSpamConfig,
+) -> QueryResult<bool> {
+    diesel::insert_into(spent_form_tokens::table)
+        .values((
+            spent_form_tokens::token_hash.eq(hex::encode(Sha256::digest(token.as_bytes()))),
+            spent_form_tokens::expires_at.eq(Utc::now() + config.max_token_age),
+        ))
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await
+        .map(|inserted| inserted > 0)
+} // end fn spend_token
+
+/// This function forgets the used tokens that have expired anyway.
+/// It returns the number of the forgotten tokens.
+pub async fn forget_expired_tokens(conn: &mut AsyncPgConnection) -> QueryResult<usize> {
+    diesel::delete(spent_form_tokens::table)
+        .filter(spent_form_tokens::expires_at.le(Utc::now()))
+        .execute(conn)
+        .await
+} // end fn forget_expired_tokens
+
+/// This function records a rejected submission in the metrics.
+pub fn record_rejection(form: &str, reason: &SpamReason) {
+    eprintln!("A spam submission of the form \"{}\": {:?}", form, reason);
+    SPAM_REJECTIONS_TOTAL
+        .with_label_values(&[reason.label()])
+        .inc();
+} // end fn record_rejection
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spam_is_detected() {
+        if env::var("JWT_SECRET").is_err() {
+            env::set_var("JWT_SECRET", "test-secret");
+        } // end if
+
+        let config = AntiSpamConfig {
+            require_token: true,
+            min_fill_time: Duration::zero(),
+            max_token_age: Duration::hours(1),
+            pow_difficulty: 8,
+        };
+        let fields = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+
+        let token = issue_challenge("default", &config).unwrap().token;
+        let nonce = (0..)
+            .map(|nonce: u64| nonce.to_string())
+            .find(|nonce| leading_zero_bits(&token, nonce) >= 8)
+            .unwrap();
+
+        let valid = fields(&[(TOKEN_FIELD, &token), (NONCE_FIELD, &nonce)]);
+        assert_eq!(
+            check_submission("default", &valid, &config),
+            Ok(Some(token.as_str()))
+        );
+
+        let mut with_honeypot = valid.clone();
+        with_honeypot.push((HONEYPOT_FIELD.to_string(), "555".to_string()));
+        assert_eq!(
+            check_submission("default", &with_honeypot, &config),
+            Err(SpamReason::Honeypot)
+        );
+
+        assert_eq!(
+            check_submission("other", &valid, &config),
+            Err(SpamReason::InvalidToken)
+        );
+        assert_eq!(
+            check_submission("default", &[], &config),
+            Err(SpamReason::MissingToken)
+        );
+        assert_eq!(
+            check_submission("default", &fields(&[(TOKEN_FIELD, &token)]), &config),
+            Err(SpamReason::ProofOfWork)
+        );
+
+        // The token cannot be left out if the proof-of-work is enabled.
+        let optional = AntiSpamConfig {
+            require_token: false,
+            ..config.clone()
+        };
+        assert_eq!(
+            check_submission("default", &[], &optional),
+            Err(SpamReason::MissingToken)
+        );
+        let disabled = AntiSpamConfig {
+            pow_difficulty: 0,
+            ..optional
+        };
+        assert_eq!(check_submission("default", &[], &disabled), Ok(None));
+
+        let slow = AntiSpamConfig {
+            min_fill_time: Duration::hours(1),
+            ..config
+        };
+        assert_eq!(
+            check_submission("default", &valid, &slow),
+            Err(SpamReason::TooFast)
+        );
+    }
+}
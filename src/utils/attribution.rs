@@ -0,0 +1,337 @@
+// This file contains the tools for recording and reporting
+// the marketing attribution of the leads.
+//
+// NOTE: The landing page forwards its own query string (the UTM
+// parameters) to "/insert", together with the referrer and its
+// own URL, because the browser does not send them to another origin.
+
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Utc};
+use diesel::dsl::{count, count_star, sql};
+use diesel::sql_types::{BigInt, Nullable, Timestamptz};
+use diesel::{ExpressionMethods, QueryDsl, QueryResult};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    models::NewLeadAttribution,
+    schema::{lead_attribution, users},
+};
+
+// The maximum length of the UTM parameters.
+const MAX_PARAMETER_LENGTH: usize = 255;
+
+// The maximum length of the URLs and the user agent.
+const MAX_TEXT_LENGTH: usize = 2048;
+
+/// This struct represents the attribution parameters that are
+/// carried through from the landing page.
+#[derive(Deserialize, IntoParams, Default, Debug)]
+pub struct AttributionParams {
+    pub utm_source: Option<String>,
+    pub utm_medium: Option<String>,
+    pub utm_campaign: Option<String>,
+    pub utm_term: Option<String>,
+    pub utm_content: Option<String>,
+    /// The page that brought the client to the landing page.
+    pub referrer: Option<String>,
+    /// The URL of the landing page, it is taken from the Referer
+    /// header if it is absent.
+    pub landing_url: Option<String>,
+} // end struct AttributionParams
+
+/// This function drops the empty values and cuts the long ones.
+fn clip(value: Option<String>, max_length: usize) -> Option<String> {
+    let value = value?.trim().to_string();
+    if value.is_empty() {
+        return None;
+    } // end if
+
+    Some(value.chars().take(max_length).collect())
+} // end fn clip
+
+/// This function returns the value of a header if it is a valid string.
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+} // end fn header_value
+
+/// This function returns the number of the reverse proxies in front of
+/// the application, which is set in the TRUSTED_PROXIES environment
+/// variable (0 by default).
+fn trusted_proxies() -> usize {
+    env::var("TRUSTED_PROXIES")
+        .ok()
+        .and_then(|proxies| proxies.trim().parse().ok())
+        .unwrap_or(0)
+} // end fn trusted_proxies
+
+/// This function finds out the IP address of the client.
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<String> {
+    forwarded_ip(headers, peer, trusted_proxies()).map(|ip| ip.to_string())
+} // end fn client_ip
+
+/// This function finds out the IP address of the client behind
+/// the number of the trusted reverse proxies.
+///
+/// NOTE: Every proxy appends the address it has been connected from
+/// to X-Forwarded-For, while the client can put anything in front of
+/// it. So only the addresses appended by the trusted proxies are
+/// taken, i.e. the one that is as far from the right as there are
+/// proxies. Without proxies the address of the connection is used.
+fn forwarded_ip(headers: &HeaderMap, peer: Option<SocketAddr>, proxies: usize) -> Option<IpAddr> {
+    let peer = peer.map(|peer| peer.ip());
+    if proxies == 0 {
+        return peer;
+    } // end if
+
+    // The headers can be repeated, they are joined in order.
+    let hops: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim())
+        .filter(|hop| !hop.is_empty())
+        .collect();
+
+    let forwarded = match hops.len() {
+        // The nearest proxy may report the client in X-Real-IP instead.
+        0 => headers
+            .get("x-real-ip")
+            .and_then(|value| value.to_str().ok()),
+        // If the request has passed fewer proxies than expected,
+        // then all the addresses have been appended by them.
+        length => hops.get(length.saturating_sub(proxies)).copied(),
+    }; // end match
+
+    forwarded
+        .and_then(|hop| hop.parse::<IpAddr>().ok())
+        .or(peer)
+} // end fn forwarded_ip
+
+/// This function collects the attribution of a lead from
+/// the parameters and the headers of the request.
+pub fn collect(
+    user_id: i32,
+    params: AttributionParams,
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+) -> NewLeadAttribution {
+    NewLeadAttribution {
+        user_id,
+        utm_source: clip(params.utm_source, MAX_PARAMETER_LENGTH),
+        utm_medium: clip(params.utm_medium, MAX_PARAMETER_LENGTH),
+        utm_campaign: clip(params.utm_campaign, MAX_PARAMETER_LENGTH),
+        utm_term: clip(params.utm_term, MAX_PARAMETER_LENGTH),
+        utm_content: clip(params.utm_content, MAX_PARAMETER_LENGTH),
+        referrer: clip(params.referrer, MAX_TEXT_LENGTH),
+        landing_url: clip(
+            params
+                .landing_url
+                .or_else(|| header_value(headers, header::REFERER)),
+            MAX_TEXT_LENGTH,
+        ),
+        user_agent: clip(header_value(headers, header::USER_AGENT), MAX_TEXT_LENGTH),
+        ip: client_ip(headers, peer),
+    } // end NewLeadAttribution
+} // end fn collect
+
+/// This function saves the attribution of a lead.
+pub async fn record(
+    conn: &mut AsyncPgConnection,
+    attribution: &NewLeadAttribution,
+) -> QueryResult<()> {
+    diesel::insert_into(lead_attribution::table)
+        .values(attribution)
+        .execute(conn)
+        .await
+        .map(|_inserted| ())
+} // end fn record
+
+/// This struct represents the filters of the campaign report.
+#[derive(Deserialize, IntoParams, Default, Debug)]
+pub struct CampaignReportQuery {
+    /// Only the leads that came at or after this moment are counted.
+    pub from: Option<DateTime<Utc>>,
+    /// Only the leads that came before this moment are counted.
+    pub to: Option<DateTime<Utc>>,
+    pub utm_source: Option<String>,
+} // end struct CampaignReportQuery
+
+/// This struct represents the results of a campaign.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct CampaignReportRow {
+    #[schema(example = "newsletter")]
+    pub utm_source: Option<String>,
+    #[schema(example = "email")]
+    pub utm_medium: Option<String>,
+    #[schema(example = "spring_sale")]
+    pub utm_campaign: Option<String>,
+    // The number of the leads that came from the campaign.
+    #[schema(example = 42)]
+    pub leads: i64,
+    // The number of the leads that confirmed their subscription.
+    #[schema(example = 30)]
+    pub confirmed: i64,
+    // The number of the leads that verified their account.
+    #[schema(example = 10)]
+    pub verified: i64,
+    pub first_lead_at: Option<DateTime<Utc>>,
+    pub last_lead_at: Option<DateTime<Utc>>,
+} // end struct CampaignReportRow
+
+/// This function counts the leads per campaign.
+/// The campaigns with the most leads come first.
+pub async fn campaign_report(
+    conn: &mut AsyncPgConnection,
+    query: &CampaignReportQuery,
+) -> QueryResult<Vec<CampaignReportRow>> {
+    let mut statement = lead_attribution::table
+        .inner_join(users::table)
+        .group_by((
+            lead_attribution::utm_source,
+            lead_attribution::utm_medium,
+            lead_attribution::utm_campaign,
+        ))
+        .select((
+            lead_attribution::utm_source,
+            lead_attribution::utm_medium,
+            lead_attribution::utm_campaign,
+            count_star(),
+            count(users::confirmed_at),
+            sql::<BigInt>("COUNT(*) FILTER (WHERE users.verified)"),
+            sql::<Nullable<Timestamptz>>("MIN(lead_attribution.created_at)"),
+            sql::<Nullable<Timestamptz>>("MAX(lead_attribution.created_at)"),
+        ))
+        .order_by(count_star().desc())
+        .into_boxed();
+
+    if let Some(from) = query.from {
+        statement = statement.filter(lead_attribution::created_at.ge(from));
+    } // end if
+    if let Some(to) = query.to {
+        statement = statement.filter(lead_attribution::created_at.lt(to));
+    } // end if
+    if let Some(utm_source) = &query.utm_source {
+        statement = statement.filter(lead_attribution::utm_source.eq(utm_source));
+    } // end if
+
+    let rows = statement
+        .load::<(
+            Option<String>,
+            Option<String>,
+            Option<String>,
+            i64,
+            i64,
+            i64,
+            Option<DateTime<Utc>>,
+            Option<DateTime<Utc>>,
+        )>(conn)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(
+            |(
+                utm_source,
+                utm_medium,
+                utm_campaign,
+                leads,
+                confirmed,
+                verified,
+                first_lead_at,
+                last_lead_at,
+            )| CampaignReportRow {
+                utm_source,
+                utm_medium,
+                utm_campaign,
+                leads,
+                confirmed,
+                verified,
+                first_lead_at,
+                last_lead_at,
+            },
+        )
+        .collect())
+} // end fn campaign_report
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attribution_is_collected_from_the_request() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7, 10.0.0.1".parse().unwrap());
+        headers.insert(
+            header::REFERER,
+            "https://example.com/?utm_source=x".parse().unwrap(),
+        );
+        headers.insert(header::USER_AGENT, "Mozilla/5.0".parse().unwrap());
+        let peer = Some("10.0.0.2:5000".parse().unwrap());
+
+        let params = AttributionParams {
+            utm_source: Some(" newsletter ".to_string()),
+            utm_campaign: Some("x".repeat(300)),
+            utm_term: Some(String::new()),
+            ..Default::default()
+        };
+        let attribution = collect(1, params, &headers, peer);
+
+        assert_eq!(attribution.utm_source.as_deref(), Some("newsletter"));
+        assert_eq!(attribution.utm_campaign.map(|value| value.len()), Some(255));
+        assert_eq!(attribution.utm_term, None);
+        assert_eq!(
+            attribution.landing_url.as_deref(),
+            Some("https://example.com/?utm_source=x")
+        );
+        assert_eq!(attribution.user_agent.as_deref(), Some("Mozilla/5.0"));
+        // X-Forwarded-For is not trusted without a proxy.
+        assert_eq!(attribution.ip.as_deref(), Some("10.0.0.2"));
+    }
+
+    #[test]
+    fn client_ip_is_taken_behind_the_trusted_proxies() {
+        let peer = Some("10.0.0.2:5000".parse().unwrap());
+        let ip = |forwarded: &[&str], proxies| {
+            let mut headers = HeaderMap::new();
+            for value in forwarded {
+                headers.append("x-forwarded-for", value.parse().unwrap());
+            } // end for
+            forwarded_ip(&headers, peer, proxies).map(|ip| ip.to_string())
+        };
+
+        // The client cannot spoof its address with its own header.
+        let spoofed = ["198.51.100.1, 203.0.113.7, 10.0.0.1"];
+        assert_eq!(ip(&spoofed, 0).as_deref(), Some("10.0.0.2"));
+        assert_eq!(ip(&spoofed, 1).as_deref(), Some("10.0.0.1"));
+        assert_eq!(ip(&spoofed, 2).as_deref(), Some("203.0.113.7"));
+        assert_eq!(
+            ip(&["198.51.100.1", "203.0.113.7, 10.0.0.1"], 2).as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(ip(&["203.0.113.7"], 2).as_deref(), Some("203.0.113.7"));
+
+        // The address of the connection is used without a valid header.
+        assert_eq!(ip(&[], 1).as_deref(), Some("10.0.0.2"));
+        assert_eq!(ip(&["unknown"], 1).as_deref(), Some("10.0.0.2"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "203.0.113.7".parse().unwrap());
+        assert_eq!(
+            forwarded_ip(&headers, peer, 1).map(|ip| ip.to_string()),
+            Some("203.0.113.7".to_string())
+        );
+        assert_eq!(
+            forwarded_ip(&headers, peer, 0).map(|ip| ip.to_string()),
+            Some("10.0.0.2".to_string())
+        );
+    }
+}
@@ -0,0 +1,23 @@
+// This file contains the tools for writing to the audit log.
+
+use diesel::QueryResult;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::{models::NewAuditEntry, schema::audit_log};
+
+/// This function appends entries to the audit log.
+///
+/// NOTE: Pass a connection with an open transaction to make
+/// the entries a part of the change they describe.
+pub async fn record(conn: &mut AsyncPgConnection, entries: &[NewAuditEntry]) -> QueryResult<()> {
+    // There is nothing to insert.
+    if entries.is_empty() {
+        return Ok(());
+    } // end if
+
+    diesel::insert_into(audit_log::table)
+        .values(entries)
+        .execute(conn)
+        .await
+        .map(|_rows| ())
+} // end fn record
@@ -0,0 +1,262 @@
+// This file contains the CAPTCHA verification of the public endpoints.
+//
+// The verifier is chosen with the CAPTCHA_PROVIDER environment variable:
+// - "recaptcha", "hcaptcha" or "turnstile" verify the response of the
+//   widget with the siteverify API of the provider. The secret is set
+//   in CAPTCHA_SECRET, the address of the API can be changed with
+//   CAPTCHA_VERIFY_URL (e.g. for a self-hosted compatible service).
+// - "always-pass" and "always-fail" are meant for tests.
+// If it is not set, then there is no CAPTCHA at all.
+//
+// The landing forms require a CAPTCHA if "captcha_required" is set,
+// "/auth/register" and "/auth/login" require it unless
+// CAPTCHA_AUTH_REQUIRED is "false".
+
+use std::env;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+/// This is the name of the generic field with the response of a widget.
+/// NOTE: The field of the provider (e.g. "h-captcha-response") works too.
+pub const RESPONSE_FIELD: &str = "captcha_response";
+
+/// This trait is implemented by the services that verify CAPTCHAs.
+#[async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    /// This function returns the name of the form field
+    /// the widget puts its response into.
+    fn response_field(&self) -> &str;
+
+    /// This function checks the response of the widget.
+    /// It returns an error if the service cannot be reached.
+    async fn verify(&self, response: &str, remote_ip: Option<&str>) -> Result<bool, String>;
+} // end trait CaptchaVerifier
+
+/// This verifier calls a siteverify API, which is the same for
+/// reCAPTCHA, hCaptcha and Cloudflare Turnstile.
+pub struct SiteVerify {
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+    response_field: String,
+} // end struct SiteVerify
+
+impl SiteVerify {
+    /// This function creates a verifier for the siteverify API at the URL.
+    pub fn new(url: &str, secret: &str, response_field: &str) -> Self {
+        SiteVerify {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+            secret: secret.to_string(),
+            response_field: response_field.to_string(),
+        }
+    } // end fn new
+} // end impl SiteVerify
+
+/// This struct represents the part of the siteverify response
+/// that is needed.
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+} // end struct SiteVerifyResponse
+
+#[async_trait]
+impl CaptchaVerifier for SiteVerify {
+    fn response_field(&self) -> &str {
+        &self.response_field
+    } // end fn response_field
+
+    async fn verify(&self, response: &str, remote_ip: Option<&str>) -> Result<bool, String> {
+        let mut form = vec![("secret", self.secret.as_str()), ("response", response)];
+        if let Some(remote_ip) = remote_ip {
+            form.push(("remoteip", remote_ip));
+        } // end if
+
+        let result = self
+            .client
+            .post(&self.url)
+            .form(&form)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|error| error.to_string())?
+            .json::<SiteVerifyResponse>()
+            .await
+            .map_err(|error| error.to_string())?;
+
+        Ok(result.success)
+    } // end fn verify
+} // end impl CaptchaVerifier
+
+/// This verifier accepts every response, it is meant for tests.
+pub struct AlwaysPass;
+
+#[async_trait]
+impl CaptchaVerifier for AlwaysPass {
+    fn response_field(&self) -> &str {
+        RESPONSE_FIELD
+    } // end fn response_field
+
+    async fn verify(&self, _response: &str, _remote_ip: Option<&str>) -> Result<bool, String> {
+        Ok(true)
+    } // end fn verify
+} // end impl CaptchaVerifier
+
+/// This verifier rejects every response, it is meant for tests.
+pub struct AlwaysFail;
+
+#[async_trait]
+impl CaptchaVerifier for AlwaysFail {
+    fn response_field(&self) -> &str {
+        RESPONSE_FIELD
+    } // end fn response_field
+
+    async fn verify(&self, _response: &str, _remote_ip: Option<&str>) -> Result<bool, String> {
+        Ok(false)
+    } // end fn verify
+} // end impl CaptchaVerifier
+
+/// This function creates the verifier chosen in the CAPTCHA_PROVIDER
+/// environment variable. It returns None if there is no CAPTCHA.
+pub fn from_env() -> Result<Option<Arc<dyn CaptchaVerifier>>, String> {
+    // The default siteverify APIs and response fields of the providers.
+    let (default_url, response_field) = match env::var("CAPTCHA_PROVIDER").as_deref() {
+        Err(_) | Ok("") | Ok("none") => return Ok(None),
+        Ok("always-pass") => return Ok(Some(Arc::new(AlwaysPass))),
+        Ok("always-fail") => return Ok(Some(Arc::new(AlwaysFail))),
+        Ok("recaptcha") => (
+            "https://www.google.com/recaptcha/api/siteverify",
+            "g-recaptcha-response",
+        ),
+        Ok("hcaptcha") => ("https://api.hcaptcha.com/siteverify", "h-captcha-response"),
+        Ok("turnstile") => (
+            "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            "cf-turnstile-response",
+        ),
+        Ok(provider) => return Err(format!("Unknown CAPTCHA provider \"{}\"", provider)),
+    }; // end match
+
+    let secret = env::var("CAPTCHA_SECRET")
+        .map_err(|_error| "Failed to find the environment variable CAPTCHA_SECRET".to_string())?;
+    let url = env::var("CAPTCHA_VERIFY_URL").unwrap_or_else(|_error| default_url.to_string());
+
+    Ok(Some(Arc::new(SiteVerify::new(
+        &url,
+        &secret,
+        response_field,
+    ))))
+} // end fn from_env
+
+/// This function checks if the authentication endpoints require a CAPTCHA.
+pub fn required_on_auth() -> bool {
+    env::var("CAPTCHA_AUTH_REQUIRED").map_or(true, |value| value != "false")
+} // end fn required_on_auth
+
+/// This enum contains the reasons a CAPTCHA is not passed.
+#[derive(Debug, PartialEq)]
+pub enum CaptchaError {
+    // The response is absent or is rejected by the verifier.
+    Failed,
+    // The CAPTCHA cannot be checked.
+    Unavailable(String),
+} // end enum CaptchaError
+
+impl CaptchaError {
+    /// This is the message that is shown to the client
+    /// if the CAPTCHA has failed.
+    pub const MESSAGE: &'static str = "The CAPTCHA verification has failed, please try again";
+} // end impl CaptchaError
+
+/// This function checks the CAPTCHA of a submission if it is required.
+pub async fn check(
+    verifier: Option<&dyn CaptchaVerifier>,
+    required: bool,
+    fields: &[(String, String)],
+    remote_ip: Option<&str>,
+) -> Result<(), CaptchaError> {
+    if !required {
+        return Ok(());
+    } // end if
+
+    let verifier = verifier.ok_or_else(|| {
+        CaptchaError::Unavailable(
+            "A CAPTCHA is required, but CAPTCHA_PROVIDER is not set".to_string(),
+        )
+    })?;
+
+    let response = fields
+        .iter()
+        .find(|(name, value)| {
+            (name == verifier.response_field() || name == RESPONSE_FIELD) && !value.is_empty()
+        })
+        .map(|(_name, value)| value.as_str())
+        .ok_or(CaptchaError::Failed)?;
+
+    match verifier.verify(response, remote_ip).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(CaptchaError::Failed),
+        Err(error) => Err(CaptchaError::Unavailable(error)),
+    } // end match
+} // end fn check
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{routing::post, Form, Json, Router};
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn site_verify_calls_the_configured_endpoint() {
+        // A local stand-in for the siteverify API.
+        let app = Router::new().route(
+            "/siteverify",
+            post(|Form(form): Form<HashMap<String, String>>| async move {
+                let success = form.get("secret").map(String::as_str) == Some("secret")
+                    && form.get("response").map(String::as_str) == Some("good")
+                    && form.get("remoteip").map(String::as_str) == Some("203.0.113.7");
+                Json(serde_json::json!({ "success": success }))
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let verifier = SiteVerify::new(
+            &format!("http://{}/siteverify", address),
+            "secret",
+            "h-captcha-response",
+        );
+        let fields =
+            |response: &str| vec![("h-captcha-response".to_string(), response.to_string())];
+        let ip = Some("203.0.113.7");
+
+        assert_eq!(
+            check(Some(&verifier), true, &fields("good"), ip).await,
+            Ok(())
+        );
+        assert_eq!(
+            check(Some(&verifier), true, &fields("bad"), ip).await,
+            Err(CaptchaError::Failed)
+        );
+        assert_eq!(
+            check(Some(&verifier), true, &[], ip).await,
+            Err(CaptchaError::Failed)
+        );
+        assert_eq!(check(Some(&AlwaysFail), false, &[], ip).await, Ok(()));
+        assert!(matches!(
+            check(None, true, &fields("good"), ip).await,
+            Err(CaptchaError::Unavailable(_))
+        ));
+
+        server.abort();
+    }
+}
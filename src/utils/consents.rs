@@ -0,0 +1,572 @@
+// This file contains the consent ledger.
+//
+// The landing forms and the registration accept the flags
+// "consent_privacy_policy" and "consent_marketing": "true", "on", "yes"
+// or "1" grant the consent, "false", "off", "no" or "0" refuse it, and
+// nothing is recorded if a flag is absent. Every flag is appended to the
+// ledger with the version of the privacy policy (PRIVACY_POLICY_VERSION,
+// "1" by default), the time, the IP address and the user agent.
+//
+// The latest record of a purpose is in force. No emails are sent to the
+// users who have withdrawn the consent to the privacy policy, and the
+// marketing emails are sent only to the users who have granted the
+// consent to marketing. Unsubscribing withdraws the consent to marketing
+// as well.
+//
+// A submission for a lead that already exists cannot grant a consent on
+// its own, anyone could submit a form with the email or the phone number
+// of a lead. Such a grant is pending until the user opens the confirmation
+// link, see submit and confirm_pending.
+//
+// NOTE: The users who have never given or refused a consent (e.g. the
+// leads collected before the ledger) get only the transactional emails.
+
+use std::env;
+use std::net::SocketAddr;
+
+use axum::http::{header, HeaderMap};
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
+use diesel::sql_types::Bool;
+use diesel::{ExpressionMethods, QueryDsl, QueryResult};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::{
+    models::{Consent, NewConsent},
+    schema::{consents, users},
+};
+
+use super::attribution::client_ip;
+
+// These are the purposes of the consents.
+pub const PRIVACY_POLICY: &str = "privacy_policy";
+pub const MARKETING: &str = "marketing";
+pub const PURPOSES: [&str; 2] = [PRIVACY_POLICY, MARKETING];
+
+// These are the names of the flags in the forms.
+pub const PRIVACY_POLICY_FIELD: &str = "consent_privacy_policy";
+pub const MARKETING_FIELD: &str = "consent_marketing";
+
+// These are the ways the consents are recorded.
+pub const FROM_FORM: &str = "form";
+pub const FROM_REGISTRATION: &str = "registration";
+pub const FROM_WITHDRAWAL: &str = "withdrawal";
+pub const FROM_UNSUBSCRIBE: &str = "unsubscribe";
+pub const FROM_IMPORT: &str = "import";
+pub const FROM_CONFIRMATION: &str = "confirmation";
+
+// The maximum length of a saved user agent.
+const MAX_USER_AGENT_LENGTH: usize = 1000;
+
+/// This function returns the current version of the privacy policy,
+/// which is set in the PRIVACY_POLICY_VERSION environment variable.
+pub fn policy_version() -> String {
+    env::var("PRIVACY_POLICY_VERSION")
+        .ok()
+        .filter(|version| !version.is_empty())
+        .unwrap_or_else(|| "1".to_string())
+} // end fn policy_version
+
+/// This function reads a consent flag of a form.
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "on" | "yes" | "1" => Some(true),
+        "false" | "off" | "no" | "0" => Some(false),
+        _ => None,
+    } // end match
+} // end fn parse_flag
+
+/// This function reads the consent flags of a submission.
+/// It returns the purposes and whether they are granted.
+pub fn parse_flags(fields: &[(String, String)]) -> Result<Vec<(&'static str, bool)>, String> {
+    let mut flags = Vec::new();
+
+    for (field, purpose) in [
+        (PRIVACY_POLICY_FIELD, PRIVACY_POLICY),
+        (MARKETING_FIELD, MARKETING),
+    ] {
+        // The last value wins, like in the rest of the form.
+        let value = fields
+            .iter()
+            .rev()
+            .find(|(name, _value)| name == field)
+            .map(|(_name, value)| value);
+        if let Some(value) = value {
+            let granted = parse_flag(value)
+                .ok_or_else(|| format!("The \"{}\" field must be \"true\" or \"false\"", field))?;
+            flags.push((purpose, granted));
+        } // end if
+    } // end for
+
+    Ok(flags)
+} // end fn parse_flags
+
+/// This struct contains the details of the request a consent
+/// is given or withdrawn with.
+#[derive(Debug, Clone, Default)]
+pub struct ConsentContext {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+} // end struct ConsentContext
+
+impl ConsentContext {
+    /// This function collects the details from the request.
+    pub fn collect(headers: &HeaderMap, peer: Option<SocketAddr>) -> Self {
+        ConsentContext {
+            ip: client_ip(headers, peer),
+            user_agent: headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+        }
+    } // end fn collect
+} // end impl ConsentContext
+
+/// This function appends the consents of a user to the ledger,
+/// the pending ones are not in force.
+async fn append(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    flags: &[(&str, bool, bool)],
+    source: &str,
+    context: &ConsentContext,
+) -> QueryResult<()> {
+    // Nothing has been submitted.
+    if flags.is_empty() {
+        return Ok(());
+    } // end if
+
+    let version = policy_version();
+    diesel::insert_into(consents::table)
+        .values(
+            flags
+                .iter()
+                .map(|(purpose, granted, pending)| NewConsent {
+                    user_id,
+                    purpose: purpose.to_string(),
+                    granted: *granted,
+                    pending: *pending,
+                    policy_version: version.clone(),
+                    source: source.to_string(),
+                    ip: context.ip.clone(),
+                    user_agent: context.user_agent.clone(),
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)
+        .await
+        .map(|_inserted| ())
+} // end fn append
+
+/// This function appends the consents of a user to the ledger.
+/// They are in force at once, so the grants must come from the user
+/// themselves (or be vouched for by an Admin).
+pub async fn record(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    flags: &[(&str, bool)],
+    source: &str,
+    context: &ConsentContext,
+) -> QueryResult<()> {
+    let flags = flags
+        .iter()
+        .map(|(purpose, granted)| (*purpose, *granted, false))
+        .collect::<Vec<_>>();
+
+    append(conn, user_id, &flags, source, context).await
+} // end fn record
+
+/// This function returns the whole ledger of a user in order.
+async fn history(conn: &mut AsyncPgConnection, user_id: i32) -> QueryResult<Vec<Consent>> {
+    consents::table
+        .filter(consents::user_id.eq(user_id))
+        .order_by(consents::id)
+        .load::<Consent>(conn)
+        .await
+} // end fn history
+
+/// This function returns the record of the purpose that is in force,
+/// i.e. the latest one that is not pending, and whether a grant has
+/// been pending since then.
+fn in_force<'a>(history: &'a [Consent], purpose: &str) -> (Option<&'a Consent>, bool) {
+    let records = history
+        .iter()
+        .filter(|consent| consent.purpose == purpose)
+        .collect::<Vec<_>>();
+    let latest = records.iter().rposition(|consent| !consent.pending);
+    let pending = records[latest.map_or(0, |latest| latest + 1)..]
+        .iter()
+        .any(|consent| consent.granted);
+
+    (latest.map(|latest| records[latest]), pending)
+} // end fn in_force
+
+/// This function appends the consents submitted by a user who has not
+/// proven who they are (e.g. with a landing form) to the ledger.
+/// Only the grants for a lead that has just been created are in force
+/// at once, the grants for an existing lead are pending until the user
+/// confirms them. It returns true if a grant has become pending,
+/// i.e. the user should be sent the confirmation link.
+pub async fn submit(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    flags: &[(&str, bool)],
+    source: &str,
+    context: &ConsentContext,
+    created: bool,
+) -> QueryResult<bool> {
+    let history = history(conn, user_id).await?;
+
+    let mut asked = false;
+    let flags = flags
+        .iter()
+        .map(|(purpose, granted)| {
+            if *granted && !created {
+                // The user is asked only once until they confirm.
+                asked |= !in_force(&history, purpose).1;
+                (*purpose, true, true)
+            } else {
+                (*purpose, *granted, false)
+            } // end if
+        })
+        .collect::<Vec<_>>();
+
+    append(conn, user_id, &flags, source, context).await?;
+    Ok(asked)
+} // end fn submit
+
+/// This function puts the pending grants of a user in force,
+/// once the user has opened the confirmation link.
+pub async fn confirm_pending(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    context: &ConsentContext,
+) -> QueryResult<()> {
+    let history = history(conn, user_id).await?;
+    let flags = PURPOSES
+        .into_iter()
+        .filter(|purpose| in_force(&history, purpose).1)
+        .map(|purpose| (purpose, true))
+        .collect::<Vec<_>>();
+
+    record(conn, user_id, &flags, FROM_CONFIRMATION, context).await
+} // end fn confirm_pending
+
+/// This function returns the consents of a user that are in force,
+/// i.e. the latest record of every purpose.
+pub async fn current(conn: &mut AsyncPgConnection, user_id: i32) -> QueryResult<Vec<Consent>> {
+    consents::table
+        .filter(consents::user_id.eq(user_id))
+        .filter(consents::pending.eq(false))
+        .distinct_on(consents::purpose)
+        .order_by((consents::purpose, consents::id.desc()))
+        .load::<Consent>(conn)
+        .await
+} // end fn current
+
+/// This function withdraws the consent to marketing of all the users
+/// with the email, e.g. when the email is unsubscribed.
+pub async fn withdraw_marketing(
+    conn: &mut AsyncPgConnection,
+    email: &str,
+    source: &str,
+    context: &ConsentContext,
+) -> QueryResult<()> {
+    let user_ids = users::table
+        .filter(users::email.eq(email.to_lowercase()))
+        .select(users::id)
+        .load::<i32>(conn)
+        .await?;
+
+    for user_id in user_ids {
+        record(conn, user_id, &[(MARKETING, false)], source, context).await?;
+    } // end for
+
+    Ok(())
+} // end fn withdraw_marketing
+
+/// This function returns the condition of the users queries that keeps
+/// only the users who have not withdrawn or refused any consent,
+/// i.e. the latest record of every purpose grants it.
+pub fn not_withdrawn() -> SqlLiteral<Bool> {
+    sql::<Bool>(
+        "NOT EXISTS (SELECT 1 FROM (\
+            SELECT DISTINCT ON (consents.purpose) consents.granted FROM consents \
+            WHERE consents.user_id = users.id AND NOT consents.pending \
+            ORDER BY consents.purpose, consents.id DESC\
+        ) AS in_force WHERE NOT in_force.granted)",
+    )
+} // end fn not_withdrawn
+
+/// This function checks if an email can be sent to the address.
+/// The transactional emails (e.g. the confirmation of a subscription)
+/// are not affected by the consent to marketing, the marketing emails
+/// need it to be granted.
+pub async fn may_send(
+    conn: &mut AsyncPgConnection,
+    email: &str,
+    transactional: bool,
+) -> QueryResult<bool> {
+    let in_force = consents::table
+        .inner_join(users::table)
+        .filter(users::email.eq(email.to_lowercase()))
+        .filter(consents::pending.eq(false))
+        .distinct_on((consents::user_id, consents::purpose))
+        .order_by((consents::user_id, consents::purpose, consents::id.desc()))
+        .select((consents::purpose, consents::granted))
+        .load::<(String, bool)>(conn)
+        .await?;
+
+    Ok(allowed(&in_force, transactional))
+} // end fn may_send
+
+/// This function decides if an email can be sent
+/// under the consents in force.
+fn allowed(in_force: &[(String, bool)], transactional: bool) -> bool {
+    let withdrawn = |purpose: &str| {
+        in_force
+            .iter()
+            .any(|(other, granted)| other == purpose && !granted)
+    };
+    let granted = |purpose: &str| {
+        in_force
+            .iter()
+            .any(|(other, granted)| other == purpose && *granted)
+    };
+
+    !withdrawn(PRIVACY_POLICY) && (transactional || (granted(MARKETING) && !withdrawn(MARKETING)))
+} // end fn allowed
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Utc;
+
+    use crate::utils::test_db;
+
+    #[test]
+    fn consent_flags_are_parsed() {
+        let fields = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            parse_flags(&fields(&[
+                ("name", "John"),
+                (PRIVACY_POLICY_FIELD, "on"),
+                (MARKETING_FIELD, "false"),
+                (MARKETING_FIELD, "True"),
+            ])),
+            Ok(vec![(PRIVACY_POLICY, true), (MARKETING, true)])
+        );
+        assert_eq!(parse_flags(&fields(&[("name", "John")])), Ok(vec![]));
+        assert!(parse_flags(&fields(&[(MARKETING_FIELD, "maybe")])).is_err());
+    }
+
+    /// This is a helper function that builds a ledger from
+    /// the purposes, the flags and whether they are pending.
+    fn ledger(records: &[(&str, bool, bool)]) -> Vec<Consent> {
+        records
+            .iter()
+            .enumerate()
+            .map(|(id, (purpose, granted, pending))| Consent {
+                id: id as i32 + 1,
+                user_id: 1,
+                purpose: purpose.to_string(),
+                granted: *granted,
+                pending: *pending,
+                policy_version: "1".to_string(),
+                source: FROM_FORM.to_string(),
+                ip: None,
+                user_agent: None,
+                created_at: Utc::now(),
+            })
+            .collect()
+    } // end fn ledger
+
+    #[test]
+    fn latest_record_wins() {
+        let history = ledger(&[
+            (MARKETING, true, false),
+            (PRIVACY_POLICY, true, false),
+            (MARKETING, false, false),
+            (MARKETING, true, true),
+        ]);
+
+        // The pending grant does not take back the withdrawal.
+        let (marketing, pending) = in_force(&history, MARKETING);
+        assert_eq!(
+            (marketing.map(|consent| consent.id), pending),
+            (Some(3), true)
+        );
+        let (privacy, pending) = in_force(&history, PRIVACY_POLICY);
+        assert_eq!(
+            (privacy.map(|consent| consent.id), pending),
+            (Some(2), false)
+        );
+        assert_eq!(in_force(&ledger(&[]), MARKETING).0.map(|c| c.id), None);
+
+        // A later record puts an end to the pending grant.
+        let history = ledger(&[
+            (MARKETING, false, false),
+            (MARKETING, true, true),
+            (MARKETING, true, false),
+        ]);
+        assert_eq!(
+            in_force(&history, MARKETING).0.map(|consent| consent.id),
+            Some(3)
+        );
+        assert!(!in_force(&history, MARKETING).1);
+    }
+
+    #[test]
+    fn marketing_needs_a_grant() {
+        let in_force = |records: &[(&str, bool)]| {
+            records
+                .iter()
+                .map(|(purpose, granted)| (purpose.to_string(), *granted))
+                .collect::<Vec<_>>()
+        };
+
+        // Nothing has been given.
+        assert!(allowed(&[], true));
+        assert!(!allowed(&[], false));
+        assert!(!allowed(&in_force(&[(PRIVACY_POLICY, true)]), false));
+
+        assert!(allowed(&in_force(&[(MARKETING, true)]), false));
+        assert!(allowed(&in_force(&[(MARKETING, false)]), true));
+        assert!(!allowed(&in_force(&[(MARKETING, false)]), false));
+        // Another lead with the email has withdrawn it.
+        assert!(!allowed(
+            &in_force(&[(MARKETING, true), (MARKETING, false)]),
+            false
+        ));
+
+        // No emails at all without the privacy policy.
+        assert!(!allowed(
+            &in_force(&[(PRIVACY_POLICY, false), (MARKETING, true)]),
+            true
+        ));
+    }
+
+    #[tokio::test]
+    async fn withdrawals_are_taken_back_only_with_the_confirmation() {
+        let mut conn = test_db::connection().await;
+        let context = ConsentContext::default();
+
+        let email = "consenting@example.com";
+        let user_id = diesel::insert_into(users::table)
+            .values((
+                users::name.eq("Jane"),
+                users::email.eq(email),
+                users::phone_number_code.eq(1),
+                users::phone_number.eq("+12025550220"),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(&mut conn)
+            .await
+            .unwrap();
+        assert!(!may_send(&mut conn, email, false).await.unwrap());
+
+        let granted = submit(
+            &mut conn,
+            user_id,
+            &[(MARKETING, true)],
+            FROM_FORM,
+            &context,
+            true,
+        );
+        assert!(!granted.await.unwrap());
+        assert!(may_send(&mut conn, email, false).await.unwrap());
+
+        // Someone submits the form again after the withdrawal.
+        withdraw_marketing(&mut conn, email, FROM_UNSUBSCRIBE, &context)
+            .await
+            .unwrap();
+        for asked in [true, false] {
+            let regranted = submit(
+                &mut conn,
+                user_id,
+                &[(MARKETING, true)],
+                FROM_FORM,
+                &context,
+                false,
+            );
+            assert_eq!(regranted.await.unwrap(), asked);
+        } // end for
+        assert!(!may_send(&mut conn, email, false).await.unwrap());
+        assert!(may_send(&mut conn, email, true).await.unwrap());
+
+        // The lead opens the confirmation link.
+        confirm_pending(&mut conn, user_id, &context).await.unwrap();
+        let in_force = current(&mut conn, user_id).await.unwrap();
+        assert_eq!(in_force.len(), 1);
+        assert_eq!(in_force[0].source, FROM_CONFIRMATION);
+        assert!(may_send(&mut conn, email, false).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn grants_for_existing_leads_wait_for_the_confirmation() {
+        let mut conn = test_db::connection().await;
+        let context = ConsentContext::default();
+
+        let email = "opted-in@example.com";
+        let user_id = diesel::insert_into(users::table)
+            .values((
+                users::name.eq("Joan"),
+                users::email.eq(email),
+                users::phone_number_code.eq(1),
+                users::phone_number.eq("+12025550290"),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(&mut conn)
+            .await
+            .unwrap();
+
+        // Someone who knows the email of the lead submits the form.
+        let granted = submit(
+            &mut conn,
+            user_id,
+            &[(PRIVACY_POLICY, true), (MARKETING, true)],
+            FROM_FORM,
+            &context,
+            false,
+        );
+        assert!(granted.await.unwrap());
+        assert!(current(&mut conn, user_id).await.unwrap().is_empty());
+        assert!(!may_send(&mut conn, email, false).await.unwrap());
+
+        // The refusals are in force at once.
+        let refused = submit(
+            &mut conn,
+            user_id,
+            &[(PRIVACY_POLICY, false)],
+            FROM_FORM,
+            &context,
+            false,
+        );
+        assert!(!refused.await.unwrap());
+        assert!(!may_send(&mut conn, email, true).await.unwrap());
+
+        // The lead opens the confirmation link, only the grant
+        // that has not been refused since is put in force.
+        confirm_pending(&mut conn, user_id, &context).await.unwrap();
+        let in_force = current(&mut conn, user_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|consent| (consent.purpose, consent.granted, consent.source))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            in_force,
+            vec![
+                (MARKETING.to_string(), true, FROM_CONFIRMATION.to_string()),
+                (PRIVACY_POLICY.to_string(), false, FROM_FORM.to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,995 @@
+// This file contains the synchronization of the leads with a CRM.
+//
+// The connector is chosen with the CRM_PROVIDER environment variable:
+// - "rest" is a generic REST API at CRM_URL (see RestConnector),
+//   the requests carry "Authorization: Bearer CRM_TOKEN" if it is set.
+// If it is not set, then the leads are not synchronized.
+//
+// A background job pushes the confirmed leads and the registered users
+// to the CRM in the order of their ids, the last pushed id is the cursor
+// of the connector (CRM_NAME, "rest" by default). The contacts are found
+// by the email and then by the phone number, so the existing contacts are
+// updated instead of duplicated. Another job reconciles all the leads
+// with the CRM from time to time, e.g. the leads that have been confirmed
+// later or filled in by another submission, and the contacts removed
+// from the CRM.
+//
+// The fields are sent under their own names ("name", "email",
+// "phone_number", "form", "created_at" and the custom fields) unless
+// CRM_FIELD_MAPPING maps them, e.g. "name:full_name,phone_number:phone".
+//
+// CRM_CONFLICT_POLICY decides what happens to the fields that are
+// different in the CRM:
+// - "local-wins" (the default) overwrites them,
+// - "remote-wins" keeps them and fills in only the missing fields,
+// - "manual" leaves the contact alone and marks the lead as a conflict.
+//
+// The replica that pushes the leads holds a lease on the cursor, so the
+// requests to the CRM are made outside of the transactions. The leads
+// that have failed are retried with a backoff (see webhooks::retry_delay).
+//
+// NOTE: The Admins and the Managers are not pushed, they are not leads.
+// Neither are the leads who have withdrawn a consent or unsubscribed.
+
+use std::env;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use diesel::dsl::{exists, not};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, NullableExpressionMethods, OptionalExtension,
+    QueryDsl, QueryResult, Queryable,
+};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    models::NewCrmContact,
+    schema::{crm_contacts, crm_sync_cursors, forms, roles, unsubscribes, users, users_roles},
+};
+
+use super::{consents, imports::ColumnMapping, webhooks::retry_delay};
+
+// These are the statuses of the leads in a CRM.
+pub const SYNCED: &str = "synced";
+pub const CONFLICT: &str = "conflict";
+pub const FAILED: &str = "failed";
+
+// The roles of the users that are not leads.
+const STAFF_ROLES: [&str; 2] = ["Admin", "Manager"];
+
+// The number of the leads that are pushed at once.
+const BATCH_SIZE: i64 = 50;
+
+// The time limits of the requests to the CRM.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+// For how long the cursor is left to the replica that pushes the leads,
+// the lease is renewed after every lead.
+const LEASE_SECONDS: i64 = 300;
+
+/// This struct represents a contact in a CRM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrmContact {
+    pub id: String,
+    pub fields: Map<String, Value>,
+} // end struct CrmContact
+
+/// This trait is implemented by the connectors to the CRMs.
+#[async_trait]
+pub trait CrmConnector: Send + Sync {
+    /// This function returns the name the progress of
+    /// the synchronization is kept under.
+    fn name(&self) -> &str;
+
+    /// This function returns the contact with the id,
+    /// or None if it has been removed.
+    async fn get_contact(&self, id: &str) -> Result<Option<CrmContact>, String>;
+
+    /// This function returns a contact with the value of the field.
+    async fn find_contact(&self, field: &str, value: &str) -> Result<Option<CrmContact>, String>;
+
+    /// This function creates a contact and returns its id.
+    async fn create_contact(&self, fields: &Map<String, Value>) -> Result<String, String>;
+
+    /// This function changes the fields of a contact.
+    async fn update_contact(&self, id: &str, fields: &Map<String, Value>) -> Result<(), String>;
+} // end trait CrmConnector
+
+/// This connector works with a generic REST API:
+/// - GET {url}/contacts/{id} returns {"id": ..., "fields": {...}} or 404,
+/// - GET {url}/contacts?{field}={value} returns a list of such contacts,
+/// - POST {url}/contacts with {"fields": {...}} returns {"id": ...},
+/// - PATCH {url}/contacts/{id} with {"fields": {...}} changes the fields.
+///
+/// The ids can be strings or numbers.
+pub struct RestConnector {
+    client: reqwest::Client,
+    name: String,
+    url: String,
+    token: Option<String>,
+} // end struct RestConnector
+
+/// This struct represents a contact in the responses of a REST API.
+#[derive(Deserialize)]
+struct RestContact {
+    id: Value,
+    #[serde(default)]
+    fields: Map<String, Value>,
+} // end struct RestContact
+
+impl RestContact {
+    /// This function converts the contact, the ids are kept as strings.
+    fn into_contact(self) -> CrmContact {
+        CrmContact {
+            id: id_to_string(self.id),
+            fields: self.fields,
+        }
+    } // end fn into_contact
+} // end impl RestContact
+
+/// This function converts an id from a response to a string.
+fn id_to_string(id: Value) -> String {
+    match id {
+        Value::String(id) => id,
+        id => id.to_string(),
+    } // end match
+} // end fn id_to_string
+
+impl RestConnector {
+    /// This function creates a connector to the API at the URL.
+    pub fn new(name: &str, url: &str, token: Option<&str>) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|error| error.to_string())?;
+
+        Ok(RestConnector {
+            client,
+            name: name.to_string(),
+            url: url.trim_end_matches('/').to_string(),
+            token: token.map(String::from),
+        })
+    } // end fn new
+
+    /// This function returns the URL of a contact.
+    fn contact_url(&self, id: &str) -> Result<reqwest::Url, String> {
+        let mut url = reqwest::Url::parse(&format!("{}/contacts", self.url))
+            .map_err(|error| error.to_string())?;
+        url.path_segments_mut()
+            .map_err(|()| "The CRM URL cannot be a base".to_string())?
+            .push(id);
+
+        Ok(url)
+    } // end fn contact_url
+
+    /// This function adds the token to a request if there is one.
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        } // end match
+    } // end fn authorize
+} // end impl RestConnector
+
+#[async_trait]
+impl CrmConnector for RestConnector {
+    fn name(&self) -> &str {
+        &self.name
+    } // end fn name
+
+    async fn get_contact(&self, id: &str) -> Result<Option<CrmContact>, String> {
+        let response = self
+            .authorize(self.client.get(self.contact_url(id)?))
+            .send()
+            .await
+            .map_err(|error| error.to_string())?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        } // end if
+
+        response
+            .error_for_status()
+            .map_err(|error| error.to_string())?
+            .json::<RestContact>()
+            .await
+            .map(|contact| Some(contact.into_contact()))
+            .map_err(|error| error.to_string())
+    } // end fn get_contact
+
+    async fn find_contact(&self, field: &str, value: &str) -> Result<Option<CrmContact>, String> {
+        let contacts = self
+            .authorize(
+                self.client
+                    .get(format!("{}/contacts", self.url))
+                    .query(&[(field, value)]),
+            )
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|error| error.to_string())?
+            .json::<Vec<RestContact>>()
+            .await
+            .map_err(|error| error.to_string())?;
+
+        Ok(contacts.into_iter().next().map(RestContact::into_contact))
+    } // end fn find_contact
+
+    async fn create_contact(&self, fields: &Map<String, Value>) -> Result<String, String> {
+        self.authorize(
+            self.client
+                .post(format!("{}/contacts", self.url))
+                .json(&json!({ "fields": fields })),
+        )
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|error| error.to_string())?
+        .json::<RestContact>()
+        .await
+        .map(|contact| id_to_string(contact.id))
+        .map_err(|error| error.to_string())
+    } // end fn create_contact
+
+    async fn update_contact(&self, id: &str, fields: &Map<String, Value>) -> Result<(), String> {
+        self.authorize(
+            self.client
+                .patch(self.contact_url(id)?)
+                .json(&json!({ "fields": fields })),
+        )
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map(|_response| ())
+        .map_err(|error| error.to_string())
+    } // end fn update_contact
+} // end impl CrmConnector
+
+/// This enum contains the ways to handle the fields
+/// that are different in the CRM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    // The fields are overwritten.
+    LocalWins,
+    // Only the missing fields are filled in.
+    RemoteWins,
+    // The contact is not changed and the lead is marked as a conflict.
+    Manual,
+} // end enum ConflictPolicy
+
+/// This enum contains the results of pushing a lead to a CRM.
+#[derive(Debug, PartialEq)]
+pub enum Upserted {
+    Created(String),
+    Updated(String),
+    Unchanged(String),
+    // The contact and the fields that are different in the CRM.
+    Conflict(String, Vec<String>),
+} // end enum Upserted
+
+/// This function creates a contact or updates the existing one.
+/// The contact is looked up by its id if it is known and then by
+/// the keys (e.g. the email and the phone number) in order.
+pub async fn upsert(
+    connector: &dyn CrmConnector,
+    known_id: Option<&str>,
+    keys: &[(&str, &str)],
+    fields: &Map<String, Value>,
+    policy: ConflictPolicy,
+) -> Result<Upserted, String> {
+    let mut existing = match known_id {
+        Some(id) => connector.get_contact(id).await?,
+        None => None,
+    }; // end match
+    for (field, value) in keys {
+        if existing.is_some() {
+            break;
+        } // end if
+        existing = connector.find_contact(field, value).await?;
+    } // end for
+
+    let contact = match existing {
+        Some(contact) => contact,
+        None => {
+            return connector
+                .create_contact(fields)
+                .await
+                .map(Upserted::Created)
+        }
+    }; // end match
+
+    // Split the fields into the missing ones and the different ones.
+    let mut missing = Map::new();
+    let mut different = Map::new();
+    for (field, value) in fields {
+        match contact.fields.get(field) {
+            None | Some(Value::Null) => missing.insert(field.clone(), value.clone()),
+            Some(Value::String(remote)) if remote.is_empty() => {
+                missing.insert(field.clone(), value.clone())
+            }
+            Some(remote) if remote != value => different.insert(field.clone(), value.clone()),
+            Some(_same) => None,
+        }; // end match
+    } // end for
+
+    let changes = match policy {
+        ConflictPolicy::LocalWins => {
+            missing.extend(different);
+            missing
+        } // end LocalWins
+        ConflictPolicy::RemoteWins => missing,
+        ConflictPolicy::Manual if !different.is_empty() => {
+            return Ok(Upserted::Conflict(
+                contact.id,
+                different.into_iter().map(|(field, _value)| field).collect(),
+            ));
+        } // end Manual
+        ConflictPolicy::Manual => missing,
+    }; // end match
+
+    if changes.is_empty() {
+        return Ok(Upserted::Unchanged(contact.id));
+    } // end if
+    connector.update_contact(&contact.id, &changes).await?;
+    Ok(Upserted::Updated(contact.id))
+} // end fn upsert
+
+/// This struct represents a lead that is pushed to a CRM.
+#[derive(Queryable, Debug)]
+struct CrmLead {
+    id: i32,
+    name: String,
+    email: Option<String>,
+    phone_number: String,
+    created_at: DateTime<Utc>,
+    custom_fields: Value,
+    form: Option<String>,
+} // end struct CrmLead
+
+/// This struct counts the results of a synchronization.
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncReport {
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub conflicts: usize,
+    pub failed: usize,
+} // end struct SyncReport
+
+impl SyncReport {
+    /// This function returns the number of the leads
+    /// that have been pushed or tried to.
+    pub fn total(&self) -> usize {
+        self.created + self.updated + self.unchanged + self.conflicts + self.failed
+    } // end fn total
+} // end impl SyncReport
+
+/// This struct contains the connector and the settings of the synchronization.
+pub struct CrmSync {
+    pub connector: Box<dyn CrmConnector>,
+    pub mapping: ColumnMapping,
+    pub conflicts: ConflictPolicy,
+} // end struct CrmSync
+
+impl CrmSync {
+    /// This function loads the leads that are pushed to the CRM, i.e. the
+    /// confirmed leads and the registered users, in the order of their ids.
+    /// Only the leads that have failed and are due to be retried
+    /// are loaded if "failed" is set.
+    async fn load_leads(
+        &self,
+        conn: &mut AsyncPgConnection,
+        after: i32,
+        until: Option<i32>,
+        failed: bool,
+    ) -> QueryResult<Vec<CrmLead>> {
+        let mut query = users::table
+            .left_join(forms::table)
+            .filter(users::id.gt(after))
+            .filter(
+                users::confirmed_at
+                    .is_not_null()
+                    .or(users::verified.eq(true)),
+            )
+            .filter(not(exists(
+                users_roles::table
+                    .inner_join(roles::table)
+                    .filter(users_roles::user_id.eq(users::id))
+                    .filter(roles::title.eq_any(STAFF_ROLES)),
+            )))
+            .filter(not(exists(
+                unsubscribes::table.filter(unsubscribes::email.nullable().eq(users::email)),
+            )))
+            .filter(consents::not_withdrawn())
+            .select((
+                users::id,
+                users::name,
+                users::email,
+                users::phone_number,
+                users::created_at,
+                users::custom_fields,
+                forms::slug.nullable(),
+            ))
+            .order_by(users::id)
+            .limit(BATCH_SIZE)
+            .into_boxed();
+        if let Some(until) = until {
+            query = query.filter(users::id.le(until));
+        } // end if
+        if failed {
+            query = query.filter(exists(
+                crm_contacts::table
+                    .filter(crm_contacts::connector.eq(self.connector.name()))
+                    .filter(crm_contacts::user_id.eq(users::id))
+                    .filter(crm_contacts::status.eq(FAILED))
+                    .filter(crm_contacts::next_attempt_at.le(Utc::now())),
+            ));
+        } // end if
+
+        query.load::<CrmLead>(conn).await
+    } // end fn load_leads
+
+    /// This function returns the fields of a lead under the names in the CRM.
+    fn fields(&self, lead: &CrmLead) -> Map<String, Value> {
+        let mut fields = vec![
+            ("name", json!(lead.name)),
+            ("email", json!(lead.email)),
+            ("phone_number", json!(lead.phone_number)),
+            ("form", json!(lead.form)),
+            ("created_at", json!(lead.created_at)),
+        ]
+        .into_iter()
+        .map(|(field, value)| (field.to_string(), value))
+        .collect::<Vec<_>>();
+        if let Value::Object(custom_fields) = &lead.custom_fields {
+            fields.extend(custom_fields.clone());
+        } // end if
+
+        fields
+            .into_iter()
+            .filter(|(_field, value)| !value.is_null())
+            .map(|(field, value)| (self.mapping.column(&field).to_string(), value))
+            .collect()
+    } // end fn fields
+
+    /// This function pushes a lead to the CRM and records the result.
+    async fn sync_lead(
+        &self,
+        conn: &mut AsyncPgConnection,
+        lead: &CrmLead,
+        report: &mut SyncReport,
+    ) -> QueryResult<()> {
+        let name = self.connector.name();
+        let (known_id, attempts) = crm_contacts::table
+            .find((name, lead.id))
+            .select((crm_contacts::external_id, crm_contacts::attempts))
+            .first::<(Option<String>, i32)>(conn)
+            .await
+            .optional()?
+            .unwrap_or_default();
+
+        let mut keys = vec![(self.mapping.column("email"), lead.email.as_deref())];
+        keys.push((
+            self.mapping.column("phone_number"),
+            Some(&lead.phone_number),
+        ));
+        let keys = keys
+            .into_iter()
+            .filter_map(|(field, value)| value.map(|value| (field, value)))
+            .collect::<Vec<_>>();
+
+        let result = upsert(
+            self.connector.as_ref(),
+            known_id.as_deref(),
+            &keys,
+            &self.fields(lead),
+            self.conflicts,
+        )
+        .await;
+        let now = Utc::now();
+        let (external_id, status, last_error) = match result {
+            Ok(Upserted::Created(id)) => {
+                report.created += 1;
+                (Some(id), SYNCED, None)
+            } // end Created
+            Ok(Upserted::Updated(id)) => {
+                report.updated += 1;
+                (Some(id), SYNCED, None)
+            } // end Updated
+            Ok(Upserted::Unchanged(id)) => {
+                report.unchanged += 1;
+                (Some(id), SYNCED, None)
+            } // end Unchanged
+            Ok(Upserted::Conflict(id, fields)) => {
+                report.conflicts += 1;
+                let message = format!("The fields are different in the CRM: {}", fields.join(", "));
+                (Some(id), CONFLICT, Some(message))
+            } // end Conflict
+            Err(error) => {
+                report.failed += 1;
+                (known_id, FAILED, Some(error))
+            } // end Err
+        }; // end match
+
+        // The failed leads are retried later and later.
+        let attempts = if status == FAILED { attempts + 1 } else { 0 };
+        let link = NewCrmContact {
+            connector: name,
+            user_id: lead.id,
+            external_id,
+            status,
+            last_error,
+            attempts,
+            next_attempt_at: now + retry_delay(attempts),
+            synced_at: now,
+        };
+        diesel::insert_into(crm_contacts::table)
+            .values(&link)
+            .on_conflict((crm_contacts::connector, crm_contacts::user_id))
+            .do_update()
+            .set(&link)
+            .execute(conn)
+            .await
+            .map(|_inserted| ())
+    } // end fn sync_lead
+
+    /// This function takes the lease of the cursor of the connector
+    /// and returns the last pushed and the last reconciled leads.
+    /// It returns None if another replica holds the lease.
+    async fn lease_cursor(&self, conn: &mut AsyncPgConnection) -> QueryResult<Option<(i32, i32)>> {
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            async move {
+                diesel::insert_into(crm_sync_cursors::table)
+                    .values(crm_sync_cursors::connector.eq(self.connector.name()))
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .await?;
+
+                let now = Utc::now();
+                let cursor = crm_sync_cursors::table
+                    .find(self.connector.name())
+                    .filter(
+                        crm_sync_cursors::leased_until
+                            .is_null()
+                            .or(crm_sync_cursors::leased_until.le(now)),
+                    )
+                    .select((
+                        crm_sync_cursors::last_user_id,
+                        crm_sync_cursors::reconciled_user_id,
+                    ))
+                    .for_update()
+                    .skip_locked()
+                    .first::<(i32, i32)>(conn)
+                    .await
+                    .optional()?;
+                if cursor.is_some() {
+                    self.renew_lease(conn).await?;
+                } // end if
+
+                Ok(cursor)
+            }
+            .scope_boxed()
+        })
+        .await
+    } // end fn lease_cursor
+
+    /// This function extends the lease of the cursor.
+    async fn renew_lease(&self, conn: &mut AsyncPgConnection) -> QueryResult<()> {
+        diesel::update(crm_sync_cursors::table.find(self.connector.name()))
+            .set(
+                crm_sync_cursors::leased_until
+                    .eq(Utc::now() + chrono::Duration::seconds(LEASE_SECONDS)),
+            )
+            .execute(conn)
+            .await
+            .map(|_updated| ())
+    } // end fn renew_lease
+
+    /// This function moves the cursor and gives up the lease.
+    async fn release_cursor(
+        &self,
+        conn: &mut AsyncPgConnection,
+        last_user_id: i32,
+        reconciled_user_id: i32,
+    ) -> QueryResult<()> {
+        diesel::update(crm_sync_cursors::table.find(self.connector.name()))
+            .set((
+                crm_sync_cursors::last_user_id.eq(last_user_id),
+                crm_sync_cursors::reconciled_user_id.eq(reconciled_user_id),
+                crm_sync_cursors::leased_until.eq(None::<DateTime<Utc>>),
+                crm_sync_cursors::updated_at.eq(Utc::now()),
+            ))
+            .execute(conn)
+            .await
+            .map(|_updated| ())
+    } // end fn release_cursor
+
+    /// This function pushes the leads one by one, the lease is renewed
+    /// after every lead.
+    async fn sync_leads(
+        &self,
+        conn: &mut AsyncPgConnection,
+        leads: &[CrmLead],
+        report: &mut SyncReport,
+    ) -> QueryResult<()> {
+        for lead in leads {
+            self.sync_lead(conn, lead, report).await?;
+            self.renew_lease(conn).await?;
+        } // end for
+
+        Ok(())
+    } // end fn sync_leads
+
+    /// This function pushes the new leads after the cursor and retries
+    /// the leads that have failed.
+    pub async fn push_new_leads(&self, conn: &mut AsyncPgConnection) -> QueryResult<SyncReport> {
+        let mut report = SyncReport::default();
+        let Some((last_user_id, reconciled_user_id)) = self.lease_cursor(conn).await? else {
+            return Ok(report);
+        };
+
+        let pushed = async {
+            let leads = self.load_leads(conn, last_user_id, None, false).await?;
+            self.sync_leads(conn, &leads, &mut report).await?;
+
+            let failed = self.load_leads(conn, 0, Some(last_user_id), true).await?;
+            self.sync_leads(conn, &failed, &mut report).await?;
+
+            Ok(leads.last().map_or(last_user_id, |lead| lead.id))
+        }
+        .await;
+
+        // The cursor stays where it was if the leads could not be
+        // loaded or recorded, they are pushed again next time.
+        let moved = *pushed.as_ref().unwrap_or(&last_user_id);
+        self.release_cursor(conn, moved, reconciled_user_id).await?;
+        pushed.map(|_moved: i32| report)
+    } // end fn push_new_leads
+
+    /// This function reconciles the next batch of the leads with the CRM.
+    /// It also returns true once all the leads have been reconciled
+    /// (or another replica is doing it), the next call starts over.
+    pub async fn reconcile_batch(
+        &self,
+        conn: &mut AsyncPgConnection,
+    ) -> QueryResult<(SyncReport, bool)> {
+        let mut report = SyncReport::default();
+        let Some((last_user_id, reconciled_user_id)) = self.lease_cursor(conn).await? else {
+            return Ok((report, true));
+        };
+
+        // The new leads are left to push_new_leads.
+        let reconciled = async {
+            let leads = self
+                .load_leads(conn, reconciled_user_id, Some(last_user_id), false)
+                .await?;
+            self.sync_leads(conn, &leads, &mut report).await?;
+
+            let done = leads.len() < BATCH_SIZE as usize;
+            Ok(match leads.last() {
+                Some(lead) if !done => (lead.id, false),
+                _ => (0, true),
+            })
+        }
+        .await;
+
+        let moved = match &reconciled {
+            Ok((moved, _done)) => *moved,
+            Err(_error) => reconciled_user_id,
+        }; // end match
+        self.release_cursor(conn, last_user_id, moved).await?;
+        reconciled.map(|(_moved, done)| (report, done))
+    } // end fn reconcile_batch
+} // end impl CrmSync
+
+/// This function creates the synchronization chosen in the CRM_PROVIDER
+/// environment variable. It returns None if there is no CRM.
+pub fn from_env() -> Result<Option<CrmSync>, String> {
+    let name = env::var("CRM_NAME").ok().filter(|name| !name.is_empty());
+    let connector: Box<dyn CrmConnector> = match env::var("CRM_PROVIDER").as_deref() {
+        Err(_) | Ok("") | Ok("none") => return Ok(None),
+        Ok("rest") => {
+            let url = env::var("CRM_URL")
+                .map_err(|_error| "Failed to find the environment variable CRM_URL".to_string())?;
+            let token = env::var("CRM_TOKEN").ok().filter(|token| !token.is_empty());
+            Box::new(RestConnector::new(
+                name.as_deref().unwrap_or("rest"),
+                &url,
+                token.as_deref(),
+            )?)
+        } // end rest
+        Ok(provider) => return Err(format!("Unknown CRM provider \"{}\"", provider)),
+    }; // end match
+
+    let mapping = ColumnMapping::parse(&env::var("CRM_FIELD_MAPPING").unwrap_or_default())?;
+    let conflicts = match env::var("CRM_CONFLICT_POLICY").as_deref() {
+        Err(_) | Ok("") | Ok("local-wins") => ConflictPolicy::LocalWins,
+        Ok("remote-wins") => ConflictPolicy::RemoteWins,
+        Ok("manual") => ConflictPolicy::Manual,
+        Ok(policy) => return Err(format!("Unknown CRM conflict policy \"{}\"", policy)),
+    }; // end match
+
+    Ok(Some(CrmSync {
+        connector,
+        mapping,
+        conflicts,
+    }))
+} // end fn from_env
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        extract::{Path, Query, State},
+        http::{HeaderMap, StatusCode},
+        routing::get,
+        Json, Router,
+    };
+
+    use crate::utils::test_db::connection;
+
+    // The contacts of the stand-in CRM.
+    type Contacts = Arc<Mutex<Vec<Map<String, Value>>>>;
+
+    /// This function checks the token of a request to the stand-in CRM.
+    fn authorized(headers: &HeaderMap) -> Result<(), StatusCode> {
+        match headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+        {
+            Some("Bearer token") => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+
+    /// This function starts a local stand-in for a CRM with a REST API.
+    fn stand_in() -> (String, Contacts) {
+        let contacts = Contacts::default();
+        let contact = |id: usize, fields: &Map<String, Value>| json!({"id": id, "fields": fields});
+        let app = Router::new()
+            .route(
+                "/contacts",
+                get(
+                    move |State(contacts): State<Contacts>,
+                          headers: HeaderMap,
+                          Query(query): Query<HashMap<String, String>>| async move {
+                        authorized(&headers)?;
+                        let found = contacts
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .enumerate()
+                            .filter(|(_id, fields)| {
+                                query
+                                    .iter()
+                                    .all(|(field, value)| fields.get(field) == Some(&json!(value)))
+                            })
+                            .map(|(id, fields)| contact(id + 1, fields))
+                            .collect::<Vec<_>>();
+                        Ok::<_, StatusCode>(Json(found))
+                    },
+                )
+                .post(
+                    |State(contacts): State<Contacts>,
+                     headers: HeaderMap,
+                     Json(body): Json<Value>| async move {
+                        authorized(&headers)?;
+                        let mut contacts = contacts.lock().unwrap();
+                        contacts.push(body["fields"].as_object().unwrap().clone());
+                        Ok::<_, StatusCode>(Json(json!({"id": contacts.len()})))
+                    },
+                ),
+            )
+            .route(
+                "/contacts/:id",
+                get(
+                    move |State(contacts): State<Contacts>,
+                          headers: HeaderMap,
+                          Path(id): Path<usize>| async move {
+                        authorized(&headers)?;
+                        let contacts = contacts.lock().unwrap();
+                        let fields = contacts.get(id - 1).ok_or(StatusCode::NOT_FOUND)?;
+                        Ok::<_, StatusCode>(Json(contact(id, fields)))
+                    },
+                )
+                .patch(
+                    |State(contacts): State<Contacts>,
+                     headers: HeaderMap,
+                     Path(id): Path<usize>,
+                     Json(body): Json<Value>| async move {
+                        authorized(&headers)?;
+                        let mut contacts = contacts.lock().unwrap();
+                        let fields = contacts.get_mut(id - 1).ok_or(StatusCode::NOT_FOUND)?;
+                        fields.extend(body["fields"].as_object().unwrap().clone());
+                        Ok::<_, StatusCode>(StatusCode::NO_CONTENT)
+                    },
+                ),
+            )
+            .with_state(contacts.clone());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        (url, contacts)
+    }
+
+    #[tokio::test]
+    async fn rest_connector_upserts_contacts() {
+        let (url, contacts) = stand_in();
+        let crm = RestConnector::new("rest", &url, Some("token")).unwrap();
+        let fields = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(field, value)| (field.to_string(), json!(value)))
+                .collect::<Map<_, _>>()
+        };
+        let keys = [("email", "jane@example.com"), ("phone", "+12015550123")];
+        let jane = fields(&[
+            ("email", "jane@example.com"),
+            ("phone", "+12015550123"),
+            ("name", "Jane"),
+        ]);
+
+        // A new contact is created, then it is found by the email.
+        let created = upsert(&crm, None, &keys, &jane, ConflictPolicy::LocalWins).await;
+        assert_eq!(created, Ok(Upserted::Created("1".to_string())));
+        let same = upsert(&crm, None, &keys, &jane, ConflictPolicy::LocalWins).await;
+        assert_eq!(same, Ok(Upserted::Unchanged("1".to_string())));
+
+        // The sales team has renamed the contact in the CRM.
+        contacts.lock().unwrap()[0].insert("name".to_string(), json!("Jane Doe"));
+        contacts.lock().unwrap()[0].remove("phone");
+        let manual = upsert(&crm, Some("1"), &keys, &jane, ConflictPolicy::Manual).await;
+        assert_eq!(
+            manual,
+            Ok(Upserted::Conflict(
+                "1".to_string(),
+                vec!["name".to_string()]
+            ))
+        );
+        assert_eq!(contacts.lock().unwrap()[0].get("phone"), None);
+
+        let remote = upsert(&crm, Some("1"), &keys, &jane, ConflictPolicy::RemoteWins).await;
+        assert_eq!(remote, Ok(Upserted::Updated("1".to_string())));
+        assert_eq!(contacts.lock().unwrap()[0]["name"], "Jane Doe");
+        assert_eq!(contacts.lock().unwrap()[0]["phone"], "+12015550123");
+
+        let local = upsert(&crm, Some("1"), &keys, &jane, ConflictPolicy::LocalWins).await;
+        assert_eq!(local, Ok(Upserted::Updated("1".to_string())));
+        assert_eq!(contacts.lock().unwrap()[0]["name"], "Jane");
+
+        // A contact with another email is found by the phone number,
+        // a removed contact is looked up again.
+        let keys = [("email", "jane@example.org"), ("phone", "+12015550123")];
+        let moved = upsert(&crm, Some("7"), &keys, &jane, ConflictPolicy::LocalWins).await;
+        assert_eq!(moved, Ok(Upserted::Unchanged("1".to_string())));
+        assert_eq!(contacts.lock().unwrap().len(), 1);
+
+        // The token is checked by the CRM.
+        let intruder = RestConnector::new("rest", &url, None).unwrap();
+        assert!(
+            upsert(&intruder, None, &keys, &jane, ConflictPolicy::LocalWins)
+                .await
+                .is_err()
+        );
+    }
+
+    /// This is a helper function that creates a confirmed lead.
+    async fn lead(conn: &mut AsyncPgConnection, email: &str, phone_number: &str) -> i32 {
+        diesel::insert_into(users::table)
+            .values((
+                users::name.eq("Jane"),
+                users::email.eq(email),
+                users::phone_number_code.eq(1),
+                users::phone_number.eq(phone_number),
+                users::confirmed_at.eq(Utc::now()),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(conn)
+            .await
+            .unwrap()
+    } // end fn lead
+
+    /// This is a helper function that synchronizes with the CRM at the URL.
+    fn crm_sync(url: &str) -> CrmSync {
+        CrmSync {
+            connector: Box::new(RestConnector::new("test", url, Some("token")).unwrap()),
+            mapping: ColumnMapping::default(),
+            conflicts: ConflictPolicy::LocalWins,
+        }
+    } // end fn crm_sync
+
+    #[tokio::test]
+    async fn failed_leads_are_retried_later() {
+        let mut conn = connection().await;
+        let (url, contacts) = stand_in();
+        let crm = crm_sync(&url);
+
+        let jane = lead(&mut conn, "jane@example.com", "+12025550210").await;
+        lead(&mut conn, "gone@example.com", "+12025550211").await;
+        diesel::insert_into(unsubscribes::table)
+            .values(unsubscribes::email.eq("gone@example.com"))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        let withdrawn = lead(&mut conn, "no@example.com", "+12025550212").await;
+        consents::record(
+            &mut conn,
+            withdrawn,
+            &[(consents::MARKETING, false)],
+            consents::FROM_WITHDRAWAL,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        // Only the leads of the test are pushed.
+        diesel::insert_into(crm_sync_cursors::table)
+            .values((
+                crm_sync_cursors::connector.eq("test"),
+                crm_sync_cursors::last_user_id.eq(jane - 1),
+            ))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        // The leads who have unsubscribed or withdrawn are not pushed.
+        let report = crm.push_new_leads(&mut conn).await.unwrap();
+        assert_eq!(report.created, 1);
+        assert_eq!(report.total(), 1);
+        assert_eq!(contacts.lock().unwrap().len(), 1);
+        let cursor = crm_sync_cursors::table
+            .find("test")
+            .select((
+                crm_sync_cursors::last_user_id,
+                crm_sync_cursors::leased_until,
+            ))
+            .first::<(i32, Option<DateTime<Utc>>)>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(cursor, (jane, None));
+
+        // The CRM is down, the lead is not retried before its time.
+        let john = lead(&mut conn, "john@example.com", "+12025550213").await;
+        let down = crm_sync("http://127.0.0.1:1/");
+        assert_eq!(down.push_new_leads(&mut conn).await.unwrap().failed, 1);
+        assert_eq!(down.push_new_leads(&mut conn).await.unwrap().total(), 0);
+        let (attempts, next_attempt_at) = crm_contacts::table
+            .find(("test", john))
+            .select((crm_contacts::attempts, crm_contacts::next_attempt_at))
+            .first::<(i32, DateTime<Utc>)>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(attempts, 1);
+        assert!(next_attempt_at > Utc::now());
+
+        // Once it is due, it is pushed again.
+        diesel::update(crm_contacts::table.find(("test", john)))
+            .set(crm_contacts::next_attempt_at.eq(Utc::now()))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        let report = crm.push_new_leads(&mut conn).await.unwrap();
+        assert_eq!((report.created, report.total()), (1, 1));
+        let (status, attempts) = crm_contacts::table
+            .find(("test", john))
+            .select((crm_contacts::status, crm_contacts::attempts))
+            .first::<(String, i32)>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!((status.as_str(), attempts), (SYNCED, 0));
+    }
+}
@@ -0,0 +1,402 @@
+// This file contains the tools for the requests of the users
+// to export or erase their data (GDPR articles 15, 17 and 20).
+//
+// Every request is logged in "data_requests" before it is handled,
+// and the log is completed with the result afterwards, so that the
+// failed requests can be found and handled again.
+//
+// The leads who have never registered have no session, they make
+// the requests with a signed link that is emailed to them.
+//
+// NOTE: The erasure removes the user together with the roles, the
+// session token, the consents, the attribution, the submissions, the
+// tags, the stages of the funnel, the waitlist place and the links
+// to the CRM. The records that must be kept are anonymized instead:
+// the audit log loses the link to the user, and the events queued for
+// the webhooks lose the personal data. The address stays in
+// "unsubscribes" if it has unsubscribed, so that no marketing email
+// is sent to it again. The contact in the CRM itself has to be erased
+// there.
+
+use chrono::Utc;
+use diesel::{
+    sql_types::{Array, Integer, Jsonb, Nullable, Text},
+    ExpressionMethods, OptionalExtension, QueryDsl, QueryResult, QueryableByName,
+};
+use diesel_async::{
+    scoped_futures::ScopedFutureExt, AsyncConnection, AsyncPgConnection, RunQueryDsl,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    models::{NewAuditEntry, NewDataRequest},
+    schema::{audit_log, data_requests, roles, users, users_roles},
+};
+
+use super::{audit, webhooks};
+
+// These are the kinds of the requests.
+pub const EXPORT: &str = "export";
+pub const ERASURE: &str = "erasure";
+
+// These are the statuses of the requests.
+pub const PENDING: &str = "pending";
+pub const COMPLETED: &str = "completed";
+pub const FAILED: &str = "failed";
+
+// The reason an Admin is not erased.
+pub const ADMIN_ERROR: &str = "The Admin role has to be revoked before the user is erased";
+
+// This query collects all the data stored about a user.
+// NOTE: The password and the session token are not a part of it.
+const ARCHIVE_QUERY: &str = "
+SELECT jsonb_build_object(
+    'generated_at', NOW(),
+    'user', (SELECT to_jsonb(u) - 'password' - 'token' FROM users u WHERE u.id = $1),
+    'roles', (
+        SELECT COALESCE(jsonb_agg(jsonb_build_object(
+            'role', r.title, 'expires_at', ur.expires_at, 'reason', ur.reason
+        ) ORDER BY ur.id), '[]')
+        FROM users_roles ur JOIN roles r ON r.id = ur.role_id WHERE ur.user_id = $1
+    ),
+    'consents', (
+        SELECT COALESCE(jsonb_agg(to_jsonb(c) - 'user_id' ORDER BY c.id), '[]')
+        FROM consents c WHERE c.user_id = $1
+    ),
+    'attribution', (
+        SELECT COALESCE(jsonb_agg(to_jsonb(a) - 'user_id' ORDER BY a.id), '[]')
+        FROM lead_attribution a WHERE a.user_id = $1
+    ),
+    'submissions', (
+        SELECT COALESCE(jsonb_agg(to_jsonb(s) - 'user_id' ORDER BY s.id), '[]')
+        FROM lead_submissions s WHERE s.user_id = $1
+    ),
+    'tags', (
+        SELECT COALESCE(jsonb_agg(t.tag ORDER BY t.tag), '[]')
+        FROM lead_tags t WHERE t.user_id = $1
+    ),
+    'funnel', (
+        SELECT COALESCE(jsonb_agg(to_jsonb(f) - 'user_id' ORDER BY f.created_at), '[]')
+        FROM funnel_events f WHERE f.user_id = $1
+    ),
+    'waitlist', (SELECT to_jsonb(w) - 'user_id' FROM waitlist w WHERE w.user_id = $1),
+    'crm_contacts', (
+        SELECT COALESCE(jsonb_agg(to_jsonb(c) - 'user_id' ORDER BY c.connector), '[]')
+        FROM crm_contacts c WHERE c.user_id = $1
+    ),
+    'audit_log', (
+        SELECT COALESCE(jsonb_agg(to_jsonb(l) - 'user_id' ORDER BY l.id), '[]')
+        FROM audit_log l WHERE l.user_id = $1
+    ),
+    'unsubscribed', EXISTS(
+        SELECT 1 FROM unsubscribes, users u WHERE u.id = $1 AND unsubscribes.email = u.email
+    )
+) AS archive
+WHERE EXISTS(SELECT 1 FROM users WHERE id = $1)";
+
+// This query removes the personal data from the events
+// about a user that are queued for the webhooks.
+const ANONYMIZE_DELIVERIES_QUERY: &str = "
+UPDATE webhook_deliveries
+SET payload = payload - ARRAY['name', 'email', 'phone_number_code', 'phone_number', 'custom_fields']
+WHERE event = ANY($2) AND payload->>'id' = $1::TEXT";
+
+/// This struct represents the archive of the data of a user.
+#[derive(QueryableByName)]
+struct Archive {
+    #[diesel(sql_type = Nullable<Jsonb>)]
+    archive: Option<Value>,
+} // end struct Archive
+
+/// This enum represents the result of an erasure.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Erasure {
+    Erased,
+    // The user does not exist (anymore).
+    NotFound,
+    // The Admins have to lose the role before they are erased,
+    // so that nobody erases the last Admin by mistake.
+    Admin,
+} // end enum Erasure
+
+/// This function logs a new request and returns its id.
+pub async fn open(
+    conn: &mut AsyncPgConnection,
+    subject_id: i32,
+    kind: &str,
+    requested_by: i32,
+) -> QueryResult<i32> {
+    diesel::insert_into(data_requests::table)
+        .values(NewDataRequest {
+            subject_id,
+            kind,
+            requested_by,
+        })
+        .returning(data_requests::id)
+        .get_result::<i32>(conn)
+        .await
+} // end fn open
+
+/// This function completes the log of a request with its result.
+pub async fn close(
+    conn: &mut AsyncPgConnection,
+    request_id: i32,
+    result: Result<(), &str>,
+) -> QueryResult<()> {
+    let (status, error) = match result {
+        Ok(()) => (COMPLETED, None),
+        Err(error) => (FAILED, Some(error)),
+    }; // end match
+
+    diesel::update(data_requests::table.find(request_id))
+        .set((
+            data_requests::status.eq(status),
+            data_requests::error.eq(error),
+            data_requests::completed_at.eq(Utc::now()),
+        ))
+        .execute(conn)
+        .await
+        .map(|_updated| ())
+} // end fn close
+
+/// This function collects all the data stored about a user.
+/// It returns None if the user does not exist.
+pub async fn export(conn: &mut AsyncPgConnection, user_id: i32) -> QueryResult<Option<Value>> {
+    diesel::sql_query(ARCHIVE_QUERY)
+        .bind::<Integer, _>(user_id)
+        .get_result::<Archive>(conn)
+        .await
+        .optional()
+        .map(|archive| archive.and_then(|archive| archive.archive))
+} // end fn export
+
+/// This function erases a user and anonymizes the records about them
+/// that are kept, all at once.
+pub async fn erase(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    erased_by: i32,
+) -> QueryResult<Erasure> {
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            // Lock the user, so that nothing is added to them meanwhile.
+            let found = users::table
+                .find(user_id)
+                .select(users::id)
+                .for_update()
+                .first::<i32>(conn)
+                .await
+                .optional()?;
+            if found.is_none() {
+                return Ok(Erasure::NotFound);
+            } // end if
+
+            let admins = users_roles::table
+                .inner_join(roles::table)
+                .filter(users_roles::user_id.eq(user_id))
+                .filter(roles::title.eq("Admin"))
+                .filter(crate::utils::roles::active())
+                .count()
+                .get_result::<i64>(conn)
+                .await?;
+            if admins > 0 {
+                return Ok(Erasure::Admin);
+            } // end if
+
+            // The audit log is kept, without the link to the user.
+            diesel::update(audit_log::table.filter(audit_log::user_id.eq(user_id)))
+                .set(audit_log::user_id.eq(None::<i32>))
+                .execute(conn)
+                .await?;
+
+            diesel::sql_query(ANONYMIZE_DELIVERIES_QUERY)
+                .bind::<Integer, _>(user_id)
+                .bind::<Array<Text>, _>(webhooks::EVENTS.to_vec())
+                .execute(conn)
+                .await?;
+
+            // Everything else is removed together with the user.
+            diesel::delete(users::table.find(user_id))
+                .execute(conn)
+                .await?;
+
+            audit::record(
+                conn,
+                &[NewAuditEntry {
+                    user_id: None,
+                    action: "user.erased".to_string(),
+                    details: json!({
+                        "subject_id": user_id,
+                        "erased_by": erased_by,
+                    }),
+                }],
+            )
+            .await?;
+
+            Ok(Erasure::Erased)
+        }
+        .scope_boxed()
+    })
+    .await
+} // end fn erase
+
+/// This function exports the data of a user and logs the request
+/// with its result. It returns None if the user does not exist.
+pub async fn handle_export(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    requested_by: i32,
+) -> QueryResult<Option<Value>> {
+    let request_id = open(conn, user_id, EXPORT, requested_by).await?;
+
+    let archive = export(conn, user_id).await;
+    let result = match &archive {
+        Ok(Some(_archive)) => Ok(()),
+        Ok(None) => Err("The user does not exist"),
+        Err(_error) => Err("The data could not be collected"),
+    }; // end match
+    close(conn, request_id, result).await?;
+
+    archive
+} // end fn handle_export
+
+/// This function erases a user and logs the request with its result.
+pub async fn handle_erasure(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    requested_by: i32,
+) -> QueryResult<Erasure> {
+    let request_id = open(conn, user_id, ERASURE, requested_by).await?;
+
+    let erasure = erase(conn, user_id, requested_by).await;
+    let result = match &erasure {
+        Ok(Erasure::Erased) => Ok(()),
+        Ok(Erasure::NotFound) => Err("The user does not exist"),
+        Ok(Erasure::Admin) => Err(ADMIN_ERROR),
+        Err(_error) => Err("The data could not be erased"),
+    }; // end match
+    close(conn, request_id, result).await?;
+
+    erasure
+} // end fn handle_erasure
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use diesel::PgJsonbExpressionMethods;
+
+    use crate::{models::DataRequest, schema::audit_log, utils::test_db::connection};
+
+    /// This is a helper function that creates a user with the roles.
+    async fn user(conn: &mut AsyncPgConnection, phone_number: &str, roles: &[&str]) -> i32 {
+        let user_id = diesel::insert_into(users::table)
+            .values((
+                users::name.eq("John"),
+                users::phone_number_code.eq(1),
+                users::phone_number.eq(phone_number),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(conn)
+            .await
+            .unwrap();
+
+        for role in roles {
+            let role_id = roles::table
+                .filter(roles::title.eq(role))
+                .select(roles::id)
+                .first::<i32>(conn)
+                .await
+                .unwrap();
+            diesel::insert_into(users_roles::table)
+                .values((
+                    users_roles::user_id.eq(user_id),
+                    users_roles::role_id.eq(role_id),
+                ))
+                .execute(conn)
+                .await
+                .unwrap();
+        } // end for
+
+        user_id
+    } // end fn user
+
+    /// This is a helper function that returns the log of the requests about a user.
+    async fn requests(conn: &mut AsyncPgConnection, subject_id: i32) -> Vec<DataRequest> {
+        data_requests::table
+            .filter(data_requests::subject_id.eq(subject_id))
+            .order_by(data_requests::id)
+            .load::<DataRequest>(conn)
+            .await
+            .unwrap()
+    } // end fn requests
+
+    #[tokio::test]
+    async fn admins_are_not_erased() {
+        let mut conn = connection().await;
+        let admin = user(&mut conn, "+12025550230", &["User", "Admin"]).await;
+
+        let erasure = handle_erasure(&mut conn, admin, admin).await;
+        assert_eq!(erasure, Ok(Erasure::Admin));
+        assert!(users::table
+            .find(admin)
+            .select(users::id)
+            .first::<i32>(&mut conn)
+            .await
+            .is_ok());
+
+        let log = requests(&mut conn, admin).await;
+        assert_eq!(log.len(), 1);
+        assert_eq!(
+            (log[0].kind.as_str(), log[0].status.as_str()),
+            (ERASURE, FAILED)
+        );
+        assert_eq!(log[0].error.as_deref(), Some(ADMIN_ERROR));
+        assert!(log[0].completed_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn requests_are_logged_with_their_results() {
+        let mut conn = connection().await;
+        let lead = user(&mut conn, "+12025550231", &[]).await;
+
+        let archive = handle_export(&mut conn, lead, lead).await.unwrap().unwrap();
+        assert_eq!(archive["user"]["id"], lead);
+        assert_eq!(
+            handle_erasure(&mut conn, lead, lead).await,
+            Ok(Erasure::Erased)
+        );
+
+        // The user is gone, so are the requests that follow.
+        assert_eq!(handle_export(&mut conn, lead, lead).await, Ok(None));
+        assert_eq!(
+            handle_erasure(&mut conn, lead, lead).await,
+            Ok(Erasure::NotFound)
+        );
+
+        let log = requests(&mut conn, lead)
+            .await
+            .into_iter()
+            .map(|request| (request.kind, request.status, request.error))
+            .collect::<Vec<_>>();
+        let missing = Some("The user does not exist".to_string());
+        assert_eq!(
+            log,
+            vec![
+                (EXPORT.to_string(), COMPLETED.to_string(), None),
+                (ERASURE.to_string(), COMPLETED.to_string(), None),
+                (EXPORT.to_string(), FAILED.to_string(), missing.clone()),
+                (ERASURE.to_string(), FAILED.to_string(), missing),
+            ]
+        );
+
+        // The erasure is kept in the audit log without the user.
+        let erased = audit_log::table
+            .filter(audit_log::action.eq("user.erased"))
+            .filter(audit_log::details.contains(json!({ "subject_id": lead })))
+            .select(audit_log::user_id)
+            .first::<Option<i32>>(&mut conn)
+            .await;
+        assert_eq!(erased, Ok(None));
+    }
+}
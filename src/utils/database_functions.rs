@@ -12,12 +12,3 @@ pub fn insert_user(connection: &mut PgConnection, new_user: NewUser) -> Result<U
         .get_result(connection)
         .map_err(|_error| StatusCode::INTERNAL_SERVER_ERROR)
 } // end fn insert_user
-
-/// This function tries to remove a user from a database.
-pub fn delete_user(connection: &mut PgConnection, user_id: i32) -> Result<(), StatusCode> {
-    diesel::delete(users::table)
-        .filter(users::columns::id.eq(user_id))
-        .execute(connection)
-        .map_err(|_error| StatusCode::INTERNAL_SERVER_ERROR)
-        .map(|_res| ())
-} // end fn delete_user
@@ -0,0 +1,477 @@
+// This file contains the validation of the email addresses.
+//
+// The addresses are checked in several steps:
+// 1. The syntax is checked according to RFC 5321/5322 (the domain
+//    literals, e.g. "john@[127.0.0.1]", and the comments are not accepted).
+// 2. The internationalized domains are converted to ASCII (IDNA) and the
+//    whole address is lowercased, so that it is stored only once.
+// 3. The domains from the DISPOSABLE_DOMAINS_FILE file (one domain
+//    per line, the subdomains are blocked too) are rejected.
+// 4. If EMAIL_MX_CHECK is "true", then the domain has to have an MX
+//    record or, if there is none, an A/AAAA record. The name server
+//    can be set in EMAIL_DNS_SERVER (e.g. "127.0.0.1:5353"),
+//    otherwise the system one is used.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    TokioAsyncResolver,
+};
+
+// These are the special symbols the local part can contain without quotes.
+const ATEXT_SYMBOLS: &str = "!#$%&'*+-/=?^_`{|}~";
+
+/// This function checks the syntax of an email address and returns
+/// it in the form it is stored in, i.e. lowercased with an ASCII domain.
+pub fn normalize(email: &str) -> Result<String, String> {
+    let invalid = || "The email address is not valid".to_string();
+
+    // The local part can contain "@" if it is quoted.
+    let email = email.trim();
+    let (local_part, domain) = email.rsplit_once('@').ok_or_else(invalid)?;
+
+    if !is_valid_local_part(local_part) {
+        return Err(invalid());
+    } // end if
+
+    // The internationalized domains are stored as Punycode.
+    let domain = idna::domain_to_ascii_strict(domain).map_err(|_error| invalid())?;
+    if !is_valid_domain(&domain) {
+        return Err(invalid());
+    } // end if
+
+    // RFC 5321 limits the path to 256 octets, including the brackets.
+    let email = format!("{}@{}", local_part, domain).to_lowercase();
+    if email.len() > 254 {
+        return Err(invalid());
+    } // end if
+
+    Ok(email)
+} // end fn normalize
+
+/// This struct represents an email address as it is stored.
+#[derive(Debug, Clone)]
+pub struct StoredEmail {
+    pub user_id: i32,
+    pub email: String,
+} // end struct StoredEmail
+
+/// This enum contains the outcomes of normalizing a stored email address.
+#[derive(Debug, PartialEq)]
+pub enum Backfill {
+    // The address has to be replaced with the normalized one.
+    Update {
+        user_id: i32,
+        email: String,
+    },
+    // The address is not valid, it is left as it is.
+    Invalid {
+        user_id: i32,
+        message: String,
+    },
+    // Another user has the same address, it is left as it is.
+    Taken {
+        user_id: i32,
+        email: String,
+        owner_id: i32,
+    },
+} // end enum Backfill
+
+/// This function decides what happens to the email addresses stored
+/// before they were normalized. The normalized addresses are not reported.
+///
+/// NOTE: When several addresses are the same after the normalization,
+/// the user who has it stored already or, otherwise, the oldest user
+/// keeps it, the others have to be merged or corrected by hand.
+pub fn plan_backfill(stored: &[StoredEmail]) -> Vec<Backfill> {
+    let mut rows = stored.iter().collect::<Vec<_>>();
+    rows.sort_by_key(|row| row.user_id);
+
+    // The oldest user with a normalized address owns it.
+    let mut owners = HashMap::new();
+    for row in &rows {
+        if normalize(&row.email).as_ref() == Ok(&row.email) {
+            owners.entry(row.email.clone()).or_insert(row.user_id);
+        } // end if
+    } // end for
+
+    let mut plan = Vec::new();
+    for row in rows {
+        let email = match normalize(&row.email) {
+            Ok(email) if email == row.email => continue,
+            Ok(email) => email,
+            Err(message) => {
+                plan.push(Backfill::Invalid {
+                    user_id: row.user_id,
+                    message,
+                });
+                continue;
+            } // end Err
+        }; // end match
+
+        match owners.get(&email) {
+            Some(&owner_id) => plan.push(Backfill::Taken {
+                user_id: row.user_id,
+                email,
+                owner_id,
+            }),
+            None => {
+                owners.insert(email.clone(), row.user_id);
+                plan.push(Backfill::Update {
+                    user_id: row.user_id,
+                    email,
+                });
+            } // end None
+        } // end match
+    } // end for
+
+    plan
+} // end fn plan_backfill
+
+/// This function checks the local part, i.e. a dot-atom or
+/// a quoted string of at most 64 octets.
+fn is_valid_local_part(local_part: &str) -> bool {
+    if local_part.is_empty() || local_part.len() > 64 {
+        return false;
+    } // end if
+
+    // A quoted string can contain any printable ASCII symbols,
+    // the quotes and the backslashes have to be escaped.
+    if let Some(quoted) = local_part
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+    {
+        let mut symbols = quoted.chars();
+        while let Some(symbol) = symbols.next() {
+            match symbol {
+                '\\' => match symbols.next() {
+                    Some(escaped) if escaped == ' ' || escaped.is_ascii_graphic() => {}
+                    _ => return false,
+                }, // end match
+                '"' => return false,
+                ' ' => {}
+                _ if symbol.is_ascii_graphic() => {}
+                _ => return false,
+            } // end match
+        } // end while
+
+        return true;
+    } // end if
+
+    // A dot-atom consists of non-empty atoms separated by dots.
+    local_part.split('.').all(|atom| {
+        !atom.is_empty()
+            && atom
+                .chars()
+                .all(|symbol| symbol.is_ascii_alphanumeric() || ATEXT_SYMBOLS.contains(symbol))
+    })
+} // end fn is_valid_local_part
+
+/// This function checks an ASCII domain, it has to consist of at least
+/// two labels of letters, digits and hyphens, and cannot be an IP address.
+fn is_valid_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+
+    domain.len() <= 253
+        && labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|symbol| symbol.is_ascii_alphanumeric() || symbol == '-')
+        })
+        // The top-level domains are never numeric.
+        && labels
+            .last()
+            .is_some_and(|label| !label.chars().all(|symbol| symbol.is_ascii_digit()))
+} // end fn is_valid_domain
+
+/// This struct contains the checks of the domains
+/// of the email addresses that need some configuration.
+pub struct EmailPolicy {
+    // The disposable domains.
+    blocklist: HashSet<String>,
+    // The resolver of the MX records, it is None
+    // if the records are not checked.
+    resolver: Option<TokioAsyncResolver>,
+} // end struct EmailPolicy
+
+impl EmailPolicy {
+    /// This function creates a policy that checks the domains against
+    /// the blocklist and, if there is a resolver, against the DNS.
+    pub fn new(blocklist: HashSet<String>, resolver: Option<TokioAsyncResolver>) -> Self {
+        EmailPolicy {
+            blocklist,
+            resolver,
+        }
+    } // end fn new
+
+    /// This function reads the policy from the environment variables
+    /// DISPOSABLE_DOMAINS_FILE, EMAIL_MX_CHECK and EMAIL_DNS_SERVER.
+    pub fn from_env() -> Result<Self, String> {
+        let blocklist = match env::var("DISPOSABLE_DOMAINS_FILE") {
+            Ok(path) => parse_blocklist(
+                &fs::read_to_string(&path).map_err(|error| format!("{}: {}", path, error))?,
+            ),
+            Err(_error) => HashSet::new(),
+        }; // end match
+
+        let resolver = if env::var("EMAIL_MX_CHECK").is_ok_and(|value| value == "true") {
+            Some(match env::var("EMAIL_DNS_SERVER") {
+                Ok(server) => resolver_for(
+                    server
+                        .parse()
+                        .map_err(|_error| format!("Invalid EMAIL_DNS_SERVER \"{}\"", server))?,
+                ),
+                Err(_error) => TokioAsyncResolver::tokio_from_system_conf()
+                    .map_err(|error| error.to_string())?,
+            }) // end Some
+        } else {
+            None
+        }; // end if
+
+        Ok(Self::new(blocklist, resolver))
+    } // end fn from_env
+
+    /// This function checks the domain of a normalized email address.
+    pub async fn check(&self, email: &str) -> Result<(), String> {
+        let domain = email
+            .rsplit_once('@')
+            .map(|(_local_part, domain)| domain)
+            .unwrap_or_default();
+
+        // The subdomains of the disposable domains are disposable too.
+        let mut parent = Some(domain);
+        while let Some(current) = parent {
+            if self.blocklist.contains(current) {
+                return Err("The disposable email addresses are not accepted".to_string());
+            } // end if
+            parent = current.split_once('.').map(|(_label, rest)| rest);
+        } // end while
+
+        if let Some(resolver) = &self.resolver {
+            if !accepts_mail(resolver, domain).await {
+                return Err("The email domain does not accept emails".to_string());
+            } // end if
+        } // end if
+
+        Ok(())
+    } // end fn check
+} // end impl EmailPolicy
+
+/// This function parses a blocklist, i.e. a domain per line,
+/// the empty lines and the lines starting with "#" are skipped.
+fn parse_blocklist(content: &str) -> HashSet<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|domain| idna::domain_to_ascii_strict(domain).ok())
+        .collect()
+} // end fn parse_blocklist
+
+/// This function creates a resolver that asks a single name server.
+pub fn resolver_for(server: SocketAddr) -> TokioAsyncResolver {
+    let name_servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+
+    TokioAsyncResolver::tokio(
+        ResolverConfig::from_parts(None, vec![], name_servers),
+        ResolverOpts::default(),
+    )
+} // end fn resolver_for
+
+/// This function checks if a domain can receive emails, i.e. it has
+/// an MX record that is not a "null MX" (RFC 7505) or, if it has no
+/// MX records at all, an A/AAAA record (RFC 5321, section 5.1).
+///
+/// NOTE: If the name server is not available, then the domain
+/// is accepted, so that the leads are not lost because of the DNS.
+async fn accepts_mail(resolver: &TokioAsyncResolver, domain: &str) -> bool {
+    // The domain is fully qualified, so that the search domains are not used.
+    let domain = format!("{}.", domain);
+
+    // This is a helper function that tells the missing records from the failures.
+    fn is_missing(error: &ResolveError) -> bool {
+        matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. })
+    } // end fn is_missing
+
+    match resolver.mx_lookup(domain.as_str()).await {
+        Ok(records) => {
+            return records.iter().any(|record| !record.exchange().is_root());
+        } // end Ok
+        Err(error) if !is_missing(&error) => {
+            eprintln!("Failed to look up the MX records of {}: {}", domain, error);
+            return true;
+        } // end Err
+        Err(_error) => {}
+    } // end match
+
+    match resolver.lookup_ip(domain.as_str()).await {
+        Ok(addresses) => addresses.iter().next().is_some(),
+        Err(error) if !is_missing(&error) => {
+            eprintln!("Failed to look up the addresses of {}: {}", domain, error);
+            true
+        } // end Err
+        Err(_error) => false,
+    } // end match
+} // end fn accepts_mail
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::str::FromStr;
+
+    use hickory_proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{rdata::A, rdata::MX, Name, RData, Record, RecordType},
+    };
+
+    #[test]
+    fn email_syntax_is_checked() {
+        assert_eq!(
+            normalize(" John.Doe+Sale@Example.COM ").unwrap(),
+            "john.doe+sale@example.com"
+        );
+        assert_eq!(
+            normalize("ivan@пример.рф").unwrap(),
+            "ivan@xn--e1afmkfd.xn--p1ai"
+        );
+        assert_eq!(
+            normalize("\"john doe\"@example.com").unwrap(),
+            "\"john doe\"@example.com"
+        );
+
+        for email in [
+            "john",
+            "john@",
+            "@example.com",
+            "john@example",
+            "john..doe@example.com",
+            ".john@example.com",
+            "john doe@example.com",
+            "john@-example.com",
+            "john@example.123",
+            "john@[127.0.0.1]",
+            "\"john\"doe\"@example.com",
+            &format!("{}@example.com", "j".repeat(65)),
+        ] {
+            assert!(normalize(email).is_err(), "{}", email);
+        } // end for
+    }
+
+    #[test]
+    fn stored_emails_are_backfilled() {
+        let stored = |user_id: i32, email: &str| StoredEmail {
+            user_id,
+            email: email.to_string(),
+        };
+        let plan = plan_backfill(&[
+            stored(4, "John@Example.com"),
+            stored(2, "john@example.com"),
+            stored(1, "Jane@Example.com "),
+            stored(3, "JANE@example.com"),
+            stored(5, "jane"),
+        ]);
+
+        assert_eq!(
+            plan,
+            vec![
+                Backfill::Update {
+                    user_id: 1,
+                    email: "jane@example.com".to_string(),
+                },
+                // The oldest user keeps the address.
+                Backfill::Taken {
+                    user_id: 3,
+                    email: "jane@example.com".to_string(),
+                    owner_id: 1,
+                },
+                // The user who has stored the normalized address keeps it.
+                Backfill::Taken {
+                    user_id: 4,
+                    email: "john@example.com".to_string(),
+                    owner_id: 2,
+                },
+                Backfill::Invalid {
+                    user_id: 5,
+                    message: "The email address is not valid".to_string(),
+                },
+            ]
+        );
+    }
+
+    /// This is a helper function that runs a stub name server, which
+    /// knows an MX of "example.com", a null MX of "null.example.com"
+    /// and an A record of "a.example.com".
+    fn run_stub_name_server() -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = socket.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let mut buffer = [0; 512];
+            while let Ok((length, client)) = socket.recv_from(&mut buffer) {
+                let request = Message::from_vec(&buffer[..length]).unwrap();
+                let query = request.queries()[0].clone();
+                let name = query.name().clone();
+
+                let answer = match (name.to_ascii().as_str(), query.query_type()) {
+                    ("example.com.", RecordType::MX) => Some(RData::MX(MX::new(
+                        10,
+                        Name::from_str("mail.example.com.").unwrap(),
+                    ))),
+                    ("null.example.com.", RecordType::MX) => {
+                        Some(RData::MX(MX::new(0, Name::root())))
+                    }
+                    ("a.example.com.", RecordType::A) => Some(RData::A(A::new(192, 0, 2, 1))),
+                    _ => None,
+                };
+
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(request.op_code())
+                    .set_recursion_desired(request.recursion_desired())
+                    .set_recursion_available(true)
+                    .add_query(query);
+                match answer {
+                    Some(rdata) => {
+                        response.add_answer(Record::from_rdata(name, 60, rdata));
+                    }
+                    None => {
+                        response.set_response_code(ResponseCode::NXDomain);
+                    }
+                }
+
+                socket.send_to(&response.to_vec().unwrap(), client).unwrap();
+            }
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn email_domains_are_checked() {
+        let policy = EmailPolicy::new(
+            parse_blocklist("# Disposable domains\n\nMailinator.com\n"),
+            Some(resolver_for(run_stub_name_server())),
+        );
+
+        assert_eq!(policy.check("john@example.com").await, Ok(()));
+        assert_eq!(policy.check("john@a.example.com").await, Ok(()));
+        assert!(policy.check("john@null.example.com").await.is_err());
+        assert!(policy.check("john@missing.example.com").await.is_err());
+        assert!(policy.check("john@mailinator.com").await.is_err());
+        assert!(policy.check("john@eu.mailinator.com").await.is_err());
+    }
+}
@@ -0,0 +1,523 @@
+// This file contains the export of the leads for the managers.
+//
+// The leads are read through a database cursor in batches, so that
+// a large export never has to fit in memory, and every batch is written
+// out as CSV (or to an XLSX worksheet) as soon as it has been fetched.
+// The columns are the fields of the leads, their attribution, their
+// tags and the custom fields declared for the exported forms.
+//
+// NOTE: The response has already started when the rows are read,
+// so a failure in the middle of an export aborts the response body.
+//
+// The text that came from the leads is prefixed with "'" if it starts
+// with a symbol a spreadsheet would take for a formula ("=", "+", "-",
+// "@", a tab or a carriage return), so that opening an export cannot
+// run anything.
+
+use std::io::{self, Write};
+
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use diesel::dsl::sql;
+use diesel::pg::Pg;
+use diesel::query_builder::{AstPass, QueryFragment, QueryId};
+use diesel::sql_types::{Array, Bool, Int4, Jsonb, Nullable, Text, Timestamptz, Varchar};
+use diesel::{
+    ExpressionMethods, NullableExpressionMethods, PgSortExpressionMethods, QueryDsl, QueryResult,
+    QueryableByName,
+};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use rust_xlsxwriter::{Workbook, XlsxError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::{Receiver, Sender};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::schema::{forms, lead_attribution, lead_scores, lead_tags, users};
+
+// The name of the cursor the leads are read through.
+const CURSOR: &str = "lead_export";
+
+// The number of the leads fetched from the cursor at once.
+const BATCH_SIZE: usize = 500;
+
+// The separator of the tags, both in the filter and in the files.
+pub const TAG_SEPARATOR: char = ',';
+
+// The symbols a spreadsheet starts a formula with.
+const FORMULA_PREFIXES: [char; 6] = ['=', '+', '-', '@', '\t', '\r'];
+
+// These are the columns every export starts with,
+// the custom fields of the forms follow them.
+const COLUMNS: [&str; 18] = [
+    "id",
+    "name",
+    "email",
+    "phone_number",
+    "verified",
+    "created_at",
+    "confirmed_at",
+    "form",
+    "tags",
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+    "referrer",
+    "landing_url",
+    "ip",
+    "score",
+];
+
+/// This enum contains the formats of the exports.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Xlsx,
+} // end enum ExportFormat
+
+/// This enum contains the orders the leads are exported in.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LeadSort {
+    // The oldest leads first.
+    #[default]
+    Id,
+    // The leads with the highest score first, the leads
+    // that have not been scored yet are the last.
+    Score,
+} // end enum LeadSort
+
+/// This struct represents the filters of the export.
+#[derive(Deserialize, Serialize, IntoParams, Default, Debug)]
+pub struct LeadExportQuery {
+    /// The format of the file, "csv" by default.
+    #[serde(default)]
+    #[param(inline)]
+    pub format: ExportFormat,
+    /// Only the leads that came at or after this moment are exported.
+    pub from: Option<DateTime<Utc>>,
+    /// Only the leads that came before this moment are exported.
+    pub to: Option<DateTime<Utc>>,
+    /// The slug of the form the leads came from.
+    pub form: Option<String>,
+    /// Whether the leads have verified their account.
+    pub verified: Option<bool>,
+    /// Whether the leads have confirmed their subscription.
+    pub confirmed: Option<bool>,
+    /// The comma separated tags, the leads have to have all of them.
+    pub tags: Option<String>,
+    /// The order of the leads, "id" by default.
+    #[serde(default)]
+    #[param(inline)]
+    pub sort: LeadSort,
+} // end struct LeadExportQuery
+
+impl LeadExportQuery {
+    /// This function returns the tags of the filter.
+    pub fn tags(&self) -> Vec<String> {
+        self.tags
+            .iter()
+            .flat_map(|tags| tags.split(TAG_SEPARATOR))
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    } // end fn tags
+} // end impl LeadExportQuery
+
+/// This struct represents an exported lead.
+#[derive(QueryableByName, Debug)]
+pub struct LeadExportRow {
+    #[diesel(sql_type = Int4)]
+    pub id: i32,
+    #[diesel(sql_type = Varchar)]
+    pub name: String,
+    #[diesel(sql_type = Nullable<Varchar>)]
+    pub email: Option<String>,
+    #[diesel(sql_type = Varchar)]
+    pub phone_number: String,
+    #[diesel(sql_type = Bool)]
+    pub verified: bool,
+    #[diesel(sql_type = Timestamptz)]
+    pub created_at: DateTime<Utc>,
+    #[diesel(sql_type = Nullable<Timestamptz>)]
+    pub confirmed_at: Option<DateTime<Utc>>,
+    // The slug of the form.
+    #[diesel(sql_type = Nullable<Varchar>, column_name = slug)]
+    pub form: Option<String>,
+    #[diesel(sql_type = Array<Text>)]
+    pub tags: Vec<String>,
+    #[diesel(sql_type = Nullable<Varchar>)]
+    pub utm_source: Option<String>,
+    #[diesel(sql_type = Nullable<Varchar>)]
+    pub utm_medium: Option<String>,
+    #[diesel(sql_type = Nullable<Varchar>)]
+    pub utm_campaign: Option<String>,
+    #[diesel(sql_type = Nullable<Varchar>)]
+    pub utm_term: Option<String>,
+    #[diesel(sql_type = Nullable<Varchar>)]
+    pub utm_content: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub referrer: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub landing_url: Option<String>,
+    #[diesel(sql_type = Nullable<Varchar>)]
+    pub ip: Option<String>,
+    #[diesel(sql_type = Nullable<Int4>)]
+    pub score: Option<i32>,
+    #[diesel(sql_type = Jsonb)]
+    pub custom_fields: Value,
+} // end struct LeadExportRow
+
+impl LeadExportRow {
+    /// This function returns the cells of the lead in the order of
+    /// the columns, the custom fields are taken in the given order.
+    pub fn cells(&self, custom_fields: &[String]) -> Vec<String> {
+        // This is a helper function for the optional cells.
+        fn optional(value: &Option<String>) -> String {
+            value.as_deref().map(text).unwrap_or_default()
+        } // end fn optional
+
+        // NOTE: The phone number is in E.164, i.e. a "+" and digits,
+        // so it is written as it is.
+        let mut cells = vec![
+            self.id.to_string(),
+            text(&self.name),
+            optional(&self.email),
+            self.phone_number.clone(),
+            self.verified.to_string(),
+            self.created_at.to_rfc3339(),
+            self.confirmed_at
+                .map(|confirmed_at| confirmed_at.to_rfc3339())
+                .unwrap_or_default(),
+            optional(&self.form),
+            text(&self.tags.join(&TAG_SEPARATOR.to_string())),
+            optional(&self.utm_source),
+            optional(&self.utm_medium),
+            optional(&self.utm_campaign),
+            optional(&self.utm_term),
+            optional(&self.utm_content),
+            optional(&self.referrer),
+            optional(&self.landing_url),
+            optional(&self.ip),
+            self.score
+                .map(|score| score.to_string())
+                .unwrap_or_default(),
+        ];
+
+        // The text values are written as they are, the rest as JSON.
+        cells.extend(custom_fields.iter().map(|name| {
+            match self.custom_fields.get(name) {
+                Some(Value::String(value)) => text(value),
+                Some(Value::Null) | None => String::new(),
+                Some(value) => value.to_string(),
+            } // end match
+        }));
+
+        cells
+    } // end fn cells
+} // end impl LeadExportRow
+
+/// This function returns a text cell that a spreadsheet
+/// cannot take for a formula.
+fn text(value: &str) -> String {
+    if value.starts_with(FORMULA_PREFIXES) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    } // end if
+} // end fn text
+
+/// This function returns the header of an export.
+pub fn header_row(custom_fields: &[String]) -> Vec<String> {
+    COLUMNS
+        .iter()
+        .map(|column| column.to_string())
+        .chain(custom_fields.iter().cloned())
+        .collect()
+} // end fn header_row
+
+/// This struct declares a cursor for a query, so that its rows
+/// can be fetched in batches.
+struct DeclareCursor<Q>(Q);
+
+impl<Q> QueryId for DeclareCursor<Q> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+} // end impl QueryId
+
+impl<Q: QueryFragment<Pg>> QueryFragment<Pg> for DeclareCursor<Q> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        out.push_sql("DECLARE ");
+        out.push_identifier(CURSOR)?;
+        out.push_sql(" NO SCROLL CURSOR FOR ");
+        self.0.walk_ast(out.reborrow())
+    } // end fn walk_ast
+} // end impl QueryFragment
+
+/// This function reads the leads that match the filters through a cursor
+/// and sends them in batches. It stops early if the receiver is dropped,
+/// e.g. when the client has gone away.
+pub async fn fetch_leads(
+    conn: &mut AsyncPgConnection,
+    query: &LeadExportQuery,
+    batches: &Sender<QueryResult<Vec<LeadExportRow>>>,
+) -> QueryResult<()> {
+    let mut statement = users::table
+        .left_join(forms::table)
+        .left_join(lead_attribution::table)
+        .left_join(lead_scores::table)
+        .select((
+            users::id,
+            users::name,
+            users::email,
+            users::phone_number,
+            users::verified,
+            users::created_at,
+            users::confirmed_at,
+            forms::slug.nullable(),
+            sql::<Array<Text>>(
+                "ARRAY(SELECT lead_tags.tag FROM lead_tags \
+                WHERE lead_tags.user_id = users.id ORDER BY lead_tags.tag) AS tags",
+            ),
+            lead_attribution::utm_source.nullable(),
+            lead_attribution::utm_medium.nullable(),
+            lead_attribution::utm_campaign.nullable(),
+            lead_attribution::utm_term.nullable(),
+            lead_attribution::utm_content.nullable(),
+            lead_attribution::referrer.nullable(),
+            lead_attribution::landing_url.nullable(),
+            lead_attribution::ip.nullable(),
+            lead_scores::score.nullable(),
+            users::custom_fields,
+        ))
+        .into_boxed();
+
+    if let Some(from) = query.from {
+        statement = statement.filter(users::created_at.ge(from));
+    } // end if
+    if let Some(to) = query.to {
+        statement = statement.filter(users::created_at.lt(to));
+    } // end if
+    if let Some(form) = &query.form {
+        statement = statement.filter(forms::slug.eq(form.clone()));
+    } // end if
+    if let Some(verified) = query.verified {
+        statement = statement.filter(users::verified.eq(verified));
+    } // end if
+    match query.confirmed {
+        Some(true) => statement = statement.filter(users::confirmed_at.is_not_null()),
+        Some(false) => statement = statement.filter(users::confirmed_at.is_null()),
+        None => (),
+    } // end match
+    for tag in query.tags() {
+        statement = statement.filter(
+            users::id.eq_any(
+                lead_tags::table
+                    .filter(lead_tags::tag.eq(tag))
+                    .select(lead_tags::user_id),
+            ),
+        );
+    } // end for
+    statement = match query.sort {
+        LeadSort::Id => statement.order_by(users::id),
+        LeadSort::Score => statement
+            .order_by(lead_scores::score.nullable().desc().nulls_last())
+            .then_order_by(users::id),
+    }; // end match
+
+    // The cursor lives until the end of the transaction.
+    conn.transaction::<(), diesel::result::Error, _>(|conn| {
+        async move {
+            DeclareCursor(statement).execute(conn).await?;
+
+            loop {
+                let rows = diesel::sql_query(format!("FETCH {} FROM {}", BATCH_SIZE, CURSOR))
+                    .load::<LeadExportRow>(conn)
+                    .await?;
+                let last = rows.len() < BATCH_SIZE;
+
+                if batches.send(Ok(rows)).await.is_err() || last {
+                    return Ok(());
+                } // end if
+            } // end loop
+        }
+        .scope_boxed()
+    })
+    .await
+} // end fn fetch_leads
+
+/// This function writes the records (the header or the cells
+/// of the leads) as a chunk of CSV.
+pub fn csv_chunk(cells: &[Vec<String>]) -> io::Result<Bytes> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for record in cells {
+        writer.write_record(record)?;
+    } // end for
+
+    writer
+        .into_inner()
+        .map(Bytes::from)
+        .map_err(|error| io::Error::other(error.to_string()))
+} // end fn csv_chunk
+
+/// This struct passes the written bytes on to a channel.
+struct ChannelWriter(Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_error| io::Error::new(io::ErrorKind::BrokenPipe, "The client has gone"))?;
+        Ok(buf.len())
+    } // end fn write
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    } // end fn flush
+} // end impl Write
+
+/// This function writes the batches of the leads to an XLSX worksheet
+/// and sends the workbook once all of them have been written.
+///
+/// WARNING: It blocks, so it has to be run with spawn_blocking.
+/// NOTE: The worksheet is kept in a temporary file rather than in memory.
+pub fn write_xlsx(
+    custom_fields: &[String],
+    mut batches: Receiver<QueryResult<Vec<LeadExportRow>>>,
+    chunks: &Sender<io::Result<Bytes>>,
+) -> Result<(), XlsxError> {
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet_with_constant_memory();
+    worksheet.set_name("Leads")?;
+
+    for (column, title) in header_row(custom_fields).iter().enumerate() {
+        worksheet.write_string(0, column as u16, title)?;
+    } // end for
+
+    let mut row_number = 0;
+    while let Some(batch) = batches.blocking_recv() {
+        let rows = batch.map_err(|error| XlsxError::CustomError(error.to_string()))?;
+
+        for row in rows {
+            row_number += 1;
+            // The id and the verification are typed, the rest is text,
+            // so the phone number stays in E.164 rather than a number.
+            worksheet.write_number(row_number, 0, row.id)?;
+            worksheet.write_boolean(row_number, 4, row.verified)?;
+            for (column, cell) in row.cells(custom_fields).iter().enumerate() {
+                if column != 0 && column != 4 {
+                    worksheet.write_string(row_number, column as u16, cell)?;
+                } // end if
+            } // end for
+        } // end for
+    } // end while
+
+    let mut writer = io::BufWriter::with_capacity(64 * 1024, ChannelWriter(chunks.clone()));
+    workbook.save_to_writer(&mut writer)?;
+    writer.flush()?;
+    Ok(())
+} // end fn write_xlsx
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn leads_are_written_as_csv() {
+        let row = LeadExportRow {
+            id: 7,
+            name: "Doe, John".to_string(),
+            email: Some("john@example.com".to_string()),
+            phone_number: "+12015550123".to_string(),
+            verified: false,
+            created_at: "2024-01-31T18:00:00Z".parse().unwrap(),
+            confirmed_at: None,
+            form: Some("default".to_string()),
+            tags: vec!["hot".to_string(), "vip".to_string()],
+            utm_source: Some("newsletter".to_string()),
+            utm_medium: None,
+            utm_campaign: None,
+            utm_term: None,
+            utm_content: None,
+            referrer: None,
+            landing_url: None,
+            ip: None,
+            score: Some(42),
+            custom_fields: json!({"company": "Acme", "team_size": 5}),
+        };
+        let fields = vec![
+            "company".to_string(),
+            "team_size".to_string(),
+            "role".to_string(),
+        ];
+
+        let csv = csv_chunk(&[header_row(&fields), row.cells(&fields)]).unwrap();
+        let mut lines = std::str::from_utf8(&csv).unwrap().lines();
+
+        assert!(lines
+            .next()
+            .unwrap()
+            .ends_with(",ip,score,company,team_size,role"));
+        assert_eq!(
+            lines.next().unwrap(),
+            "7,\"Doe, John\",john@example.com,+12015550123,false,\
+            2024-01-31T18:00:00+00:00,,default,\"hot,vip\",newsletter,,,,,,,,42,Acme,5,"
+        );
+
+        let query = LeadExportQuery {
+            tags: Some(" Hot,,vip ".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query.tags(), vec!["hot", "vip"]);
+    }
+
+    #[test]
+    fn formulas_are_not_exported() {
+        let row = LeadExportRow {
+            id: 8,
+            name: "=HYPERLINK(\"https://example.com\")".to_string(),
+            email: Some("@john@example.com".to_string()),
+            phone_number: "+12015550123".to_string(),
+            verified: true,
+            created_at: "2024-01-31T18:00:00Z".parse().unwrap(),
+            confirmed_at: None,
+            form: None,
+            tags: vec!["-hot".to_string()],
+            utm_source: Some("+newsletter".to_string()),
+            utm_medium: Some("\tcpc".to_string()),
+            utm_campaign: Some("\rspring".to_string()),
+            utm_term: Some("sale=1".to_string()),
+            utm_content: None,
+            referrer: None,
+            landing_url: None,
+            ip: None,
+            score: Some(-3),
+            custom_fields: json!({"company": "-Acme", "team_size": -5}),
+        };
+        let fields = vec!["company".to_string(), "team_size".to_string()];
+        let cells = row.cells(&fields);
+
+        assert_eq!(cells[1], "'=HYPERLINK(\"https://example.com\")");
+        assert_eq!(cells[2], "'@john@example.com");
+        assert_eq!(cells[8], "'-hot");
+        assert_eq!(cells[9], "'+newsletter");
+        assert_eq!(cells[10], "'\tcpc");
+        assert_eq!(cells[11], "'\rspring");
+        assert_eq!(cells[12], "sale=1");
+        assert_eq!(cells[18], "'-Acme");
+
+        // The values that are not text stay readable.
+        assert_eq!(cells[3], "+12015550123");
+        assert_eq!(cells[17], "-3");
+        assert_eq!(cells[19], "-5");
+    }
+}
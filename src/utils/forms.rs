@@ -0,0 +1,469 @@
+// This file contains the definitions of the forms on the landing pages.
+//
+// Every landing page can ask for something more than a name and
+// a phone number (e.g. a company or a team size). The extra fields
+// are declared in a JSON file (FORM_DEFINITIONS environment variable),
+// the submissions are validated against the declarations and the
+// values are stored in the "custom_fields" column of the users.
+// A definition applies to the landing form with the same slug.
+//
+// An example of the file:
+// {
+//     "default": {
+//         "description": "The main landing page",
+//         "fields": [
+//             { "name": "company", "type": "text", "required": true, "max_length": 100 },
+//             { "name": "team_size", "type": "integer", "min": 1 },
+//             { "name": "role", "type": "choice", "options": ["CEO", "CTO", "Other"] }
+//         ]
+//     }
+// }
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::FromRequest,
+    http::{header, Request, StatusCode},
+};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use utoipa::openapi::{
+    schema::{ObjectBuilder, SchemaFormat, SchemaType},
+    OpenApi,
+};
+use utoipa::ToSchema;
+
+use super::{antispam, captcha, consents, responses::DefaultResponse, waitlist};
+
+/// This is the slug of the form that is used by "/insert".
+pub const DEFAULT_FORM: &str = "default";
+
+// These names are taken by the fixed fields of the forms.
+const RESERVED_NAMES: [&str; 12] = [
+    "name",
+    "email",
+    "phone_number_code",
+    "phone_number",
+    "password",
+    antispam::HONEYPOT_FIELD,
+    antispam::TOKEN_FIELD,
+    antispam::NONCE_FIELD,
+    captcha::RESPONSE_FIELD,
+    waitlist::REFERRAL_FIELD,
+    consents::PRIVACY_POLICY_FIELD,
+    consents::MARKETING_FIELD,
+];
+
+/// This enum contains the types of the custom fields.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    Text,
+    Integer,
+    Number,
+    Boolean,
+    Email,
+    Url,
+    // One of the "options".
+    Choice,
+} // end enum FieldType
+
+/// This struct declares a custom field of a form.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug)]
+pub struct FieldDefinition {
+    #[schema(example = "company")]
+    pub name: String,
+    #[schema(example = "Company")]
+    pub label: Option<String>,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    #[serde(default)]
+    pub required: bool,
+    // The limits of the length of the text values.
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    // The limits of the numeric values.
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    // A regular expression the whole text value has to match.
+    pub pattern: Option<String>,
+    // The allowed values of a choice.
+    pub options: Option<Vec<String>>,
+    #[serde(skip)]
+    #[schema(ignore)]
+    compiled_pattern: Option<Regex>,
+} // end struct FieldDefinition
+
+/// This struct declares the custom fields of a form.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug, Default)]
+pub struct FormDefinition {
+    #[schema(example = "The main landing page")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<FieldDefinition>,
+} // end struct FormDefinition
+
+/// This struct contains all the form definitions by their names.
+#[derive(Clone, Debug)]
+pub struct FormDefinitions(HashMap<String, FormDefinition>);
+
+impl FormDefinitions {
+    /// This function parses the form definitions from JSON and checks them.
+    /// NOTE: The default form is added if it is not declared.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let mut definitions: HashMap<String, FormDefinition> =
+            serde_json::from_str(json).map_err(|error| error.to_string())?;
+
+        for (form, definition) in definitions.iter_mut() {
+            for field in definition.fields.iter_mut() {
+                if RESERVED_NAMES.contains(&field.name.as_str()) {
+                    return Err(format!(
+                        "The field \"{}\" of the form \"{}\" has a reserved name",
+                        field.name, form
+                    ));
+                } // end if
+
+                // No value would ever pass a choice without options.
+                if field.field_type == FieldType::Choice
+                    && field.options.as_deref().unwrap_or_default().is_empty()
+                {
+                    return Err(format!(
+                        "The choice \"{}\" of the form \"{}\" has no options",
+                        field.name, form
+                    ));
+                } // end if
+
+                // The whole value has to match the pattern.
+                if let Some(pattern) = &field.pattern {
+                    field.compiled_pattern = Some(
+                        Regex::new(&format!("^(?:{})$", pattern))
+                            .map_err(|error| error.to_string())?,
+                    );
+                } // end if
+            } // end for
+        } // end for
+
+        definitions.entry(DEFAULT_FORM.to_string()).or_default();
+
+        Ok(Self(definitions))
+    } // end fn parse
+
+    /// This function loads the form definitions from the file specified
+    /// in the FORM_DEFINITIONS environment variable. If it is not set,
+    /// then there is only the default form without custom fields.
+    pub fn load() -> Result<Self, String> {
+        match env::var("FORM_DEFINITIONS") {
+            Ok(path) => Self::parse(
+                &fs::read_to_string(&path).map_err(|error| format!("{}: {}", path, error))?,
+            ),
+            Err(_error) => Self::parse("{}"),
+        } // end match
+    } // end fn load
+
+    /// This function returns the definition of a form.
+    pub fn get(&self, form: &str) -> Option<&FormDefinition> {
+        self.0.get(form)
+    } // end fn get
+
+    /// This function returns the names of the custom fields of a form,
+    /// or of all the forms (sorted by the form) if the form is None.
+    pub fn field_names(&self, form: Option<&str>) -> Vec<String> {
+        let mut forms = self
+            .0
+            .iter()
+            .filter(|(name, _definition)| form.is_none_or(|form| form == name.as_str()))
+            .collect::<Vec<_>>();
+        forms.sort_by_key(|(name, _definition)| name.as_str());
+
+        let mut names = Vec::new();
+        for (_name, definition) in forms {
+            for field in &definition.fields {
+                if !names.contains(&field.name) {
+                    names.push(field.name.clone());
+                } // end if
+            } // end for
+        } // end for
+        names
+    } // end fn field_names
+
+    /// This function adds a schema of the custom fields of every form
+    /// to the OpenAPI documentation, e.g. "CustomFields_default".
+    pub fn document(&self, openapi: &mut OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        for (form, definition) in &self.0 {
+            components
+                .schemas
+                .insert(format!("CustomFields_{}", form), definition.schema().into());
+        } // end for
+    } // end fn document
+} // end impl FormDefinitions
+
+impl FormDefinition {
+    /// This function validates the custom fields of a submission.
+    /// It returns the values converted to their types, the fields
+    /// that are not declared are ignored.
+    pub fn validate(&self, submitted: &[(String, String)]) -> Result<Map<String, Value>, String> {
+        let mut values = Map::new();
+
+        for field in &self.fields {
+            // Empty inputs of HTML forms are sent as empty strings.
+            let raw = submitted
+                .iter()
+                .find(|(name, _value)| name == &field.name)
+                .map(|(_name, value)| value.trim())
+                .filter(|value| !value.is_empty());
+
+            let raw = match raw {
+                Some(raw) => raw,
+                None if field.required => {
+                    return Err(format!("The \"{}\" field cannot be empty", field.name));
+                } // end None
+                None => continue,
+            }; // end match
+
+            values.insert(field.name.clone(), field.parse(raw)?);
+        } // end for
+
+        Ok(values)
+    } // end fn validate
+
+    /// This function describes the custom fields as a JSON schema.
+    fn schema(&self) -> ObjectBuilder {
+        let mut schema = ObjectBuilder::new()
+            .schema_type(SchemaType::Object)
+            .description(self.description.clone());
+
+        for field in &self.fields {
+            let schema_type = match field.field_type {
+                FieldType::Integer => SchemaType::Integer,
+                FieldType::Number => SchemaType::Number,
+                FieldType::Boolean => SchemaType::Boolean,
+                _ => SchemaType::String,
+            }; // end match
+            let format = match field.field_type {
+                FieldType::Email => Some(SchemaFormat::Custom("email".to_string())),
+                FieldType::Url => Some(SchemaFormat::Custom("uri".to_string())),
+                _ => None,
+            }; // end match
+
+            let mut property = ObjectBuilder::new()
+                .schema_type(schema_type)
+                .format(format)
+                .title(field.label.clone())
+                .min_length(field.min_length)
+                .max_length(field.max_length)
+                .minimum(field.min)
+                .maximum(field.max)
+                .pattern(field.pattern.clone());
+            if let Some(options) = &field.options {
+                property = property.enum_values(Some(options.clone()));
+            } // end if
+
+            schema = schema.property(&field.name, property);
+            if field.required {
+                schema = schema.required(&field.name);
+            } // end if
+        } // end for
+
+        schema
+    } // end fn schema
+} // end impl FormDefinition
+
+impl FieldDefinition {
+    /// This function checks a submitted value and converts it to its type.
+    fn parse(&self, raw: &str) -> Result<Value, String> {
+        let invalid = || format!("The \"{}\" field is not valid", self.name);
+
+        let value = match self.field_type {
+            FieldType::Integer => {
+                let number = raw.parse::<i64>().map_err(|_error| invalid())?;
+                self.check_range(number as f64)?;
+                Value::from(number)
+            } // end Integer
+            FieldType::Number => {
+                let number = raw.parse::<f64>().map_err(|_error| invalid())?;
+                self.check_range(number)?;
+                Value::Number(Number::from_f64(number).ok_or_else(invalid)?)
+            } // end Number
+            FieldType::Boolean => match raw {
+                "true" | "on" | "1" | "yes" => Value::Bool(true),
+                "false" | "off" | "0" | "no" => Value::Bool(false),
+                _ => return Err(invalid()),
+            }, // end Boolean
+            FieldType::Email => {
+                let (local, domain) = raw.split_once('@').ok_or_else(invalid)?;
+                if local.is_empty() || !domain.contains('.') {
+                    return Err(invalid());
+                } // end if
+                Value::from(raw)
+            } // end Email
+            FieldType::Url => {
+                if !raw.starts_with("http://") && !raw.starts_with("https://") {
+                    return Err(invalid());
+                } // end if
+                Value::from(raw)
+            } // end Url
+            FieldType::Choice => {
+                let options = self.options.as_deref().unwrap_or_default();
+                if !options.iter().any(|option| option == raw) {
+                    return Err(invalid());
+                } // end if
+                Value::from(raw)
+            } // end Choice
+            FieldType::Text => Value::from(raw),
+        }; // end match
+
+        // Check the text rules.
+        if let Value::String(text) = &value {
+            let length = text.chars().count();
+            if self.min_length.is_some_and(|min| length < min) {
+                return Err(format!("The \"{}\" field is too short", self.name));
+            } // end if
+            if self.max_length.is_some_and(|max| length > max) {
+                return Err(format!("The \"{}\" field is too long", self.name));
+            } // end if
+            if let Some(pattern) = &self.compiled_pattern {
+                if !pattern.is_match(text) {
+                    return Err(invalid());
+                } // end if
+            } // end if
+        } // end if
+
+        Ok(value)
+    } // end fn parse
+
+    /// This function checks the limits of a numeric value.
+    fn check_range(&self, number: f64) -> Result<(), String> {
+        if self.min.is_some_and(|min| number < min) || self.max.is_some_and(|max| number > max) {
+            return Err(format!("The \"{}\" field is out of range", self.name));
+        } // end if
+
+        Ok(())
+    } // end fn check_range
+} // end impl FieldDefinition
+
+/// This struct is an extractor of url-encoded forms that have
+/// custom fields. It contains the fixed fields parsed into "data"
+/// and all the submitted fields as they are.
+pub struct FormSubmission<T> {
+    pub data: T,
+    pub fields: Vec<(String, String)>,
+} // end struct FormSubmission
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for FormSubmission<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: Send + 'static,
+    Bytes: FromRequest<S, B>,
+{
+    type Rejection = DefaultResponse;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        // This is a response for all the bodies that cannot be parsed.
+        let invalid = |message: String| DefaultResponse {
+            status_code: StatusCode::UNPROCESSABLE_ENTITY,
+            message: Some(message),
+            redirect: None,
+        }; // end invalid
+
+        let is_form = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+        if !is_form {
+            return Err(DefaultResponse {
+                status_code: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                message: Some(
+                    "Form requests must have `Content-Type: application/x-www-form-urlencoded`"
+                        .to_string(),
+                ),
+                redirect: None,
+            }); // end return
+        } // end if
+
+        let body = Bytes::from_request(request, state)
+            .await
+            .map_err(|_error| invalid("Failed to read the request body".to_string()))?;
+
+        Ok(Self {
+            data: serde_urlencoded::from_bytes(&body)
+                .map_err(|error| invalid(error.to_string()))?,
+            fields: serde_urlencoded::from_bytes(&body)
+                .map_err(|error| invalid(error.to_string()))?,
+        }) // end Ok
+    } // end fn from_request
+} // end impl FromRequest
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_fields_are_validated() {
+        let definitions = FormDefinitions::parse(
+            r#"{"default": {"fields": [
+                {"name": "company", "type": "text", "required": true, "max_length": 5},
+                {"name": "team_size", "type": "integer", "min": 1},
+                {"name": "role", "type": "choice", "options": ["CEO", "CTO"]},
+                {"name": "code", "type": "text", "pattern": "[A-Z]{2}"}
+            ]}}"#,
+        )
+        .unwrap();
+        let form = definitions.get(DEFAULT_FORM).unwrap();
+        let fields = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+
+        let values = form
+            .validate(&fields(&[
+                ("company", "Acme"),
+                ("team_size", "12"),
+                ("extra", "x"),
+            ]))
+            .unwrap();
+        assert_eq!(
+            Value::Object(values),
+            serde_json::json!({"company": "Acme", "team_size": 12})
+        );
+
+        assert!(form.validate(&fields(&[("company", "")])).is_err());
+        assert!(form.validate(&fields(&[("company", "Acme Inc")])).is_err());
+        assert!(form
+            .validate(&fields(&[("company", "Acme"), ("team_size", "0")]))
+            .is_err());
+        assert!(form
+            .validate(&fields(&[("company", "Acme"), ("role", "CFO")]))
+            .is_err());
+        assert!(form
+            .validate(&fields(&[("company", "Acme"), ("code", "ABC")]))
+            .is_err());
+
+        // A choice needs something to choose from.
+        assert!(FormDefinitions::parse(
+            r#"{"x": {"fields": [{"name": "role", "type": "choice", "options": []}]}}"#
+        )
+        .is_err());
+        assert!(FormDefinitions::parse(
+            r#"{"x": {"fields": [{"name": "role", "type": "choice"}]}}"#
+        )
+        .is_err());
+
+        // The fixed fields cannot be redeclared.
+        assert!(FormDefinitions::parse(
+            r#"{"x": {"fields": [{"name": "email", "type": "email"}]}}"#
+        )
+        .is_err());
+    }
+}
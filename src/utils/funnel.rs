@@ -0,0 +1,523 @@
+// This file contains the conversion funnel of the users.
+//
+// The stages are "subscribed" (a new lead from a landing form),
+// "confirmed" (the subscription), "registered" and "first_login".
+// Every user reaches a stage once, which is recorded in "funnel_events",
+// and the rollup "funnel_daily" counts the users by day (UTC), stage,
+// form, campaign (utm_campaign) and country (of the phone number).
+// The reports read the rollup only, so they stay fast however many
+// users there are.
+//
+// The users that came before the funnel are recorded by the subcommand
+// "backfill-funnel" at the stages they have reached, but they are not
+// counted in the rollup, which starts with the upgrade.
+//
+// NOTE: A rate is the number of a stage in a period divided by the
+// number of the previous stage in the same period, the users are not
+// followed from one period to another.
+
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, Duration, NaiveDate, Utc};
+use diesel::pg::Pg;
+use diesel::sql_types::{BigInt, Date, Nullable, Text};
+use diesel::{
+    ExpressionMethods, NullableExpressionMethods, OptionalExtension, QueryDsl, QueryResult,
+    QueryableByName,
+};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::schema::{forms, funnel_daily, funnel_events, lead_attribution, users};
+
+use super::phones;
+
+// These are the stages of the funnel in their order.
+pub const SUBSCRIBED: &str = "subscribed";
+pub const CONFIRMED: &str = "confirmed";
+pub const REGISTERED: &str = "registered";
+pub const FIRST_LOGIN: &str = "first_login";
+
+// The number of the days reported if the period is not set.
+const DEFAULT_DAYS: i64 = 30;
+
+// The number of the weeks reported if the period is not set.
+const DEFAULT_WEEKS: i64 = 12;
+
+/// This enum contains the periods the users are counted by.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FunnelInterval {
+    #[default]
+    Day,
+    // The weeks start on Monday.
+    Week,
+} // end enum FunnelInterval
+
+/// This enum contains the breakdowns of the funnel.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FunnelBreakdown {
+    Form,
+    Campaign,
+    Country,
+} // end enum FunnelBreakdown
+
+impl FunnelBreakdown {
+    /// This function returns the column of the rollup.
+    fn column(self) -> &'static str {
+        match self {
+            FunnelBreakdown::Form => "form",
+            FunnelBreakdown::Campaign => "campaign",
+            FunnelBreakdown::Country => "country",
+        } // end match
+    } // end fn column
+} // end impl FunnelBreakdown
+
+/// This struct represents the settings of the funnel report.
+#[derive(Deserialize, IntoParams, Default, Debug)]
+pub struct FunnelQuery {
+    /// The periods the users are counted by, "day" by default.
+    #[serde(default)]
+    #[param(inline)]
+    pub interval: FunnelInterval,
+    /// The first day of the report, 30 days or 12 weeks ago by default.
+    pub from: Option<NaiveDate>,
+    /// The last day of the report, today by default.
+    pub to: Option<NaiveDate>,
+    /// The users are counted by "form", "campaign" or "country" as well.
+    #[param(inline)]
+    pub by: Option<FunnelBreakdown>,
+    /// Only the users of this form are counted.
+    pub form: Option<String>,
+    /// Only the users of this campaign (utm_campaign) are counted.
+    pub campaign: Option<String>,
+    /// Only the users of this country (e.g. "RU") are counted.
+    pub country: Option<String>,
+} // end struct FunnelQuery
+
+impl FunnelQuery {
+    /// This function returns the first and the last day of the report.
+    pub fn period(&self) -> Result<(NaiveDate, NaiveDate), String> {
+        let to = self.to.unwrap_or_else(|| Utc::now().date_naive());
+        let from = self.from.unwrap_or_else(|| match self.interval {
+            FunnelInterval::Day => to - Duration::days(DEFAULT_DAYS - 1),
+            FunnelInterval::Week => to - Duration::weeks(DEFAULT_WEEKS - 1),
+        }); // end unwrap_or_else
+
+        if from > to {
+            return Err("The report must not end before it starts".to_string());
+        } // end if
+
+        // The weeks are reported whole.
+        let from = match self.interval {
+            FunnelInterval::Day => from,
+            FunnelInterval::Week => {
+                from - Duration::days(from.weekday().num_days_from_monday().into())
+            }
+        }; // end match
+
+        Ok((from, to))
+    } // end fn period
+} // end impl FunnelQuery
+
+/// This struct contains the numbers of the stages and the rates between them.
+#[derive(Serialize, ToSchema, Debug, Default, PartialEq)]
+pub struct FunnelStages {
+    #[schema(example = 120)]
+    pub subscribed: i64,
+    #[schema(example = 84)]
+    pub confirmed: i64,
+    #[schema(example = 21)]
+    pub registered: i64,
+    #[schema(example = 15)]
+    pub first_login: i64,
+    // confirmed / subscribed, None if nobody has subscribed.
+    #[schema(example = 0.7)]
+    pub confirmation_rate: Option<f64>,
+    // registered / confirmed.
+    #[schema(example = 0.25)]
+    pub registration_rate: Option<f64>,
+    // first_login / registered.
+    #[schema(example = 0.7143)]
+    pub first_login_rate: Option<f64>,
+} // end struct FunnelStages
+
+impl FunnelStages {
+    /// This function returns the numbers together with the rates.
+    fn new(subscribed: i64, confirmed: i64, registered: i64, first_login: i64) -> Self {
+        // This is a helper function that returns a rate rounded to 4 places.
+        fn rate(stage: i64, previous: i64) -> Option<f64> {
+            (previous > 0).then(|| (stage as f64 / previous as f64 * 10000.0).round() / 10000.0)
+        } // end fn rate
+
+        FunnelStages {
+            subscribed,
+            confirmed,
+            registered,
+            first_login,
+            confirmation_rate: rate(confirmed, subscribed),
+            registration_rate: rate(registered, confirmed),
+            first_login_rate: rate(first_login, registered),
+        }
+    } // end fn new
+} // end impl FunnelStages
+
+/// This struct represents the users of a period (and of a segment).
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct FunnelRow {
+    // The first day of the period.
+    pub period: NaiveDate,
+    // The form, the campaign or the country if the report is broken
+    // down by it, None for the users for whom it is not known.
+    #[schema(example = "default")]
+    pub segment: Option<String>,
+    #[serde(flatten)]
+    pub stages: FunnelStages,
+} // end struct FunnelRow
+
+/// This struct represents the users of the whole report (of a segment).
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct FunnelTotal {
+    #[schema(example = "default")]
+    pub segment: Option<String>,
+    #[serde(flatten)]
+    pub stages: FunnelStages,
+} // end struct FunnelTotal
+
+/// This struct represents the funnel report.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct FunnelReport {
+    pub interval: FunnelInterval,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    // The periods without users are absent.
+    pub rows: Vec<FunnelRow>,
+    pub totals: Vec<FunnelTotal>,
+} // end struct FunnelReport
+
+/// This struct represents a row of the rollup summed up by period.
+#[derive(QueryableByName, Debug)]
+struct RollupRow {
+    #[diesel(sql_type = Date)]
+    period: NaiveDate,
+    #[diesel(sql_type = Nullable<Text>)]
+    segment: Option<String>,
+    #[diesel(sql_type = BigInt)]
+    subscribed: i64,
+    #[diesel(sql_type = BigInt)]
+    confirmed: i64,
+    #[diesel(sql_type = BigInt)]
+    registered: i64,
+    #[diesel(sql_type = BigInt)]
+    first_login: i64,
+} // end struct RollupRow
+
+/// This function records that a user has reached a stage and counts
+/// them in the rollup. It returns false if they had reached it before.
+pub async fn record(conn: &mut AsyncPgConnection, user_id: i32, stage: &str) -> QueryResult<bool> {
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let inserted = diesel::insert_into(funnel_events::table)
+                .values((
+                    funnel_events::user_id.eq(user_id),
+                    funnel_events::stage.eq(stage),
+                ))
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .await?;
+            if inserted == 0 {
+                return Ok(false);
+            } // end if
+
+            let Some((phone_number_code, phone_number, form, campaign)) = users::table
+                .left_join(forms::table)
+                .left_join(lead_attribution::table)
+                .filter(users::id.eq(user_id))
+                .select((
+                    users::phone_number_code,
+                    users::phone_number,
+                    forms::slug.nullable(),
+                    lead_attribution::utm_campaign.nullable(),
+                ))
+                .first::<(i32, String, Option<String>, Option<String>)>(conn)
+                .await
+                .optional()?
+            else {
+                return Ok(false);
+            };
+            let country = phones::parse(phone_number_code, &phone_number)
+                .ok()
+                .and_then(|phone| phone.region);
+
+            diesel::insert_into(funnel_daily::table)
+                .values((
+                    funnel_daily::day.eq(Utc::now().date_naive()),
+                    funnel_daily::stage.eq(stage),
+                    funnel_daily::form.eq(form.unwrap_or_default()),
+                    funnel_daily::campaign.eq(campaign.unwrap_or_default()),
+                    funnel_daily::country.eq(country.unwrap_or_default()),
+                    funnel_daily::count.eq(1),
+                ))
+                .on_conflict((
+                    funnel_daily::day,
+                    funnel_daily::stage,
+                    funnel_daily::form,
+                    funnel_daily::campaign,
+                    funnel_daily::country,
+                ))
+                .do_update()
+                .set(funnel_daily::count.eq(funnel_daily::count + 1))
+                .execute(conn)
+                .await?;
+
+            Ok(true)
+        }
+        .scope_boxed()
+    })
+    .await
+} // end fn record
+
+/// This function records the stages the existing users have reached,
+/// so that they are not counted in the rollup when they come back.
+/// It returns the number of the recorded stages.
+/// NOTE: The moments of the registration and of the first login are
+/// not stored, the registered users are taken as logged in, since the
+/// registration logs them in, and both are dated by the subscription.
+pub async fn backfill(conn: &mut AsyncPgConnection) -> QueryResult<usize> {
+    diesel::sql_query(
+        "INSERT INTO funnel_events (user_id, stage, created_at) \
+        SELECT id, $1, created_at FROM users \
+        UNION ALL SELECT id, $2, confirmed_at FROM users WHERE confirmed_at IS NOT NULL \
+        UNION ALL SELECT id, $3, created_at FROM users WHERE verified \
+        UNION ALL SELECT id, $4, created_at FROM users WHERE verified \
+        ON CONFLICT DO NOTHING",
+    )
+    .bind::<Text, _>(SUBSCRIBED)
+    .bind::<Text, _>(CONFIRMED)
+    .bind::<Text, _>(REGISTERED)
+    .bind::<Text, _>(FIRST_LOGIN)
+    .execute(conn)
+    .await
+} // end fn backfill
+
+/// This function sums up the rows of the periods by segment.
+fn totals(rows: &[FunnelRow]) -> Vec<FunnelTotal> {
+    let mut totals = BTreeMap::<Option<String>, [i64; 4]>::new();
+    for row in rows {
+        let total = totals.entry(row.segment.clone()).or_default();
+        total[0] += row.stages.subscribed;
+        total[1] += row.stages.confirmed;
+        total[2] += row.stages.registered;
+        total[3] += row.stages.first_login;
+    } // end for
+
+    totals
+        .into_iter()
+        .map(
+            |(segment, [subscribed, confirmed, registered, first_login])| FunnelTotal {
+                segment,
+                stages: FunnelStages::new(subscribed, confirmed, registered, first_login),
+            },
+        )
+        .collect()
+} // end fn totals
+
+/// This function reports the users that have reached the stages
+/// of the funnel in the period of the query.
+pub async fn report(
+    conn: &mut AsyncPgConnection,
+    query: &FunnelQuery,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> QueryResult<FunnelReport> {
+    let interval = match query.interval {
+        FunnelInterval::Day => "day",
+        FunnelInterval::Week => "week",
+    }; // end match
+    let segment = query
+        .by
+        .map(|by| format!("NULLIF({}, '')", by.column()))
+        .unwrap_or_else(|| "NULL::TEXT".to_string());
+
+    let mut sql = format!(
+        "SELECT DATE_TRUNC('{interval}', day)::DATE AS period, {segment} AS segment, \
+        COALESCE(SUM(count) FILTER (WHERE stage = '{SUBSCRIBED}'), 0) AS subscribed, \
+        COALESCE(SUM(count) FILTER (WHERE stage = '{CONFIRMED}'), 0) AS confirmed, \
+        COALESCE(SUM(count) FILTER (WHERE stage = '{REGISTERED}'), 0) AS registered, \
+        COALESCE(SUM(count) FILTER (WHERE stage = '{FIRST_LOGIN}'), 0) AS first_login \
+        FROM funnel_daily WHERE day >= $1 AND day <= $2"
+    );
+    let filters = [
+        ("form", query.form.clone()),
+        ("campaign", query.campaign.clone()),
+        ("country", query.country.as_deref().map(str::to_uppercase)),
+    ]
+    .into_iter()
+    .filter_map(|(column, value)| value.map(|value| (column, value)))
+    .collect::<Vec<_>>();
+    for (number, (column, _value)) in filters.iter().enumerate() {
+        sql.push_str(&format!(" AND {} = ${}", column, number + 3));
+    } // end for
+    sql.push_str(" GROUP BY 1, 2 ORDER BY 1, 2");
+
+    let mut statement = diesel::sql_query(sql)
+        .into_boxed::<Pg>()
+        .bind::<Date, _>(from)
+        .bind::<Date, _>(to);
+    for (_column, value) in filters {
+        statement = statement.bind::<Text, _>(value);
+    } // end for
+
+    let rows = statement
+        .load::<RollupRow>(conn)
+        .await?
+        .into_iter()
+        .map(|row| FunnelRow {
+            period: row.period,
+            segment: row.segment,
+            stages: FunnelStages::new(
+                row.subscribed,
+                row.confirmed,
+                row.registered,
+                row.first_login,
+            ),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(FunnelReport {
+        interval: query.interval,
+        from,
+        to,
+        totals: totals(&rows),
+        rows,
+    })
+} // end fn report
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::utils::test_db;
+
+    #[test]
+    fn funnel_is_summed_up_with_rates() {
+        let day = |day| NaiveDate::from_ymd_opt(2024, 1, day).unwrap();
+        let rows = [
+            FunnelRow {
+                period: day(1),
+                segment: Some("default".to_string()),
+                stages: FunnelStages::new(10, 5, 1, 0),
+            },
+            FunnelRow {
+                period: day(2),
+                segment: Some("default".to_string()),
+                stages: FunnelStages::new(10, 7, 2, 3),
+            },
+            FunnelRow {
+                period: day(2),
+                segment: None,
+                stages: FunnelStages::new(0, 0, 4, 1),
+            },
+        ];
+
+        assert_eq!(
+            totals(&rows),
+            vec![
+                FunnelTotal {
+                    segment: None,
+                    stages: FunnelStages {
+                        subscribed: 0,
+                        confirmed: 0,
+                        registered: 4,
+                        first_login: 1,
+                        confirmation_rate: None,
+                        registration_rate: None,
+                        first_login_rate: Some(0.25),
+                    },
+                },
+                FunnelTotal {
+                    segment: Some("default".to_string()),
+                    stages: FunnelStages::new(20, 12, 3, 3),
+                },
+            ]
+        );
+        assert_eq!(
+            FunnelStages::new(3, 2, 0, 0).confirmation_rate,
+            Some(0.6667)
+        );
+
+        // The weeks are reported whole.
+        let query = FunnelQuery {
+            interval: FunnelInterval::Week,
+            from: Some(day(3)),
+            to: Some(day(20)),
+            ..Default::default()
+        };
+        assert_eq!(query.period(), Ok((day(1), day(20))));
+        let query = FunnelQuery {
+            from: Some(day(21)),
+            to: Some(day(20)),
+            ..Default::default()
+        };
+        assert!(query.period().is_err());
+    }
+
+    #[tokio::test]
+    async fn existing_users_are_backfilled_without_the_rollup() {
+        let mut conn = test_db::connection().await;
+
+        let mut ids = Vec::new();
+        for (index, confirmed, verified) in [(0, false, false), (1, true, false), (2, true, true)] {
+            let user_id = diesel::insert_into(users::table)
+                .values((
+                    users::name.eq("John"),
+                    users::phone_number_code.eq(1),
+                    users::phone_number.eq(format!("+1202555026{}", index)),
+                    users::confirmed_at.eq(confirmed.then(Utc::now)),
+                    users::verified.eq(verified),
+                ))
+                .returning(users::id)
+                .get_result::<i32>(&mut conn)
+                .await
+                .unwrap();
+            ids.push(user_id);
+        } // end for
+          // This is a helper function that returns the number of the
+          // users counted in the rollup.
+        async fn counted(conn: &mut AsyncPgConnection) -> Option<i64> {
+            funnel_daily::table
+                .select(diesel::dsl::sum(funnel_daily::count))
+                .first::<Option<i64>>(conn)
+                .await
+                .unwrap()
+        } // end fn counted
+
+        // This is a helper function that returns the stages of a user.
+        async fn stages(conn: &mut AsyncPgConnection, user_id: i32) -> Vec<String> {
+            funnel_events::table
+                .filter(funnel_events::user_id.eq(user_id))
+                .select(funnel_events::stage)
+                .order_by(funnel_events::stage)
+                .load::<String>(conn)
+                .await
+                .unwrap()
+        } // end fn stages
+
+        let before = counted(&mut conn).await;
+        backfill(&mut conn).await.unwrap();
+
+        assert_eq!(stages(&mut conn, ids[0]).await, vec![SUBSCRIBED]);
+        assert_eq!(stages(&mut conn, ids[1]).await, vec![CONFIRMED, SUBSCRIBED]);
+        assert_eq!(
+            stages(&mut conn, ids[2]).await,
+            vec![CONFIRMED, FIRST_LOGIN, REGISTERED, SUBSCRIBED]
+        );
+        assert_eq!(counted(&mut conn).await, before);
+
+        // The login of a backfilled user is not their first one.
+        assert!(!record(&mut conn, ids[2], FIRST_LOGIN).await.unwrap());
+    }
+}
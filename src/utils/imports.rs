@@ -0,0 +1,523 @@
+// This file contains the bulk import of the leads from CSV files,
+// e.g. the lists of the attendees of an event.
+//
+// Every row goes through the same checks as a submission of the form
+// and is merged into the lead with the same phone number or email if
+// there is one, the result of every row is reported. A dry run makes
+// the same changes in a transaction that is rolled back, so that its
+// report is exactly the report of the real import.
+//
+// The columns are imported as the fields with the same names unless
+// they are mapped, e.g. "name:Full name,email:E-mail" imports the
+// "Full name" column as the name and the "E-mail" column as the email.
+//
+// NOTE: The imported leads get no emails. The new ones stay pending,
+// since they have not confirmed the subscription themselves, unless
+// an Admin confirms them with the provenance of the consents (e.g.
+// "The signup sheet of the meetup on 2024-05-01"). The provenance
+// is kept in the audit log and the consents to marketing are recorded.
+
+use std::collections::HashMap;
+
+use axum::http::StatusCode;
+use chrono::Utc;
+use diesel::result::Error;
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+use serde_json::{json, Value};
+use utoipa::ToSchema;
+
+use crate::{
+    models::{LeadForm, NewAuditEntry, NewUser},
+    routes::insert::is_valid_form,
+    schema::users,
+};
+
+use super::{
+    audit,
+    consents::{self, ConsentContext},
+    emails::EmailPolicy,
+    forms::FormDefinition,
+    funnel,
+    lead_forms::check_required_fields,
+    leads::{self, upsert_lead},
+};
+
+/// This struct maps the fields of the leads to the columns of a file
+/// (or to the properties of the contacts in a CRM).
+#[derive(Debug, Default, Clone)]
+pub struct ColumnMapping(HashMap<String, String>);
+
+impl ColumnMapping {
+    /// This function parses a mapping like "name:Full name,email:E-mail".
+    pub fn parse(mapping: &str) -> Result<Self, String> {
+        let mut columns = HashMap::new();
+
+        for pair in mapping.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (field, column) = pair
+                .split_once(':')
+                .map(|(field, column)| (field.trim(), column.trim()))
+                .filter(|(field, column)| !field.is_empty() && !column.is_empty())
+                .ok_or_else(|| {
+                    format!("The column mapping \"{}\" is not \"field:column\"", pair)
+                })?;
+
+            if columns
+                .insert(field.to_string(), column.to_string())
+                .is_some()
+            {
+                return Err(format!("The field \"{}\" is mapped twice", field));
+            } // end if
+        } // end for
+
+        Ok(ColumnMapping(columns))
+    } // end fn parse
+
+    /// This function returns the field a column is imported as.
+    fn field<'a>(&'a self, column: &'a str) -> &'a str {
+        self.0
+            .iter()
+            .find(|(_field, mapped)| mapped.as_str() == column)
+            .map_or(column, |(field, _mapped)| field.as_str())
+    } // end fn field
+
+    /// This function returns the column a field is exported as.
+    pub fn column<'a>(&'a self, field: &'a str) -> &'a str {
+        self.0.get(field).map_or(field, String::as_str)
+    } // end fn column
+} // end impl ColumnMapping
+
+/// This struct contains the settings of an import.
+#[derive(Debug, Default)]
+pub struct ImportOptions {
+    pub columns: ColumnMapping,
+    // The code for the rows without the "phone_number_code" column.
+    pub phone_number_code: Option<i32>,
+    // The changes are rolled back.
+    pub dry_run: bool,
+    // The user who imports the leads, if it is done over HTTP.
+    pub imported_by: Option<i32>,
+    // Where the consents of the leads have been collected, the new
+    // leads are confirmed only if it is given.
+    pub consent: Option<String>,
+} // end struct ImportOptions
+
+/// This enum contains the results of importing a row.
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    // A new lead has been created.
+    Imported,
+    // The row has been merged into an existing lead.
+    Merged,
+    Rejected,
+} // end enum ImportStatus
+
+/// This struct represents the result of importing a row.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct ImportedRow {
+    // The line of the file, the header is line 1.
+    #[schema(example = 2)]
+    pub line: u64,
+    pub status: ImportStatus,
+    #[schema(example = 15)]
+    pub user_id: Option<i32>,
+    // The reason the row has been rejected.
+    #[schema(example = "The phone number is not valid")]
+    pub reason: Option<String>,
+} // end struct ImportedRow
+
+/// This struct represents the report of an import.
+#[derive(Serialize, ToSchema, Debug, Default)]
+pub struct ImportReport {
+    // Nothing has been saved.
+    pub dry_run: bool,
+    #[schema(example = 120)]
+    pub imported: usize,
+    #[schema(example = 14)]
+    pub merged: usize,
+    #[schema(example = 3)]
+    pub rejected: usize,
+    pub rows: Vec<ImportedRow>,
+} // end struct ImportReport
+
+/// This enum contains the reasons an import fails as a whole.
+#[derive(Debug)]
+pub enum ImportError {
+    // The file is not a CSV file with a header.
+    InvalidFile(String),
+    Database(Error),
+} // end enum ImportError
+
+// This is a row of a file, i.e. its line and its fields
+// or the reason it cannot be read.
+type FileRow = (u64, Result<Vec<(String, String)>, String>);
+
+/// This function reads the rows of a file as the fields of the leads.
+/// The rows that cannot be read are returned as errors.
+fn read_rows(file: &[u8], columns: &ColumnMapping) -> Result<Vec<FileRow>, ImportError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(file);
+
+    let fields = reader
+        .headers()
+        .map_err(|error| ImportError::InvalidFile(error.to_string()))?
+        .iter()
+        .map(|column| columns.field(column).to_string())
+        .collect::<Vec<_>>();
+
+    Ok(reader
+        .records()
+        .map(|record| match record {
+            Ok(record) => (
+                record.position().map_or(0, |position| position.line()),
+                Ok(fields
+                    .iter()
+                    .cloned()
+                    .zip(record.iter().map(String::from))
+                    .collect()),
+            ),
+            Err(error) => (
+                error.position().map_or(0, |position| position.line()),
+                Err(format!("The row cannot be read: {}", error)),
+            ),
+        })
+        .collect())
+} // end fn read_rows
+
+/// This function checks a row the same way as a submission of the form.
+/// It returns the lead and its custom fields.
+async fn validate_row(
+    fields: &[(String, String)],
+    form: &LeadForm,
+    definition: Option<&FormDefinition>,
+    emails: &EmailPolicy,
+    options: &ImportOptions,
+) -> Result<(NewUser, Value), String> {
+    let value = |name: &str| {
+        fields
+            .iter()
+            .find(|(field, value)| field == name && !value.is_empty())
+            .map(|(_field, value)| value.clone())
+    };
+
+    let phone_number_code = match value("phone_number_code") {
+        Some(code) => code
+            .trim_start_matches('+')
+            .parse::<i32>()
+            .map_err(|_error| "The phone number code is invalid".to_string())?,
+        None => options
+            .phone_number_code
+            .ok_or_else(|| "The phone number code is missing".to_string())?,
+    }; // end match
+    let mut user = NewUser {
+        name: value("name").unwrap_or_default(),
+        email: value("email"),
+        phone_number_code,
+        phone_number: value("phone_number").unwrap_or_default(),
+        password: None,
+    }; // end NewUser
+
+    let (status, message) = is_valid_form(&mut user);
+    if status != StatusCode::OK {
+        return Err(message);
+    } // end if
+    if let Some(email) = &user.email {
+        emails.check(email).await?;
+    } // end if
+    check_required_fields(form, fields)?;
+
+    let custom_fields = match definition.map(|definition| definition.validate(fields)) {
+        Some(values) => Value::Object(values?),
+        None => json!({}),
+    }; // end match
+
+    Ok((user, custom_fields))
+} // end fn validate_row
+
+/// This function imports the leads from a CSV file into a form.
+pub async fn import_leads(
+    conn: &mut AsyncPgConnection,
+    file: &[u8],
+    form: &LeadForm,
+    definition: Option<&FormDefinition>,
+    emails: &EmailPolicy,
+    options: &ImportOptions,
+) -> Result<ImportReport, ImportError> {
+    // The rows are checked before the transaction,
+    // since the checks of the emails can take a while.
+    let mut candidates = Vec::new();
+    for (line, fields) in read_rows(file, &options.columns)? {
+        let candidate = match fields {
+            Ok(fields) => validate_row(&fields, form, definition, emails, options).await,
+            Err(reason) => Err(reason),
+        }; // end match
+        candidates.push((line, candidate));
+    } // end for
+
+    let mut report = ImportReport {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+    let rows = &mut report.rows;
+    let result = conn
+        .transaction::<(), Error, _>(|conn| {
+            async move {
+                for (line, candidate) in candidates {
+                    let (user, custom_fields) = match candidate {
+                        Ok(lead) => lead,
+                        Err(reason) => {
+                            rows.push(ImportedRow {
+                                line,
+                                status: ImportStatus::Rejected,
+                                user_id: None,
+                                reason: Some(reason),
+                            });
+                            continue;
+                        } // end Err
+                    }; // end match
+
+                    let lead =
+                        upsert_lead(conn, &user, &custom_fields, form.id, leads::FROM_IMPORT)
+                            .await?;
+                    if lead.created && options.consent.is_some() {
+                        diesel::update(users::table.find(lead.user.id))
+                            .set(users::confirmed_at.eq(Utc::now()))
+                            .execute(conn)
+                            .await?;
+                        consents::record(
+                            conn,
+                            lead.user.id,
+                            &[(consents::MARKETING, true)],
+                            consents::FROM_IMPORT,
+                            &ConsentContext::default(),
+                        )
+                        .await?;
+                    } // end if
+
+                    // Count the new lead in the funnel, and its confirmation.
+                    // NOTE: The import does not fail if it cannot be done,
+                    // the stages are recorded in savepoints of their own.
+                    if lead.created {
+                        let mut stages = vec![funnel::SUBSCRIBED];
+                        if options.consent.is_some() {
+                            stages.push(funnel::CONFIRMED);
+                        } // end if
+                        for stage in stages {
+                            if let Err(error) = funnel::record(conn, lead.user.id, stage).await {
+                                eprintln!("{}", error);
+                            } // end if
+                        } // end for
+                    } // end if
+
+                    rows.push(ImportedRow {
+                        line,
+                        status: if lead.created {
+                            ImportStatus::Imported
+                        } else {
+                            ImportStatus::Merged
+                        },
+                        user_id: Some(lead.user.id),
+                        reason: None,
+                    });
+                } // end for
+
+                if options.dry_run {
+                    return Err(Error::RollbackTransaction);
+                } // end if
+
+                let count = |status| rows.iter().filter(|row| row.status == status).count();
+                audit::record(
+                    conn,
+                    &[NewAuditEntry {
+                        user_id: None,
+                        action: "leads.imported".to_string(),
+                        details: json!({
+                            "form": form.slug,
+                            "imported": count(ImportStatus::Imported),
+                            "merged": count(ImportStatus::Merged),
+                            "rejected": count(ImportStatus::Rejected),
+                            "imported_by": options.imported_by,
+                            "consent": options.consent,
+                        }),
+                    }],
+                )
+                .await
+            }
+            .scope_boxed()
+        })
+        .await;
+
+    match result {
+        Ok(()) => {}
+        Err(Error::RollbackTransaction) if options.dry_run => {}
+        Err(error) => return Err(ImportError::Database(error)),
+    } // end match
+
+    for row in &report.rows {
+        match row.status {
+            ImportStatus::Imported => report.imported += 1,
+            ImportStatus::Merged => report.merged += 1,
+            ImportStatus::Rejected => report.rejected += 1,
+        } // end match
+    } // end for
+
+    Ok(report)
+} // end fn import_leads
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::utils::test_db::connection;
+
+    #[test]
+    fn columns_are_mapped_to_fields() {
+        let columns = ColumnMapping::parse("name:Full name, email:E-mail").unwrap();
+        let file = "Full name,E-mail,phone_number,company\n\
+            John,john@example.com,(201) 555-0123,Acme\n\
+            \"Doe, Jane\",jane@example.com\n";
+
+        let rows = read_rows(file.as_bytes(), &columns).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].0, 2);
+        assert_eq!(
+            rows[0].1.as_ref().unwrap(),
+            &[
+                ("name".to_string(), "John".to_string()),
+                ("email".to_string(), "john@example.com".to_string()),
+                ("phone_number".to_string(), "(201) 555-0123".to_string()),
+                ("company".to_string(), "Acme".to_string()),
+            ]
+        );
+        // The short rows are read as well, they fail the checks later.
+        assert_eq!(rows[1].1.as_ref().unwrap()[0].1, "Doe, Jane");
+
+        assert!(ColumnMapping::parse("name").is_err());
+        assert!(ColumnMapping::parse("name:A,name:B").is_err());
+        assert!(ColumnMapping::parse("").is_ok());
+    }
+
+    /// This is a helper function that finds the default form.
+    async fn default_form(conn: &mut AsyncPgConnection) -> LeadForm {
+        crate::utils::lead_forms::find_form(conn, "default")
+            .await
+            .unwrap()
+            .unwrap()
+    } // end fn default_form
+
+    /// This is a helper function that imports a file into the default form.
+    async fn import(
+        conn: &mut AsyncPgConnection,
+        form: &LeadForm,
+        file: &str,
+        options: &ImportOptions,
+    ) -> ImportReport {
+        let emails = EmailPolicy::new(Default::default(), None);
+        import_leads(conn, file.as_bytes(), form, None, &emails, options)
+            .await
+            .unwrap()
+    } // end fn import
+
+    /// This is a helper function that finds a lead by the phone number.
+    async fn find(conn: &mut AsyncPgConnection, phone_number: &str) -> Option<crate::models::User> {
+        use diesel::OptionalExtension;
+
+        users::table
+            .filter(users::phone_number.eq(phone_number))
+            .first(conn)
+            .await
+            .optional()
+            .unwrap()
+    } // end fn find
+
+    const FILE: &str = "name,email,phone_number\n\
+        John,import@example.com,(202) 555-0190\n\
+        Johnny,,202 555 0190\n\
+        Jane,jane@example.com,555\n";
+
+    #[tokio::test]
+    async fn dry_runs_are_rolled_back() {
+        let mut conn = connection().await;
+        let form = default_form(&mut conn).await;
+        let options = ImportOptions {
+            phone_number_code: Some(1),
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let report = import(&mut conn, &form, FILE, &options).await;
+        assert!(report.dry_run);
+        assert_eq!((report.imported, report.merged, report.rejected), (1, 1, 1));
+        assert_eq!(
+            find(&mut conn, "+12025550190").await.map(|user| user.id),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn every_row_is_reported() {
+        let mut conn = connection().await;
+        let form = default_form(&mut conn).await;
+        let options = ImportOptions {
+            phone_number_code: Some(1),
+            ..Default::default()
+        };
+
+        let report = import(&mut conn, &form, FILE, &options).await;
+        assert!(!report.dry_run);
+        assert_eq!((report.imported, report.merged, report.rejected), (1, 1, 1));
+
+        let lead = find(&mut conn, "+12025550190").await.unwrap();
+        let statuses = report
+            .rows
+            .iter()
+            .map(|row| (row.line, row.status, row.user_id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![
+                (2, ImportStatus::Imported, Some(lead.id)),
+                (3, ImportStatus::Merged, Some(lead.id)),
+                (4, ImportStatus::Rejected, None),
+            ]
+        );
+        assert_eq!(
+            report.rows[2].reason.as_deref(),
+            Some("The phone number is not valid")
+        );
+
+        // The imported leads have not confirmed anything themselves.
+        assert_eq!(lead.email.as_deref(), Some("import@example.com"));
+        assert_eq!(lead.confirmed_at, None);
+        assert!(consents::current(&mut conn, lead.id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn leads_are_confirmed_with_the_provenance() {
+        let mut conn = connection().await;
+        let form = default_form(&mut conn).await;
+        let options = ImportOptions {
+            phone_number_code: Some(1),
+            consent: Some("The signup sheet of the meetup".to_string()),
+            ..Default::default()
+        };
+
+        import(&mut conn, &form, FILE, &options).await;
+
+        let lead = find(&mut conn, "+12025550190").await.unwrap();
+        assert!(lead.confirmed_at.is_some());
+        let in_force = consents::current(&mut conn, lead.id).await.unwrap();
+        assert_eq!(in_force.len(), 1);
+        assert_eq!(in_force[0].purpose, consents::MARKETING);
+        assert!(in_force[0].granted);
+        assert_eq!(in_force[0].source, consents::FROM_IMPORT);
+    }
+}
@@ -1,6 +1,6 @@
 // This file contains the tools for JWT authentication.
 
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use std::env;
@@ -47,20 +47,117 @@ pub fn is_valid_jwt(token: &str) -> (bool, String) {
     let secret = DecodingKey::from_secret(env::var("JWT_SECRET").unwrap().as_bytes());
 
     // Try to decode the token and check whether or not it is valid.
-    match decode::<Claims>(&token, &secret, &Validation::default()) {
+    match decode::<Claims>(token, &secret, &Validation::default()) {
         // Deal with errors
         Err(error) => match error.kind() {
             // This error might occur if the token has already expired.
-            jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
-                return (
-                    false,
-                    "Your session has expired, please log in again".to_string(),
-                );
-            }
+            jsonwebtoken::errors::ErrorKind::ExpiredSignature => (
+                false,
+                "Your session has expired, please log in again".to_string(),
+            ),
             // If any other error occurs, just inform a user about it.
-            _ => return (false, "Something went wrong on the server side".to_string()),
+            _ => (false, "Something went wrong on the server side".to_string()),
         },
         // The token is valid, return OK.
-        Ok(_) => return (true, "OK".to_string()),
+        Ok(_) => (true, "OK".to_string()),
     } // end match
 } // end fn is_valid_jwt
+
+/// This function returns the expiration time of a session JWT.
+/// It returns None if the token is not valid, e.g. if it has been
+/// signed with another secret.
+pub fn jwt_expiry(token: &str) -> Option<DateTime<Utc>> {
+    // Import secret.
+    let secret = DecodingKey::from_secret(env::var("JWT_SECRET").ok()?.as_bytes());
+
+    // The expired tokens are the point here.
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+
+    decode::<Claims>(token, &secret, &validation)
+        .ok()
+        .and_then(|token| Utc.timestamp_opt(token.claims.exp as i64, 0).single())
+} // end fn jwt_expiry
+
+/// This structure represents claims for the signed links
+/// that are sent to users (e.g. in emails).
+#[derive(Serialize, Deserialize)]
+struct LinkClaims {
+    // The purpose of the link, a link for one purpose
+    // cannot be used for another one.
+    aud: String,
+    // The subject of the link, e.g. a user id.
+    sub: String,
+    // Issued at time.
+    iat: usize,
+    // Expiration time, the link never expires if it is absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<usize>,
+} // end struct LinkClaims
+
+/// This function creates a signed token for a link.
+/// If the lifetime is None, then the token never expires.
+/// If token generation is successful, then it returns
+/// a string with the token. Otherwise, it returns None.
+pub fn create_link_token(
+    purpose: &str,
+    subject: &str,
+    lifetime: Option<Duration>,
+) -> Option<String> {
+    // Setup data for claims.
+    let now = Utc::now();
+    let claims = LinkClaims {
+        aud: purpose.to_string(),
+        sub: subject.to_string(),
+        iat: now.timestamp() as usize,
+        exp: lifetime.map(|lifetime| (now + lifetime).timestamp() as usize),
+    }; // end LinkClaims
+
+    // Import secret for encoding the token.
+    let secret = EncodingKey::from_secret(env::var("JWT_SECRET").ok()?.as_bytes());
+
+    encode(&Header::default(), &claims, &secret).ok()
+} // end fn create_link_token
+
+/// This function checks a token of a link created for the purpose.
+/// If the token is valid, then it returns the subject of the link.
+/// Otherwise, it returns None.
+pub fn decode_link_token(token: &str, purpose: &str) -> Option<String> {
+    // Import secret.
+    let secret = DecodingKey::from_secret(env::var("JWT_SECRET").ok()?.as_bytes());
+
+    // The expiration time is checked only if it is present.
+    let mut validation = Validation::default();
+    validation.set_required_spec_claims(&["aud", "sub"]);
+    validation.set_audience(&[purpose]);
+
+    decode::<LinkClaims>(token, &secret, &validation)
+        .ok()
+        .map(|token| token.claims.sub)
+} // end fn decode_link_token
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_token_is_bound_to_its_purpose() {
+        // Other tests may rely on the secret from the environment.
+        if env::var("JWT_SECRET").is_err() {
+            env::set_var("JWT_SECRET", "test-secret");
+        } // end if
+
+        let token = create_link_token("confirm", "42", Some(Duration::hours(1))).unwrap();
+        assert_eq!(decode_link_token(&token, "confirm").as_deref(), Some("42"));
+        assert_eq!(decode_link_token(&token, "unsubscribe"), None);
+
+        let expired = create_link_token("confirm", "42", Some(Duration::hours(-1))).unwrap();
+        assert_eq!(decode_link_token(&expired, "confirm"), None);
+
+        let permanent = create_link_token("unsubscribe", "42", None).unwrap();
+        assert_eq!(
+            decode_link_token(&permanent, "unsubscribe").as_deref(),
+            Some("42")
+        );
+    }
+}
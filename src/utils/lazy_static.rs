@@ -2,8 +2,8 @@ use std::collections::HashSet;
 
 use lazy_static::lazy_static;
 use prometheus::{
-    opts, register_histogram_vec, register_int_counter, register_int_counter_vec, HistogramVec,
-    IntCounter, IntCounterVec,
+    opts, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge_vec, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec,
 };
 
 const HTTP_RESPONSE_TIME_CUSTOM_BUCKETS: &[f64; 14] = &[
@@ -32,12 +32,34 @@ lazy_static! {
     )
     .expect("Cannot create a metric");
 
+    // The number of the form submissions rejected as spam.
+    pub static ref SPAM_REJECTIONS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!("spam_rejections_total", "Form submissions rejected as spam"),
+        &["reason"]
+    )
+    .expect("Cannot create a metric");
+
+    // The number of the rows removed by the retention policies.
+    pub static ref RETENTION_PURGED_ROWS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!("retention_purged_rows_total", "Rows removed by the retention policies"),
+        &["class"]
+    )
+    .expect("Cannot create a metric");
+
+    // The number of the rows that have expired but are still stored,
+    // e.g. the rows a dry run of the purge would remove.
+    pub static ref RETENTION_EXPIRED_ROWS: IntGaugeVec = register_int_gauge_vec!(
+        opts!("retention_expired_rows", "Expired rows that have not been removed"),
+        &["class"]
+    )
+    .expect("Cannot create a metric");
+
     // This static variable stores all the approved paths.
     //
     // WARNING: Any path that does not begin with any of the approved
     // beginnings are not served.
     pub static ref ALLOWED_PATHS: HashSet<&'static str> = {
-        let allowed_paths: HashSet<&str> = HashSet::from(["/", "/insert", "/metrics", "/swagger-ui", "/api-doc", "/auth", "/dispatch_email"]);
+        let allowed_paths: HashSet<&str> = HashSet::from(["/", "/insert", "/metrics", "/swagger-ui", "/api-doc", "/auth", "/dispatch_email", "/users", "/admin", "/subscription", "/unsubscribe", "/waitlist", "/consent"]);
         allowed_paths
     };
 } // end lazy_static
@@ -0,0 +1,441 @@
+// This file contains the tools for the landing forms.
+//
+// Every landing page submits its own form to "/insert/{form}".
+// The form decides which email is sent to the client, which
+// pages the client is redirected to and who is notified about
+// the new lead. The custom fields of a form are declared in the
+// form definition with the same name as the slug (see forms.rs).
+//
+// The notifications about the submissions are queued and sent by
+// a background job, so that a slow SMTP server does not hold up the
+// clients. The failed ones are retried with the same backoff as the
+// webhooks, the sent ones are removed, like the ones that have run
+// out of attempts. They are removed together with the lead as well.
+// The same queue sends the other emails that must not hold up the
+// requests, e.g. the new places in the waitlist.
+
+use chrono::{Duration, Utc};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, QueryResult};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use lettre::message::Mailbox;
+use tokio::sync::Notify;
+
+use crate::{
+    models::{FormNotification, LeadForm, LeadFormPayload, NewFormNotification},
+    schema::{form_notifications, forms, users},
+};
+
+use super::webhooks::retry_delay;
+
+// These are the optional fixed fields that a form can require.
+const OPTIONAL_FIELDS: [&str; 2] = ["email", "password"];
+
+// The number of attempts before a notification is dropped.
+const MAX_NOTIFICATION_ATTEMPTS: i32 = 8;
+
+// For how long a claimed notification is hidden from the other
+// replicas, it is longer than the timeout of the SMTP client.
+const CLAIM_SECONDS: i64 = 120;
+
+/// This function loads a form by its public slug.
+pub async fn find_form(conn: &mut AsyncPgConnection, slug: &str) -> QueryResult<Option<LeadForm>> {
+    forms::table
+        .filter(forms::slug.eq(slug))
+        .first::<LeadForm>(conn)
+        .await
+        .optional()
+} // end fn find_form
+
+/// This function loads all the forms.
+pub async fn list_forms(conn: &mut AsyncPgConnection) -> QueryResult<Vec<LeadForm>> {
+    forms::table
+        .order_by(forms::id)
+        .load::<LeadForm>(conn)
+        .await
+} // end fn list_forms
+
+/// This function returns the page the user is redirected to
+/// after confirming their subscription, if their form has one.
+pub async fn confirmed_redirect(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+) -> QueryResult<Option<String>> {
+    users::table
+        .inner_join(forms::table)
+        .filter(users::id.eq(user_id))
+        .select(forms::confirmed_redirect)
+        .first::<Option<String>>(conn)
+        .await
+        .optional()
+        .map(Option::flatten)
+} // end fn confirmed_redirect
+
+/// This function fills in the welcome email of the form.
+pub fn render_welcome(
+    form: &LeadForm,
+    name: &str,
+    confirmation_link: &str,
+    waitlist_link: &str,
+    consent_link: &str,
+) -> String {
+    form.welcome_template
+        .replace("{name}", name)
+        .replace("{confirmation_link}", confirmation_link)
+        .replace("{waitlist_link}", waitlist_link)
+        .replace("{consent_link}", consent_link)
+} // end fn render_welcome
+
+/// This function checks that the submission contains all the fields
+/// required by the form.
+/// NOTE: The empty inputs of HTML forms count as absent.
+pub fn check_required_fields(
+    form: &LeadForm,
+    submitted: &[(String, String)],
+) -> Result<(), String> {
+    for required in &form.required_fields {
+        let present = submitted
+            .iter()
+            .any(|(name, value)| name == required && !value.trim().is_empty());
+        if !present {
+            return Err(format!("The \"{}\" field cannot be empty", required));
+        } // end if
+    } // end for
+
+    Ok(())
+} // end fn check_required_fields
+
+/// This function loads the slugs of the forms that require a CAPTCHA.
+pub async fn captcha_forms(conn: &mut AsyncPgConnection) -> QueryResult<Vec<String>> {
+    forms::table
+        .filter(forms::captcha_required.eq(true))
+        .order_by(forms::id)
+        .select(forms::slug)
+        .load::<String>(conn)
+        .await
+} // end fn captcha_forms
+
+/// This function checks the settings of a form before they are saved.
+/// The form can require a CAPTCHA only if there is a verifier.
+pub fn is_valid_form_payload(payload: &LeadFormPayload, captcha: bool) -> Result<(), String> {
+    let valid_slug = !payload.slug.is_empty()
+        && payload
+            .slug
+            .chars()
+            .all(|symbol| symbol.is_ascii_lowercase() || symbol.is_ascii_digit() || symbol == '-');
+    if !valid_slug {
+        return Err(
+            "The slug can contain only lowercase latin letters, digits and \"-\"".to_string(),
+        );
+    } // end if
+
+    if payload.title.is_empty() {
+        return Err("The \"title\" field cannot be empty".to_string());
+    } // end if
+
+    // The addresses are checked the same way they are parsed when sending.
+    if payload.sender.parse::<Mailbox>().is_err() {
+        return Err("The sender address is not valid".to_string());
+    } // end if
+    if let Some(recipient) = payload
+        .notification_recipients
+        .iter()
+        .find(|recipient| recipient.parse::<Mailbox>().is_err())
+    {
+        return Err(format!(
+            "The recipient address \"{}\" is not valid",
+            recipient
+        ));
+    } // end if
+
+    if !payload.welcome_template.contains("{confirmation_link}") {
+        return Err("The welcome template has to contain {confirmation_link}".to_string());
+    } // end if
+
+    // Nobody could submit the form otherwise.
+    if payload.captcha_required && !captcha {
+        return Err(
+            "The form cannot require a CAPTCHA, because CAPTCHA_PROVIDER is not set".to_string(),
+        );
+    } // end if
+
+    // The custom fields are required in their definitions.
+    if let Some(field) = payload
+        .required_fields
+        .iter()
+        .find(|field| !OPTIONAL_FIELDS.contains(&field.as_str()))
+    {
+        return Err(format!("The field \"{}\" cannot be required", field));
+    } // end if
+
+    Ok(())
+} // end fn is_valid_form_payload
+
+/// This function queues the emails that notify the recipients of
+/// the form about a submission and wakes up the sending.
+pub async fn queue_notifications(
+    conn: &mut AsyncPgConnection,
+    signal: &Notify,
+    form: &LeadForm,
+    user_id: i32,
+    subject: &str,
+    message: &str,
+) -> QueryResult<()> {
+    // Nobody is interested in the submissions.
+    if form.notification_recipients.is_empty() {
+        return Ok(());
+    } // end if
+
+    diesel::insert_into(form_notifications::table)
+        .values(
+            form.notification_recipients
+                .iter()
+                .map(|recipient| NewFormNotification {
+                    user_id,
+                    recipient: recipient.clone(),
+                    full_name: None,
+                    sender: Some(form.sender.clone()),
+                    transactional: true,
+                    subject: subject.to_string(),
+                    message: message.to_string(),
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)
+        .await?;
+
+    signal.notify_one();
+    Ok(())
+} // end fn queue_notifications
+
+/// This function queues an email to be sent in the background
+/// and wakes up the sending.
+pub async fn queue_email(
+    conn: &mut AsyncPgConnection,
+    signal: &Notify,
+    email: &NewFormNotification,
+) -> QueryResult<()> {
+    diesel::insert_into(form_notifications::table)
+        .values(email)
+        .execute(conn)
+        .await?;
+
+    signal.notify_one();
+    Ok(())
+} // end fn queue_email
+
+/// This function claims the next notification that is due.
+///
+/// NOTE: The notifications are claimed one by one and postponed for
+/// a while, so that the other replicas do not send them too. If the
+/// replica dies, they are retried afterwards.
+pub async fn claim_notification(
+    conn: &mut AsyncPgConnection,
+) -> QueryResult<Option<FormNotification>> {
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let now = Utc::now();
+            let id = form_notifications::table
+                .filter(form_notifications::next_attempt_at.le(now))
+                .order_by(form_notifications::next_attempt_at)
+                .select(form_notifications::id)
+                .for_update()
+                .skip_locked()
+                .first::<i32>(conn)
+                .await
+                .optional()?;
+            let Some(id) = id else {
+                return Ok(None);
+            };
+
+            diesel::update(form_notifications::table.find(id))
+                .set(form_notifications::next_attempt_at.eq(now + Duration::seconds(CLAIM_SECONDS)))
+                .get_result::<FormNotification>(conn)
+                .await
+                .map(Some)
+        }
+        .scope_boxed()
+    })
+    .await
+} // end fn claim_notification
+
+/// This function records the result of an attempt to send a notification.
+/// A failed notification is retried later unless it has run out of
+/// attempts, otherwise it is removed.
+pub async fn record_notification_attempt(
+    conn: &mut AsyncPgConnection,
+    notification: &FormNotification,
+    result: Result<(), String>,
+) -> QueryResult<()> {
+    let attempts = notification.attempts + 1;
+    match result {
+        Err(error) if attempts < MAX_NOTIFICATION_ATTEMPTS => {
+            diesel::update(form_notifications::table.find(notification.id))
+                .set((
+                    form_notifications::attempts.eq(attempts),
+                    form_notifications::next_attempt_at.eq(Utc::now() + retry_delay(attempts)),
+                    form_notifications::last_error.eq(error),
+                ))
+                .execute(conn)
+                .await?;
+        } // end Err
+        result => {
+            if let Err(error) = result {
+                eprintln!(
+                    "The notification {} to {} has been dropped: {}",
+                    notification.id, notification.recipient, error
+                );
+            } // end if
+            diesel::delete(form_notifications::table.find(notification.id))
+                .execute(conn)
+                .await?;
+        } // end result
+    } // end match
+
+    Ok(())
+} // end fn record_notification_attempt
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::utils::test_db::connection;
+
+    /// This is a helper function that creates the settings of a form.
+    fn payload() -> LeadFormPayload {
+        LeadFormPayload {
+            slug: "spring-sale".to_string(),
+            title: "Spring sale".to_string(),
+            sender: "Manuspect <manuspect.prod@gmail.com>".to_string(),
+            welcome_subject: "Please confirm your subscription".to_string(),
+            welcome_template: "Hello, {name}! Confirm: {confirmation_link}".to_string(),
+            success_redirect: None,
+            duplicate_redirect: None,
+            confirmed_redirect: None,
+            required_fields: vec!["email".to_string()],
+            notification_recipients: vec!["sales@example.com".to_string()],
+            captcha_required: false,
+        }
+    } // end fn payload
+
+    /// This is a helper function that creates a form from its settings.
+    fn form(payload: LeadFormPayload) -> LeadForm {
+        LeadForm {
+            id: 1,
+            slug: payload.slug,
+            title: payload.title,
+            sender: payload.sender,
+            welcome_subject: payload.welcome_subject,
+            welcome_template: payload.welcome_template,
+            success_redirect: payload.success_redirect,
+            duplicate_redirect: payload.duplicate_redirect,
+            confirmed_redirect: payload.confirmed_redirect,
+            required_fields: payload.required_fields,
+            notification_recipients: payload.notification_recipients,
+            created_at: Utc::now(),
+            captcha_required: payload.captcha_required,
+        }
+    } // end fn form
+
+    #[test]
+    fn form_settings_are_validated() {
+        assert!(is_valid_form_payload(&payload(), false).is_ok());
+
+        // A CAPTCHA can be required only if it can be checked.
+        let with_captcha = LeadFormPayload {
+            captcha_required: true,
+            ..payload()
+        };
+        assert!(is_valid_form_payload(&with_captcha, true).is_ok());
+        assert!(is_valid_form_payload(&with_captcha, false).is_err());
+
+        let invalid: [fn(&mut LeadFormPayload); 7] = [
+            |payload| payload.slug = "Spring sale".to_string(),
+            |payload| payload.slug = String::new(),
+            |payload| payload.title = String::new(),
+            |payload| payload.sender = "not an address".to_string(),
+            |payload| payload.notification_recipients.push("sales@".to_string()),
+            |payload| payload.welcome_template = "Hello, {name}!".to_string(),
+            |payload| payload.required_fields.push("company".to_string()),
+        ];
+        for change in invalid {
+            let mut payload = payload();
+            change(&mut payload);
+            assert!(is_valid_form_payload(&payload, true).is_err());
+        } // end for
+    }
+
+    #[test]
+    fn submissions_are_checked_and_welcomed() {
+        let form = form(payload());
+        let fields = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+
+        assert!(check_required_fields(&form, &fields(&[("email", "john@example.com")])).is_ok());
+        assert!(check_required_fields(&form, &fields(&[("email", "  ")])).is_err());
+        assert!(check_required_fields(&form, &fields(&[("name", "John")])).is_err());
+
+        assert_eq!(
+            render_welcome(&form, "John", "https://x/confirm", "", ""),
+            "Hello, John! Confirm: https://x/confirm"
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_emails_are_claimed_as_they_were_queued() {
+        let mut conn = connection().await;
+        let user_id = diesel::insert_into(users::table)
+            .values((
+                users::name.eq("John"),
+                users::phone_number_code.eq(1),
+                users::phone_number.eq("+12025550270"),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(&mut conn)
+            .await
+            .unwrap();
+
+        let email = NewFormNotification {
+            user_id,
+            recipient: "john@example.com".to_string(),
+            full_name: Some("John".to_string()),
+            sender: None,
+            transactional: false,
+            subject: "You have moved up the waitlist".to_string(),
+            message: "Hello, John!".to_string(),
+        };
+        queue_email(&mut conn, &Notify::new(), &email)
+            .await
+            .unwrap();
+
+        // The other queued emails could be due earlier.
+        let mut claimed = None;
+        while let Some(notification) = claim_notification(&mut conn).await.unwrap() {
+            if notification.user_id == user_id {
+                claimed = Some(notification);
+                break;
+            } // end if
+        } // end while
+        let claimed = claimed.unwrap();
+        assert_eq!(claimed.recipient, email.recipient);
+        assert_eq!(claimed.full_name, email.full_name);
+        assert_eq!(claimed.sender, None);
+        assert!(!claimed.transactional);
+
+        // A failed email is kept for a retry.
+        record_notification_attempt(&mut conn, &claimed, Err("timeout".to_string()))
+            .await
+            .unwrap();
+        let attempts = form_notifications::table
+            .find(claimed.id)
+            .select(form_notifications::attempts)
+            .first::<i32>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(attempts, 1);
+    }
+}
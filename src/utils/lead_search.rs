@@ -0,0 +1,525 @@
+// This file contains the search of the leads for the managers.
+//
+// The text is searched in the name, the email and the phone number with
+// ILIKE, which the trigram indexes of these columns keep fast. The pages
+// are read with a cursor (the sort key and the id of the last lead of the
+// page) rather than with an offset, so that a page costs the same however
+// far it is, and the leads that come meanwhile do not shift the pages.
+//
+// NOTE: The cursor is opaque to the clients, it is a base64 of JSON.
+
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Utc};
+use diesel::dsl::sql;
+use diesel::sql_types::{Array, Bool, Integer, Text, Timestamptz};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, NullableExpressionMethods, PgTextExpressionMethods,
+    QueryDsl, QueryResult, Queryable,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::schema::{forms, lead_scores, lead_tags, roles, users, users_roles};
+
+use super::exports::TAG_SEPARATOR;
+
+// The number of the leads on a page if the client does not set it.
+const DEFAULT_LIMIT: i64 = 50;
+
+// The maximum number of the leads on a page.
+const MAX_LIMIT: i64 = 200;
+
+// The message about the cursors that cannot be used.
+pub const INVALID_CURSOR: &str = "The cursor is invalid or belongs to another sort";
+
+/// This enum contains the orders of the leads.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LeadSearchSort {
+    // The latest leads first.
+    #[default]
+    Newest,
+    Oldest,
+    // The leads with the highest score first, the leads
+    // that have not been scored yet are not listed.
+    Score,
+    // The leads by name from A to Z.
+    Name,
+} // end enum LeadSearchSort
+
+/// This struct represents the search of the leads.
+#[derive(Deserialize, IntoParams, Default, Debug)]
+pub struct LeadSearchQuery {
+    /// The text searched in the names, the emails and the phone numbers.
+    pub q: Option<String>,
+    /// Whether the leads have verified their account.
+    pub verified: Option<bool>,
+    /// Whether the leads have confirmed their subscription.
+    pub confirmed: Option<bool>,
+    /// Only the leads that came at or after this moment are found.
+    pub from: Option<DateTime<Utc>>,
+    /// Only the leads that came before this moment are found.
+    pub to: Option<DateTime<Utc>>,
+    /// The slug of the form the leads came from.
+    pub form: Option<String>,
+    /// The comma separated tags, the leads have to have all of them.
+    pub tags: Option<String>,
+    /// The role the leads have, e.g. "User".
+    pub role: Option<String>,
+    /// The order of the leads, "newest" by default.
+    #[serde(default)]
+    #[param(inline)]
+    pub sort: LeadSearchSort,
+    /// The "next_cursor" of the previous page.
+    pub cursor: Option<String>,
+    /// The number of the leads on a page, 50 by default and 200 at most.
+    pub limit: Option<i64>,
+} // end struct LeadSearchQuery
+
+/// This struct represents the position after the last lead of a page.
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct Cursor {
+    sort: LeadSearchSort,
+    // The value the leads are sorted by.
+    key: Value,
+    id: i32,
+} // end struct Cursor
+
+impl Cursor {
+    /// This function returns the cursor as the clients get it.
+    pub fn encode(&self) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    } // end fn encode
+
+    /// This function reads a cursor of a page sorted in the given order.
+    pub fn decode(cursor: &str, sort: LeadSearchSort) -> Option<Self> {
+        let bytes = general_purpose::URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice::<Cursor>(&bytes)
+            .ok()
+            .filter(|cursor| cursor.sort == sort)
+    } // end fn decode
+} // end impl Cursor
+
+/// This struct represents a found lead.
+#[derive(Queryable, Serialize, ToSchema, Debug)]
+pub struct LeadSummary {
+    #[schema(example = 42)]
+    pub id: i32,
+    #[schema(example = "John")]
+    pub name: String,
+    #[schema(example = "john@example.com")]
+    pub email: Option<String>,
+    #[schema(example = "+12015550123")]
+    pub phone_number: String,
+    pub verified: bool,
+    pub created_at: DateTime<Utc>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+    // The slug of the form.
+    #[schema(example = "default")]
+    pub form: Option<String>,
+    #[schema(example = json!(["hot", "vip"]))]
+    pub tags: Vec<String>,
+    // The roles that have not expired.
+    #[schema(example = json!(["User"]))]
+    pub roles: Vec<String>,
+    #[schema(example = 25)]
+    pub score: Option<i32>,
+} // end struct LeadSummary
+
+/// This struct represents a page of the found leads.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct LeadPage {
+    pub leads: Vec<LeadSummary>,
+    // The cursor of the next page, None on the last page.
+    #[schema(example = "eyJzb3J0IjoibmV3ZXN0Iiwia2V5IjoiMjAyNC0wMS0zMVQxODowMDowMFoiLCJpZCI6NDJ9")]
+    pub next_cursor: Option<String>,
+} // end struct LeadPage
+
+impl LeadSearchQuery {
+    /// This function returns the number of the leads on a page.
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    } // end fn limit
+
+    /// This function reads the cursor of the query.
+    pub fn cursor(&self) -> Result<Option<Cursor>, String> {
+        self.cursor
+            .as_deref()
+            .map(|cursor| {
+                Cursor::decode(cursor, self.sort).ok_or_else(|| INVALID_CURSOR.to_string())
+            })
+            .transpose()
+    } // end fn cursor
+} // end impl LeadSearchQuery
+
+/// This function escapes the wildcards of a LIKE pattern.
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+} // end fn escape_like
+
+/// This function returns the columns the leads are sorted by and
+/// whether they are sorted in the descending order. The id is the last
+/// column, so that the leads with the same key keep their order.
+fn sort_columns(sort: LeadSearchSort) -> (&'static str, bool) {
+    match sort {
+        LeadSearchSort::Newest => ("users.created_at, users.id", true),
+        LeadSearchSort::Oldest => ("users.created_at, users.id", false),
+        LeadSearchSort::Score => ("lead_scores.score, lead_scores.user_id", true),
+        LeadSearchSort::Name => ("users.name, users.id", false),
+    } // end match
+} // end fn sort_columns
+
+/// This function returns the ORDER BY of a sort.
+fn order_clause(sort: LeadSearchSort) -> String {
+    let (columns, descending) = sort_columns(sort);
+    let direction = if descending { " DESC" } else { "" };
+    columns
+        .split(", ")
+        .map(|column| format!("{}{}", column, direction))
+        .collect::<Vec<_>>()
+        .join(", ")
+} // end fn order_clause
+
+/// This function returns the start of the condition of the leads after
+/// a cursor, the key and the id of the cursor are bound after it.
+fn after_clause(sort: LeadSearchSort) -> String {
+    let (columns, descending) = sort_columns(sort);
+    let operator = if descending { "<" } else { ">" };
+    format!("({}) {} (", columns, operator)
+} // end fn after_clause
+
+/// This function returns the cursor after a lead.
+fn cursor_after(sort: LeadSearchSort, lead: &LeadSummary) -> Cursor {
+    let key = match sort {
+        LeadSearchSort::Newest | LeadSearchSort::Oldest => {
+            Value::from(lead.created_at.to_rfc3339())
+        }
+        LeadSearchSort::Score => Value::from(lead.score.unwrap_or(i32::MIN)),
+        LeadSearchSort::Name => Value::from(lead.name.clone()),
+    }; // end match
+
+    Cursor {
+        sort,
+        key,
+        id: lead.id,
+    }
+} // end fn cursor_after
+
+/// This function searches the leads and returns a page of them.
+/// It returns None if the key of the cursor is not of the sort.
+pub async fn find_leads(
+    conn: &mut AsyncPgConnection,
+    query: &LeadSearchQuery,
+    cursor: Option<&Cursor>,
+) -> QueryResult<Option<LeadPage>> {
+    let mut statement = users::table
+        .left_join(forms::table)
+        .left_join(lead_scores::table)
+        .select((
+            users::id,
+            users::name,
+            users::email,
+            users::phone_number,
+            users::verified,
+            users::created_at,
+            users::confirmed_at,
+            forms::slug.nullable(),
+            sql::<Array<Text>>(
+                "ARRAY(SELECT lead_tags.tag FROM lead_tags \
+                WHERE lead_tags.user_id = users.id ORDER BY lead_tags.tag)",
+            ),
+            sql::<Array<Text>>(
+                "ARRAY(SELECT roles.title FROM users_roles \
+                JOIN roles ON roles.id = users_roles.role_id \
+                WHERE users_roles.user_id = users.id \
+                AND (users_roles.expires_at IS NULL OR users_roles.expires_at > NOW()) \
+                ORDER BY roles.title)",
+            ),
+            lead_scores::score.nullable(),
+        ))
+        .into_boxed();
+
+    if let Some(text) = query
+        .q
+        .as_deref()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+    {
+        let pattern = format!("%{}%", escape_like(text));
+        // The phone numbers are stored without spaces, dashes and brackets,
+        // so only the digits of a text that looks like one are searched.
+        let digits = text
+            .chars()
+            .filter(char::is_ascii_digit)
+            .collect::<String>();
+        let phone_like = text
+            .chars()
+            .all(|symbol| symbol.is_ascii_digit() || " +-().".contains(symbol));
+        let phone_pattern = if phone_like && !digits.is_empty() {
+            format!("%{}%", digits)
+        } else {
+            pattern.clone()
+        }; // end if
+        statement = statement.filter(
+            users::name
+                .ilike(pattern.clone())
+                .nullable()
+                .or(users::email.ilike(pattern))
+                .or(users::phone_number.ilike(phone_pattern).nullable()),
+        );
+    } // end if
+    if let Some(verified) = query.verified {
+        statement = statement.filter(users::verified.eq(verified));
+    } // end if
+    match query.confirmed {
+        Some(true) => statement = statement.filter(users::confirmed_at.is_not_null()),
+        Some(false) => statement = statement.filter(users::confirmed_at.is_null()),
+        None => (),
+    } // end match
+    if let Some(from) = query.from {
+        statement = statement.filter(users::created_at.ge(from));
+    } // end if
+    if let Some(to) = query.to {
+        statement = statement.filter(users::created_at.lt(to));
+    } // end if
+    if let Some(form) = &query.form {
+        statement = statement.filter(forms::slug.eq(form.clone()));
+    } // end if
+    for tag in query
+        .tags
+        .iter()
+        .flat_map(|tags| tags.split(TAG_SEPARATOR))
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+    {
+        statement = statement.filter(
+            users::id.eq_any(
+                lead_tags::table
+                    .filter(lead_tags::tag.eq(tag))
+                    .select(lead_tags::user_id),
+            ),
+        );
+    } // end for
+    if let Some(role) = &query.role {
+        statement = statement.filter(
+            users::id.eq_any(
+                users_roles::table
+                    .inner_join(roles::table)
+                    .filter(roles::title.eq(role.clone()))
+                    .filter(crate::utils::roles::active())
+                    .select(users_roles::user_id),
+            ),
+        );
+    } // end if
+
+    // Continue after the last lead of the previous page.
+    if let Some(cursor) = cursor {
+        let after = sql::<Bool>(&after_clause(cursor.sort));
+        statement = match cursor.sort {
+            LeadSearchSort::Newest | LeadSearchSort::Oldest => {
+                let Some(created_at) = cursor
+                    .key
+                    .as_str()
+                    .and_then(|key| key.parse::<DateTime<Utc>>().ok())
+                else {
+                    return Ok(None);
+                };
+                statement.filter(
+                    after
+                        .bind::<Timestamptz, _>(created_at)
+                        .sql(", ")
+                        .bind::<Integer, _>(cursor.id)
+                        .sql(")"),
+                )
+            }
+            LeadSearchSort::Score => {
+                let Some(score) = cursor.key.as_i64().and_then(|key| i32::try_from(key).ok())
+                else {
+                    return Ok(None);
+                };
+                statement.filter(
+                    after
+                        .bind::<Integer, _>(score)
+                        .sql(", ")
+                        .bind::<Integer, _>(cursor.id)
+                        .sql(")"),
+                )
+            }
+            LeadSearchSort::Name => {
+                let Some(name) = cursor.key.as_str() else {
+                    return Ok(None);
+                };
+                statement.filter(
+                    after
+                        .bind::<Text, _>(name.to_string())
+                        .sql(", ")
+                        .bind::<Integer, _>(cursor.id)
+                        .sql(")"),
+                )
+            }
+        }; // end match
+    } // end if
+
+    // NOTE: The leads are paged by the score on the side of the scores,
+    // so only the scored leads are listed when sorted by the score.
+    if query.sort == LeadSearchSort::Score {
+        statement = statement.filter(lead_scores::user_id.is_not_null());
+    } // end if
+    statement = statement.order_by(sql::<Text>(&order_clause(query.sort)));
+
+    // One more lead tells if there is a next page.
+    let limit = query.limit();
+    let mut leads = statement.limit(limit + 1).load::<LeadSummary>(conn).await?;
+    let next_cursor = if leads.len() as i64 > limit {
+        leads.truncate(limit as usize);
+        leads
+            .last()
+            .map(|lead| cursor_after(query.sort, lead).encode())
+    } else {
+        None
+    }; // end if
+
+    Ok(Some(LeadPage { leads, next_cursor }))
+} // end fn find_leads
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    use crate::utils::test_db;
+
+    #[test]
+    fn cursors_are_read_back() {
+        let cursor = Cursor {
+            sort: LeadSearchSort::Score,
+            key: Value::from(25),
+            id: 42,
+        };
+        let encoded = cursor.encode();
+
+        assert_eq!(
+            Cursor::decode(&encoded, LeadSearchSort::Score),
+            Some(cursor)
+        );
+        assert_eq!(Cursor::decode(&encoded, LeadSearchSort::Newest), None);
+        assert_eq!(Cursor::decode("not a cursor", LeadSearchSort::Score), None);
+        assert_eq!(escape_like("50%_off\\"), "50\\%\\_off\\\\");
+    }
+
+    #[test]
+    fn cursors_follow_the_order() {
+        assert_eq!(
+            order_clause(LeadSearchSort::Newest),
+            "users.created_at DESC, users.id DESC"
+        );
+        assert_eq!(
+            after_clause(LeadSearchSort::Newest),
+            "(users.created_at, users.id) < ("
+        );
+        assert_eq!(
+            order_clause(LeadSearchSort::Oldest),
+            "users.created_at, users.id"
+        );
+        assert_eq!(
+            after_clause(LeadSearchSort::Oldest),
+            "(users.created_at, users.id) > ("
+        );
+        assert_eq!(
+            order_clause(LeadSearchSort::Score),
+            "lead_scores.score DESC, lead_scores.user_id DESC"
+        );
+        assert_eq!(
+            after_clause(LeadSearchSort::Score),
+            "(lead_scores.score, lead_scores.user_id) < ("
+        );
+        assert_eq!(order_clause(LeadSearchSort::Name), "users.name, users.id");
+        assert_eq!(
+            after_clause(LeadSearchSort::Name),
+            "(users.name, users.id) > ("
+        );
+    }
+
+    #[tokio::test]
+    async fn pages_add_up_to_the_whole_list() {
+        let mut conn = test_db::connection().await;
+
+        // The leads share the times, the names and the scores,
+        // so that the pages have to be told apart by the ids.
+        let now = Utc::now();
+        for (index, (name, hours, score)) in [
+            ("Pagetest B", 1, Some(10)),
+            ("Pagetest A", 1, Some(10)),
+            ("Pagetest B", 2, Some(5)),
+            ("Pagetest A", 2, Some(10)),
+            ("Pagetest C", 3, None),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let user_id = diesel::insert_into(users::table)
+                .values((
+                    users::name.eq(name),
+                    users::phone_number_code.eq(1),
+                    users::phone_number.eq(format!("+1202555025{}", index)),
+                    users::created_at.eq(now - Duration::hours(hours)),
+                ))
+                .returning(users::id)
+                .get_result::<i32>(&mut conn)
+                .await
+                .unwrap();
+            if let Some(score) = score {
+                diesel::insert_into(lead_scores::table)
+                    .values((
+                        lead_scores::user_id.eq(user_id),
+                        lead_scores::score.eq(score),
+                    ))
+                    .execute(&mut conn)
+                    .await
+                    .unwrap();
+            } // end if
+        } // end for
+
+        for sort in [
+            LeadSearchSort::Newest,
+            LeadSearchSort::Oldest,
+            LeadSearchSort::Score,
+            LeadSearchSort::Name,
+        ] {
+            let mut query = LeadSearchQuery {
+                q: Some("Pagetest".to_string()),
+                sort,
+                ..Default::default()
+            };
+            let whole = find_leads(&mut conn, &query, None).await.unwrap().unwrap();
+            assert_eq!(
+                whole.leads.len(),
+                if sort == LeadSearchSort::Score { 4 } else { 5 }
+            );
+            assert_eq!(whole.next_cursor, None);
+
+            query.limit = Some(2);
+            let mut paged = Vec::new();
+            let mut cursor = None;
+            loop {
+                let page = find_leads(&mut conn, &query, cursor.as_ref())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                paged.extend(page.leads.iter().map(|lead| lead.id));
+                match page.next_cursor {
+                    Some(next) => cursor = Cursor::decode(&next, sort),
+                    None => break,
+                } // end match
+            } // end loop
+
+            let whole = whole.leads.iter().map(|lead| lead.id).collect::<Vec<_>>();
+            assert_eq!(paged, whole, "{:?}", sort);
+        } // end for
+    }
+}
@@ -0,0 +1,314 @@
+// This file contains the merging of the form submissions into leads.
+//
+// A lead is identified by the normalized phone number or email. The
+// first submission creates the lead, the next ones fill in the fields
+// that are still missing and every submission is kept in the history.
+//
+// NOTE: The client gets the same response in both cases,
+// so that the forms do not disclose who has subscribed.
+//
+// The submissions with the same phone number or email are serialized
+// with advisory locks, since the emails are not unique in the database.
+
+use diesel::result::{DatabaseErrorKind, Error};
+use diesel::sql_types::Text;
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, QueryResult};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde_json::Value;
+
+use crate::models::{NewLeadSubmission, NewUser, User};
+use crate::schema::{lead_submissions, users};
+
+// These are the sources of the submissions.
+pub const FROM_FORM: &str = "form";
+pub const FROM_IMPORT: &str = "import";
+
+/// This struct represents a lead after a submission.
+pub struct UpsertedLead {
+    pub user: User,
+    // The lead has been created by the submission.
+    pub created: bool,
+    // The submitted email has been given to the lead, either with
+    // the new lead or because the lead did not have one.
+    pub email_added: bool,
+} // end struct UpsertedLead
+
+/// This function creates a lead or merges the submission into the lead
+/// with the same phone number or email. The submission is recorded too.
+///
+/// NOTE: The phone number and the email have to be normalized.
+pub async fn upsert_lead(
+    conn: &mut AsyncPgConnection,
+    user: &NewUser,
+    custom_fields: &Value,
+    form_id: i32,
+    source: &str,
+) -> QueryResult<UpsertedLead> {
+    // Two submissions of a new lead can race each other, the unique
+    // phone number makes the second one fail, then it is merged.
+    match try_upsert_lead(conn, user, custom_fields, form_id, source).await {
+        Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _info)) => {
+            try_upsert_lead(conn, user, custom_fields, form_id, source).await
+        } // end Err
+        result => result,
+    } // end match
+} // end fn upsert_lead
+
+/// This function makes a single attempt to upsert a lead.
+async fn try_upsert_lead(
+    conn: &mut AsyncPgConnection,
+    user: &NewUser,
+    custom_fields: &Value,
+    form_id: i32,
+    source: &str,
+) -> QueryResult<UpsertedLead> {
+    conn.transaction::<UpsertedLead, Error, _>(|conn| {
+        async move {
+            // Wait for the other submissions of the same lead. The phone
+            // number is always locked first, so that they cannot deadlock.
+            lock(conn, &format!("phone:{}", user.phone_number)).await?;
+            if let Some(email) = &user.email {
+                lock(conn, &format!("email:{}", email)).await?;
+            } // end if
+
+            // The phone number is always there, so it is checked first.
+            let mut existing = users::table
+                .filter(users::phone_number.eq(&user.phone_number))
+                .first::<User>(conn)
+                .await
+                .optional()?;
+            if let (None, Some(email)) = (&existing, &user.email) {
+                existing = users::table
+                    .filter(users::email.eq(email))
+                    .first::<User>(conn)
+                    .await
+                    .optional()?;
+            } // end if
+
+            let lead = match existing {
+                None => UpsertedLead {
+                    user: diesel::insert_into(users::table)
+                        .values((
+                            user,
+                            users::custom_fields.eq(custom_fields),
+                            users::form_id.eq(form_id),
+                        ))
+                        .get_result::<User>(conn)
+                        .await?,
+                    created: true,
+                    email_added: user.email.is_some(),
+                }, // end None
+                Some(existing) => {
+                    // The email is added only if no other lead has it.
+                    let new_email = match (&existing.email, &user.email) {
+                        (None, Some(email)) => users::table
+                            .filter(users::email.eq(email))
+                            .count()
+                            .get_result::<i64>(conn)
+                            .await
+                            .map(|count| (count == 0).then(|| email.clone()))?,
+                        _ => None,
+                    }; // end match
+
+                    let email_added = new_email.is_some();
+                    UpsertedLead {
+                        user: diesel::update(users::table.find(existing.id))
+                            .set((
+                                users::email.eq(existing.email.or(new_email)),
+                                users::custom_fields.eq(merge_custom_fields(
+                                    &existing.custom_fields,
+                                    custom_fields,
+                                )),
+                                users::form_id.eq(existing.form_id.or(Some(form_id))),
+                            ))
+                            .get_result::<User>(conn)
+                            .await?,
+                        created: false,
+                        email_added,
+                    } // end UpsertedLead
+                } // end Some
+            }; // end match
+
+            diesel::insert_into(lead_submissions::table)
+                .values(NewLeadSubmission {
+                    user_id: lead.user.id,
+                    form_id,
+                    created_lead: lead.created,
+                    source,
+                    name: &user.name,
+                    email: user.email.as_deref(),
+                    phone_number: &user.phone_number,
+                    custom_fields,
+                })
+                .execute(conn)
+                .await?;
+
+            Ok(lead)
+        }
+        .scope_boxed()
+    })
+    .await
+} // end fn try_upsert_lead
+
+/// This function takes a lock on a value until the end of the transaction.
+async fn lock(conn: &mut AsyncPgConnection, key: &str) -> QueryResult<()> {
+    diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind::<Text, _>(key)
+        .execute(conn)
+        .await
+        .map(|_rows| ())
+} // end fn lock
+
+/// This function adds the submitted custom fields that the lead
+/// does not have yet, the known values are never overwritten.
+fn merge_custom_fields(existing: &Value, submitted: &Value) -> Value {
+    let mut merged = existing.as_object().cloned().unwrap_or_default();
+
+    if let Some(submitted) = submitted.as_object() {
+        for (name, value) in submitted {
+            merged.entry(name.clone()).or_insert_with(|| value.clone());
+        } // end for
+    } // end if
+
+    Value::Object(merged)
+} // end fn merge_custom_fields
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::utils::test_db::{connection, default_form_id};
+
+    /// This is a helper function that creates a submitted lead.
+    fn lead(email: Option<&str>, phone_number: &str) -> NewUser {
+        NewUser {
+            name: "John".to_string(),
+            email: email.map(str::to_string),
+            phone_number_code: 1,
+            phone_number: phone_number.to_string(),
+            password: None,
+        }
+    } // end fn lead
+
+    /// This is a helper function that counts the submissions of a lead.
+    async fn submissions(conn: &mut AsyncPgConnection, user_id: i32) -> i64 {
+        lead_submissions::table
+            .filter(lead_submissions::user_id.eq(user_id))
+            .count()
+            .get_result::<i64>(conn)
+            .await
+            .unwrap()
+    } // end fn submissions
+
+    #[tokio::test]
+    async fn returning_leads_are_merged() {
+        let mut conn = connection().await;
+        let form_id = default_form_id(&mut conn).await;
+
+        let first = upsert_lead(
+            &mut conn,
+            &lead(None, "+12025550170"),
+            &json!({"company": "Acme"}),
+            form_id,
+            FROM_FORM,
+        )
+        .await
+        .unwrap();
+        assert!(first.created);
+        assert!(!first.email_added);
+
+        // The same phone number fills in the missing email and fields.
+        let second = upsert_lead(
+            &mut conn,
+            &lead(Some("returning@example.com"), "+12025550170"),
+            &json!({"company": "Other", "role": "CTO"}),
+            form_id,
+            FROM_FORM,
+        )
+        .await
+        .unwrap();
+        assert!(!second.created);
+        assert!(second.email_added);
+        assert_eq!(second.user.id, first.user.id);
+        assert_eq!(second.user.email.as_deref(), Some("returning@example.com"));
+        assert_eq!(
+            second.user.custom_fields,
+            json!({"company": "Acme", "role": "CTO"})
+        );
+
+        // The same email is enough to find the lead.
+        let third = upsert_lead(
+            &mut conn,
+            &lead(Some("returning@example.com"), "+12025550171"),
+            &json!({}),
+            form_id,
+            FROM_FORM,
+        )
+        .await
+        .unwrap();
+        assert!(!third.created);
+        assert!(!third.email_added);
+        assert_eq!(third.user.id, first.user.id);
+        assert_eq!(third.user.phone_number, "+12025550170");
+
+        assert_eq!(submissions(&mut conn, first.user.id).await, 3);
+    }
+
+    #[tokio::test]
+    async fn emails_of_other_leads_are_not_taken() {
+        let mut conn = connection().await;
+        let form_id = default_form_id(&mut conn).await;
+
+        let owner = upsert_lead(
+            &mut conn,
+            &lead(Some("owner@example.com"), "+12025550172"),
+            &json!({}),
+            form_id,
+            FROM_FORM,
+        )
+        .await
+        .unwrap();
+        let other = upsert_lead(
+            &mut conn,
+            &lead(None, "+12025550173"),
+            &json!({}),
+            form_id,
+            FROM_FORM,
+        )
+        .await
+        .unwrap();
+
+        // The phone number of one lead and the email of another one.
+        let merged = upsert_lead(
+            &mut conn,
+            &lead(Some("owner@example.com"), "+12025550173"),
+            &json!({}),
+            form_id,
+            FROM_FORM,
+        )
+        .await
+        .unwrap();
+        assert_eq!(merged.user.id, other.user.id);
+        assert_eq!(merged.user.email, None);
+        assert!(!merged.email_added);
+        assert_eq!(submissions(&mut conn, owner.user.id).await, 1);
+    }
+
+    #[test]
+    fn missing_custom_fields_are_filled_in() {
+        assert_eq!(
+            merge_custom_fields(
+                &json!({"company": "Acme", "team_size": 5}),
+                &json!({"company": "Other", "role": "CTO"}),
+            ),
+            json!({"company": "Acme", "team_size": 5, "role": "CTO"})
+        );
+        assert_eq!(
+            merge_custom_fields(&json!({}), &json!({"role": "CEO"})),
+            json!({"role": "CEO"})
+        );
+    }
+}
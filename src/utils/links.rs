@@ -0,0 +1,121 @@
+// This file contains the tools for building links to the
+// application that are sent to users (e.g. in emails).
+
+use std::env;
+
+use chrono::Duration;
+
+use super::jwt::create_link_token;
+
+/// This is the purpose of the links that confirm subscriptions.
+pub const CONFIRM_SUBSCRIPTION: &str = "confirm_subscription";
+
+/// This is the purpose of the links that unsubscribe recipients
+/// from the emails.
+pub const UNSUBSCRIBE: &str = "unsubscribe";
+
+/// This is the purpose of the links that show the place
+/// of a lead in the waitlist.
+pub const WAITLIST_STATUS: &str = "waitlist_status";
+
+/// This is the purpose of the links that show the consents
+/// of a user and let them withdraw the consents.
+pub const CONSENT: &str = "consent";
+
+/// This is the purpose of the links that let the leads
+/// export or erase their data.
+pub const DATA_REQUEST: &str = "data_request";
+
+/// This function returns how long a confirmation link works.
+/// NOTE: The pending leads are removed later by the retention
+/// policy of the unconfirmed leads.
+pub fn confirmation_lifetime() -> Duration {
+    Duration::hours(48)
+} // end fn confirmation_lifetime
+
+/// This function returns the public URL of the application,
+/// which is set in the PUBLIC_URL environment variable.
+pub fn public_url() -> String {
+    env::var("PUBLIC_URL")
+        .unwrap_or_else(|_error| "http://localhost".to_string())
+        .trim_end_matches('/')
+        .to_string()
+} // end fn public_url
+
+/// This function returns the URL of the frontend,
+/// which is set in the FRONTEND_URL environment variable.
+pub fn frontend_url() -> String {
+    env::var("FRONTEND_URL")
+        .unwrap_or_else(|_error| "http://127.0.0.1:5500/frontend".to_string())
+        .trim_end_matches('/')
+        .to_string()
+} // end fn frontend_url
+
+/// This function creates a signed link that confirms
+/// the subscription of the user.
+pub fn confirmation_link(user_id: i32) -> Option<String> {
+    let token = create_link_token(
+        CONFIRM_SUBSCRIPTION,
+        &user_id.to_string(),
+        Some(confirmation_lifetime()),
+    )?;
+
+    Some(format!(
+        "{}/subscription/confirm?token={}",
+        public_url(),
+        token
+    ))
+} // end fn confirmation_link
+
+/// This function creates a signed link that unsubscribes
+/// the recipient from all the emails.
+///
+/// NOTE: The link never expires, because it is sent in every
+/// email and an old email should still be able to unsubscribe.
+pub fn unsubscribe_link(email: &str) -> Option<String> {
+    let token = create_link_token(UNSUBSCRIBE, &email.to_lowercase(), None)?;
+
+    Some(format!("{}/unsubscribe?token={}", public_url(), token))
+} // end fn unsubscribe_link
+
+/// This function creates a signed link to the place
+/// of the lead in the waitlist.
+///
+/// NOTE: The link never expires, the leads come back to it
+/// to see how their referrals have moved them.
+pub fn waitlist_link(user_id: i32) -> Option<String> {
+    let token = create_link_token(WAITLIST_STATUS, &user_id.to_string(), None)?;
+
+    Some(format!("{}/waitlist/status?token={}", public_url(), token))
+} // end fn waitlist_link
+
+/// This function returns how long a link to the consents works.
+///
+/// NOTE: The consents can still be withdrawn after that, the marketing
+/// with the unsubscribe link that never expires.
+pub fn consent_link_lifetime() -> Duration {
+    Duration::days(30)
+} // end fn consent_link_lifetime
+
+/// This function creates a signed link to the consents of the user.
+pub fn consent_link(user_id: i32) -> Option<String> {
+    let token = create_link_token(CONSENT, &user_id.to_string(), Some(consent_link_lifetime()))?;
+
+    Some(format!("{}/consent?token={}", public_url(), token))
+} // end fn consent_link
+
+/// This function creates a signed link to the page that exports
+/// or erases the data of the user.
+///
+/// NOTE: The link is sent on request, so it expires in a day.
+pub fn data_link(user_id: i32) -> Option<String> {
+    let token = create_link_token(DATA_REQUEST, &user_id.to_string(), Some(Duration::days(1)))?;
+
+    Some(format!("{}/data?token={}", public_url(), token))
+} // end fn data_link
+
+/// This function creates a link to the landing page
+/// with the referral code of a lead.
+pub fn referral_link(referral_code: &str) -> String {
+    format!("{}/index.html?ref={}", frontend_url(), referral_code)
+} // end fn referral_link
@@ -1,5 +1,33 @@
+pub mod antispam;
+pub mod attribution;
+pub mod audit;
+pub mod captcha;
+pub mod consents;
+pub mod crm;
+pub mod data_requests;
 pub mod database_functions;
+pub mod emails;
+pub mod exports;
+pub mod forms;
+pub mod funnel;
+pub mod imports;
 pub mod jwt;
 pub mod lazy_static;
+pub mod lead_forms;
+pub mod lead_search;
+pub mod leads;
+pub mod links;
+pub mod permissions;
+pub mod phones;
+pub mod policies;
+pub mod rate_limits;
 pub mod responses;
+pub mod retention;
+pub mod roles;
+pub mod scoring;
 pub mod security;
+pub mod subscriptions;
+#[cfg(test)]
+pub mod test_db;
+pub mod waitlist;
+pub mod webhooks;
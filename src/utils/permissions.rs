@@ -0,0 +1,145 @@
+// This file contains a precompiled table of route permissions.
+//
+// The table is built once from a mapping of routes to the roles
+// that can access them and is never mutated afterwards. In order
+// to change the permissions a new table has to be compiled and
+// swapped in atomically (see AppState::allowed_roles).
+
+use std::collections::{HashMap, HashSet};
+
+/// This struct is an immutable prefix trie with route permissions.
+/// Every node of the trie corresponds to a single path segment.
+///
+/// NOTE: The lookups do not allocate and do not take any locks,
+/// so the trie can be shared between any number of requests.
+#[derive(Default, Debug)]
+pub struct PermissionTrie {
+    root: PermissionNode,
+} // end struct PermissionTrie
+
+/// This struct is a single node of the PermissionTrie.
+#[derive(Default, Debug)]
+struct PermissionNode {
+    // The roles that can access the route ending at this node.
+    // If it is None, then the route is not protected by itself.
+    allowed_roles: Option<Box<[Box<str>]>>,
+    // The nested routes.
+    children: HashMap<Box<str>, PermissionNode>,
+} // end struct PermissionNode
+
+impl PermissionNode {
+    /// This function checks if at least one of the roles
+    /// grants access to the node.
+    fn admits(&self, roles: &[String]) -> bool {
+        match &self.allowed_roles {
+            // The route is protected, one of the roles is required.
+            Some(allowed_roles) => roles
+                .iter()
+                .any(|role| allowed_roles.iter().any(|allowed| **allowed == **role)),
+            // The route is not protected.
+            None => true,
+        } // end match
+    } // end fn admits
+} // end impl PermissionNode
+
+impl PermissionTrie {
+    /// This function compiles a mapping of routes to the sets of roles
+    /// into a trie.
+    ///
+    /// NOTE: Empty path segments are ignored, so "/metrics",
+    /// "metrics/" and "//metrics" describe the same route.
+    /// Both "" and "/" describe the root.
+    pub fn compile(allowed_roles: &HashMap<String, HashSet<String>>) -> Self {
+        let mut trie = PermissionTrie::default();
+
+        // Add all the routes one by one.
+        for (route, roles) in allowed_roles {
+            // Find or create the node for the route.
+            let mut node = &mut trie.root;
+            for part in route.split('/').filter(|part| !part.is_empty()) {
+                node = node.children.entry(part.into()).or_default();
+            } // end for
+
+            // Store the roles that can access the route.
+            node.allowed_roles = Some(roles.iter().map(|role| role.as_str().into()).collect());
+        } // end for
+
+        trie
+    } // end fn compile
+
+    /// This function checks if the roles grant access to the path
+    /// and to all of its parent paths.
+    pub fn permits(&self, roles: &[String], path: &str) -> bool {
+        // Start with the root of the trie.
+        let mut node = &self.root;
+        if !node.admits(roles) {
+            return false;
+        } // end if
+
+        // Descend the trie and on each step check if the
+        // client has access to the parent path.
+        for part in path.split('/').filter(|part| !part.is_empty()) {
+            node = match node.children.get(part) {
+                Some(child) => child,
+                // There are no protected routes below this point.
+                None => return true,
+            }; // end match
+
+            if !node.admits(roles) {
+                // The client is not allowed to access the route.
+                return false;
+            } // end if
+        } // end for
+
+        // All checks have been performed,
+        // a user can access the route.
+        true
+    } // end fn permits
+} // end impl PermissionTrie
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// This is a helper function that builds a trie from
+    /// a list of routes and roles.
+    fn trie(routes: &[(&str, &[&str])]) -> PermissionTrie {
+        let allowed_roles = routes
+            .iter()
+            .map(|(route, roles)| {
+                (
+                    route.to_string(),
+                    roles.iter().map(|role| role.to_string()).collect(),
+                )
+            })
+            .collect();
+
+        PermissionTrie::compile(&allowed_roles)
+    } // end fn trie
+
+    #[test]
+    fn permits_checks_every_parent_route() {
+        let trie = trie(&[
+            ("/metrics", &["Admin", "Manager"]),
+            ("/admin/leads", &["Admin"]),
+        ]);
+        let user = vec!["User".to_string()];
+        let manager = vec!["User".to_string(), "Manager".to_string()];
+
+        assert!(trie.permits(&user, "/"));
+        assert!(trie.permits(&user, "/insert"));
+        assert!(!trie.permits(&user, "/metrics"));
+        assert!(!trie.permits(&user, "//metrics/"));
+        assert!(trie.permits(&manager, "/metrics/anything"));
+        assert!(trie.permits(&manager, "/admin"));
+        assert!(!trie.permits(&manager, "/admin/leads/export"));
+    }
+
+    #[test]
+    fn protected_root_applies_to_every_route() {
+        let trie = trie(&[("/", &["Admin"])]);
+
+        assert!(!trie.permits(&["User".to_string()], "/insert"));
+        assert!(trie.permits(&["Admin".to_string()], "/insert"));
+    }
+}
@@ -0,0 +1,243 @@
+// This file contains the parsing of the phone numbers.
+//
+// The phone numbers are checked against the numbering plans
+// of the countries and are stored in the E.164 format, e.g.
+// "+79991234567", so that "+7 999 123-45-67" and "8 999 123 45 67"
+// are the same number.
+
+use std::collections::HashMap;
+
+use diesel::result::{DatabaseErrorKind, Error};
+use phonenumber::{country, metadata::DATABASE, Mode, PhoneNumber, Type};
+
+/// The constraint that keeps the phone numbers of the users unique.
+const UNIQUE_PHONE_NUMBER: &str = "users_phone_number_key";
+
+/// This struct represents a phone number that has been parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NormalizedPhone {
+    // The country calling code, e.g. 7.
+    pub country_code: i32,
+    // The country, e.g. "RU", it is None for the non-geographic
+    // numbers (e.g. the international freephone numbers).
+    pub region: Option<String>,
+    // The type of the number, e.g. mobile or fixed line.
+    pub number_type: Type,
+    // The whole number in the E.164 format, e.g. "+79991234567".
+    pub e164: String,
+} // end struct NormalizedPhone
+
+/// This function parses a phone number entered by a client.
+///
+/// The number is parsed in the national format of the country with
+/// the calling code (so that the trunk prefixes, e.g. "8" in Russia,
+/// are dropped), unless it starts with "+", in which case it has to
+/// contain the same calling code.
+pub fn parse(code: i32, number: &str) -> Result<NormalizedPhone, String> {
+    let code = u16::try_from(code)
+        .ok()
+        .filter(|code| DATABASE.by_code(code).is_some())
+        .ok_or_else(|| "The phone number code is invalid".to_string())?;
+
+    // The main country of the calling code knows its trunk prefix.
+    let region = DATABASE.region(&code).and_then(|regions| {
+        regions
+            .first()
+            .and_then(|id| id.parse::<country::Id>().ok())
+    });
+
+    let parsed = match region {
+        Some(region) if !number.trim_start().starts_with('+') => {
+            phonenumber::parse(Some(region), number)
+        } // end Some
+        Some(_) => phonenumber::parse(None, number),
+        None => phonenumber::parse(None, format!("+{} {}", code, number)),
+    }; // end match
+    let parsed: PhoneNumber =
+        parsed.map_err(|_error| "The phone number is not valid".to_string())?;
+
+    if parsed.code().value() != code || !parsed.is_valid() {
+        return Err("The phone number is not valid".to_string());
+    } // end if
+
+    // The leads have to be reachable with the number.
+    let number_type = parsed.number_type(&DATABASE);
+    if matches!(
+        number_type,
+        Type::PremiumRate | Type::SharedCost | Type::Emergency | Type::Unknown
+    ) {
+        return Err("The phone number cannot be used".to_string());
+    } // end if
+
+    Ok(NormalizedPhone {
+        country_code: i32::from(code),
+        region: parsed.country().id().map(|id| id.as_ref().to_string()),
+        number_type,
+        e164: parsed.format().mode(Mode::E164).to_string(),
+    }) // end Ok
+} // end fn parse
+
+/// This function checks whether a query has failed because
+/// another user has the same phone number.
+pub fn is_taken(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)
+            if info.constraint_name() == Some(UNIQUE_PHONE_NUMBER)
+    )
+} // end fn is_taken
+
+/// This struct represents a phone number as it is stored.
+#[derive(Debug, Clone)]
+pub struct StoredPhone {
+    pub user_id: i32,
+    pub phone_number_code: i32,
+    pub phone_number: String,
+} // end struct StoredPhone
+
+/// This enum contains the outcomes of bringing
+/// a stored phone number to E.164.
+#[derive(Debug, PartialEq)]
+pub enum Backfill {
+    // The number has to be replaced with the normalized one.
+    Update {
+        user_id: i32,
+        phone: NormalizedPhone,
+    },
+    // The number cannot be parsed, it is left as it is.
+    Invalid {
+        user_id: i32,
+        message: String,
+    },
+    // Another user has the same number, it is left as it is.
+    Taken {
+        user_id: i32,
+        e164: String,
+        owner_id: i32,
+    },
+} // end enum Backfill
+
+/// This function decides what happens to the phone numbers stored
+/// before they were normalized. The numbers that are already
+/// in E.164 are not reported.
+///
+/// NOTE: When several numbers are the same in E.164, the user who
+/// has it stored already or, otherwise, the oldest user keeps it,
+/// the others have to be merged or corrected by hand.
+pub fn plan_backfill(stored: &[StoredPhone]) -> Vec<Backfill> {
+    // Every stored number belongs to its user until it is replaced.
+    let mut owners = stored
+        .iter()
+        .map(|row| (row.phone_number.clone(), row.user_id))
+        .collect::<HashMap<String, i32>>();
+
+    let mut rows = stored.iter().collect::<Vec<_>>();
+    rows.sort_by_key(|row| row.user_id);
+
+    let mut plan = Vec::new();
+    for row in rows {
+        let phone = match parse(row.phone_number_code, &row.phone_number) {
+            Ok(phone) => phone,
+            Err(message) => {
+                plan.push(Backfill::Invalid {
+                    user_id: row.user_id,
+                    message,
+                });
+                continue;
+            } // end Err
+        }; // end match
+        if phone.e164 == row.phone_number && phone.country_code == row.phone_number_code {
+            continue;
+        } // end if
+
+        match owners.get(&phone.e164) {
+            Some(&owner_id) if owner_id != row.user_id => plan.push(Backfill::Taken {
+                user_id: row.user_id,
+                e164: phone.e164,
+                owner_id,
+            }),
+            _ => {
+                owners.insert(phone.e164.clone(), row.user_id);
+                plan.push(Backfill::Update {
+                    user_id: row.user_id,
+                    phone,
+                });
+            } // end _
+        } // end match
+    } // end for
+
+    plan
+} // end fn plan_backfill
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phone_numbers_are_normalized() {
+        let international = parse(7, "+7 999 123-45-67").unwrap();
+        assert_eq!(international.e164, "+79991234567");
+        assert_eq!(international.region.as_deref(), Some("RU"));
+        assert_eq!(international.number_type, Type::Mobile);
+
+        // The trunk prefix and the punctuation are dropped.
+        assert_eq!(parse(7, "8 (999) 123 45 67").unwrap(), international);
+        assert_eq!(parse(7, "9991234567").unwrap(), international);
+
+        assert_eq!(parse(1, "(201) 555-0123").unwrap().e164, "+12015550123");
+        assert_eq!(
+            parse(44, "020 7946 0958").unwrap().region.as_deref(),
+            Some("GB")
+        );
+
+        // The impossible numbers and codes are rejected.
+        assert!(parse(1, "1111111111").is_err());
+        assert!(parse(7, "999").is_err());
+        assert!(parse(7, "+1 201 555 0123").is_err());
+        assert!(parse(999, "9991234567").is_err());
+        assert!(parse(0, "9991234567").is_err());
+    }
+
+    #[test]
+    fn stored_phone_numbers_are_backfilled() {
+        let stored = |user_id: i32, phone_number_code: i32, phone_number: &str| StoredPhone {
+            user_id,
+            phone_number_code,
+            phone_number: phone_number.to_string(),
+        };
+        let plan = plan_backfill(&[
+            stored(5, 7, "8 999 123 45 67"),
+            stored(1, 7, "+79991234567"),
+            stored(2, 1, "(201) 555-0123"),
+            stored(3, 1, "201.555.0123"),
+            stored(4, 7, "999"),
+            stored(6, 44, "+442079460958"),
+        ]);
+
+        assert_eq!(
+            plan,
+            vec![
+                Backfill::Update {
+                    user_id: 2,
+                    phone: parse(1, "2015550123").unwrap(),
+                },
+                // The oldest user keeps the number.
+                Backfill::Taken {
+                    user_id: 3,
+                    e164: "+12015550123".to_string(),
+                    owner_id: 2,
+                },
+                Backfill::Invalid {
+                    user_id: 4,
+                    message: "The phone number is not valid".to_string(),
+                },
+                // The user who has stored the number in E.164 keeps it.
+                Backfill::Taken {
+                    user_id: 5,
+                    e164: "+79991234567".to_string(),
+                    owner_id: 1,
+                },
+            ]
+        );
+    }
+}
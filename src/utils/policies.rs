@@ -0,0 +1,132 @@
+// This file contains ownership-based access policies.
+//
+// auth_guard decides whether a client can reach a route at all,
+// these policies decide whether the client can reach a particular
+// resource, once the handler has found out who owns it.
+
+use axum::http::StatusCode;
+
+use crate::{middleware::auth_guard::AuthenticatedUser, models::User};
+
+use super::responses::DefaultResponse;
+
+/// This trait is implemented by resources that belong to a user.
+pub trait Owned {
+    /// This function returns the id of the user that owns the resource.
+    fn owner_id(&self) -> i32;
+} // end trait Owned
+
+impl Owned for User {
+    fn owner_id(&self) -> i32 {
+        self.id
+    } // end fn owner_id
+} // end impl Owned
+
+/// This trait describes a rule that decides whether a client
+/// can access a resource owned by a particular user.
+pub trait Policy {
+    /// This function checks if the client can access a resource
+    /// owned by the user with the given id.
+    fn allows(&self, client: &AuthenticatedUser, owner_id: i32) -> bool;
+} // end trait Policy
+
+/// This policy lets the owners access their own resources
+/// and the clients with any of the roles access everything.
+pub struct SelfOrRoles {
+    pub roles: &'static [&'static str],
+} // end struct SelfOrRoles
+
+impl Policy for SelfOrRoles {
+    fn allows(&self, client: &AuthenticatedUser, owner_id: i32) -> bool {
+        client.user.id == owner_id || client.has_any_role(self.roles)
+    } // end fn allows
+} // end impl Policy
+
+/// This is a policy for the personal data of users. The users can
+/// access their own records, Admins and Managers can access any.
+pub const SELF_OR_STAFF: SelfOrRoles = SelfOrRoles {
+    roles: &["Admin", "Manager"],
+};
+
+/// This is a policy for exporting and erasing the data of users.
+/// The users can do it themselves, Admins can act for anyone.
+pub const SELF_OR_ADMIN: SelfOrRoles = SelfOrRoles { roles: &["Admin"] };
+
+/// This function checks the policy for a resource loaded by a handler.
+/// It returns an error response that can be sent to the client as is.
+pub fn authorize<P: Policy, R: Owned>(
+    policy: &P,
+    client: &AuthenticatedUser,
+    resource: &R,
+) -> Result<(), DefaultResponse> {
+    authorize_owner(policy, client, resource.owner_id())
+} // end fn authorize
+
+/// This function checks the policy for a resource owned by the user
+/// with the given id.
+///
+/// NOTE: It can be used before the resource is loaded, so that the
+/// clients without access cannot find out whether it exists.
+pub fn authorize_owner<P: Policy>(
+    policy: &P,
+    client: &AuthenticatedUser,
+    owner_id: i32,
+) -> Result<(), DefaultResponse> {
+    if policy.allows(client, owner_id) {
+        return Ok(());
+    } // end if
+
+    Err(DefaultResponse {
+        status_code: StatusCode::UNAUTHORIZED,
+        message: Some("You do not have permissions to access this page".to_string()),
+        redirect: None,
+    }) // end Err
+} // end fn authorize_owner
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// This is a helper function that creates a client with the roles.
+    fn client(id: i32, roles: &[&str]) -> AuthenticatedUser {
+        AuthenticatedUser {
+            user: User {
+                id,
+                name: "John".to_string(),
+                email: None,
+                phone_number_code: 1,
+                phone_number: "+12015550123".to_string(),
+                password: None,
+                token: None,
+                verified: true,
+                created_at: chrono::Utc::now(),
+                confirmed_at: None,
+                custom_fields: serde_json::json!({}),
+                form_id: None,
+            },
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    } // end fn client
+
+    #[test]
+    fn self_or_staff_policy() {
+        let owner = client(1, &["User"]);
+        let stranger = client(2, &["User"]);
+        let manager = client(3, &["User", "Manager"]);
+
+        assert!(authorize(&SELF_OR_STAFF, &owner, &owner.user).is_ok());
+        assert!(authorize(&SELF_OR_STAFF, &stranger, &owner.user).is_err());
+        assert!(authorize(&SELF_OR_STAFF, &manager, &owner.user).is_ok());
+    }
+
+    #[test]
+    fn self_or_admin_policy() {
+        let owner = client(1, &["User"]);
+        let manager = client(3, &["User", "Manager"]);
+        let admin = client(4, &["User", "Admin"]);
+
+        assert!(authorize(&SELF_OR_ADMIN, &owner, &owner.user).is_ok());
+        assert!(authorize(&SELF_OR_ADMIN, &manager, &owner.user).is_err());
+        assert!(authorize(&SELF_OR_ADMIN, &admin, &owner.user).is_ok());
+    }
+}
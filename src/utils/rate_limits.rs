@@ -0,0 +1,90 @@
+// This file contains the limits of the requests to the public endpoints
+// that could be abused, e.g. to flood the inbox of a lead with links.
+//
+// The requests are counted by key (e.g. the address or the IP) in fixed
+// windows in "rate_limits", so that the limits hold across the replicas.
+// A window starts with the first request after the previous one has
+// ended. The keys are stored as SHA-256 hashes.
+
+use chrono::{Duration, Utc};
+use diesel::sql_types::{Integer, Text, Timestamptz};
+use diesel::{ExpressionMethods, QueryResult, QueryableByName};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use sha2::{Digest, Sha256};
+
+use crate::schema::rate_limits;
+
+/// This struct represents a limit of the requests.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    // The number of the requests allowed in a window.
+    pub hits: i32,
+    pub window: Duration,
+} // end struct RateLimit
+
+/// This struct represents the number of the requests in the window.
+#[derive(QueryableByName, Debug)]
+struct Hits {
+    #[diesel(sql_type = Integer)]
+    hits: i32,
+} // end struct Hits
+
+/// This function counts a request with the key.
+/// It returns false if the key has run out of the requests.
+pub async fn hit(conn: &mut AsyncPgConnection, key: &str, limit: RateLimit) -> QueryResult<bool> {
+    let now = Utc::now();
+    let counted = diesel::sql_query(
+        "INSERT INTO rate_limits (key_hash, hits, expires_at) VALUES ($1, 1, $2) \
+        ON CONFLICT (key_hash) DO UPDATE SET \
+        hits = CASE WHEN rate_limits.expires_at <= $3 THEN 1 ELSE rate_limits.hits + 1 END, \
+        expires_at = CASE WHEN rate_limits.expires_at <= $3 \
+        THEN EXCLUDED.expires_at ELSE rate_limits.expires_at END \
+        RETURNING hits",
+    )
+    .bind::<Text, _>(hex::encode(Sha256::digest(key.as_bytes())))
+    .bind::<Timestamptz, _>(now + limit.window)
+    .bind::<Timestamptz, _>(now)
+    .get_result::<Hits>(conn)
+    .await?;
+
+    Ok(counted.hits <= limit.hits)
+} // end fn hit
+
+/// This function forgets the windows that have ended.
+pub async fn forget_expired_limits(conn: &mut AsyncPgConnection) -> QueryResult<usize> {
+    diesel::delete(rate_limits::table)
+        .filter(rate_limits::expires_at.le(Utc::now()))
+        .execute(conn)
+        .await
+} // end fn forget_expired_limits
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::utils::test_db::connection;
+
+    #[tokio::test]
+    async fn keys_run_out_of_requests_until_the_window_ends() {
+        let mut conn = connection().await;
+        let limit = RateLimit {
+            hits: 2,
+            window: Duration::hours(1),
+        };
+
+        assert!(hit(&mut conn, "test:a", limit).await.unwrap());
+        assert!(hit(&mut conn, "test:a", limit).await.unwrap());
+        assert!(!hit(&mut conn, "test:a", limit).await.unwrap());
+        // The other keys are counted on their own.
+        assert!(hit(&mut conn, "test:b", limit).await.unwrap());
+
+        // A new window starts after the previous one has ended.
+        diesel::update(rate_limits::table)
+            .set(rate_limits::expires_at.eq(Utc::now() - Duration::seconds(1)))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        assert!(hit(&mut conn, "test:a", limit).await.unwrap());
+        assert!(forget_expired_limits(&mut conn).await.unwrap() >= 1);
+    }
+}
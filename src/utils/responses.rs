@@ -19,6 +19,20 @@ pub struct LoginResponse {
     pub token: Option<String>,
 }
 
+impl DefaultResponse {
+    /// This function logs an error and converts it to a response with
+    /// a default error message, in order not to disclose some
+    /// information that could be used to destroy the work of servers.
+    pub fn server_error(error: impl std::fmt::Display) -> Self {
+        eprintln!("{}", error);
+        DefaultResponse {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            message: Some("Something went wrong on the server side".to_string()),
+            redirect: None,
+        }
+    } // end fn server_error
+} // end impl DefaultResponse
+
 /// This is a required implementation of IntoResponse for DefaultResponse.
 impl IntoResponse for DefaultResponse {
     fn into_response(self) -> axum::response::Response {
@@ -61,3 +75,28 @@ pub struct LoginResponseJson {
     #[schema(example = "93$3vs$l3#$^*((*$#@%@#af49284")]
     pub token: Option<String>,
 }
+
+/// This structure represents a user record that is sent to the client.
+/// NOTE: It never contains the password or the token of the user.
+#[derive(Serialize, ToSchema)]
+pub struct UserResponseJson {
+    #[schema(example = 1)]
+    pub id: i32,
+    #[schema(example = "John")]
+    pub name: String,
+    #[schema(example = "john@gmail.com")]
+    pub email: Option<String>,
+    #[schema(example = 1)]
+    pub phone_number_code: i32,
+    #[schema(example = "+12015550123")]
+    pub phone_number: String,
+    #[schema(example = true)]
+    pub verified: bool,
+    // If it is null, then the subscription is pending.
+    #[schema(example = "2023-07-05T12:00:00Z")]
+    pub confirmed_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[schema(example = json!({"company": "Acme"}))]
+    pub custom_fields: serde_json::Value,
+    #[schema(example = json!(["User"]))]
+    pub roles: Vec<String>,
+}
@@ -0,0 +1,413 @@
+// This file contains the retention policies of the stored data.
+//
+// Every class of data is kept for a number of days, which is set in
+// the RETENTION_<CLASS>_DAYS environment variable, 0 keeps the data
+// forever:
+//   unconfirmed_leads   (30)  - the leads that have never confirmed
+//                               the subscription nor registered, out
+//                               of those who were sent the link;
+//   attribution         (730) - the marketing attribution of the leads;
+//   audit_log           (365) - the entries of the audit log;
+//   webhook_deliveries  (30)  - the deliveries of the events;
+//   session_tokens      (1)   - the session tokens after they expire.
+//
+// The purge job removes the expired data every hour. With
+// RETENTION_DRY_RUN=true it only reports how much would be removed.
+// The numbers are exported as Prometheus metrics.
+//
+// NOTE: The leads with a role (e.g. the staff) are never removed, nor
+// are the leads without an email and the imported ones, they have never
+// been asked to confirm anything.
+
+use std::env;
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::{ExpressionMethods, QueryDsl, QueryResult};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::schema::{
+    audit_log, lead_attribution, lead_submissions, users, users_roles, webhook_deliveries,
+};
+
+use super::{
+    jwt::jwt_expiry,
+    lazy_static::{RETENTION_EXPIRED_ROWS, RETENTION_PURGED_ROWS_TOTAL},
+    leads,
+};
+
+// The number of rows that are removed at once,
+// so that the tables are not locked for long.
+const BATCH_SIZE: i64 = 1000;
+
+/// This enum contains the classes of the data with their own retention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataClass {
+    UnconfirmedLeads,
+    Attribution,
+    AuditLog,
+    WebhookDeliveries,
+    SessionTokens,
+} // end enum DataClass
+
+impl DataClass {
+    pub const ALL: [DataClass; 5] = [
+        DataClass::UnconfirmedLeads,
+        DataClass::Attribution,
+        DataClass::AuditLog,
+        DataClass::WebhookDeliveries,
+        DataClass::SessionTokens,
+    ];
+
+    /// This function returns the name of the class
+    /// used in the settings and in the metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            DataClass::UnconfirmedLeads => "unconfirmed_leads",
+            DataClass::Attribution => "attribution",
+            DataClass::AuditLog => "audit_log",
+            DataClass::WebhookDeliveries => "webhook_deliveries",
+            DataClass::SessionTokens => "session_tokens",
+        } // end match
+    } // end fn name
+
+    /// This function returns the number of days the data is kept for
+    /// if the retention is not set.
+    fn default_days(self) -> u32 {
+        match self {
+            DataClass::UnconfirmedLeads => 30,
+            DataClass::Attribution => 730,
+            DataClass::AuditLog => 365,
+            DataClass::WebhookDeliveries => 30,
+            DataClass::SessionTokens => 1,
+        } // end match
+    } // end fn default_days
+} // end impl DataClass
+
+/// This struct represents the retention of a class of data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub class: DataClass,
+    // The number of days the data is kept for.
+    pub days: u32,
+} // end struct RetentionPolicy
+
+/// This struct contains the result of the purge of a class of data.
+#[derive(Debug)]
+pub struct PurgeResult {
+    pub policy: RetentionPolicy,
+    // The number of the removed rows, or of the rows
+    // that would be removed in a dry run.
+    pub rows: usize,
+} // end struct PurgeResult
+
+/// This struct contains the retention policies.
+#[derive(Debug)]
+pub struct Retention {
+    // The classes that are kept forever are absent.
+    pub policies: Vec<RetentionPolicy>,
+    // Only report what would be removed.
+    pub dry_run: bool,
+} // end struct Retention
+
+impl Retention {
+    /// This function reads the retention policies from the environment.
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(|name| env::var(name).ok())
+    } // end fn from_env
+
+    /// This function reads the retention policies from the variables.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut policies = Vec::new();
+        for class in DataClass::ALL {
+            let name = format!("RETENTION_{}_DAYS", class.name().to_uppercase());
+            let days = match var(&name) {
+                Some(days) if !days.trim().is_empty() => days
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|_error| format!("{} must be a number of days", name))?,
+                _ => class.default_days(),
+            }; // end match
+            if days > 0 {
+                policies.push(RetentionPolicy { class, days });
+            } // end if
+        } // end for
+
+        let dry_run = var("RETENTION_DRY_RUN").is_some_and(|value| value == "true");
+
+        Ok(Retention { policies, dry_run })
+    } // end fn from_vars
+
+    /// This function removes the data that has expired, or only counts
+    /// it in a dry run, and records the numbers in the metrics.
+    pub async fn purge(&self, conn: &mut AsyncPgConnection) -> QueryResult<Vec<PurgeResult>> {
+        let mut results = Vec::new();
+
+        for policy in &self.policies {
+            let rows = if self.dry_run {
+                count_expired(conn, policy).await?
+            } else {
+                let mut rows = 0;
+                loop {
+                    let removed = purge_batch(conn, policy).await?;
+                    rows += removed;
+                    if removed < BATCH_SIZE as usize {
+                        break;
+                    } // end if
+                } // end loop
+                RETENTION_PURGED_ROWS_TOTAL
+                    .with_label_values(&[policy.class.name()])
+                    .inc_by(rows as u64);
+                rows
+            }; // end if
+
+            // The data that is still there after the run.
+            RETENTION_EXPIRED_ROWS
+                .with_label_values(&[policy.class.name()])
+                .set(if self.dry_run { rows as i64 } else { 0 });
+
+            results.push(PurgeResult {
+                policy: *policy,
+                rows,
+            });
+        } // end for
+
+        Ok(results)
+    } // end fn purge
+} // end impl Retention
+
+/// This function returns a query of the unconfirmed leads
+/// created before the deadline.
+fn unconfirmed_leads(deadline: DateTime<Utc>) -> users::BoxedQuery<'static, diesel::pg::Pg> {
+    users::table
+        .filter(users::confirmed_at.is_null())
+        .filter(users::verified.eq(false))
+        .filter(users::created_at.le(deadline))
+        // Only the leads that have been sent the confirmation link expire.
+        .filter(users::email.is_not_null())
+        .filter(diesel::dsl::exists(
+            lead_submissions::table
+                .filter(lead_submissions::user_id.eq(users::id))
+                .filter(lead_submissions::created_lead.eq(true))
+                .filter(lead_submissions::source.eq(leads::FROM_FORM))
+                .filter(lead_submissions::email.is_not_null()),
+        ))
+        // The leads that have come back recently are kept.
+        .filter(diesel::dsl::not(diesel::dsl::exists(
+            lead_submissions::table
+                .filter(lead_submissions::user_id.eq(users::id))
+                .filter(lead_submissions::created_at.gt(deadline)),
+        )))
+        .filter(diesel::dsl::not(diesel::dsl::exists(
+            users_roles::table.filter(users_roles::user_id.eq(users::id)),
+        )))
+        .into_boxed()
+} // end fn unconfirmed_leads
+
+/// This function returns the session tokens that expired before the deadline.
+async fn stale_tokens(
+    conn: &mut AsyncPgConnection,
+    deadline: DateTime<Utc>,
+) -> QueryResult<Vec<String>> {
+    let tokens = users::table
+        .filter(users::token.is_not_null())
+        .select(users::token)
+        .load::<Option<String>>(conn)
+        .await?;
+
+    // The tokens that cannot be read are of no use either.
+    Ok(tokens
+        .into_iter()
+        .flatten()
+        .filter(|token| jwt_expiry(token).is_none_or(|expiry| expiry <= deadline))
+        .collect())
+} // end fn stale_tokens
+
+/// This function counts the rows of the class that have expired.
+async fn count_expired(
+    conn: &mut AsyncPgConnection,
+    policy: &RetentionPolicy,
+) -> QueryResult<usize> {
+    let deadline = Utc::now() - Duration::days(policy.days.into());
+
+    let count = match policy.class {
+        DataClass::UnconfirmedLeads => {
+            unconfirmed_leads(deadline)
+                .count()
+                .get_result::<i64>(conn)
+                .await?
+        }
+        DataClass::Attribution => {
+            lead_attribution::table
+                .filter(lead_attribution::created_at.le(deadline))
+                .count()
+                .get_result::<i64>(conn)
+                .await?
+        }
+        DataClass::AuditLog => {
+            audit_log::table
+                .filter(audit_log::created_at.le(deadline))
+                .count()
+                .get_result::<i64>(conn)
+                .await?
+        }
+        DataClass::WebhookDeliveries => {
+            webhook_deliveries::table
+                .filter(webhook_deliveries::status.ne(super::webhooks::PENDING))
+                .filter(webhook_deliveries::created_at.le(deadline))
+                .count()
+                .get_result::<i64>(conn)
+                .await?
+        }
+        DataClass::SessionTokens => stale_tokens(conn, deadline).await?.len() as i64,
+    }; // end match
+
+    Ok(count as usize)
+} // end fn count_expired
+
+/// This function removes a batch of the rows of the class
+/// that have expired. It returns the number of removed rows.
+async fn purge_batch(conn: &mut AsyncPgConnection, policy: &RetentionPolicy) -> QueryResult<usize> {
+    let deadline = Utc::now() - Duration::days(policy.days.into());
+
+    match policy.class {
+        DataClass::UnconfirmedLeads => {
+            let ids = unconfirmed_leads(deadline)
+                .select(users::id)
+                .limit(BATCH_SIZE)
+                .load::<i32>(conn)
+                .await?;
+            diesel::delete(users::table.filter(users::id.eq_any(ids)))
+                .execute(conn)
+                .await
+        }
+        DataClass::Attribution => {
+            let ids = lead_attribution::table
+                .filter(lead_attribution::created_at.le(deadline))
+                .select(lead_attribution::id)
+                .limit(BATCH_SIZE)
+                .load::<i32>(conn)
+                .await?;
+            diesel::delete(lead_attribution::table.filter(lead_attribution::id.eq_any(ids)))
+                .execute(conn)
+                .await
+        }
+        DataClass::AuditLog => {
+            let ids = audit_log::table
+                .filter(audit_log::created_at.le(deadline))
+                .select(audit_log::id)
+                .limit(BATCH_SIZE)
+                .load::<i32>(conn)
+                .await?;
+            diesel::delete(audit_log::table.filter(audit_log::id.eq_any(ids)))
+                .execute(conn)
+                .await
+        }
+        DataClass::WebhookDeliveries => {
+            let ids = webhook_deliveries::table
+                .filter(webhook_deliveries::status.ne(super::webhooks::PENDING))
+                .filter(webhook_deliveries::created_at.le(deadline))
+                .select(webhook_deliveries::id)
+                .limit(BATCH_SIZE)
+                .load::<i32>(conn)
+                .await?;
+            diesel::delete(webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(ids)))
+                .execute(conn)
+                .await
+        }
+        // NOTE: All the stale tokens are removed at once, a token that
+        // has been replaced by a new login meanwhile is left alone.
+        DataClass::SessionTokens => {
+            let tokens = stale_tokens(conn, deadline).await?;
+            diesel::update(users::table.filter(users::token.eq_any(tokens)))
+                .set(users::token.eq(None::<String>))
+                .execute(conn)
+                .await
+        }
+    } // end match
+} // end fn purge_batch
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::{
+        models::NewUser,
+        utils::{leads::upsert_lead, test_db},
+    };
+
+    #[test]
+    fn retention_is_read_from_the_variables() {
+        let retention = |vars: &[(&str, &str)]| {
+            Retention::from_vars(|name| {
+                vars.iter()
+                    .find(|(var, _value)| *var == name)
+                    .map(|(_var, value)| value.to_string())
+            })
+        };
+        let retention = retention(&[
+            ("RETENTION_ATTRIBUTION_DAYS", "90"),
+            ("RETENTION_AUDIT_LOG_DAYS", "0"),
+        ])
+        .unwrap();
+
+        let days = |class| {
+            retention
+                .policies
+                .iter()
+                .find(|policy| policy.class == class)
+                .map(|policy| policy.days)
+        };
+        assert_eq!(days(DataClass::Attribution), Some(90));
+        assert_eq!(days(DataClass::UnconfirmedLeads), Some(30));
+        assert_eq!(days(DataClass::AuditLog), None);
+        assert!(!retention.dry_run);
+
+        let invalid = Retention::from_vars(|name| {
+            (name == "RETENTION_WEBHOOK_DELIVERIES_DAYS").then(|| "a month".to_string())
+        });
+        assert!(invalid.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_the_leads_asked_to_confirm_expire() {
+        let mut conn = test_db::connection().await;
+        let form_id = test_db::default_form_id(&mut conn).await;
+
+        let mut ids = Vec::new();
+        for (email, phone_number, source) in [
+            (
+                Some("expiring@example.com"),
+                "+12025550240",
+                leads::FROM_FORM,
+            ),
+            (None, "+12025550241", leads::FROM_FORM),
+            (
+                Some("imported@example.com"),
+                "+12025550242",
+                leads::FROM_IMPORT,
+            ),
+        ] {
+            let user = NewUser {
+                name: "John".to_string(),
+                email: email.map(str::to_string),
+                phone_number_code: 1,
+                phone_number: phone_number.to_string(),
+                password: None,
+            };
+            let lead = upsert_lead(&mut conn, &user, &json!({}), form_id, source)
+                .await
+                .unwrap();
+            ids.push(lead.user.id);
+        } // end for
+
+        let expired = unconfirmed_leads(Utc::now() + Duration::days(1))
+            .filter(users::id.eq_any(&ids))
+            .select(users::id)
+            .load::<i32>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(expired, vec![ids[0]]);
+    }
+}
@@ -0,0 +1,206 @@
+// This file contains the tools for granting roles to users.
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::dsl::{now, Gt, IsNull, Or};
+use diesel::sql_types::{Bool, Nullable};
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, QueryResult};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde_json::json;
+
+use crate::{
+    models::{NewAuditEntry, NewRoleGrant},
+    schema::{roles, users_roles},
+};
+
+use super::audit;
+
+/// This type represents the filter of the role grants that are active.
+pub type Active =
+    Or<IsNull<users_roles::expires_at>, Gt<users_roles::expires_at, now>, Nullable<Bool>>;
+
+/// This function returns the filter of the role grants that are active.
+///
+/// NOTE: The expired grants are ignored even if the background
+/// job has not removed them yet.
+pub fn active() -> Active {
+    users_roles::expires_at
+        .is_null()
+        .or(users_roles::expires_at.gt(now))
+} // end fn active
+
+/// This function checks if a grant with the expiration is active at the moment.
+pub fn is_active(expires_at: Option<DateTime<Utc>>, moment: DateTime<Utc>) -> bool {
+    match expires_at {
+        Some(expires_at) => expires_at > moment,
+        None => true,
+    } // end match
+} // end fn is_active
+
+/// This function returns the moment a grant expires at, either after
+/// the number of days or at the given moment. It returns None if
+/// the grant is permanent.
+pub fn expiry(
+    days: Option<u32>,
+    expires_at: Option<DateTime<Utc>>,
+    moment: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    match days {
+        Some(days) => Some(moment + Duration::days(days.into())),
+        None => expires_at,
+    } // end match
+} // end fn expiry
+
+/// This function loads the id of a role by its title.
+pub async fn find_role_id(conn: &mut AsyncPgConnection, title: &str) -> QueryResult<Option<i32>> {
+    roles::table
+        .filter(roles::title.eq(title))
+        .select(roles::id)
+        .first::<i32>(conn)
+        .await
+        .optional()
+} // end fn find_role_id
+
+/// This function grants a role to a user and records it in the audit log.
+/// It returns false if the user already has a permanent grant
+/// of the role, in this case nothing is changed.
+///
+/// NOTE: Temporary grants do not replace each other, every one of
+/// them expires on its own.
+pub async fn grant_role(conn: &mut AsyncPgConnection, grant: NewRoleGrant) -> QueryResult<bool> {
+    conn.transaction::<bool, diesel::result::Error, _>(|conn| {
+        async move {
+            // Check if the role has already been granted for good.
+            let permanent = users_roles::table
+                .filter(users_roles::user_id.eq(grant.user_id))
+                .filter(users_roles::role_id.eq(grant.role_id))
+                .filter(users_roles::expires_at.is_null())
+                .count()
+                .get_result::<i64>(conn)
+                .await?;
+            if permanent > 0 {
+                return Ok(false);
+            } // end if
+
+            diesel::insert_into(users_roles::table)
+                .values(&grant)
+                .execute(conn)
+                .await?;
+
+            audit::record(
+                conn,
+                &[NewAuditEntry {
+                    user_id: Some(grant.user_id),
+                    action: "role_grant.created".to_string(),
+                    details: json!({
+                        "role_id": grant.role_id,
+                        "granted_by": grant.granted_by,
+                        "expires_at": grant.expires_at,
+                        "reason": grant.reason,
+                    }),
+                }],
+            )
+            .await?;
+
+            Ok(true)
+        }
+        .scope_boxed()
+    })
+    .await
+} // end fn grant_role
+
+/// This function removes all the expired role grants and records
+/// every removal in the audit log. It returns the number of
+/// removed grants.
+///
+/// NOTE: It is safe to run concurrently on several replicas,
+/// every grant is removed and recorded exactly once.
+pub async fn expire_role_grants(conn: &mut AsyncPgConnection) -> QueryResult<usize> {
+    conn.transaction::<usize, diesel::result::Error, _>(|conn| {
+        async move {
+            let expired = diesel::delete(users_roles::table)
+                .filter(users_roles::expires_at.le(diesel::dsl::now))
+                .returning((
+                    users_roles::user_id,
+                    users_roles::role_id,
+                    users_roles::granted_by,
+                    users_roles::expires_at,
+                    users_roles::reason,
+                ))
+                .get_results::<(
+                    i32,
+                    i32,
+                    Option<i32>,
+                    Option<chrono::DateTime<chrono::Utc>>,
+                    Option<String>,
+                )>(conn)
+                .await?;
+
+            let entries: Vec<NewAuditEntry> = expired
+                .into_iter()
+                .map(
+                    |(user_id, role_id, granted_by, expires_at, reason)| NewAuditEntry {
+                        user_id: Some(user_id),
+                        action: "role_grant.expired".to_string(),
+                        details: json!({
+                            "role_id": role_id,
+                            "granted_by": granted_by,
+                            "expires_at": expires_at,
+                            "reason": reason,
+                        }),
+                    },
+                )
+                .collect();
+            audit::record(conn, &entries).await?;
+
+            Ok(entries.len())
+        }
+        .scope_boxed()
+    })
+    .await
+} // end fn expire_role_grants
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grants_expire_in_time() {
+        let moment = Utc::now();
+
+        assert!(is_active(None, moment));
+        assert!(is_active(Some(moment + Duration::seconds(1)), moment));
+        assert!(!is_active(Some(moment), moment));
+        assert!(!is_active(Some(moment - Duration::days(1)), moment));
+
+        assert_eq!(expiry(None, None, moment), None);
+        assert_eq!(
+            expiry(Some(7), None, moment),
+            Some(moment + Duration::days(7))
+        );
+        assert_eq!(
+            expiry(None, Some(moment + Duration::hours(1)), moment),
+            Some(moment + Duration::hours(1))
+        );
+        // A grant for 0 days has expired right away.
+        assert!(!is_active(expiry(Some(0), None, moment), moment));
+    }
+
+    #[test]
+    fn expired_grants_are_filtered_out() {
+        let query = users_roles::table
+            .filter(active())
+            .select(users_roles::user_id);
+
+        assert_eq!(
+            diesel::debug_query::<diesel::pg::Pg, _>(&query)
+                .to_string()
+                .split(" -- ")
+                .next()
+                .unwrap(),
+            "SELECT \"users_roles\".\"user_id\" FROM \"users_roles\" \
+            WHERE ((\"users_roles\".\"expires_at\" IS NULL) \
+            OR (\"users_roles\".\"expires_at\" > CURRENT_TIMESTAMP))"
+        );
+    }
+}
@@ -0,0 +1,510 @@
+// This file contains the scoring of the leads.
+//
+// A rule matches a field of the lead with an operator and adds its
+// points (negative ones as well) to the score of every lead it matches.
+// The fields are:
+//   name, email, email_domain, phone_number_code, form   - the lead;
+//   utm_source, utm_medium, utm_campaign, utm_term,
+//   utm_content, referrer, landing_url                   - the attribution;
+//   confirmed, registered, verified, referrals, tags     - the engagement;
+//   custom.<name>                                        - the custom fields.
+//
+// The operators are "exists" (the field is set, not empty and not false),
+// "equals", "not_equals", "contains" (a part of a text or an item of the
+// tags), "gte" and "lte" (numbers). The texts are compared ignoring case.
+// No operator but "exists" matches a lead without the field, e.g.
+// "not_equals" does not match the leads that have not said anything.
+//
+// A lead is scored again when it submits a form, confirms the
+// subscription, registers, is tagged or imported, all the leads are
+// scored again when the rules change and every 6 hours. Every change
+// of a score is kept in the history.
+//
+// NOTE: The emails are plain text, so the opened emails cannot be
+// tracked and are not a part of the engagement. Scoring them needs
+// HTML emails with a tracking pixel, which is a change of its own.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use chrono::Utc;
+use diesel::dsl::sql;
+use diesel::sql_types::{Array, BigInt, Text};
+use diesel::upsert::excluded;
+use diesel::{ExpressionMethods, NullableExpressionMethods, QueryDsl, QueryResult};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use utoipa::ToSchema;
+
+use crate::{
+    models::ScoringRule,
+    schema::{forms, lead_attribution, lead_score_history, lead_scores, scoring_rules, users},
+};
+
+// These are the fields of the leads the rules can match.
+pub const FIELDS: [&str; 17] = [
+    "name",
+    "email",
+    "email_domain",
+    "phone_number_code",
+    "form",
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+    "referrer",
+    "landing_url",
+    "confirmed",
+    "registered",
+    "verified",
+    "referrals",
+    "tags",
+];
+
+// The prefix of the custom fields, e.g. "custom.company".
+pub const CUSTOM_PREFIX: &str = "custom.";
+
+// These are the reasons the leads are scored again for.
+pub const SUBMISSION: &str = "submission";
+pub const CONFIRMED: &str = "confirmed";
+pub const REGISTERED: &str = "registered";
+pub const TAGS: &str = "tags";
+pub const IMPORT: &str = "import";
+pub const RULES: &str = "rules";
+pub const REFRESH: &str = "refresh";
+
+// The number of the leads that are scored at once.
+const BATCH_SIZE: i64 = 500;
+
+/// This enum contains the operators of the rules.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operator {
+    Exists,
+    Equals,
+    NotEquals,
+    Contains,
+    Gte,
+    Lte,
+} // end enum Operator
+
+impl Operator {
+    const ALL: [Operator; 6] = [
+        Operator::Exists,
+        Operator::Equals,
+        Operator::NotEquals,
+        Operator::Contains,
+        Operator::Gte,
+        Operator::Lte,
+    ];
+
+    /// This function returns the name the operator is stored under.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operator::Exists => "exists",
+            Operator::Equals => "equals",
+            Operator::NotEquals => "not_equals",
+            Operator::Contains => "contains",
+            Operator::Gte => "gte",
+            Operator::Lte => "lte",
+        } // end match
+    } // end fn as_str
+
+    /// This function reads a stored operator.
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|operator| operator.as_str() == name)
+    } // end fn parse
+} // end impl Operator
+
+/// This function checks the settings of a rule.
+pub fn validate(field: &str, operator: Operator, value: Option<&Value>) -> Result<(), String> {
+    let custom = field
+        .strip_prefix(CUSTOM_PREFIX)
+        .is_some_and(|name| !name.is_empty());
+    if !custom && !FIELDS.contains(&field) {
+        return Err(format!(
+            "The field \"{}\" does not exist, the fields are {} and {}<name>",
+            field,
+            FIELDS.join(", "),
+            CUSTOM_PREFIX
+        ));
+    } // end if
+
+    match (operator, value) {
+        (Operator::Exists, _) => Ok(()),
+        (_, None | Some(Value::Null)) => Err(format!(
+            "The operator \"{}\" needs a value",
+            operator.as_str()
+        )),
+        (Operator::Gte | Operator::Lte, Some(value)) if number(value).is_none() => Err(format!(
+            "The operator \"{}\" needs a number",
+            operator.as_str()
+        )),
+        _ => Ok(()),
+    } // end match
+} // end fn validate
+
+/// This function reads a number from a number or a text.
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse::<f64>().ok(),
+        _ => None,
+    } // end match
+} // end fn number
+
+/// This function checks if a field is set.
+fn is_set(value: &Value) -> bool {
+    match value {
+        Value::Null | Value::Bool(false) => false,
+        Value::String(text) => !text.trim().is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        _ => true,
+    } // end match
+} // end fn is_set
+
+/// This function compares a field with a value,
+/// a list of values (e.g. the tags) matches if any of them does.
+fn equals(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Array(items), _) => items.iter().any(|item| equals(item, expected)),
+        (Value::String(actual), Value::String(expected)) => {
+            actual.to_lowercase() == expected.to_lowercase()
+        }
+        _ => match (number(actual), number(expected)) {
+            (Some(actual), Some(expected)) => actual == expected,
+            _ => actual == expected,
+        }, // end match
+    } // end match
+} // end fn equals
+
+/// This function checks if a field contains a value.
+fn contains(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Array(items), _) => items.iter().any(|item| equals(item, expected)),
+        (Value::String(actual), Value::String(expected)) => {
+            actual.to_lowercase().contains(&expected.to_lowercase())
+        }
+        _ => false,
+    } // end match
+} // end fn contains
+
+/// This function checks if a rule matches the facts about a lead.
+fn matches(rule: &ScoringRule, facts: &Map<String, Value>) -> bool {
+    let actual = facts.get(&rule.field).unwrap_or(&Value::Null);
+    let expected = rule.value.as_ref().unwrap_or(&Value::Null);
+    let compared = || {
+        number(actual)
+            .zip(number(expected))
+            .and_then(|(a, b)| a.partial_cmp(&b))
+    };
+
+    match Operator::parse(&rule.operator) {
+        Some(Operator::Exists) => is_set(actual),
+        Some(Operator::Equals) => equals(actual, expected),
+        Some(Operator::NotEquals) => !actual.is_null() && !equals(actual, expected),
+        Some(Operator::Contains) => contains(actual, expected),
+        Some(Operator::Gte) => compared().is_some_and(|order| order != Ordering::Less),
+        Some(Operator::Lte) => compared().is_some_and(|order| order != Ordering::Greater),
+        // The rules are checked when they are saved.
+        None => false,
+    } // end match
+} // end fn matches
+
+/// This function returns the score of a lead.
+pub fn score(rules: &[ScoringRule], facts: &Map<String, Value>) -> i32 {
+    rules
+        .iter()
+        .filter(|rule| matches(rule, facts))
+        .fold(0, |score, rule| score.saturating_add(rule.points))
+} // end fn score
+
+/// This type represents the row the facts about a lead are read from.
+type LeadRow = (
+    i32,
+    String,
+    Option<String>,
+    i32,
+    bool,
+    bool,
+    bool,
+    Value,
+    Option<String>,
+    (
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+    ),
+    Vec<String>,
+    i64,
+);
+
+/// This function returns the id of a lead and the facts about it by field.
+fn into_facts(row: LeadRow) -> (i32, Map<String, Value>) {
+    let (
+        id,
+        name,
+        email,
+        phone_number_code,
+        confirmed,
+        registered,
+        verified,
+        custom_fields,
+        form,
+        (utm_source, utm_medium, utm_campaign, utm_term, utm_content, referrer, landing_url),
+        tags,
+        referrals,
+    ) = row;
+
+    let email_domain = email
+        .as_deref()
+        .and_then(|email| email.rsplit_once('@'))
+        .map(|(_local, domain)| domain.to_string());
+    let mut facts = json!({
+        "name": name,
+        "email": email,
+        "email_domain": email_domain,
+        "phone_number_code": phone_number_code,
+        "form": form,
+        "utm_source": utm_source,
+        "utm_medium": utm_medium,
+        "utm_campaign": utm_campaign,
+        "utm_term": utm_term,
+        "utm_content": utm_content,
+        "referrer": referrer,
+        "landing_url": landing_url,
+        "confirmed": confirmed,
+        "registered": registered,
+        "verified": verified,
+        "referrals": referrals,
+        "tags": tags,
+    });
+    if let (Some(facts), Value::Object(custom_fields)) = (facts.as_object_mut(), custom_fields) {
+        for (name, value) in custom_fields {
+            facts.insert(format!("{}{}", CUSTOM_PREFIX, name), value);
+        } // end for
+    } // end if
+
+    match facts {
+        Value::Object(facts) => (id, facts),
+        _ => (id, Map::new()),
+    } // end match
+} // end fn into_facts
+
+/// This function loads the facts about the leads.
+async fn load_facts(
+    conn: &mut AsyncPgConnection,
+    user_ids: &[i32],
+) -> QueryResult<Vec<(i32, Map<String, Value>)>> {
+    users::table
+        .left_join(forms::table)
+        .left_join(lead_attribution::table)
+        .filter(users::id.eq_any(user_ids))
+        .select((
+            users::id,
+            users::name,
+            users::email,
+            users::phone_number_code,
+            users::confirmed_at.is_not_null(),
+            users::password.is_not_null(),
+            users::verified,
+            users::custom_fields,
+            forms::slug.nullable(),
+            (
+                lead_attribution::utm_source.nullable(),
+                lead_attribution::utm_medium.nullable(),
+                lead_attribution::utm_campaign.nullable(),
+                lead_attribution::utm_term.nullable(),
+                lead_attribution::utm_content.nullable(),
+                lead_attribution::referrer.nullable(),
+                lead_attribution::landing_url.nullable(),
+            ),
+            sql::<Array<Text>>(
+                "ARRAY(SELECT lead_tags.tag FROM lead_tags WHERE lead_tags.user_id = users.id)",
+            ),
+            sql::<BigInt>("(SELECT COUNT(*) FROM waitlist WHERE waitlist.referred_by = users.id)"),
+        ))
+        .load::<LeadRow>(conn)
+        .await
+        .map(|leads| leads.into_iter().map(into_facts).collect())
+} // end fn load_facts
+
+/// This function scores the leads again and saves the scores that
+/// have changed. It returns the number of the changed scores.
+pub async fn rescore(
+    conn: &mut AsyncPgConnection,
+    user_ids: &[i32],
+    reason: &str,
+) -> QueryResult<usize> {
+    let rules = scoring_rules::table
+        .order_by(scoring_rules::id)
+        .load::<ScoringRule>(conn)
+        .await?;
+
+    let leads = load_facts(conn, user_ids).await?;
+    let user_ids = leads.iter().map(|(id, _facts)| *id).collect::<Vec<_>>();
+
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            // Lock the scores, so that a change is recorded only once.
+            let previous = lead_scores::table
+                .filter(lead_scores::user_id.eq_any(&user_ids))
+                .select((lead_scores::user_id, lead_scores::score))
+                .for_update()
+                .load::<(i32, i32)>(conn)
+                .await?
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+
+            let now = Utc::now();
+            let mut scores = Vec::new();
+            let mut changes = Vec::new();
+            for (user_id, facts) in &leads {
+                let score = score(&rules, facts);
+                let previous_score = previous.get(user_id).copied();
+                if previous_score == Some(score) {
+                    continue;
+                } // end if
+
+                scores.push((
+                    lead_scores::user_id.eq(*user_id),
+                    lead_scores::score.eq(score),
+                    lead_scores::updated_at.eq(now),
+                ));
+                changes.push((
+                    lead_score_history::user_id.eq(*user_id),
+                    lead_score_history::score.eq(score),
+                    lead_score_history::previous_score.eq(previous_score),
+                    lead_score_history::reason.eq(reason),
+                ));
+            } // end for
+
+            // Nothing has changed.
+            if scores.is_empty() {
+                return Ok(0);
+            } // end if
+
+            diesel::insert_into(lead_scores::table)
+                .values(&scores)
+                .on_conflict(lead_scores::user_id)
+                .do_update()
+                .set((
+                    lead_scores::score.eq(excluded(lead_scores::score)),
+                    lead_scores::updated_at.eq(excluded(lead_scores::updated_at)),
+                ))
+                .execute(conn)
+                .await?;
+            diesel::insert_into(lead_score_history::table)
+                .values(&changes)
+                .execute(conn)
+                .await
+        }
+        .scope_boxed()
+    })
+    .await
+} // end fn rescore
+
+/// This function scores a lead again, e.g. after it has changed.
+pub async fn rescore_lead(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    reason: &str,
+) -> QueryResult<usize> {
+    rescore(conn, &[user_id], reason).await
+} // end fn rescore_lead
+
+/// This function scores all the leads again batch by batch.
+/// It returns the number of the changed scores.
+pub async fn rescore_all(conn: &mut AsyncPgConnection, reason: &str) -> QueryResult<usize> {
+    let mut changed = 0;
+    let mut after = 0;
+
+    loop {
+        let user_ids = users::table
+            .filter(users::id.gt(after))
+            .select(users::id)
+            .order_by(users::id)
+            .limit(BATCH_SIZE)
+            .load::<i32>(conn)
+            .await?;
+        let Some(last) = user_ids.last() else {
+            return Ok(changed);
+        };
+        after = *last;
+
+        changed += rescore(conn, &user_ids, reason).await?;
+    } // end loop
+} // end fn rescore_all
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// This is a helper function that creates a rule.
+    fn rule(field: &str, operator: Operator, value: Value, points: i32) -> ScoringRule {
+        ScoringRule {
+            id: 1,
+            name: "A rule".to_string(),
+            field: field.to_string(),
+            operator: operator.as_str().to_string(),
+            value: Some(value),
+            points,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    } // end fn rule
+
+    #[test]
+    fn leads_are_scored_by_the_rules() {
+        let facts = json!({
+            "email_domain": "Acme.com",
+            "utm_source": "newsletter",
+            "confirmed": true,
+            "registered": false,
+            "referrals": 3,
+            "tags": ["hot", "vip"],
+            "custom.team_size": "25",
+        });
+        let facts = facts.as_object().unwrap();
+
+        let rules = [
+            rule("email_domain", Operator::Equals, json!("acme.com"), 20),
+            rule("utm_source", Operator::Contains, json!("letter"), 5),
+            rule("confirmed", Operator::Exists, Value::Null, 10),
+            rule("registered", Operator::Exists, Value::Null, 100),
+            rule("referrals", Operator::Gte, json!(3), 7),
+            rule("tags", Operator::Equals, json!("VIP"), 30),
+            rule("custom.team_size", Operator::Lte, json!(10), 50),
+            rule("custom.company", Operator::NotEquals, json!("Acme"), -4),
+            rule("utm_source", Operator::NotEquals, json!("ads"), 2),
+        ];
+
+        // The lead has not given its company.
+        assert_eq!(score(&rules, facts), 20 + 5 + 10 + 7 + 30 + 2);
+
+        let mut facts = facts.clone();
+        facts.insert("custom.company".to_string(), json!("Globex"));
+        assert_eq!(score(&rules, &facts), 20 + 5 + 10 + 7 + 30 + 2 - 4);
+        facts.insert("custom.company".to_string(), json!("ACME"));
+        assert_eq!(score(&rules, &facts), 20 + 5 + 10 + 7 + 30 + 2);
+    }
+
+    #[test]
+    fn rules_are_validated() {
+        assert!(validate("utm_source", Operator::Equals, Some(&json!("ads"))).is_ok());
+        assert!(validate("custom.company", Operator::Exists, None).is_ok());
+        assert!(validate("custom.", Operator::Exists, None).is_err());
+        assert!(validate("password", Operator::Exists, None).is_err());
+        assert!(validate("referrals", Operator::Gte, Some(&json!("many"))).is_err());
+        assert!(validate("name", Operator::Contains, None).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+// This file contains the tools for managing subscriptions.
+
+use diesel::{ExpressionMethods, QueryDsl, QueryResult};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::schema::{unsubscribes, users};
+
+/// This function marks the subscription of the user as confirmed.
+/// It returns None if the user does not exist, and whether the
+/// subscription has been confirmed just now otherwise.
+///
+/// NOTE: Confirming a confirmed subscription changes nothing.
+pub async fn confirm_subscription(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+) -> QueryResult<Option<bool>> {
+    // Set the confirmation time only once.
+    let confirmed = diesel::update(users::table)
+        .filter(users::id.eq(user_id))
+        .filter(users::confirmed_at.is_null())
+        .set(users::confirmed_at.eq(diesel::dsl::now))
+        .execute(conn)
+        .await?;
+    if confirmed > 0 {
+        return Ok(Some(true));
+    } // end if
+
+    users::table
+        .filter(users::id.eq(user_id))
+        .count()
+        .get_result::<i64>(conn)
+        .await
+        .map(|count| (count > 0).then_some(false))
+} // end fn confirm_subscription
+
+/// This function checks if a subscription with the email has been
+/// confirmed, i.e. if the recipient has opted in twice.
+pub async fn is_confirmed(conn: &mut AsyncPgConnection, email: &str) -> QueryResult<bool> {
+    users::table
+        .filter(users::email.eq(email.to_lowercase()))
+        .filter(users::confirmed_at.is_not_null())
+        .count()
+        .get_result::<i64>(conn)
+        .await
+        .map(|count| count > 0)
+} // end fn is_confirmed
+
+/// This function records that the recipient does not want
+/// to receive the marketing emails anymore.
+///
+/// NOTE: Unsubscribing twice changes nothing.
+pub async fn unsubscribe(conn: &mut AsyncPgConnection, email: &str) -> QueryResult<()> {
+    diesel::insert_into(unsubscribes::table)
+        .values(unsubscribes::email.eq(email.to_lowercase()))
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await
+        .map(|_inserted| ())
+} // end fn unsubscribe
+
+/// This function checks if the recipient has unsubscribed from the emails.
+pub async fn is_unsubscribed(conn: &mut AsyncPgConnection, email: &str) -> QueryResult<bool> {
+    unsubscribes::table
+        .filter(unsubscribes::email.eq(email.to_lowercase()))
+        .count()
+        .get_result::<i64>(conn)
+        .await
+        .map(|count| count > 0)
+} // end fn is_unsubscribed
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::utils::test_db::connection;
+
+    #[tokio::test]
+    async fn subscriptions_are_confirmed_once() {
+        let mut conn = connection().await;
+        let user_id = diesel::insert_into(users::table)
+            .values((
+                users::name.eq("John"),
+                users::email.eq("confirming@example.com"),
+                users::phone_number_code.eq(1),
+                users::phone_number.eq("+12025550280"),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(&mut conn)
+            .await
+            .unwrap();
+
+        assert!(!is_confirmed(&mut conn, "confirming@example.com")
+            .await
+            .unwrap());
+        assert_eq!(
+            confirm_subscription(&mut conn, user_id).await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            confirm_subscription(&mut conn, user_id).await.unwrap(),
+            Some(false)
+        );
+        assert!(is_confirmed(&mut conn, "confirming@example.com")
+            .await
+            .unwrap());
+
+        diesel::delete(users::table.find(user_id))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            confirm_subscription(&mut conn, user_id).await.unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,32 @@
+// This file contains the helpers of the tests that use the database.
+//
+// NOTE: The database of DATABASE_URL has to be initialized with
+// database_init/init.sql. Every test runs in a transaction that is
+// never committed, so the tests leave the database as it was.
+
+use std::env;
+
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+
+use crate::schema::forms;
+
+/// This function connects to the database
+/// in a transaction that is never committed.
+pub async fn connection() -> AsyncPgConnection {
+    dotenvy::dotenv().ok();
+    let database_url = env::var("DATABASE_URL").unwrap();
+    let mut conn = AsyncPgConnection::establish(&database_url).await.unwrap();
+    conn.begin_test_transaction().await.unwrap();
+    conn
+} // end fn connection
+
+/// This function returns the id of the default form.
+pub async fn default_form_id(conn: &mut AsyncPgConnection) -> i32 {
+    forms::table
+        .filter(forms::slug.eq("default"))
+        .select(forms::id)
+        .first::<i32>(conn)
+        .await
+        .unwrap()
+} // end fn default_form_id
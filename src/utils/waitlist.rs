@@ -0,0 +1,478 @@
+// This file contains the waitlist of the leads.
+//
+// Every new lead joins the end of the waitlist and gets a referral code.
+// The leads are ordered by their ticket (the order they have joined in)
+// minus their boost, i.e. the number of places they have skipped.
+// A signup with a referral code boosts the referrer according to the
+// rules set in the environment variables:
+// - WAITLIST_REFERRAL_BOOST is the number of places a referral skips (10).
+// - WAITLIST_MAX_REFERRALS is the number of referrals that are rewarded,
+//   0 means that there is no limit (20).
+// - WAITLIST_REWARD_ON is "signup" or "confirmation", i.e. whether the
+//   referrer is rewarded right away or once the subscription of the
+//   referred lead is confirmed ("confirmation").
+//
+// NOTE: The defaults keep the referrers from moving up with the
+// leads they make up, since every one of them has to be confirmed.
+//
+// The leads that have subscribed before the waitlist was introduced
+// join it the first time they ask for their place.
+
+use std::env;
+
+use diesel::result::{DatabaseErrorKind, Error};
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, QueryResult};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use rand::Rng;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::models::{NewWaitlistEntry, WaitlistEntry};
+use crate::schema::waitlist;
+
+use super::links::referral_link;
+
+/// This is the name of the form field with the referral code.
+pub const REFERRAL_FIELD: &str = "referral_code";
+
+// The referral codes consist of the symbols that cannot be confused.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 8;
+
+/// This enum tells when a referrer is rewarded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RewardOn {
+    Signup,
+    Confirmation,
+} // end enum RewardOn
+
+/// This struct contains the rules of the referrals.
+#[derive(Debug, Clone)]
+pub struct ReferralRules {
+    // The number of places a referral skips.
+    pub boost: i32,
+    // The number of referrals that are rewarded, None means no limit.
+    pub max_referrals: Option<i64>,
+    pub reward_on: RewardOn,
+} // end struct ReferralRules
+
+impl ReferralRules {
+    /// This function reads the rules from the environment variables
+    /// WAITLIST_REFERRAL_BOOST, WAITLIST_MAX_REFERRALS and WAITLIST_REWARD_ON.
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| env::var(name).ok())
+    } // end fn from_env
+
+    /// This function reads the rules from the variables
+    /// that the function returns by their names.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        ReferralRules {
+            boost: var("WAITLIST_REFERRAL_BOOST")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(10),
+            max_referrals: match var("WAITLIST_MAX_REFERRALS")
+                .and_then(|value| value.parse::<i64>().ok())
+            {
+                Some(max_referrals) if max_referrals <= 0 => None,
+                max_referrals => Some(max_referrals.unwrap_or(20)),
+            }, // end match
+            reward_on: match var("WAITLIST_REWARD_ON").as_deref() {
+                Some("signup") => RewardOn::Signup,
+                _ => RewardOn::Confirmation,
+            }, // end match
+        } // end ReferralRules
+    } // end fn from_vars
+} // end impl ReferralRules
+
+/// This struct represents the place of a lead in the waitlist.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct WaitlistStatus {
+    // The place in the waitlist, starting with 1.
+    #[schema(example = 12)]
+    pub position: i64,
+    // The number of leads in the waitlist.
+    #[schema(example = 340)]
+    pub total: i64,
+    #[schema(example = "K7Q2M9XA")]
+    pub referral_code: String,
+    // The landing page with the referral code.
+    #[schema(example = "https://example.com/index.html?ref=K7Q2M9XA")]
+    pub referral_link: String,
+    // The number of leads that have signed up with the referral code.
+    #[schema(example = 3)]
+    pub referral_count: i64,
+} // end struct WaitlistStatus
+
+/// This function generates a random referral code.
+fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..CODE_LENGTH)
+        .map(|_index| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+} // end fn generate_code
+
+/// This function adds a new lead to the end of the waitlist.
+/// The lead that has referred them is rewarded if the rules allow it.
+///
+/// It returns the id of the rewarded referrer, so that they can be told
+/// about their new position.
+///
+/// NOTE: The unknown referral codes are ignored, and so is a lead
+/// that is in the waitlist already.
+pub async fn join(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    referral_code: Option<&str>,
+    rules: &ReferralRules,
+) -> QueryResult<Option<i32>> {
+    let referred_by = match referral_code.map(str::trim).filter(|code| !code.is_empty()) {
+        Some(code) => waitlist::table
+            .filter(waitlist::referral_code.eq(code.to_uppercase()))
+            .select(waitlist::user_id)
+            .first::<i32>(conn)
+            .await
+            .optional()?, // end Some
+        None => None,
+    }; // end match
+
+    // The codes are random, so a collision is rare, but possible.
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let inserted = diesel::insert_into(waitlist::table)
+            .values(NewWaitlistEntry {
+                user_id,
+                referral_code: generate_code(),
+                referred_by,
+            })
+            .on_conflict(waitlist::user_id)
+            .do_nothing()
+            .execute(conn)
+            .await;
+
+        match inserted {
+            Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _info))
+                if attempts < 5 => {}
+            Err(error) => return Err(error),
+            Ok(0) => return Ok(None),
+            Ok(_inserted) => break,
+        } // end match
+    } // end loop
+
+    match rules.reward_on {
+        RewardOn::Signup => reward_referrer(conn, user_id, rules).await,
+        RewardOn::Confirmation => Ok(None),
+    } // end match
+} // end fn join
+
+/// This function boosts the lead that has referred the user,
+/// unless they have already been rewarded for this user or have
+/// reached the limit of the rewarded referrals.
+/// It returns the id of the rewarded referrer.
+pub async fn reward_referrer(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    rules: &ReferralRules,
+) -> QueryResult<Option<i32>> {
+    let rules = rules.clone();
+
+    conn.transaction::<Option<i32>, Error, _>(|conn| {
+        async move {
+            // The entry is locked, so that a referral is rewarded once.
+            let entry = waitlist::table
+                .find(user_id)
+                .for_update()
+                .first::<WaitlistEntry>(conn)
+                .await
+                .optional()?;
+            let referrer = match entry {
+                Some(WaitlistEntry {
+                    referred_by: Some(referrer),
+                    referral_rewarded: false,
+                    ..
+                }) => referrer,
+                _ => return Ok(None),
+            }; // end match
+
+            // The referrer is locked as well, so that the limit holds.
+            waitlist::table
+                .find(referrer)
+                .for_update()
+                .select(waitlist::user_id)
+                .first::<i32>(conn)
+                .await?;
+            if let Some(max_referrals) = rules.max_referrals {
+                let rewarded = waitlist::table
+                    .filter(waitlist::referred_by.eq(referrer))
+                    .filter(waitlist::referral_rewarded.eq(true))
+                    .count()
+                    .get_result::<i64>(conn)
+                    .await?;
+                if rewarded >= max_referrals {
+                    return Ok(None);
+                } // end if
+            } // end if
+
+            diesel::update(waitlist::table.find(user_id))
+                .set(waitlist::referral_rewarded.eq(true))
+                .execute(conn)
+                .await?;
+            diesel::update(waitlist::table.find(referrer))
+                .set(waitlist::boost.eq(waitlist::boost + rules.boost))
+                .execute(conn)
+                .await?;
+
+            Ok(Some(referrer))
+        }
+        .scope_boxed()
+    })
+    .await
+} // end fn reward_referrer
+
+/// This function returns the place of the lead in the waitlist.
+/// It returns None if the lead is not in the waitlist.
+pub async fn status(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+) -> QueryResult<Option<WaitlistStatus>> {
+    let entry = match waitlist::table
+        .find(user_id)
+        .first::<WaitlistEntry>(conn)
+        .await
+        .optional()?
+    {
+        Some(entry) => entry,
+        None => return Ok(None),
+    }; // end match
+
+    // The leads with a smaller ticket minus boost are ahead,
+    // the earlier ticket wins a tie.
+    let rank = entry.ticket - entry.boost;
+    let ahead = waitlist::table
+        .filter(
+            (waitlist::ticket - waitlist::boost)
+                .lt(rank)
+                .or((waitlist::ticket - waitlist::boost)
+                    .eq(rank)
+                    .and(waitlist::ticket.lt(entry.ticket))),
+        )
+        .count()
+        .get_result::<i64>(conn)
+        .await?;
+
+    let total = waitlist::table.count().get_result::<i64>(conn).await?;
+
+    let referral_count = waitlist::table
+        .filter(waitlist::referred_by.eq(user_id))
+        .count()
+        .get_result::<i64>(conn)
+        .await?;
+
+    Ok(Some(WaitlistStatus {
+        position: ahead + 1,
+        total,
+        referral_link: referral_link(&entry.referral_code),
+        referral_code: entry.referral_code,
+        referral_count,
+    })) // end Ok
+} // end fn status
+
+/// This function returns the place of the lead in the waitlist. The lead
+/// that is not there yet (e.g. they have subscribed before the waitlist
+/// was introduced) joins the end of it. It returns None if there is
+/// no such lead.
+pub async fn place(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    rules: &ReferralRules,
+) -> QueryResult<Option<WaitlistStatus>> {
+    if let Some(place) = status(conn, user_id).await? {
+        return Ok(Some(place));
+    } // end if
+
+    match join(conn, user_id, None, rules).await {
+        Err(Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _info)) => Ok(None),
+        Err(error) => Err(error),
+        Ok(_referrer) => status(conn, user_id).await,
+    } // end match
+} // end fn place
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::utils::test_db::connection;
+
+    #[test]
+    fn referral_codes_are_unambiguous() {
+        for _index in 0..100 {
+            let code = generate_code();
+            assert_eq!(code.len(), CODE_LENGTH);
+            assert!(code.bytes().all(|symbol| CODE_ALPHABET.contains(&symbol)));
+            assert!(!code.contains(['0', 'O', '1', 'I']));
+        } // end for
+    }
+
+    #[test]
+    fn referral_rules_are_read() {
+        let rules = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<std::collections::HashMap<_, _>>();
+            ReferralRules::from_vars(|name| vars.get(name).cloned())
+        };
+
+        // The referrals are rewarded once they are confirmed, up to a limit.
+        let defaults = rules(&[]);
+        assert_eq!(defaults.boost, 10);
+        assert_eq!(defaults.max_referrals, Some(20));
+        assert_eq!(defaults.reward_on, RewardOn::Confirmation);
+
+        let custom = rules(&[
+            ("WAITLIST_REFERRAL_BOOST", "3"),
+            ("WAITLIST_MAX_REFERRALS", "5"),
+            ("WAITLIST_REWARD_ON", "signup"),
+        ]);
+        assert_eq!(custom.boost, 3);
+        assert_eq!(custom.max_referrals, Some(5));
+        assert_eq!(custom.reward_on, RewardOn::Signup);
+
+        // The limit can be lifted explicitly only.
+        assert_eq!(
+            rules(&[("WAITLIST_MAX_REFERRALS", "0")]).max_referrals,
+            None
+        );
+        assert_eq!(
+            rules(&[("WAITLIST_MAX_REFERRALS", "many")]).max_referrals,
+            Some(20)
+        );
+        assert_eq!(
+            rules(&[("WAITLIST_REWARD_ON", "nonsense")]).reward_on,
+            RewardOn::Confirmation
+        );
+    }
+
+    /// This is a helper function that creates a lead
+    /// without adding them to the waitlist.
+    async fn lead(conn: &mut AsyncPgConnection, phone_number: &str) -> i32 {
+        use crate::schema::users;
+
+        diesel::insert_into(users::table)
+            .values((
+                users::name.eq("John"),
+                users::phone_number_code.eq(1),
+                users::phone_number.eq(phone_number),
+            ))
+            .returning(users::id)
+            .get_result::<i32>(conn)
+            .await
+            .unwrap()
+    } // end fn lead
+
+    /// This is a helper function that returns the place of a lead.
+    async fn position(conn: &mut AsyncPgConnection, user_id: i32) -> i64 {
+        status(conn, user_id).await.unwrap().unwrap().position
+    } // end fn position
+
+    /// This is a helper function that returns the ticket of a lead.
+    async fn ticket(conn: &mut AsyncPgConnection, user_id: i32) -> i32 {
+        waitlist::table
+            .find(user_id)
+            .select(waitlist::ticket)
+            .first::<i32>(conn)
+            .await
+            .unwrap()
+    } // end fn ticket
+
+    #[tokio::test]
+    async fn referrals_move_the_referrer_up() {
+        let mut conn = connection().await;
+
+        // NOTE: The other tests can take tickets in between,
+        // so only the order of these leads is checked.
+        let mut leads = Vec::new();
+        for phone_number in [
+            "+12025550180",
+            "+12025550181",
+            "+12025550182",
+            "+12025550183",
+        ] {
+            let user_id = lead(&mut conn, phone_number).await;
+            join(
+                &mut conn,
+                user_id,
+                None,
+                &ReferralRules::from_vars(|_name| None),
+            )
+            .await
+            .unwrap();
+            leads.push(user_id);
+        } // end for
+        let mut positions = Vec::new();
+        for user_id in &leads {
+            positions.push(position(&mut conn, *user_id).await);
+        } // end for
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // The last lead skips to the rank of the second one,
+        // the earlier ticket wins the tie.
+        let referrer = leads[3];
+        let rules = ReferralRules {
+            boost: ticket(&mut conn, referrer).await - ticket(&mut conn, leads[1]).await,
+            max_referrals: Some(1),
+            reward_on: RewardOn::Signup,
+        };
+        let code = status(&mut conn, referrer)
+            .await
+            .unwrap()
+            .unwrap()
+            .referral_code;
+        let referred = lead(&mut conn, "+12025550184").await;
+        assert_eq!(
+            join(&mut conn, referred, Some(&code.to_lowercase()), &rules)
+                .await
+                .unwrap(),
+            Some(referrer)
+        );
+        let order = [leads[0], leads[1], referrer, leads[2], referred];
+        let mut positions = Vec::new();
+        for user_id in order {
+            positions.push(position(&mut conn, user_id).await);
+        } // end for
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // The limit of the rewarded referrals holds.
+        let before = status(&mut conn, referrer).await.unwrap().unwrap();
+        let another = lead(&mut conn, "+12025550185").await;
+        assert_eq!(
+            join(&mut conn, another, Some(&code), &rules).await.unwrap(),
+            None
+        );
+        let after = status(&mut conn, referrer).await.unwrap().unwrap();
+        assert_eq!(after.position, before.position);
+        assert_eq!(after.referral_count, 2);
+    }
+
+    #[tokio::test]
+    async fn existing_leads_join_when_they_ask() {
+        let mut conn = connection().await;
+        let rules = ReferralRules::from_vars(|_name| None);
+
+        let user_id = lead(&mut conn, "+12025550186").await;
+        assert!(status(&mut conn, user_id).await.unwrap().is_none());
+
+        let first = place(&mut conn, user_id, &rules).await.unwrap().unwrap();
+        assert!(status(&mut conn, user_id).await.unwrap().is_some());
+
+        // Joining again changes nothing.
+        assert_eq!(join(&mut conn, user_id, None, &rules).await.unwrap(), None);
+        let again = place(&mut conn, user_id, &rules).await.unwrap().unwrap();
+        assert_eq!(again.referral_code, first.referral_code);
+
+        // There is no such lead.
+        assert!(place(&mut conn, -1, &rules).await.unwrap().is_none());
+    }
+}
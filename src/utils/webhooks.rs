@@ -0,0 +1,416 @@
+// This file contains the outbound webhooks.
+//
+// An event (e.g. a new lead) is queued as a delivery for every webhook
+// subscribed to it, then a background job posts it to the endpoint:
+//
+// POST <url>
+// X-Webhook-Id: <the id of the delivery>
+// X-Webhook-Event: lead.created
+// X-Webhook-Timestamp: <the unix time of the attempt>
+// X-Webhook-Signature: sha256=<hex of HMAC-SHA256("<timestamp>.<body>", secret)>
+//
+// {"id": 42, "event": "lead.created", "created_at": "...", "data": {...}}
+//
+// The receivers should check the signature and reject the old timestamps,
+// so that the deliveries cannot be replayed. The failed deliveries are
+// retried with an exponential backoff, the retries and the redeliveries
+// keep the id, so that the receivers can skip the duplicates.
+//
+// NOTE: The redirects are not followed, a redirect is a failed attempt.
+
+use std::time::Duration as StdDuration;
+
+use axum::http::header;
+use chrono::{Duration, Utc};
+use diesel::{ExpressionMethods, PgArrayExpressionMethods, QueryDsl, QueryResult};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+
+use crate::models::{NewWebhookDelivery, WebhookDelivery};
+use crate::schema::{webhook_deliveries, webhooks};
+
+// These are the events the webhooks can subscribe to.
+pub const LEAD_CREATED: &str = "lead.created";
+pub const USER_REGISTERED: &str = "user.registered";
+// The lead has verified their email by confirming the subscription.
+pub const USER_VERIFIED: &str = "user.verified";
+pub const EVENTS: [&str; 3] = [LEAD_CREATED, USER_REGISTERED, USER_VERIFIED];
+
+// These are the statuses of the deliveries.
+pub const PENDING: &str = "pending";
+pub const DELIVERED: &str = "delivered";
+pub const FAILED: &str = "failed";
+
+// The number of attempts before a delivery fails.
+const MAX_ATTEMPTS: i32 = 8;
+
+// The delay before the first retry, it doubles with every attempt.
+const FIRST_RETRY_DELAY: i64 = 30;
+
+// The longest delay between the attempts.
+const MAX_RETRY_DELAY: i64 = 6 * 60 * 60;
+
+// The number of the deliveries that are claimed at once.
+const BATCH_SIZE: i64 = 20;
+
+// For how long the claimed deliveries are hidden from the other
+// replicas, it is longer than the timeout of the requests, since
+// the deliveries of a batch are sent at the same time.
+const CLAIM_SECONDS: i64 = 60;
+
+// The timeout of a request to an endpoint.
+const REQUEST_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// This function generates a secret for a new webhook.
+pub fn generate_secret() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+} // end fn generate_secret
+
+/// This function returns the signature of a delivery,
+/// i.e. the value of the X-Webhook-Signature header.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+} // end fn sign
+
+/// This function returns the delay before the next attempt
+/// after the given number of the failed attempts.
+pub fn retry_delay(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 20) as u32;
+
+    Duration::seconds((FIRST_RETRY_DELAY << exponent).min(MAX_RETRY_DELAY))
+} // end fn retry_delay
+
+/// This function creates a client for the deliveries.
+pub fn client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+} // end fn client
+
+/// This function queues an event for the webhooks subscribed to it
+/// and wakes up the delivery.
+pub async fn emit(
+    conn: &mut AsyncPgConnection,
+    signal: &Notify,
+    event: &str,
+    data: &Value,
+) -> QueryResult<()> {
+    let subscribers = webhooks::table
+        .filter(webhooks::events.contains(vec![event]))
+        .select(webhooks::id)
+        .load::<i32>(conn)
+        .await?;
+
+    // Nobody is interested in the event.
+    if subscribers.is_empty() {
+        return Ok(());
+    } // end if
+
+    diesel::insert_into(webhook_deliveries::table)
+        .values(
+            subscribers
+                .into_iter()
+                .map(|webhook_id| NewWebhookDelivery {
+                    webhook_id,
+                    event,
+                    payload: data,
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)
+        .await?;
+
+    signal.notify_one();
+    Ok(())
+} // end fn emit
+
+/// This function claims the deliveries that are due, together with
+/// the URLs and the secrets of their webhooks.
+///
+/// NOTE: The claimed deliveries are postponed for a while, so that the
+/// other replicas do not send them too. If the replica dies, they are
+/// retried afterwards.
+async fn claim_due(
+    conn: &mut AsyncPgConnection,
+) -> QueryResult<Vec<(WebhookDelivery, String, String)>> {
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let now = Utc::now();
+            let ids = webhook_deliveries::table
+                .filter(webhook_deliveries::status.eq(PENDING))
+                .filter(webhook_deliveries::next_attempt_at.le(now))
+                .order_by(webhook_deliveries::next_attempt_at)
+                .limit(BATCH_SIZE)
+                .select(webhook_deliveries::id)
+                .for_update()
+                .skip_locked()
+                .load::<i32>(conn)
+                .await?;
+
+            diesel::update(webhook_deliveries::table)
+                .filter(webhook_deliveries::id.eq_any(&ids))
+                .set(webhook_deliveries::next_attempt_at.eq(now + Duration::seconds(CLAIM_SECONDS)))
+                .execute(conn)
+                .await?;
+
+            webhook_deliveries::table
+                .inner_join(webhooks::table)
+                .filter(webhook_deliveries::id.eq_any(&ids))
+                .select((
+                    webhook_deliveries::all_columns,
+                    webhooks::url,
+                    webhooks::secret,
+                ))
+                .load::<(WebhookDelivery, String, String)>(conn)
+                .await
+        }
+        .scope_boxed()
+    })
+    .await
+} // end fn claim_due
+
+/// This function posts a delivery to its endpoint. It returns the status
+/// code of the response, if there is one, and whether it has succeeded.
+async fn send(
+    client: &reqwest::Client,
+    delivery: &WebhookDelivery,
+    url: &str,
+    secret: &str,
+) -> (Option<i32>, Result<(), String>) {
+    let body = json!({
+        "id": delivery.id,
+        "event": delivery.event,
+        "created_at": delivery.created_at,
+        "data": delivery.payload,
+    })
+    .to_string();
+    let timestamp = Utc::now().timestamp();
+
+    let response = client
+        .post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Id", delivery.id)
+        .header("X-Webhook-Event", &delivery.event)
+        .header("X-Webhook-Timestamp", timestamp)
+        .header(
+            "X-Webhook-Signature",
+            sign(secret, timestamp, body.as_bytes()),
+        )
+        .body(body)
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => {
+            (Some(i32::from(response.status().as_u16())), Ok(()))
+        } // end Ok
+        Ok(response) => (
+            Some(i32::from(response.status().as_u16())),
+            Err(format!(
+                "The endpoint has responded with {}",
+                response.status()
+            )),
+        ),
+        Err(error) => (None, Err(error.to_string())),
+    } // end match
+} // end fn send
+
+/// This function records the result of an attempt to deliver an event.
+/// A failed delivery is retried later unless it has run out of attempts.
+async fn record_attempt(
+    conn: &mut AsyncPgConnection,
+    delivery: &WebhookDelivery,
+    response_status: Option<i32>,
+    result: Result<(), String>,
+) -> QueryResult<()> {
+    let attempts = delivery.attempts + 1;
+    let now = Utc::now();
+    let (status, next_attempt_at, delivered_at, last_error) = match result {
+        Ok(()) => (DELIVERED, now, Some(now), None),
+        Err(error) if attempts >= MAX_ATTEMPTS => (FAILED, now, None, Some(error)),
+        Err(error) => (PENDING, now + retry_delay(attempts), None, Some(error)),
+    }; // end match
+
+    diesel::update(webhook_deliveries::table.find(delivery.id))
+        .set((
+            webhook_deliveries::status.eq(status),
+            webhook_deliveries::attempts.eq(attempts),
+            webhook_deliveries::next_attempt_at.eq(next_attempt_at),
+            webhook_deliveries::response_status.eq(response_status),
+            webhook_deliveries::last_error.eq(last_error),
+            webhook_deliveries::delivered_at.eq(delivered_at),
+        ))
+        .execute(conn)
+        .await
+        .map(|_updated| ())
+} // end fn record_attempt
+
+/// This function sends the deliveries that are due.
+/// It returns the number of the attempted deliveries.
+pub async fn deliver_due(
+    conn: &mut AsyncPgConnection,
+    client: &reqwest::Client,
+) -> QueryResult<usize> {
+    let due = claim_due(conn).await?;
+    let count = due.len();
+
+    // The deliveries are sent at the same time, so that the whole batch
+    // is done within the timeout of a request, long before the claim expires.
+    let mut sending = JoinSet::new();
+    for (delivery, url, secret) in due {
+        let client = client.clone();
+        sending.spawn(async move {
+            let (response_status, result) = send(&client, &delivery, &url, &secret).await;
+            (delivery, response_status, result)
+        });
+    } // end for
+
+    while let Some(sent) = sending.join_next().await {
+        match sent {
+            Ok((delivery, response_status, result)) => {
+                record_attempt(conn, &delivery, response_status, result).await?
+            } // end Ok
+            // The delivery is retried once the claim expires.
+            Err(error) => eprintln!("{}", error),
+        } // end match
+    } // end while
+
+    Ok(count)
+} // end fn deliver_due
+
+/// This function queues a delivery of a webhook to be sent again
+/// right away with all the attempts. It returns false if there
+/// is no such delivery.
+pub async fn redeliver(
+    conn: &mut AsyncPgConnection,
+    signal: &Notify,
+    webhook_id: i32,
+    delivery_id: i32,
+) -> QueryResult<bool> {
+    let updated = diesel::update(webhook_deliveries::table.find(delivery_id))
+        .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+        .set((
+            webhook_deliveries::status.eq(PENDING),
+            webhook_deliveries::attempts.eq(0),
+            webhook_deliveries::next_attempt_at.eq(Utc::now()),
+        ))
+        .execute(conn)
+        .await?;
+
+    if updated > 0 {
+        signal.notify_one();
+    } // end if
+
+    Ok(updated > 0)
+} // end fn redeliver
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::State, http::HeaderMap, routing::post, Router};
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        assert_eq!(retry_delay(1), Duration::seconds(30));
+        assert_eq!(retry_delay(2), Duration::seconds(60));
+        assert_eq!(retry_delay(4), Duration::seconds(240));
+        assert_eq!(retry_delay(30), Duration::seconds(MAX_RETRY_DELAY));
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed() {
+        // A stand-in for an endpoint that remembers the last request.
+        type Received = Arc<Mutex<Option<(HeaderMap, String)>>>;
+        async fn receive(State(received): State<Received>, headers: HeaderMap, body: String) {
+            *received.lock().unwrap() = Some((headers, body));
+        }
+        let received = Received::default();
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(received.clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let delivery = WebhookDelivery {
+            id: 42,
+            webhook_id: 1,
+            event: LEAD_CREATED.to_string(),
+            payload: json!({"id": 7, "name": "John"}),
+            status: PENDING.to_string(),
+            attempts: 0,
+            next_attempt_at: Utc::now(),
+            response_status: None,
+            last_error: None,
+            created_at: Utc::now(),
+            delivered_at: None,
+        };
+        let (status, result) = send(&client().unwrap(), &delivery, &url, "secret").await;
+        assert_eq!(status, Some(200));
+        assert!(result.is_ok());
+
+        let (headers, body) = received.lock().unwrap().take().unwrap();
+        let header = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_string();
+        let timestamp = header("x-webhook-timestamp").parse::<i64>().unwrap();
+        assert_eq!(header("x-webhook-id"), "42");
+        assert_eq!(header("x-webhook-event"), LEAD_CREATED);
+        assert_eq!(
+            header("x-webhook-signature"),
+            sign("secret", timestamp, body.as_bytes())
+        );
+        assert_ne!(
+            header("x-webhook-signature"),
+            sign("another", timestamp, body.as_bytes())
+        );
+
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["id"], 42);
+        assert_eq!(body["data"]["name"], "John");
+
+        // The redirects are not followed.
+        let redirect = Router::new().route(
+            "/hook",
+            post(|| async { axum::response::Redirect::temporary("/elsewhere") }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let moved = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(redirect.into_make_service()),
+        );
+        let (status, result) = send(&client().unwrap(), &delivery, &moved, "secret").await;
+        assert_eq!(status, Some(307));
+        assert!(result.is_err());
+
+        // The endpoint is gone.
+        let (status, result) = send(
+            &client().unwrap(),
+            &delivery,
+            "http://127.0.0.1:1/hook",
+            "secret",
+        )
+        .await;
+        assert_eq!(status, None);
+        assert!(result.is_err());
+    }
+}